@@ -42,7 +42,6 @@ from anki.utils import (
     joinFields,
     maxID,
     splitFields,
-    stripHTMLMedia,
 )
 
 defaultConf = {
@@ -85,6 +84,7 @@ class _Collection:
         server: Optional["anki.storage.ServerData"] = None,
     ) -> None:
         self.backend = backend
+        backend.attach_collection(self)
         self._debugLog = not server
         self.db = db
         self.path = db._path
@@ -194,6 +194,20 @@ conf, models, decks, dconf, tags from col"""
         self.decks.load(decks, dconf)
         self.tags.load(tags)
 
+    def _flush_cached(self) -> None:
+        "Save pending changes to the config and managers before the backend reads them."
+        self.models.flush()
+        self.decks.flush()
+        self.tags.flush()
+        if self.db.mod:
+            self.flush()
+
+    def _reload_cached(self) -> None:
+        "Reload the config and managers after the backend has changed them."
+        self.load()
+        if isinstance(self.sched, V1Scheduler) != (self.schedVer() == 1):
+            self._loadScheduler()
+
     def setMod(self) -> None:
         """Mark DB modified.
 
@@ -604,7 +618,7 @@ where c.nid = n.id and c.id in %s group by nid"""
                 continue
             r.append(
                 (
-                    stripHTMLMedia(fields[self.models.sortIdx(model)]),
+                    self.models.sortFieldText(model, fields),
                     fieldChecksum(fields[0]),
                     nid,
                 )
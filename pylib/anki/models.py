@@ -13,7 +13,15 @@ import anki  # pylint: disable=unused-import
 from anki import hooks
 from anki.consts import *
 from anki.lang import _
-from anki.utils import checksum, ids2str, intTime, joinFields, splitFields
+from anki.utils import (
+    checksum,
+    ids2str,
+    intTime,
+    joinFields,
+    splitFields,
+    stripHTMLImages,
+    stripHTMLMedia,
+)
 
 # types
 NoteType = Dict[str, Any]
@@ -277,6 +285,15 @@ and notes.mid = ? and cards.ord = ?""",
     def sortIdx(self, m: NoteType) -> Any:
         return m["sortf"]
 
+    def sortFieldText(self, m: NoteType, fields: List[str]) -> str:
+        """The sort field as stored with each note, without HTML. Media filenames
+        are kept unless the notetype's sortfStripMedia is set, in which case sound
+        tags are removed and images are replaced with their alt text."""
+        text = fields[self.sortIdx(m)]
+        if not m.get("sortfStripMedia"):
+            return stripHTMLMedia(text)
+        return stripHTMLImages(self.col.backend.strip_av_tags(text))
+
     def setSortIdx(self, m: NoteType, idx: int) -> None:
         assert 0 <= idx < len(m["flds"])
         self.col.modSchema(check=True)
@@ -561,7 +578,9 @@ select id from notes where mid = ?)"""
         field_map = {}
         for (idx, fld) in enumerate(m["flds"]):
             field_map[fld["name"]] = idx
-        reqs = self.col.backend.template_requirements(fronts, field_map)
+        reqs = self.col.backend.template_requirements(
+            fronts, field_map, cloze=m["type"] == MODEL_CLOZE
+        )
         m["req"] = [list(l) for l in reqs]
 
     def availOrds(self, m: NoteType, flds: str) -> List:
@@ -83,7 +83,7 @@ from notes where id = ?""",
         "If fields or tags have changed, write changes to disk."
         assert self.scm == self.col.scm
         self._preFlush()
-        sfld = stripHTMLMedia(self.fields[self.col.models.sortIdx(self._model)])
+        sfld = self.col.models.sortFieldText(self._model, self.fields)
         tags = self.stringTags()
         fields = self.joinedFields()
         if not mod and self.col.db.scalar(
@@ -4,6 +4,7 @@
 
 import enum
 import os
+import weakref
 from dataclasses import dataclass
 from typing import (
     Any,
@@ -36,7 +37,11 @@ assert ankirspy.buildhash() == anki.buildinfo.buildhash
 
 SchedTimingToday = pb.SchedTimingTodayOut
 BuiltinSortKind = pb.BuiltinSortKind
+LogLevel = pb.LogLevel
+Weekday = pb.Weekday
 BackendCard = pb.Card
+CardField = pb.CardField
+NotetypeKind = pb.NotetypeKind
 
 try:
     import orjson
@@ -87,6 +92,10 @@ class TemplateError(StringError):
     pass
 
 
+class ConflictError(StringError):
+    pass
+
+
 def proto_exception_to_native(err: pb.BackendError) -> Exception:
     val = err.WhichOneof("value")
     if val == "interrupted":
@@ -103,6 +112,10 @@ def proto_exception_to_native(err: pb.BackendError) -> Exception:
         return TemplateError(err.localized)
     elif val == "invalid_input":
         return StringError(err.localized)
+    elif val == "conflict":
+        return ConflictError(err.localized)
+    elif val == "fatal":
+        return StringError(err.localized)
     else:
         assert_impossible_literal(val)
 
@@ -171,12 +184,31 @@ class ExtractedLatexOutput:
 class ProgressKind(enum.Enum):
     MediaSync = 0
     MediaCheck = 1
+    MediaAdd = 2
+    # operations without media-specific details; see phase
+    Other = 3
+    SearchBatch = 4
+
+
+ProgressPhase = pb.ProgressPhase
 
 
 @dataclass
 class Progress:
     kind: ProgressKind
-    val: Union[MediaSyncProgress, str]
+    val: Union[MediaSyncProgress, str, Sequence[int]]
+    # the following are provided for every kind of progress
+    phase: int = ProgressPhase.PROGRESS_PHASE_OTHER
+    current: int = 0
+    # 0 if not known
+    total: int = 0
+    message: str = ""
+    # 0 if the total is not known
+    percent: int = 0
+    # estimated seconds remaining; 0 if not known
+    eta_secs: int = 0
+    # the handle returned by start_operation(); 0 for other commands
+    operation_id: int = 0
 
 
 def proto_replacement_list_to_native(
@@ -199,12 +231,30 @@ def proto_replacement_list_to_native(
 
 def proto_progress_to_native(progress: pb.Progress) -> Progress:
     kind = progress.WhichOneof("value")
+    val: Union[MediaSyncProgress, str, Sequence[int]]
     if kind == "media_sync":
-        return Progress(kind=ProgressKind.MediaSync, val=progress.media_sync)
+        native_kind, val = ProgressKind.MediaSync, progress.media_sync
     elif kind == "media_check":
-        return Progress(kind=ProgressKind.MediaCheck, val=progress.media_check)
+        native_kind, val = ProgressKind.MediaCheck, progress.media_check
+    elif kind == "media_add":
+        native_kind, val = ProgressKind.MediaAdd, progress.media_add
+    elif kind == "search_batch":
+        native_kind, val = ProgressKind.SearchBatch, progress.search_batch.note_ids
+    elif kind is None:
+        native_kind, val = ProgressKind.Other, progress.message
     else:
         assert_impossible_literal(kind)
+    return Progress(
+        kind=native_kind,
+        val=val,
+        phase=progress.phase,
+        current=progress.current,
+        total=progress.total,
+        message=progress.message,
+        percent=progress.percent,
+        eta_secs=progress.eta_secs,
+        operation_id=progress.operation_id,
+    )
 
 
 def _on_progress(progress_bytes: bytes) -> bool:
@@ -232,12 +282,34 @@ class RustBackend:
         )
         self._backend = ankirspy.open_backend(init_msg.SerializeToString())
         self._backend.set_progress_callback(_on_progress)
+        self._col: Optional[Callable[[], Any]] = None
+
+    def attach_collection(self, col: Any) -> None:
+        "Keep col's copies of the col table in sync with commands that change it."
+        self._col = weakref.ref(col)
 
     def _run_command(
         self, input: pb.BackendInput, release_gil: bool = False
     ) -> pb.BackendOutput:
         input_bytes = input.SerializeToString()
         output_bytes = self._backend.command(input_bytes, release_gil)
+        return self._decode_output(output_bytes)
+
+    def _run_col_command(
+        self, input: pb.BackendInput, release_gil: bool = False
+    ) -> pb.BackendOutput:
+        """Like _run_command(), for commands that change the config, notetypes,
+        decks or tags the attached collection keeps a copy of. Pending changes
+        to the copies are saved first, and they're reloaded afterwards."""
+        col = self._col() if self._col else None
+        if col:
+            col._flush_cached()
+        output = self._run_command(input, release_gil)
+        if col:
+            col._reload_cached()
+        return output
+
+    def _decode_output(self, output_bytes: bytes) -> pb.BackendOutput:
         output = pb.BackendOutput()
         output.ParseFromString(output_bytes)
         kind = output.WhichOneof("value")
@@ -247,73 +319,149 @@ class RustBackend:
             return output
 
     def open_collection(
-        self, col_path: str, media_folder_path: str, media_db_path: str, log_path: str
-    ):
-        self._run_command(
+        self,
+        col_path: str,
+        media_folder_path: str,
+        media_db_path: str,
+        log_path: str,
+        log_level: int = LogLevel.LOG_LEVEL_DEFAULT,
+        media_overlay_paths: Sequence[str] = (),
+        log_rotate_keep: int = 0,
+    ) -> pb.OpenCollectionOut:
+        return self._run_command(
             pb.BackendInput(
                 open_collection=pb.OpenCollectionIn(
                     collection_path=col_path,
                     media_folder_path=media_folder_path,
                     media_db_path=media_db_path,
                     log_path=log_path,
+                    log_level=log_level,
+                    media_overlay_paths=media_overlay_paths,
+                    log_rotate_keep=log_rotate_keep,
                 )
             ),
             release_gil=True,
+        ).open_collection
+
+    def set_languages(self, langs: List[str], ftl_folder: str) -> None:
+        "Change the UI language without restarting."
+        self._run_command(
+            pb.BackendInput(
+                set_languages=pb.I18nBackendInit(
+                    preferred_langs=langs, locale_folder_path=ftl_folder
+                )
+            )
         )
 
+    def available_languages(self) -> List[pb.AvailableLanguage]:
+        "Languages in the locale folder, and how much of each is translated."
+        return list(
+            self._run_command(
+                pb.BackendInput(available_languages=pb.Empty())
+            ).available_languages.languages
+        )
+
+    def set_log_level(self, level: int) -> None:
+        "Change the log level without reopening the collection."
+        self._run_command(pb.BackendInput(set_log_level=level))
+
+    def rotate_log(self) -> None:
+        "Start a new log file, keeping the current one as a backup."
+        self._run_command(pb.BackendInput(rotate_log=pb.Empty()))
+
     def close_collection(self):
         self._run_command(
             pb.BackendInput(close_collection=pb.Empty()), release_gil=True
         )
 
     def template_requirements(
-        self, template_fronts: List[str], field_map: Dict[str, int]
+        self,
+        template_fronts: List[str],
+        field_map: Dict[str, int],
+        cloze: bool = False,
     ) -> AllTemplateReqs:
         input = pb.BackendInput(
             template_requirements=pb.TemplateRequirementsIn(
-                template_front=template_fronts, field_names_to_ordinals=field_map
+                template_front=template_fronts,
+                field_names_to_ordinals=field_map,
+                cloze=cloze,
             )
         )
         output = self._run_command(input).template_requirements
         reqs: List[pb.TemplateRequirement] = output.requirements  # type: ignore
         return proto_template_reqs_to_legacy(reqs)
 
+    def validate_template(
+        self,
+        template: str,
+        field_names: Sequence[str],
+        registered_filters: Sequence[str] = (),
+    ) -> pb.ValidateTemplateOut:
+        "Unknown field and filter references. Raises TemplateError if invalid."
+        return self._run_command(
+            pb.BackendInput(
+                validate_template=pb.ValidateTemplateIn(
+                    template=template,
+                    field_names=field_names,
+                    registered_filters=registered_filters,
+                )
+            )
+        ).validate_template
+
+    def template_fields(self, template: str) -> List[str]:
+        "Names of the note fields a template refers to, sorted."
+        out = self._run_command(pb.BackendInput(template_fields=template))
+        return list(out.template_fields.fields)
+
     def sched_timing_today(
         self,
         created_secs: int,
         created_mins_west: Optional[int],
         now_mins_west: Optional[int],
         rollover: Optional[int],
+        day_offset: int = 0,
     ) -> SchedTimingToday:
-        if created_mins_west is not None:
-            crt_west = pb.OptionalInt32(val=created_mins_west)
-        else:
-            crt_west = None
-
-        if now_mins_west is not None:
-            now_west = pb.OptionalInt32(val=now_mins_west)
-        else:
-            now_west = None
-
-        if rollover is not None:
-            roll = pb.OptionalInt32(val=rollover)
-        else:
-            roll = None
-
         return self._run_command(
             pb.BackendInput(
-                sched_timing_today=pb.SchedTimingTodayIn(
-                    created_secs=created_secs,
-                    now_secs=intTime(),
-                    created_mins_west=crt_west,
-                    now_mins_west=now_west,
-                    rollover_hour=roll,
+                sched_timing_today=sched_timing_today_in(
+                    created_secs, created_mins_west, now_mins_west, rollover, day_offset
                 )
             )
         ).sched_timing_today
 
+    def week_boundaries(
+        self,
+        created_secs: int,
+        created_mins_west: Optional[int],
+        now_mins_west: Optional[int],
+        rollover: Optional[int],
+        weeks: int,
+        first_weekday: int = Weekday.WEEKDAY_SUNDAY,
+    ) -> pb.WeekBoundariesOut:
+        "Start of the current week and the weeks before it, for stats."
+        return self._run_command(
+            pb.BackendInput(
+                week_boundaries=pb.WeekBoundariesIn(
+                    timing=sched_timing_today_in(
+                        created_secs, created_mins_west, now_mins_west, rollover
+                    ),
+                    weeks=weeks,
+                    first_weekday=first_weekday,
+                )
+            )
+        ).week_boundaries
+
     def render_card(
-        self, qfmt: str, afmt: str, fields: Dict[str, str], card_ord: int
+        self,
+        qfmt: str,
+        afmt: str,
+        fields: Dict[str, str],
+        card_ord: int,
+        tags: Sequence[str] = (),
+        registered_filters: Optional[List[str]] = None,
+        note_id: int = 0,
+        notetype_id: int = 0,
+        normalize_whitespace: bool = False,
     ) -> Tuple[TemplateReplacementList, TemplateReplacementList]:
         out = self._run_command(
             pb.BackendInput(
@@ -322,6 +470,12 @@ class RustBackend:
                     answer_template=afmt,
                     fields=fields,
                     card_ordinal=card_ord,
+                    tags=tags,
+                    flag_unknown_filters=registered_filters is not None,
+                    registered_filters=registered_filters or [],
+                    note_id=note_id,
+                    notetype_id=notetype_id,
+                    normalize_whitespace=normalize_whitespace,
                 )
             )
         ).render_card
@@ -339,6 +493,25 @@ class RustBackend:
     def strip_av_tags(self, text: str) -> str:
         return self._run_command(pb.BackendInput(strip_av_tags=text)).strip_av_tags
 
+    def cloze_gaps(self, fields: List[str]) -> List[int]:
+        "Cloze numbers skipped between the lowest and highest used in fields."
+        return list(
+            self._run_command(
+                pb.BackendInput(cloze_gaps=pb.ClozeGapsIn(fields=fields))
+            ).cloze_gaps.missing
+        )
+
+    def html_to_markdown(self, html: str) -> str:
+        return self._run_command(
+            pb.BackendInput(html_to_markdown=html)
+        ).html_to_markdown
+
+    def normalize_whitespace(self, html: str) -> str:
+        "Collapse whitespace and non-breaking spaces outside <pre> blocks."
+        return self._run_command(
+            pb.BackendInput(normalize_whitespace=html)
+        ).normalize_whitespace
+
     def extract_av_tags(
         self, text: str, question_side: bool
     ) -> Tuple[str, List[AVTag]]:
@@ -353,6 +526,15 @@ class RustBackend:
 
         return out.text, native_tags
 
+    def compare_answer(self, expected: str, provided: str) -> Tuple[str, float]:
+        "Returns (html, match_percent)."
+        out = self._run_command(
+            pb.BackendInput(
+                compare_answer=pb.CompareAnswerIn(expected=expected, provided=provided)
+            )
+        ).compare_answer
+        return out.html, out.match_percent
+
     def extract_latex(
         self, text: str, svg: bool, expand_clozes: bool
     ) -> ExtractedLatexOutput:
@@ -373,15 +555,68 @@ class RustBackend:
         )
 
     def add_file_to_media_folder(self, desired_name: str, data: bytes) -> str:
+        return self.add_media_file(desired_name, data).name
+
+    def add_media_file(self, desired_name: str, data: bytes) -> pb.AddMediaFileOut:
+        "Like add_file_to_media_folder(), but also reports if the file was deduped."
         return self._run_command(
             pb.BackendInput(
                 add_media_file=pb.AddMediaFileIn(desired_name=desired_name, data=data)
             )
         ).add_media_file
 
-    def sync_media(self, hkey: str, endpoint: str) -> None:
+    def add_files_to_media_folder(self, files: List[Tuple[str, bytes]]) -> List[str]:
+        "Add multiple files, returning the names they were stored under."
+        return list(
+            self._run_command(
+                pb.BackendInput(
+                    add_media_files=pb.AddMediaFilesIn(
+                        files=[
+                            pb.AddMediaFileIn(desired_name=name, data=data)
+                            for name, data in files
+                        ]
+                    )
+                )
+            ).add_media_files.names
+        )
+
+    def media_file_path(self, fname: str) -> str:
+        "Full path to a media file, checking overlay folders. Empty if not found."
+        return self._run_command(pb.BackendInput(media_file_path=fname)).media_file_path
+
+    def media_filename_for_bytes(self, data: bytes, extension: str) -> str:
+        "The name add_file_to_media_folder() would use, without writing the file."
+        return self._run_command(
+            pb.BackendInput(
+                media_filename_for_bytes=pb.MediaFilenameForBytesIn(
+                    data=data, extension=extension
+                )
+            )
+        ).media_filename_for_bytes
+
+    def sync_media(
+        self,
+        hkey: str,
+        endpoint: str,
+        *,
+        timeout_secs: int = 0,
+        proxy_url: str = "",
+        proxy_username: str = "",
+        proxy_password: str = "",
+    ) -> None:
+        """A timeout of 0 uses the default. If no proxy URL is provided, the
+        HTTP_PROXY and HTTPS_PROXY environment variables are used."""
         self._run_command(
-            pb.BackendInput(sync_media=pb.SyncMediaIn(hkey=hkey, endpoint=endpoint,)),
+            pb.BackendInput(
+                sync_media=pb.SyncMediaIn(
+                    hkey=hkey,
+                    endpoint=endpoint,
+                    timeout_secs=timeout_secs,
+                    proxy_url=proxy_url,
+                    proxy_username=proxy_username,
+                    proxy_password=proxy_password,
+                )
+            ),
             release_gil=True,
         )
 
@@ -390,6 +625,54 @@ class RustBackend:
             pb.BackendInput(check_media=pb.Empty()), release_gil=True,
         ).check_media
 
+    def start_sync_media(
+        self,
+        hkey: str,
+        endpoint: str,
+        *,
+        timeout_secs: int = 0,
+        proxy_url: str = "",
+        proxy_username: str = "",
+        proxy_password: str = "",
+    ) -> int:
+        "Like sync_media(), but returns immediately with an operation handle."
+        return self.start_operation(
+            pb.BackendInput(
+                sync_media=pb.SyncMediaIn(
+                    hkey=hkey,
+                    endpoint=endpoint,
+                    timeout_secs=timeout_secs,
+                    proxy_url=proxy_url,
+                    proxy_username=proxy_username,
+                    proxy_password=proxy_password,
+                )
+            )
+        )
+
+    def start_check_media(self) -> int:
+        """Like check_media(), but returns immediately with an operation handle.
+        Other commands that use the collection wait until the check completes."""
+        return self.start_operation(pb.BackendInput(check_media=pb.Empty()))
+
+    def start_operation(self, input: pb.BackendInput) -> int:
+        """Run a command on a background thread, returning a handle for
+        operation_status(). Only media sync and check are supported."""
+        return self._run_command(
+            pb.BackendInput(start_operation=input.SerializeToString())
+        ).start_operation
+
+    def operation_status(self, handle: int) -> Optional[pb.BackendOutput]:
+        """None while the operation is running, or its output once finished.
+        An error in the operation is raised as an exception. The output can
+        only be collected once."""
+        out = self._run_command(pb.BackendInput(operation_status=handle))
+        if not out.operation_status.finished:
+            return None
+        return self._decode_output(out.operation_status.output)
+
+    def cancel_operation(self, handle: int) -> None:
+        self._run_command(pb.BackendInput(operation_cancel=handle))
+
     def trash_media_files(self, fnames: List[str]) -> None:
         self._run_command(
             pb.BackendInput(trash_media_files=pb.TrashMediaFilesIn(fnames=fnames))
@@ -400,6 +683,20 @@ class RustBackend:
             pb.BackendInput(translate_string=translate_string_in(key, **kwargs))
         ).translate_string
 
+    def translate_many(
+        self, keys: Sequence[Tuple[TR, Dict[str, Union[str, int, float]]]]
+    ) -> List[str]:
+        "Translate (key, args) pairs in one call, returning strings in order."
+        return list(
+            self._run_command(
+                pb.BackendInput(
+                    translate_strings=pb.TranslateStringsIn(
+                        strings=[translate_string_in(key, **args) for key, args in keys]
+                    )
+                )
+            ).translate_strings.strings
+        )
+
     def format_time_span(
         self,
         seconds: float,
@@ -427,6 +724,26 @@ class RustBackend:
             )
         ).congrats_learn_msg
 
+    def trash_unused_media(self) -> pb.TrashUnusedMediaOut:
+        "Check media, and move any unused files into the trash."
+        return self._run_command(
+            pb.BackendInput(trash_unused_media=pb.Empty()), release_gil=True
+        ).trash_unused_media
+
+    def rename_media_file(self, old_name: str, new_name: str) -> int:
+        "Rename a media file and update references to it. Returns notes changed."
+        return self._run_command(
+            pb.BackendInput(
+                rename_media_file=pb.RenameMediaFileIn(
+                    old_name=old_name, new_name=new_name
+                )
+            )
+        ).rename_media_file
+
+    def abort_media_check(self) -> None:
+        "Stop a running check_media(), verify_media_checksums(), empty_trash(), etc."
+        self._run_command(pb.BackendInput(abort_media_check=pb.Empty()))
+
     def empty_trash(self):
         self._run_command(pb.BackendInput(empty_trash=pb.Empty()))
 
@@ -456,9 +773,21 @@ class RustBackend:
         return orjson.loads(self._backend.db_command(orjson.dumps(input)))
 
     def search_cards(
-        self, search: str, order: Union[bool, str, int], reverse: bool = False
+        self,
+        search: str,
+        order: Union[bool, str, int],
+        reverse: bool = False,
+        strip_clozes: bool = False,
+        by_relevance: bool = False,
+        unknown_field_error: bool = False,
     ) -> Sequence[int]:
-        if isinstance(order, str):
+        """If by_relevance is true, order is ignored and the best matches come first.
+
+        Terms limited to a field no note type has match nothing, unless
+        unknown_field_error is true, in which case an error is raised."""
+        if by_relevance:
+            mode = pb.SortOrder(relevance=pb.Empty())
+        elif isinstance(order, str):
             mode = pb.SortOrder(custom=order)
         elif order is True:
             mode = pb.SortOrder(from_config=pb.Empty())
@@ -473,23 +802,676 @@ class RustBackend:
             )
 
         return self._run_command(
-            pb.BackendInput(search_cards=pb.SearchCardsIn(search=search, order=mode))
+            pb.BackendInput(
+                search_cards=pb.SearchCardsIn(
+                    search=search,
+                    order=mode,
+                    strip_clozes=strip_clozes,
+                    unknown_field_error=unknown_field_error,
+                )
+            )
         ).search_cards.card_ids
 
-    def search_notes(self, search: str) -> Sequence[int]:
+    def search_notes(
+        self,
+        search: str,
+        strip_clozes: bool = False,
+        unknown_field_error: bool = False,
+    ) -> Sequence[int]:
         return self._run_command(
-            pb.BackendInput(search_notes=pb.SearchNotesIn(search=search))
+            pb.BackendInput(
+                search_notes=pb.SearchNotesIn(
+                    search=search,
+                    strip_clozes=strip_clozes,
+                    unknown_field_error=unknown_field_error,
+                )
+            )
         ).search_notes.note_ids
 
-    def get_card(self, cid: int) -> Optional[pb.Card]:
-        return self._run_command(pb.BackendInput(get_card=cid)).get_card.card
+    def search_notes_in_batches(
+        self,
+        search: str,
+        on_batch: Callable[[Sequence[int]], bool],
+        batch_size: int = 1000,
+        strip_clozes: bool = False,
+    ) -> int:
+        """Pass the ids of matching notes to on_batch in batches, returning the
+        number of notes. If on_batch returns False, the remaining batches are
+        skipped and the search is aborted."""
+
+        def on_progress(progress_bytes: bytes) -> bool:
+            progress = pb.Progress()
+            progress.ParseFromString(progress_bytes)
+            if progress.WhichOneof("value") == "search_batch":
+                return on_batch(progress.search_batch.note_ids)
+            return _on_progress(progress_bytes)
+
+        self._backend.set_progress_callback(on_progress)
+        try:
+            return self._run_command(
+                pb.BackendInput(
+                    search_notes_in_batches=pb.SearchNotesInBatchesIn(
+                        search=search, strip_clozes=strip_clozes, batch_size=batch_size
+                    )
+                )
+            ).search_notes_in_batches
+        finally:
+            self._backend.set_progress_callback(_on_progress)
+
+    def similar_notes(self, nid: int, limit: int = 0) -> Sequence[pb.SimilarNote]:
+        "Notes with a similar first field, best first."
+        return self._run_command(
+            pb.BackendInput(similar_notes=pb.SimilarNotesIn(note_id=nid, limit=limit))
+        ).similar_notes.notes
+
+    def saved_searches(self) -> Sequence[pb.SavedSearch]:
+        "Saved searches, ordered by name."
+        return self._run_command(
+            pb.BackendInput(get_saved_searches=pb.Empty())
+        ).get_saved_searches.searches
+
+    def save_search(self, name: str, search: str) -> None:
+        "Add or replace a saved search. Raises an error if the search is invalid."
+        self._run_col_command(
+            pb.BackendInput(save_search=pb.SavedSearch(name=name, search=search))
+        )
+
+    def remove_saved_search(self, name: str) -> None:
+        self._run_col_command(pb.BackendInput(remove_saved_search=name))
+
+    def get_card(self, cid: int, lenient: bool = False) -> Optional[pb.Card]:
+        # if lenient is set, an invalid type or queue in the database is read
+        # as new instead of raising an error
+        return self._run_command(
+            pb.BackendInput(
+                get_card_with_options=pb.GetCardIn(cid=cid, lenient=lenient)
+            )
+        ).get_card_with_options.card
+
+    def update_card(
+        self,
+        card: BackendCard,
+        expected_mtime: Optional[int] = None,
+        lenient: bool = False,
+    ) -> None:
+        # if expected_mtime is provided, ConflictError is raised when the
+        # stored card has a different mtime. if lenient is set, an invalid
+        # type or queue is replaced with new instead of raising an error.
+        self._run_command(
+            pb.BackendInput(
+                update_card_with_options=pb.UpdateCardIn(
+                    card=card, expected_mtime=expected_mtime or 0, lenient=lenient
+                )
+            )
+        )
 
-    def update_card(self, card: BackendCard) -> None:
-        self._run_command(pb.BackendInput(update_card=card))
+    def update_cards(
+        self,
+        cards: Sequence[BackendCard],
+        fields: Sequence[int],
+        lenient: bool = False,
+    ) -> None:
+        self._run_command(
+            pb.BackendInput(
+                update_cards=pb.UpdateCardsIn(
+                    cards=cards, fields=fields, lenient=lenient
+                )
+            )
+        )
 
     def add_card(self, card: BackendCard) -> None:
         card.id = self._run_command(pb.BackendInput(add_card=card)).add_card
 
+    def note_cards(self, nid: int) -> Sequence[pb.NoteCard]:
+        return self._run_command(pb.BackendInput(note_cards=nid)).note_cards.cards
+
+    def card_review_log(self, cid: int) -> Sequence[pb.RevlogEntry]:
+        return self._run_command(
+            pb.BackendInput(card_review_log=cid)
+        ).card_review_log.entries
+
+    def card_info(self, cid: int) -> pb.CardInfoOut:
+        return self._run_command(pb.BackendInput(card_info=cid)).card_info
+
+    def find_invalid_cards(self) -> Sequence[pb.InvalidCard]:
+        "Cards with an out of range type or queue, or other invalid state."
+        return self._run_command(
+            pb.BackendInput(find_invalid_cards=pb.Empty())
+        ).find_invalid_cards.cards
+
+    def compact_new_positions(self) -> int:
+        "Renumber new card positions from 1, returning the number of cards changed."
+        return self._run_col_command(
+            pb.BackendInput(compact_new_positions=pb.Empty())
+        ).compact_new_positions
+
+    def reorder_new_cards(
+        self,
+        search: str,
+        random_seed: Optional[int] = None,
+        field_name: Optional[str] = None,
+    ) -> int:
+        """Reorder the new cards matching search, returning the number changed.
+
+        Cards are shuffled if random_seed is provided, sorted by the named field
+        if field_name is provided, and otherwise put in note creation order."""
+        if random_seed is not None:
+            input = pb.ReorderNewCardsIn(search=search, random_seed=random_seed)
+        elif field_name is not None:
+            input = pb.ReorderNewCardsIn(search=search, field_name=field_name)
+        else:
+            input = pb.ReorderNewCardsIn(search=search, note_creation=pb.Empty())
+        return self._run_command(
+            pb.BackendInput(reorder_new_cards=input)
+        ).reorder_new_cards
+
+    def reposition_new_cards(
+        self,
+        card_ids: List[int],
+        start: int = 1,
+        step: int = 1,
+        randomize: bool = False,
+        shift: bool = False,
+    ) -> int:
+        "Like the browser's Reposition; returns the number of cards changed."
+        return self._run_command(
+            pb.BackendInput(
+                reposition_new_cards=pb.RepositionNewCardsIn(
+                    card_ids=card_ids,
+                    start=start,
+                    step=step,
+                    randomize=randomize,
+                    shift=shift,
+                )
+            )
+        ).reposition_new_cards
+
+    def canonicalize_tags(self, preferred: Sequence[str] = ()) -> Dict[str, str]:
+        """Merge tags that differ only in case, returning a map of each replaced
+        spelling to its replacement.
+
+        Tags matching one of preferred use that spelling; others use the spelling
+        found on the most notes."""
+        return dict(
+            self._run_col_command(
+                pb.BackendInput(
+                    canonicalize_tags=pb.CanonicalizeTagsIn(preferred=preferred)
+                )
+            ).canonicalize_tags.merged
+        )
+
+    def all_tags(self) -> Sequence[pb.TagTreeNode]:
+        "Top-level tags, with tags nested using :: as their children."
+        return self._run_command(pb.BackendInput(all_tags=pb.Empty())).all_tags.tags
+
+    def clear_unused_tags(self) -> int:
+        "Remove tags no note uses, returning the number removed."
+        return self._run_col_command(
+            pb.BackendInput(clear_unused_tags=pb.Empty())
+        ).clear_unused_tags
+
+    def get_deck_config(self, did: int) -> pb.DeckConfig:
+        "The study options of a deck. Filtered decks have none."
+        return self._run_command(pb.BackendInput(get_deck_config=did)).get_deck_config
+
+    def set_deck_config(self, conf: pb.DeckConfig) -> None:
+        "Save changes to an existing config, raising an error if they're invalid."
+        self._run_col_command(pb.BackendInput(set_deck_config=conf))
+
+    def deck_configs_list(self) -> Sequence[pb.DeckConfigUsage]:
+        "Each deck config, with the ids of the decks that use it."
+        return self._run_command(
+            pb.BackendInput(deck_configs_list=pb.Empty())
+        ).deck_configs_list.configs
+
+    def add_deck_config(self, name: str) -> int:
+        "Add a config with the default options, returning its id."
+        return self._run_col_command(
+            pb.BackendInput(add_deck_config=name)
+        ).add_deck_config
+
+    def set_deck_config_for_decks(self, conf_id: int, deck_ids: List[int]) -> None:
+        self._run_col_command(
+            pb.BackendInput(
+                set_deck_config_for_decks=pb.SetDeckConfigForDecksIn(
+                    config_id=conf_id, deck_ids=deck_ids
+                )
+            )
+        )
+
+    def remove_deck_config(self, conf_id: int) -> None:
+        """Remove a config, moving its decks to the default config. The default
+        config can't be removed. Requires a full sync."""
+        self._run_col_command(pb.BackendInput(remove_deck_config=conf_id))
+
+    def deck_congrats(self, did: int) -> str:
+        "The HTML message shown when a deck and its children are finished for now."
+        return self._run_command(pb.BackendInput(deck_congrats=did)).deck_congrats
+
+    def start_study(self, did: int) -> pb.StudyStateOut:
+        "Build the study queues for a deck and its children, replacing any others."
+        return self._run_command(pb.BackendInput(start_study=did)).start_study
+
+    def study_state(self, did: int) -> pb.StudyStateOut:
+        "The new, learning and review counts left in a deck's study session."
+        return self._run_command(pb.BackendInput(study_state=did)).study_state
+
+    def get_next_card(self, did: int) -> Optional[pb.Card]:
+        """The next card to study in a deck and its children, or None if the deck
+        is finished for today. The study queues are built if necessary."""
+        out = self._run_command(pb.BackendInput(get_next_card=did)).get_next_card
+        return out.card if out.HasField("card") else None
+
+    def preview_lapse(self, cid: int) -> pb.PreviewLapseOut:
+        """What answering a review card with Again would do under its deck's
+        current options. The card is not changed."""
+        return self._run_command(pb.BackendInput(preview_lapse=cid)).preview_lapse
+
+    def adjust_ease(
+        self,
+        search: str,
+        multiplier: Optional[float] = None,
+        delta: Optional[int] = None,
+    ) -> pb.AdjustEaseOut:
+        """Multiply the ease of matching review cards, or add delta (in permille)
+        to it. Ease doesn't drop below 130%."""
+        if multiplier is not None:
+            input = pb.AdjustEaseIn(search=search, multiplier=multiplier)
+        elif delta is not None:
+            input = pb.AdjustEaseIn(search=search, delta=delta)
+        else:
+            raise ValueError("multiplier or delta required")
+        return self._run_command(pb.BackendInput(adjust_ease=input)).adjust_ease
+
+    def mark_leeches(self) -> pb.MarkLeechesOut:
+        """Tag the notes of cards over their leech threshold, suspending them if
+        their options say so. Returns the newly marked card and note ids."""
+        return self._run_col_command(
+            pb.BackendInput(mark_leeches=pb.Empty())
+        ).mark_leeches
+
+    def convert_note_type(self, nids: List[int], ntid: int) -> List[int]:
+        """Add a note of standard notetype ntid for each cloze in the provided cloze
+        notes, returning the new note ids. The original notes are kept."""
+        return list(
+            self._run_col_command(
+                pb.BackendInput(
+                    convert_note_type=pb.ConvertNoteTypeIn(
+                        note_ids=nids, notetype_id=ntid
+                    )
+                )
+            ).convert_note_type.note_ids
+        )
+
+    def fix_field_counts(self) -> List[int]:
+        """Pad or merge the fields of notes that don't match their notetype,
+        returning the ids of the notes fixed."""
+        return list(
+            self._run_command(
+                pb.BackendInput(fix_field_counts=pb.Empty())
+            ).fix_field_counts.note_ids
+        )
+
+    def sanitize_fields(
+        self,
+        search: str,
+        allowed_tags: Sequence[str] = (),
+        allowed_attributes: Sequence[str] = (),
+    ) -> int:
+        """Remove scripts, event handlers and tags or attributes not allowed from
+        the fields of matching notes, returning the number of notes changed.
+        If no tags or attributes are provided, a default allowlist is used."""
+        return self._run_command(
+            pb.BackendInput(
+                sanitize_fields=pb.SanitizeFieldsIn(
+                    search=search,
+                    allowed_tags=allowed_tags,
+                    allowed_attributes=allowed_attributes,
+                )
+            )
+        ).sanitize_fields
+
+    def find_broken_html(
+        self, search: str = ""
+    ) -> List[pb.FindBrokenHtmlOut.BrokenField]:
+        """Check the HTML of matching notes' fields without changing them,
+        returning the malformed fields with a short reason for each."""
+        return list(
+            self._run_command(
+                pb.BackendInput(find_broken_html=search)
+            ).find_broken_html.fields
+        )
+
+    def dedupe_media(self) -> pb.DedupeMediaOut:
+        """Move media files with the same contents as another file to the trash,
+        updating notes that used them."""
+        return self._run_command(
+            pb.BackendInput(dedupe_media=pb.Empty()), release_gil=True
+        ).dedupe_media
+
+    def media_size_report(self, largest: int = 10) -> pb.MediaSizeReportOut:
+        """The space used by the media folder, in total and by extension, and
+        the largest files."""
+        return self._run_command(
+            pb.BackendInput(media_size_report=largest), release_gil=True
+        ).media_size_report
+
+    def verify_media_checksums(self) -> pb.VerifyMediaChecksumsOut:
+        """Reread every media file, returning those whose contents no longer
+        match the media database. Files in .corrupt were likely damaged, while
+        those in .modified were edited since the database last saw them. Can
+        be stopped with abort_media_check()."""
+        return self._run_command(
+            pb.BackendInput(verify_media_checksums=pb.Empty()), release_gil=True
+        ).verify_media_checksums
+
+    def projected_intervals(self, search: str = "") -> pb.ProjectedIntervalsOut:
+        """The intervals the due cards matching search would get if they were all
+        answered Good today. Nothing is changed."""
+        return self._run_command(
+            pb.BackendInput(projected_intervals=search)
+        ).projected_intervals
+
+    def new_guid(self) -> str:
+        """A random note guid in the format Anki uses."""
+        return self._run_command(pb.BackendInput(new_guid=pb.Empty())).new_guid
+
+    def consolidate_note_decks(self, search: str, deck_id: int = 0) -> int:
+        """Move the cards of each matching note into one deck, returning the
+        number of cards moved. If deck_id is 0, the deck most of a note's cards
+        are in is used."""
+        return self._run_command(
+            pb.BackendInput(
+                consolidate_note_decks=pb.ConsolidateNoteDecksIn(
+                    search=search, deck_id=deck_id
+                )
+            )
+        ).consolidate_note_decks
+
+    def notes_with_split_decks(self) -> List[pb.NotesWithSplitDecksOut.SplitNote]:
+        """Notes whose cards are in more than one deck, with the deck ids."""
+        return list(
+            self._run_command(
+                pb.BackendInput(notes_with_split_decks=pb.Empty())
+            ).notes_with_split_decks.notes
+        )
+
+    def search_breakdown(self, search: str) -> pb.SearchBreakdownOut:
+        """Count the cards matching a search, and the cards each of its top-level
+        terms matches on its own."""
+        return self._run_command(
+            pb.BackendInput(search_breakdown=search)
+        ).search_breakdown
+
+    def scheduler_version(self) -> int:
+        """The scheduler version the open collection uses, 1 or 2."""
+        return self._run_command(
+            pb.BackendInput(scheduler_version=pb.Empty())
+        ).scheduler_version
+
+    def upgrade_scheduler(self) -> bool:
+        """Move a collection from the v1 scheduler to v2, emptying filtered decks
+        and taking cards out of learning. Returns False if it was already on v2.
+        A full sync is required afterwards."""
+        return self._run_col_command(
+            pb.BackendInput(upgrade_scheduler=pb.Empty())
+        ).upgrade_scheduler
+
+    def collection_day_number(self) -> int:
+        """Days since the collection was created, counted the same way as review
+        cards' due numbers."""
+        return self._run_command(
+            pb.BackendInput(collection_day_number=pb.Empty())
+        ).collection_day_number
+
+    def preview_notetype_templates(
+        self,
+        ntid: int,
+        templates: Sequence[pb.NewCardTemplate],
+        css: str,
+        nids: Sequence[int],
+    ) -> List[pb.PreviewNotetypeTemplatesOut.CardPreview]:
+        """Render the sample notes with draft templates and css, without saving
+        them. Cards that fail to render have their error set."""
+        return list(
+            self._run_command(
+                pb.BackendInput(
+                    preview_notetype_templates=pb.PreviewNotetypeTemplatesIn(
+                        notetype_id=ntid, templates=templates, css=css, note_ids=nids
+                    )
+                )
+            ).preview_notetype_templates.cards
+        )
+
+    def rebuild_sort_fields(self) -> int:
+        """Recalculate each note's stored sort field after notetype changes,
+        returning the number of notes changed."""
+        return self._run_command(
+            pb.BackendInput(rebuild_sort_fields=pb.Empty())
+        ).rebuild_sort_fields
+
+    def export_revlog_csv(
+        self, path: str, days: Optional[Tuple[int, int]] = None
+    ) -> int:
+        """Write the review log to a CSV file, returning the number of entries.
+
+        If days is provided as (from_days_ago, to_days_ago), only reviews from
+        those days are included, where 0 is today."""
+        if days:
+            input = pb.ExportRevlogCsvIn(
+                path=path, limit_days=True, from_days_ago=days[0], to_days_ago=days[1]
+            )
+        else:
+            input = pb.ExportRevlogCsvIn(path=path)
+        return self._run_command(
+            pb.BackendInput(export_revlog_csv=input)
+        ).export_revlog_csv
+
+    def export_tsv(
+        self,
+        path: str,
+        search: str,
+        fields: Sequence[str] = (),
+        include_tags: bool = False,
+        include_notetype: bool = False,
+        strip_html: bool = False,
+        markdown: bool = False,
+    ) -> int:
+        """Write matching notes to a tab-separated file, one per line, returning
+        the number written. Tabs, newlines and backslashes in fields are escaped
+        with a backslash. If fields is empty, all fields are written. If markdown is
+        set, fields are converted to Markdown."""
+        return self._run_command(
+            pb.BackendInput(
+                export_tsv=pb.ExportTsvIn(
+                    path=path,
+                    search=search,
+                    fields=fields,
+                    include_tags=include_tags,
+                    include_notetype=include_notetype,
+                    strip_html=strip_html,
+                    markdown=markdown,
+                )
+            )
+        ).export_tsv
+
+    def import_revlog(
+        self, path: str, card_id_map: Dict[int, int]
+    ) -> pb.ImportRevlogOut:
+        """Merge the review log of another collection into this one.
+
+        Card ids are replaced with their id in card_id_map. Entries already
+        present are skipped, and entries for cards not in card_id_map are not
+        imported; the output counts both."""
+        return self._run_command(
+            pb.BackendInput(
+                import_revlog=pb.ImportRevlogIn(path=path, card_id_map=card_id_map)
+            )
+        ).import_revlog
+
+    def cards_since_usn(
+        self, usn: int, after_id: int = 0, limit: int = 0
+    ) -> pb.CardsSinceUsnOut:
+        return self._run_command(
+            pb.BackendInput(
+                cards_since_usn=pb.CardsSinceUsnIn(
+                    usn=usn, after_id=after_id, limit=limit
+                )
+            )
+        ).cards_since_usn
+
+    def add_notetype(
+        self,
+        name: str,
+        fields: Sequence[str],
+        templates: Sequence[Tuple[str, str, str]],
+        css: str,
+        cloze: bool = False,
+    ) -> int:
+        # templates are (name, front, back)
+        kind = pb.NOTETYPE_KIND_CLOZE if cloze else pb.NOTETYPE_KIND_STANDARD
+        return self._run_col_command(
+            pb.BackendInput(
+                add_notetype=pb.AddNotetypeIn(
+                    name=name,
+                    kind=kind,
+                    fields=fields,
+                    templates=[
+                        pb.NewCardTemplate(name=n, front=f, back=b)
+                        for (n, f, b) in templates
+                    ],
+                    css=css,
+                )
+            )
+        ).add_notetype
+
+    def notetype_kind(self, ntid: int) -> int:
+        "NotetypeKind; cloze if a template uses the cloze filter."
+        return self._run_command(pb.BackendInput(notetype_kind=ntid)).notetype_kind
+
+    # the following return the number of notes updated
+
+    def add_notetype_field(self, ntid: int, name: str) -> int:
+        return self._run_col_command(
+            pb.BackendInput(
+                add_notetype_field=pb.AddNotetypeFieldIn(notetype_id=ntid, name=name)
+            )
+        ).add_notetype_field
+
+    def remove_notetype_field(self, ntid: int, ord: int) -> int:
+        return self._run_col_command(
+            pb.BackendInput(
+                remove_notetype_field=pb.RemoveNotetypeFieldIn(
+                    notetype_id=ntid, ord=ord
+                )
+            )
+        ).remove_notetype_field
+
+    def reposition_notetype_field(self, ntid: int, ord: int, new_ord: int) -> int:
+        return self._run_col_command(
+            pb.BackendInput(
+                reposition_notetype_field=pb.RepositionNotetypeFieldIn(
+                    notetype_id=ntid, ord=ord, new_ord=new_ord
+                )
+            )
+        ).reposition_notetype_field
+
+    def add_notetype_template(
+        self, ntid: int, name: str, front: str, back: str, generate_cards: bool
+    ) -> int:
+        "Returns the number of cards added."
+        return self._run_col_command(
+            pb.BackendInput(
+                add_notetype_template=pb.AddNotetypeTemplateIn(
+                    notetype_id=ntid,
+                    template=pb.NewCardTemplate(name=name, front=front, back=back),
+                    generate_cards=generate_cards,
+                )
+            )
+        ).add_notetype_template
+
+    def remove_notetype_template(self, ntid: int, ord: int) -> int:
+        "Returns the number of cards removed."
+        return self._run_col_command(
+            pb.BackendInput(
+                remove_notetype_template=pb.RemoveNotetypeTemplateIn(
+                    notetype_id=ntid, ord=ord
+                )
+            )
+        ).remove_notetype_template
+
+    def clone_notetype(self, ntid: int, new_name: str) -> int:
+        return self._run_col_command(
+            pb.BackendInput(
+                clone_notetype=pb.CloneNotetypeIn(notetype_id=ntid, new_name=new_name)
+            )
+        ).clone_notetype
+
+    def notetype_field_removal_impact(
+        self, ntid: int, ord: int
+    ) -> pb.NotetypeChangeImpactOut:
+        return self._run_command(
+            pb.BackendInput(
+                notetype_change_impact=pb.NotetypeChangeImpactIn(
+                    remove_field=pb.RemoveNotetypeFieldIn(notetype_id=ntid, ord=ord)
+                )
+            )
+        ).notetype_change_impact
+
+    def notetype_template_removal_impact(
+        self, ntid: int, ord: int
+    ) -> pb.NotetypeChangeImpactOut:
+        return self._run_command(
+            pb.BackendInput(
+                notetype_change_impact=pb.NotetypeChangeImpactIn(
+                    remove_template=pb.RemoveNotetypeTemplateIn(
+                        notetype_id=ntid, ord=ord
+                    )
+                )
+            )
+        ).notetype_change_impact
+
+    def notetype_css(self, ntid: int, scoped: bool = False, card_ord: int = 0) -> str:
+        return self._run_command(
+            pb.BackendInput(
+                notetype_css=pb.NotetypeCssIn(
+                    notetype_id=ntid, scoped=scoped, card_ord=card_ord
+                )
+            )
+        ).notetype_css
+
+
+def sched_timing_today_in(
+    created_secs: int,
+    created_mins_west: Optional[int],
+    now_mins_west: Optional[int],
+    rollover: Optional[int],
+    day_offset: int = 0,
+) -> pb.SchedTimingTodayIn:
+    if created_mins_west is not None:
+        crt_west = pb.OptionalInt32(val=created_mins_west)
+    else:
+        crt_west = None
+
+    if now_mins_west is not None:
+        now_west = pb.OptionalInt32(val=now_mins_west)
+    else:
+        now_west = None
+
+    if rollover is not None:
+        roll = pb.OptionalInt32(val=rollover)
+    else:
+        roll = None
+
+    return pb.SchedTimingTodayIn(
+        created_secs=created_secs,
+        now_secs=intTime(),
+        created_mins_west=crt_west,
+        now_mins_west=now_west,
+        rollover_hour=roll,
+        day_offset=day_offset,
+    )
+
 
 def translate_string_in(
     key: TR, **kwargs: Union[str, int, float]
@@ -709,16 +709,21 @@ did = ? and queue = {QUEUE_TYPE_REV} and due <= ? limit ?""",
     # Dynamic deck handling
     ##########################################################################
 
-    def rebuildDyn(self, did: Optional[int] = None) -> Optional[Sequence[int]]:  # type: ignore[override]
-        "Rebuild a dynamic deck."
+    def rebuildDyn(  # type: ignore[override]
+        self, did: Optional[int] = None, preserveOrder: bool = False
+    ) -> Optional[Sequence[int]]:
+        """Rebuild a dynamic deck. If preserveOrder is set, cards that remain in
+        the deck keep their previous order relative to each other."""
         did = did or self.col.decks.selected()
         deck = self.col.decks.get(did)
         assert deck["dyn"]
+        previous = self._dynPositions(did) if preserveOrder else {}
         # move any existing cards back first, then fill
         self.emptyDyn(did)
         ids = self._fillDyn(deck)
         if not ids:
             return None
+        self._restoreDynOrder(did, previous)
         # and change to our new deck
         self.col.decks.select(did)
         return ids
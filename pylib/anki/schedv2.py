@@ -1144,16 +1144,21 @@ else
 end)
 """
 
-    def rebuildDyn(self, did: Optional[int] = None) -> Optional[int]:
-        "Rebuild a dynamic deck."
+    def rebuildDyn(
+        self, did: Optional[int] = None, preserveOrder: bool = False
+    ) -> Optional[int]:
+        """Rebuild a dynamic deck. If preserveOrder is set, cards that remain in
+        the deck keep their previous order relative to each other."""
         did = did or self.col.decks.selected()
         deck = self.col.decks.get(did)
         assert deck["dyn"]
+        previous = self._dynPositions(did) if preserveOrder else {}
         # move any existing cards back first, then fill
         self.emptyDyn(did)
         cnt = self._fillDyn(deck)
         if not cnt:
             return None
+        self._restoreDynOrder(did, previous)
         # and change to our new deck
         self.col.decks.select(did)
         return cnt
@@ -1176,6 +1181,27 @@ end)
             total += len(ids)
         return total
 
+    def _dynPositions(self, did: int) -> Dict[int, int]:
+        "Card id -> position of cards ordered within a dynamic deck."
+        return dict(
+            self.col.db.all("select id, due from cards where did = ? and due < 0", did)
+        )
+
+    def _restoreDynOrder(self, did: int, previous: Dict[int, int]) -> None:
+        """Put cards that were in the deck before a rebuild back in their previous
+        relative order, using the positions they were given. Other cards are
+        left where they are."""
+        if not previous:
+            return
+        kept = [
+            (id, due) for id, due in self._dynPositions(did).items() if id in previous
+        ]
+        positions = sorted(due for _, due in kept)
+        ids = sorted((id for id, _ in kept), key=lambda id: previous[id])
+        self.col.db.executemany(
+            "update cards set due = ? where id = ?", list(zip(positions, ids))
+        )
+
     def emptyDyn(self, did: Optional[int], lim: Optional[str] = None) -> None:
         if not lim:
             lim = "did = %s" % did
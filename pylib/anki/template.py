@@ -169,7 +169,13 @@ def render_card_from_context(ctx: TemplateRenderContext) -> TemplateRenderOutput
     col = ctx.col()
 
     (qnodes, anodes) = col.backend.render_card(
-        ctx.qfmt(), ctx.afmt(), ctx.fields(), ctx.card().ord
+        ctx.qfmt(),
+        ctx.afmt(),
+        ctx.fields(),
+        ctx.card().ord,
+        ctx.note().tags,
+        note_id=ctx.note().id,
+        notetype_id=ctx.note_type()["id"],
     )
 
     qtext = apply_custom_filters(qnodes, ctx, front_side=None)
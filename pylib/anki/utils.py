@@ -59,6 +59,8 @@ reScript = re.compile("(?si)<script.*?>.*?</script>")
 reTag = re.compile("(?s)<.*?>")
 reEnts = re.compile(r"&#?\w+;")
 reMedia = re.compile("(?i)<img[^>]+src=[\"']?([^\"'>]+)[\"']?[^>]*>")
+reImg = re.compile(r"(?si)<img\b[^>]*>")
+reAlt = re.compile(r"""(?si)\salt\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))""")
 
 
 def stripHTML(s: str) -> str:
@@ -76,6 +78,19 @@ def stripHTMLMedia(s: str) -> str:
     return stripHTML(s)
 
 
+def stripHTMLImages(s: str) -> str:
+    "Strip HTML, replacing images with their alt text."
+
+    def repl(match: re.Match) -> str:
+        alt = reAlt.search(match.group(0))
+        if not alt:
+            return ""
+        return " %s " % next(g for g in alt.groups() if g is not None)
+
+    s = reImg.sub(repl, s)
+    return stripHTML(s)
+
+
 def minimizeHTML(s: str) -> str:
     "Correct Qt's verbose bold/underline/etc."
     s = re.sub('<span style="font-weight:600;">(.*?)</span>', "<b>\\1</b>", s)
@@ -109,3 +109,21 @@ def test_gendeck():
     f["Text"] += "{{c4::four}}"
     f.flush()
     assert f.cards()[3].did == newId
+
+
+def test_compact_new_positions():
+    col = getEmptyCol()
+    for front in ("1", "2"):
+        note = col.newNote()
+        note["Front"] = front
+        col.addNote(note)
+    col.db.execute("update cards set due = due + 10")
+    assert col.backend.compact_new_positions() == 2
+    assert col.conf["nextPos"] == 3
+    # saving the collection's copy of the config keeps the new position
+    col.setMod()
+    col.save()
+    note = col.newNote()
+    note["Front"] = "3"
+    col.addNote(note)
+    assert note.cards()[0].due == 3
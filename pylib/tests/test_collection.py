@@ -121,6 +121,38 @@ def test_addDelTags():
     assert len(f.tags) == 2
 
 
+def test_canonicalize_tags():
+    col = getEmptyCol()
+    for tag in ("foo", "Foo"):
+        note = col.newNote()
+        note["Front"] = tag
+        note.tags = [tag]
+        col.addNote(note)
+    assert col.backend.canonicalize_tags(["Foo"]) == {"foo": "Foo"}
+    assert col.tags.all() == ["Foo"]
+    # saving the tag list doesn't bring back the old spelling
+    col.tags.register(["bar"])
+    col.tags.flush()
+    col.load()
+    assert sorted(col.tags.all()) == ["Foo", "bar"]
+
+
+def test_clear_unused_tags():
+    col = getEmptyCol()
+    note = col.newNote()
+    note["Front"] = "1"
+    note.tags = ["used"]
+    col.addNote(note)
+    col.tags.register(["unused"])
+    assert col.backend.clear_unused_tags() == 1
+    assert col.tags.all() == ["used"]
+    # saving the tag list doesn't bring back the removed tag
+    col.tags.register(["new"])
+    col.tags.flush()
+    col.load()
+    assert sorted(col.tags.all()) == ["new", "used"]
+
+
 def test_timestamps():
     deck = getEmptyCol()
     assert len(deck.models.models) == len(models)
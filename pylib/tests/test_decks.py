@@ -177,3 +177,33 @@ def test_check():
     # d.decks.save(FOO)
     # d.decks._checkDeckTree()
     # assert "foo" not in d.decks.allNames() or "FOO" not in d.decks.allNames()
+
+
+def test_set_deck_config():
+    col = getEmptyCol()
+    conf = col.backend.get_deck_config(1)
+    conf.new_per_day = 5
+    col.backend.set_deck_config(conf)
+    assert col.decks.confForDid(1)["new"]["perDay"] == 5
+    # saving the collection's copy of the decks keeps the change
+    col.decks.id("other")
+    col.decks.flush()
+    col.load()
+    assert col.decks.confForDid(1)["new"]["perDay"] == 5
+
+
+def test_add_remove_deck_config():
+    col = getEmptyCol()
+    did = col.decks.id("other")
+    conf_id = col.backend.add_deck_config("new options")
+    col.backend.set_deck_config_for_decks(conf_id, [did])
+    assert col.decks.confForDid(did)["name"] == "new options"
+    # saving the collection's copy of the decks keeps the changes
+    col.decks.id("another")
+    col.decks.flush()
+    col.load()
+    assert col.decks.confForDid(did)["id"] == conf_id
+
+    col.backend.remove_deck_config(conf_id)
+    assert str(conf_id) not in col.decks.dconf
+    assert col.decks.confForDid(did)["id"] == 1
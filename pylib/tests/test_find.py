@@ -292,3 +292,19 @@ def test_findDupes():
     assert not r
     # front isn't dupe
     assert deck.findDupes("Front") == []
+
+
+def test_saved_searches():
+    col = getEmptyCol()
+    col.backend.save_search("due", "is:due")
+    assert col.conf["savedFilters"] == {"due": "is:due"}
+    # saving the collection's copy of the config keeps the search
+    col.setMod()
+    col.save()
+    assert [s.name for s in col.backend.saved_searches()] == ["due"]
+
+    col.backend.remove_saved_search("due")
+    assert col.conf["savedFilters"] == {}
+    col.setMod()
+    col.save()
+    assert not col.backend.saved_searches()
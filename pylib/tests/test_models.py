@@ -349,6 +349,22 @@ def test_modelChange():
     assert deck.db.scalar("select count() from cards where nid = ?", f.id) == 1
 
 
+def test_sort_field_strip_media():
+    col = getEmptyCol()
+    m = col.models.current()
+    note = col.newNote()
+    note["Front"] = '<img src="a.jpg" alt="cat">[sound:b.mp3] x'
+    col.addNote(note)
+    assert "a.jpg" in col.db.scalar("select sfld from notes")
+
+    m["sortfStripMedia"] = True
+    col.models.save(m, updateReqs=False)
+    note.load()
+    note.flush(mod=1)
+    sfld = col.db.scalar("select sfld from notes")
+    assert "cat" in sfld
+    assert "a.jpg" not in sfld and "b.mp3" not in sfld
+
 def test_availOrds():
     d = getEmptyCol()
     m = d.models.current()
@@ -414,6 +430,82 @@ def test_req():
     assert r[2] == [0]
 
 
+
+def test_add_notetype():
+    col = getEmptyCol()
+    ntid = col.backend.add_notetype(
+        "Vocab", ["Word", "Meaning"], [("Card 1", "{{Word}}", "{{Meaning}}")], ""
+    )
+    assert col.models.get(ntid)["name"] == "Vocab"
+    # saving the collection's copy of the notetypes keeps the new one
+    basic = col.models.byName("Basic")
+    basic["css"] += "\n"
+    col.models.save(basic)
+    col.models.flush()
+    col.load()
+    assert [f["name"] for f in col.models.get(ntid)["flds"]] == ["Word", "Meaning"]
+
+def test_notetype_field_commands():
+    col = getEmptyCol()
+    ntid = col.models.byName("Basic")["id"]
+    col.backend.add_notetype_field(ntid, "Extra")
+    col.backend.reposition_notetype_field(ntid, 2, 0)
+    col.backend.remove_notetype_field(ntid, 2)
+    assert [f["name"] for f in col.models.get(ntid)["flds"]] == ["Extra", "Front"]
+    # saving the collection's copy of the notetypes keeps the changes
+    col.models.save(col.models.byName("Cloze"))
+    col.models.flush()
+    col.load()
+    assert [f["name"] for f in col.models.get(ntid)["flds"]] == ["Extra", "Front"]
+
+def test_notetype_template_commands():
+    col = getEmptyCol()
+    ntid = col.models.byName("Basic")["id"]
+    note = col.newNote()
+    note["Front"] = "1"
+    note["Back"] = "2"
+    col.addNote(note)
+    assert col.backend.add_notetype_template(ntid, "Reverse", "{{Back}}", "", True) == 1
+    assert col.backend.remove_notetype_template(ntid, 0) == 1
+    assert [t["name"] for t in col.models.get(ntid)["tmpls"]] == ["Reverse"]
+    # saving the collection's copy of the notetypes keeps the changes
+    col.models.save(col.models.byName("Cloze"))
+    col.models.flush()
+    col.load()
+    assert [t["name"] for t in col.models.get(ntid)["tmpls"]] == ["Reverse"]
+    assert [c.ord for c in note.cards()] == [0]
+
+def test_clone_notetype():
+    col = getEmptyCol()
+    ntid = col.backend.clone_notetype(col.models.byName("Basic")["id"], "Copy")
+    assert col.models.get(ntid)["name"] == "Copy"
+    # saving the collection's copy of the notetypes keeps the clone
+    col.models.save(col.models.byName("Cloze"))
+    col.models.flush()
+    col.load()
+    assert col.models.byName("Copy")["id"] == ntid
+
+def test_convert_note_type():
+    col = getEmptyCol()
+    col.models.setCurrent(col.models.byName("Cloze"))
+    note = col.newNote()
+    note["Text"] = "{{c1::one}} {{c2::two}}"
+    col.addNote(note)
+    assert col.conf["nextPos"] == 2
+
+    basic = col.models.byName("Basic")
+    assert len(col.backend.convert_note_type([note.id], basic["id"])) == 2
+    assert col.conf["nextPos"] == 4
+    # saving the collection's copy of the config keeps the new position
+    col.setMod()
+    col.save()
+    assert col.db.scalar("select max(due) from cards") == 3
+    col.models.setCurrent(basic)
+    note = col.newNote()
+    note["Front"] = "new"
+    col.addNote(note)
+    assert note.cards()[0].due == 4
+
 # def test_updatereqs_performance():
 #     import time
 #     d = getEmptyCol()
@@ -1,6 +1,7 @@
 # coding: utf-8
 
 import copy
+import json
 import time
 
 from anki import hooks
@@ -795,6 +796,40 @@ def test_filt_keep_lrn_state():
     assert c.due - intTime() > 60 * 60
 
 
+def test_filt_preserve_order():
+    d = getEmptyCol()
+    cards = []
+    for text in ("one", "two", "three"):
+        f = d.newNote()
+        f["Front"] = text
+        d.addNote(f)
+        cards.append(f.cards()[0])
+
+    did = d.decks.newDyn("Cram")
+    deck = d.decks.get(did)
+    deck["terms"][0][2] = DYN_DUE
+    d.decks.save(deck)
+    d.sched.rebuildDyn(did)
+
+    def order():
+        return d.db.list("select id from cards where did = ? order by due", did)
+
+    assert order() == [c.id for c in cards]
+    # arrange the cards by hand, then add a card
+    d.db.execute("update cards set due = -200000 where id = ?", cards[2].id)
+    f = d.newNote()
+    f["Front"] = "four"
+    d.addNote(f)
+    new = f.cards()[0]
+
+    d.sched.rebuildDyn(did, preserveOrder=True)
+    assert order() == [cards[2].id, cards[0].id, cards[1].id, new.id]
+
+    # without the option, the deck's search order is used
+    d.sched.rebuildDyn(did)
+    assert order() == [cards[0].id, cards[1].id, cards[2].id, new.id]
+
+
 def test_preview():
     # add cards
     d = getEmptyCol()
@@ -1252,6 +1287,48 @@ def test_moveVersions():
     assert c.due == 50
 
 
+def test_upgrade_scheduler():
+    col = getEmptyCol()
+    col.changeSchedulerVer(1)
+    note = col.newNote()
+    note["Front"] = "one"
+    col.addNote(note)
+    col.reset()
+    c = col.sched.getCard()
+    col.sched.answerCard(c, 1)
+
+    assert col.backend.upgrade_scheduler()
+    assert col.schedVer() == 2
+    assert col.sched.name == "std2"
+    # saving the collection's copy of the config doesn't undo the upgrade
+    col.flush()
+    col.save()
+    assert json.loads(col.db.scalar("select conf from col"))["schedVer"] == 2
+    c.load()
+    assert c.queue == QUEUE_TYPE_NEW
+
+    assert not col.backend.upgrade_scheduler()
+
+
+def test_mark_leeches():
+    col = getEmptyCol()
+    note = col.newNote()
+    note["Front"] = "one"
+    col.addNote(note)
+    c = note.cards()[0]
+    c.lapses = 8
+    c.flush()
+
+    out = col.backend.mark_leeches()
+    assert list(out.note_ids) == [note.id]
+    assert "leech" in col.tags.all()
+    # saving the tag list doesn't drop the new tag
+    col.tags.register(["other"])
+    col.tags.flush()
+    col.load()
+    assert "leech" in col.tags.all()
+
+
 # cards with a due date earlier than the collection should retain
 # their due date when removed
 def test_negativeDueFilter():
@@ -73,8 +73,18 @@ class MediaSyncer:
         self._want_stop = False
         gui_hooks.media_sync_did_start_or_stop(True)
 
+        timeout_secs = self.mw.pm.media_sync_timeout()
+        proxy = self.mw.pm.media_sync_proxy()
+
         def run() -> None:
-            self.mw.col.backend.sync_media(hkey, self._endpoint())
+            self.mw.col.backend.sync_media(
+                hkey,
+                self._endpoint(),
+                timeout_secs=timeout_secs,
+                proxy_url=proxy.get("url", ""),
+                proxy_username=proxy.get("username", ""),
+                proxy_password=proxy.get("password", ""),
+            )
 
         self.mw.taskman.run_in_background(run, self._on_finished)
 
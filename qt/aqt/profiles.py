@@ -496,6 +496,14 @@ create table if not exists profiles
     def set_night_mode(self, on: bool) -> None:
         self.meta["night_mode"] = on
 
+    def media_sync_timeout(self) -> int:
+        "Seconds before a stalled media sync request is retried; 0 for default."
+        return self.meta.get("media_sync_timeout", 0)
+
+    def media_sync_proxy(self) -> Dict[str, str]:
+        "Keys are url, username and password. Empty to use the environment."
+        return self.meta.get("media_sync_proxy", {})
+
     # Profile-specific
     ######################################################################
 
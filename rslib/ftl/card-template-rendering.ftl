@@ -33,6 +33,16 @@ card-template-rendering-conditional-not-open =
 card-template-rendering-no-such-field =
   Found '{$found}', but there is no field called '{$field}'
 
+# when conditionals such as {{#Field}} are placed inside too many others,
+# eg, Conditionals are nested more than 100 deep
+card-template-rendering-nested-too-deeply =
+  Conditionals are nested more than {$limit} deep
+
+# when the card would be unreasonably large, eg because a large field is
+# included many times
+card-template-rendering-too-large =
+  The card is larger than the limit of {$limit} KB
+
 # This message is shown when the front side of the card is blank,
 # either due to a badly-designed template, or because required fields
 # are missing.
@@ -1 +1,2 @@
 importing-failed-debug-info = Import failed. Debugging info:
+importing-added-media-count = Added media files: { $count }
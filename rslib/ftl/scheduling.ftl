@@ -68,3 +68,16 @@ scheduling-learn-remaining = { $remaining ->
     [one]   There is one remaining learning card due later today.
    *[other] There are {$remaining} learning cards due later today.
   }
+
+scheduling-congratulations-finished = Congratulations! You have finished this deck for now.
+scheduling-today-review-limit-reached =
+  Today's review limit has been reached, but there are still cards
+  waiting to be reviewed. For optimum memory, consider increasing
+  the daily limit in the options.
+scheduling-today-new-limit-reached =
+  There are more new cards available, but the daily limit has been
+  reached. You can increase the limit in the options, but please
+  bear in mind that the more new cards you introduce, the higher
+  your short-term review workload will become.
+scheduling-buried-cards-found = Some related or buried cards were delayed until a later session.
+scheduling-other-decks-due = Other decks still have cards due today. You can choose one from the deck list to continue studying.
@@ -2,54 +2,223 @@
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
 use crate::backend::dbproxy::db_command_bytes;
+use crate::backend::operations::{OperationID, OperationStatus, Operations};
 use crate::backend_proto::backend_input::Value;
 use crate::backend_proto::{BuiltinSortKind, Empty, RenderedTemplateReplacement, SyncMediaIn};
-use crate::card::{Card, CardID};
+use crate::card::{Card, CardField, CardID, InvalidCardReason};
 use crate::card::{CardQueue, CardType};
+use crate::cloze::cloze_gaps;
 use crate::collection::{open_collection, Collection};
 use crate::config::SortKind;
-use crate::decks::DeckID;
+use crate::deckconf::DeckConf;
+use crate::decks::{DeckConfID, DeckID};
 use crate::err::{AnkiError, NetworkErrorKind, Result, SyncErrorKind};
+use crate::export::TsvExportOptions;
 use crate::i18n::{tr_args, FString, I18n};
 use crate::latex::{extract_latex, extract_latex_expanding_clozes, ExtractedLatex};
-use crate::log::{default_logger, Logger};
+use crate::log::{
+    default_logger, error, set_log_level, warn, Level, Logger, DEFAULT_LOG_ROTATE_KEEP,
+};
+use crate::markdown::html_to_markdown;
 use crate::media::check::MediaChecker;
-use crate::media::sync::MediaSyncProgress;
+use crate::media::sync::{MediaSyncProgress, MediaSyncSettings, ProxySettings};
 use crate::media::MediaManager;
-use crate::notes::NoteID;
-use crate::sched::cutoff::{local_minutes_west_for_stamp, sched_timing_today};
+use crate::notes::{guid, similar_notes, NoteID};
+use crate::notetypes::{
+    scope_css, NewCardTemplate, NewNoteType, NoteTypeChange, NoteTypeID, NoteTypeKind,
+};
+use crate::revlog::{RevlogEntry, RevlogReviewKind};
+use crate::sanitize::HtmlAllowlist;
+use crate::sched::cutoff::{
+    local_minutes_west_for_stamp, next_day_at_after_days, sched_timing_today, week_boundaries,
+    LocalOffsetCache,
+};
+use crate::sched::ease::EaseAdjustment;
+use crate::sched::new::NewCardOrder;
+use crate::sched::queue::StudyCounts;
 use crate::sched::timespan::{answer_button_time, learning_congrats, studied_today, time_span};
-use crate::search::{search_cards, search_notes, SortMode};
+use crate::search::{
+    check_search_fields, search_breakdown, search_cards, search_notes, search_notes_after,
+    search_notes_in_batches, SortMode,
+};
+use crate::stats::{CardDue, CardStats};
+use crate::tags::TagTreeNode;
 use crate::template::{
-    render_card, without_legacy_template_directives, FieldMap, FieldRequirements, ParsedTemplate,
-    RenderedNode,
+    localized_template_error, render_card, without_legacy_template_directives, FieldMap,
+    FieldRequirements, ParsedTemplate, RenderLimits, RenderedNode,
 };
-use crate::text::{extract_av_tags, strip_av_tags, AVTag};
+use crate::text::{extract_av_tags, normalize_whitespace, strip_av_tags, AVTag};
 use crate::timestamp::TimestampSecs;
+use crate::typeanswer::compare_answer;
 use crate::types::Usn;
 use crate::{backend_proto as pb, log};
+use chrono::Weekday;
 use fluent::FluentValue;
 use prost::Message;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
 
 mod dbproxy;
-
-pub type ProtoProgressCallback = Box<dyn Fn(Vec<u8>) -> bool + Send>;
-
+mod operations;
+
+pub type ProtoProgressCallback = Box<dyn Fn(Vec<u8>) -> bool + Send + Sync>;
+
+/// Concurrency model
+///
+/// Commands may be invoked from multiple threads. The collection is guarded
+/// by `col`, which is only held for the duration of a single command.
+///
+/// The media DB is guarded separately by `media_lock`. A media sync holds it
+/// for the duration of the sync, but releases the collection mutex while
+/// the sync runs, so other commands such as searches continue to work.
+/// Media commands issued during a sync wait for it to complete.
+///
+/// To avoid deadlocks, `media_lock` must always be taken before `col`, and
+/// never while `col` is held.
+///
+/// Some commands can also be started with StartOperation, which runs them
+/// on a separate thread with a copy of the backend that shares the above
+/// state, and returns a handle the client can poll or cancel with
+/// OperationStatus and OperationCancel. Their progress updates are tagged
+/// with the handle.
 pub struct Backend {
     col: Arc<Mutex<Option<Collection>>>,
-    progress_callback: Option<ProtoProgressCallback>,
+    media_lock: Arc<Mutex<()>>,
+    /// Set by AbortMediaCheck; checked by the media check progress handler.
+    media_check_aborted: Arc<AtomicBool>,
+    progress_callback: Option<Arc<ProtoProgressCallback>>,
+    progress_interval: Duration,
+    /// The throttling state of each running command, keyed by the thread
+    /// running it, so concurrent commands don't hold back or replace each
+    /// other's updates.
+    progress_throttles: Mutex<HashMap<ThreadId, ProgressThrottle>>,
+    operations: Arc<Mutex<Operations>>,
+    /// Set when this copy of the backend is running a background operation.
+    /// The flag is set if the client cancels it.
+    operation: Option<(OperationID, Arc<AtomicBool>)>,
     i18n: I18n,
     server: bool,
 }
 
+/// How many notes SimilarNotes returns if the client doesn't specify.
+const DEFAULT_SIMILAR_NOTES_LIMIT: usize = 20;
+
+/// Progress updates are sent to the client at most this often by default.
+const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Limits how often the progress callback is invoked for a command.
+/// Updates that arrive too soon after the previous one are held back, and
+/// only the latest is kept; it is sent when the command finishes.
+#[derive(Default)]
+struct ProgressThrottle {
+    interval: Duration,
+    last_sent: Option<Instant>,
+    pending: Option<Vec<u8>>,
+}
+
+impl ProgressThrottle {
+    fn new(interval: Duration) -> Self {
+        ProgressThrottle {
+            interval,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the update if it should be sent now, or holds it back.
+    fn throttle(&mut self, bytes: Vec<u8>, now: Instant) -> Option<Vec<u8>> {
+        match self.last_sent {
+            Some(last) if now.duration_since(last) < self.interval => {
+                self.pending = Some(bytes);
+                None
+            }
+            _ => {
+                self.last_sent = Some(now);
+                self.pending = None;
+                Some(bytes)
+            }
+        }
+    }
+
+    /// Returns any held back update.
+    fn finish(self) -> Option<Vec<u8>> {
+        self.pending
+    }
+}
+
 enum Progress<'a> {
     MediaSync(&'a MediaSyncProgress),
     MediaCheck(u32),
+    MediaAdd {
+        added: u32,
+        total: u32,
+        started: Instant,
+    },
+    RevlogExport {
+        exported: u32,
+        total: u32,
+        started: Instant,
+    },
+    RevlogImport {
+        processed: u32,
+        total: u32,
+        started: Instant,
+    },
+    NoteExport {
+        exported: u32,
+        total: u32,
+        started: Instant,
+    },
+    SortFieldRebuild {
+        processed: u32,
+        total: u32,
+        started: Instant,
+    },
+    SchedulerUpgrade {
+        steps_done: u32,
+        total: u32,
+        started: Instant,
+    },
+    SearchBatch {
+        nids: &'a [NoteID],
+        /// Including this batch.
+        found: u32,
+    },
+}
+
+/// Lock a mutex shared with background operations. If an operation
+/// panicked while holding the lock, it is taken anyway, so that one
+/// failed operation doesn't break every later command.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Run a background operation's command, reporting a panic as an error
+/// output so the operation still finishes.
+fn output_or_panic_error<F>(func: F) -> pb::BackendOutput
+where
+    F: FnOnce() -> pb::BackendOutput,
+{
+    panic::catch_unwind(AssertUnwindSafe(func)).unwrap_or_else(|payload| {
+        let reason = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        let err = pb::BackendError {
+            localized: format!("The operation failed unexpectedly: {}", reason),
+            value: Some(pb::backend_error::Value::Fatal(pb::Empty {})),
+        };
+        pb::BackendOutput {
+            value: Some(err.into()),
+        }
+    })
 }
 
 /// Convert an Anki error to a protobuf error.
@@ -68,6 +237,7 @@ fn anki_error_to_proto_error(err: AnkiError, i18n: &I18n) -> pb::BackendError {
         AnkiError::Interrupted => V::Interrupted(Empty {}),
         AnkiError::CollectionNotOpen => V::InvalidInput(pb::Empty {}),
         AnkiError::CollectionAlreadyOpen => V::InvalidInput(pb::Empty {}),
+        AnkiError::Conflict { .. } => V::Conflict(pb::Empty {}),
     };
 
     pb::BackendError {
@@ -122,14 +292,25 @@ pub fn init_backend(init_msg: &[u8]) -> std::result::Result<Backend, String> {
         log::terminal(),
     );
 
-    Ok(Backend::new(i18n, input.server))
+    let mut backend = Backend::new(i18n, input.server);
+    if input.progress_interval_millis > 0 {
+        backend.set_progress_interval(Duration::from_millis(input.progress_interval_millis as u64));
+    }
+
+    Ok(backend)
 }
 
 impl Backend {
     pub fn new(i18n: I18n, server: bool) -> Backend {
         Backend {
             col: Arc::new(Mutex::new(None)),
+            media_lock: Arc::new(Mutex::new(())),
+            media_check_aborted: Arc::new(AtomicBool::new(false)),
             progress_callback: None,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            progress_throttles: Mutex::new(HashMap::new()),
+            operations: Arc::new(Mutex::new(Operations::default())),
+            operation: None,
             i18n,
             server,
         }
@@ -170,14 +351,23 @@ impl Backend {
         F: FnOnce(&mut Collection) -> Result<T>,
     {
         func(
-            self.col
-                .lock()
-                .unwrap()
+            lock(&self.col)
                 .as_mut()
                 .ok_or(AnkiError::CollectionNotOpen)?,
         )
     }
 
+    /// Like with_col(), but also provides the collection's media manager,
+    /// which is opened on first use and reused until the collection is
+    /// closed. Waits for any running media sync to complete first.
+    fn with_media<F, T>(&self, func: F) -> Result<T>
+    where
+        F: FnOnce(&Collection, &MediaManager) -> Result<T>,
+    {
+        let _media_guard = lock(&self.media_lock);
+        self.with_col(|col| col.with_media(func))
+    }
+
     fn run_command(&mut self, input: pb::BackendInput) -> pb::BackendOutput {
         let oval = if let Some(ival) = input.value {
             match self.run_command_inner(ival) {
@@ -192,6 +382,9 @@ impl Backend {
             .into()
         };
 
+        // make sure the client sees the final state of any progress
+        self.flush_progress();
+
         pb::BackendOutput { value: Some(oval) }
     }
 
@@ -207,15 +400,27 @@ impl Backend {
             Value::SchedTimingToday(input) => {
                 OValue::SchedTimingToday(self.sched_timing_today(input))
             }
+            Value::WeekBoundaries(input) => OValue::WeekBoundaries(self.week_boundaries(input)),
             Value::DeckTree(_) => todo!(),
             Value::RenderCard(input) => OValue::RenderCard(self.render_template(input)?),
             Value::LocalMinutesWest(stamp) => {
                 OValue::LocalMinutesWest(local_minutes_west_for_stamp(stamp))
             }
             Value::StripAvTags(text) => OValue::StripAvTags(strip_av_tags(&text).into()),
+            Value::ClozeGaps(input) => OValue::ClozeGaps(pb::ClozeGapsOut {
+                missing: cloze_gaps(input.fields.iter().map(String::as_str))
+                    .into_iter()
+                    .map(u32::from)
+                    .collect(),
+            }),
+            Value::HtmlToMarkdown(html) => OValue::HtmlToMarkdown(html_to_markdown(&html)),
+            Value::NormalizeWhitespace(text) => {
+                OValue::NormalizeWhitespace(normalize_whitespace(&text).into())
+            }
             Value::ExtractAvTags(input) => OValue::ExtractAvTags(self.extract_av_tags(input)),
             Value::ExtractLatex(input) => OValue::ExtractLatex(self.extract_latex(input)),
             Value::AddMediaFile(input) => OValue::AddMediaFile(self.add_media_file(input)?),
+            Value::AddMediaFiles(input) => OValue::AddMediaFiles(self.add_media_files(input)?),
             Value::SyncMedia(input) => {
                 self.sync_media(input)?;
                 OValue::SyncMedia(Empty {})
@@ -226,6 +431,9 @@ impl Backend {
                 OValue::TrashMediaFiles(Empty {})
             }
             Value::TranslateString(input) => OValue::TranslateString(self.translate_string(input)),
+            Value::TranslateStrings(input) => {
+                OValue::TranslateStrings(self.translate_strings(input))
+            }
             Value::FormatTimeSpan(input) => OValue::FormatTimeSpan(self.format_time_span(input)),
             Value::StudiedToday(input) => OValue::StudiedToday(studied_today(
                 input.cards as usize,
@@ -245,27 +453,364 @@ impl Backend {
                 self.restore_trash()?;
                 OValue::RestoreTrash(Empty {})
             }
-            Value::OpenCollection(input) => {
-                self.open_collection(input)?;
-                OValue::OpenCollection(Empty {})
-            }
+            Value::OpenCollection(input) => OValue::OpenCollection(self.open_collection(input)?),
             Value::CloseCollection(_) => {
                 self.close_collection()?;
                 OValue::CloseCollection(Empty {})
             }
             Value::SearchCards(input) => OValue::SearchCards(self.search_cards(input)?),
             Value::SearchNotes(input) => OValue::SearchNotes(self.search_notes(input)?),
-            Value::GetCard(cid) => OValue::GetCard(self.get_card(cid)?),
+            Value::SearchNotesInBatches(input) => {
+                OValue::SearchNotesInBatches(self.search_notes_in_batches(input)?)
+            }
+            Value::SimilarNotes(input) => OValue::SimilarNotes(self.similar_notes(input)?),
+            Value::GetSavedSearches(_) => OValue::GetSavedSearches(self.saved_searches()?),
+            Value::SaveSearch(input) => {
+                self.with_col(|col| {
+                    col.transact(None, |ctx| ctx.save_search(&input.name, &input.search))
+                })?;
+                OValue::SaveSearch(pb::Empty {})
+            }
+            Value::RemoveSavedSearch(name) => {
+                self.with_col(|col| col.transact(None, |ctx| ctx.remove_saved_search(&name)))?;
+                OValue::RemoveSavedSearch(pb::Empty {})
+            }
+            Value::GetCard(cid) => OValue::GetCard(self.get_card(cid, false)?),
+            Value::GetCardWithOptions(input) => {
+                OValue::GetCardWithOptions(self.get_card(input.cid, input.lenient)?)
+            }
             Value::UpdateCard(card) => {
-                self.update_card(card)?;
+                self.update_card(pb::UpdateCardIn {
+                    card: Some(card),
+                    ..Default::default()
+                })?;
                 OValue::UpdateCard(pb::Empty {})
             }
+            Value::UpdateCardWithOptions(input) => {
+                self.update_card(input)?;
+                OValue::UpdateCardWithOptions(pb::Empty {})
+            }
             Value::AddCard(card) => OValue::AddCard(self.add_card(card)?),
+            Value::CompareAnswer(input) => OValue::CompareAnswer(self.compare_answer(input)),
+            Value::NoteCards(nid) => OValue::NoteCards(self.note_cards(nid)?),
+            Value::CardReviewLog(cid) => OValue::CardReviewLog(self.card_review_log(cid)?),
+            Value::CardInfo(cid) => OValue::CardInfo(self.card_info(cid)?),
+            Value::AddNotetype(input) => OValue::AddNotetype(self.add_notetype(input)?),
+            Value::AddNotetypeField(input) => {
+                OValue::AddNotetypeField(self.add_notetype_field(input)?)
+            }
+            Value::RemoveNotetypeField(input) => {
+                OValue::RemoveNotetypeField(self.remove_notetype_field(input)?)
+            }
+            Value::RepositionNotetypeField(input) => {
+                OValue::RepositionNotetypeField(self.reposition_notetype_field(input)?)
+            }
+            Value::AddNotetypeTemplate(input) => {
+                OValue::AddNotetypeTemplate(self.add_notetype_template(input)?)
+            }
+            Value::RemoveNotetypeTemplate(input) => {
+                OValue::RemoveNotetypeTemplate(self.remove_notetype_template(input)?)
+            }
+            Value::CloneNotetype(input) => {
+                let ntid = NoteTypeID(input.notetype_id);
+                let new_id = self.with_col(|col| {
+                    col.transact(None, |ctx| ctx.clone_note_type(ntid, &input.new_name))
+                })?;
+                OValue::CloneNotetype(new_id.0)
+            }
+            Value::NotetypeChangeImpact(input) => {
+                OValue::NotetypeChangeImpact(self.notetype_change_impact(input)?)
+            }
+            Value::FindInvalidCards(_) => OValue::FindInvalidCards(self.find_invalid_cards()?),
+            Value::CompactNewPositions(_) => {
+                let changed =
+                    self.with_col(|col| col.transact(None, |ctx| ctx.compact_new_positions()))?;
+                OValue::CompactNewPositions(changed as u32)
+            }
+            Value::ReorderNewCards(input) => {
+                OValue::ReorderNewCards(self.reorder_new_cards(input)?)
+            }
+            Value::RepositionNewCards(input) => {
+                OValue::RepositionNewCards(self.reposition_new_cards(input)?)
+            }
+            Value::CanonicalizeTags(input) => {
+                let merged = self.with_col(|col| {
+                    col.transact(None, |ctx| ctx.canonicalize_tags(&input.preferred))
+                })?;
+                OValue::CanonicalizeTags(pb::CanonicalizeTagsOut { merged })
+            }
+            Value::AllTags(_) => OValue::AllTags(self.all_tags()?),
+            Value::ClearUnusedTags(_) => {
+                let removed =
+                    self.with_col(|col| col.transact(None, |ctx| ctx.clear_unused_tags()))?;
+                OValue::ClearUnusedTags(removed as u32)
+            }
+            Value::GetDeckConfig(did) => {
+                let conf =
+                    self.with_col(|col| col.with_ctx(|ctx| ctx.deck_conf_for_deck(DeckID(did))))?;
+                OValue::GetDeckConfig(deck_conf_to_pb(conf))
+            }
+            Value::DeckConfigsList(_) => OValue::DeckConfigsList(self.deck_configs_list()?),
+            Value::AddDeckConfig(name) => {
+                let dcid =
+                    self.with_col(|col| col.transact(None, |ctx| ctx.add_deck_conf(&name)))?;
+                OValue::AddDeckConfig(dcid.0)
+            }
+            Value::SetDeckConfigForDecks(input) => {
+                let dids: Vec<_> = input.deck_ids.into_iter().map(DeckID).collect();
+                self.with_col(|col| {
+                    col.transact(None, |ctx| {
+                        ctx.set_deck_conf_for_decks(DeckConfID(input.config_id), &dids)
+                    })
+                })?;
+                OValue::SetDeckConfigForDecks(pb::Empty {})
+            }
+            Value::RemoveDeckConfig(dcid) => {
+                self.with_col(|col| {
+                    col.transact(None, |ctx| ctx.remove_deck_conf(DeckConfID(dcid)))
+                })?;
+                OValue::RemoveDeckConfig(pb::Empty {})
+            }
+            Value::DeckCongrats(did) => OValue::DeckCongrats(
+                self.with_col(|col| col.with_ctx(|ctx| ctx.deck_congrats(DeckID(did))))?,
+            ),
+            Value::GetNextCard(did) => {
+                let card = self.with_col(|col| {
+                    col.with_study_queues(|ctx, queues| ctx.next_card(DeckID(did), queues))
+                })?;
+                OValue::GetNextCard(pb::GetCardOut {
+                    card: card.map(card_to_pb),
+                })
+            }
+            Value::StartStudy(did) => {
+                let counts = self.with_col(|col| {
+                    col.with_study_queues(|ctx, queues| ctx.start_study(DeckID(did), queues))
+                })?;
+                OValue::StartStudy(study_counts_to_pb(counts))
+            }
+            Value::StudyState(did) => {
+                let counts = self.with_col(|col| {
+                    col.with_study_queues(|ctx, queues| ctx.study_counts(DeckID(did), queues))
+                })?;
+                OValue::StudyState(study_counts_to_pb(counts))
+            }
+            Value::PreviewLapse(cid) => {
+                let preview =
+                    self.with_col(|col| col.with_ctx(|ctx| ctx.preview_lapse(CardID(cid))))?;
+                OValue::PreviewLapse(pb::PreviewLapseOut {
+                    interval: preview.interval,
+                    ease_factor: preview.ease_factor as u32,
+                    lapses: preview.lapses,
+                    relearning: preview.relearning_delay.is_some(),
+                    relearning_delay_secs: preview.relearning_delay.unwrap_or_default(),
+                    leech: preview.leech,
+                    suspended: preview.suspended,
+                })
+            }
+            Value::AdjustEase(input) => OValue::AdjustEase(self.adjust_ease(input)?),
+            Value::FixFieldCounts(_) => {
+                let nids = self.with_col(|col| col.transact(None, |ctx| ctx.fix_field_counts()))?;
+                OValue::FixFieldCounts(pb::FixFieldCountsOut {
+                    note_ids: nids.into_iter().map(|nid| nid.0).collect(),
+                })
+            }
+            Value::SanitizeFields(input) => {
+                let allowlist =
+                    if input.allowed_tags.is_empty() && input.allowed_attributes.is_empty() {
+                        HtmlAllowlist::default()
+                    } else {
+                        HtmlAllowlist::new(&input.allowed_tags, &input.allowed_attributes)
+                    };
+                OValue::SanitizeFields(self.with_col(|col| {
+                    col.transact(None, |ctx| ctx.sanitize_fields(&input.search, &allowlist))
+                })?)
+            }
+            Value::FindBrokenHtml(search) => {
+                let broken =
+                    self.with_col(|col| col.with_ctx(|ctx| ctx.find_broken_html(&search)))?;
+                OValue::FindBrokenHtml(pb::FindBrokenHtmlOut {
+                    fields: broken
+                        .into_iter()
+                        .map(|b| pb::find_broken_html_out::BrokenField {
+                            note_id: b.nid.0,
+                            field_name: b.field,
+                            reason: b.reason,
+                        })
+                        .collect(),
+                })
+            }
+            Value::DedupeMedia(_) => OValue::DedupeMedia(self.dedupe_media()?),
+            Value::MediaSizeReport(largest) => {
+                OValue::MediaSizeReport(self.media_size_report(largest as usize)?)
+            }
+            Value::VerifyMediaChecksums(_) => {
+                OValue::VerifyMediaChecksums(self.verify_media_checksums()?)
+            }
+            Value::ProjectedIntervals(search) => {
+                let projected =
+                    self.with_col(|col| col.with_ctx(|ctx| ctx.projected_intervals(&search)))?;
+                OValue::ProjectedIntervals(pb::ProjectedIntervalsOut {
+                    intervals: projected
+                        .intervals
+                        .into_iter()
+                        .map(|(days, count)| pb::projected_intervals_out::Interval { days, count })
+                        .collect(),
+                    learning: projected.learning,
+                })
+            }
+            Value::NewGuid(_) => OValue::NewGuid(guid()),
+            Value::ConsolidateNoteDecks(input) => {
+                let deck = if input.deck_id == 0 {
+                    None
+                } else {
+                    Some(DeckID(input.deck_id))
+                };
+                OValue::ConsolidateNoteDecks(self.with_col(|col| {
+                    col.transact(None, |ctx| ctx.consolidate_note_decks(&input.search, deck))
+                })?)
+            }
+            Value::NotesWithSplitDecks(_) => {
+                let notes =
+                    self.with_col(|col| col.with_ctx(|ctx| ctx.notes_with_split_decks()))?;
+                OValue::NotesWithSplitDecks(pb::NotesWithSplitDecksOut {
+                    notes: notes
+                        .into_iter()
+                        .map(|(nid, dids)| pb::notes_with_split_decks_out::SplitNote {
+                            note_id: nid.0,
+                            deck_ids: dids.into_iter().map(|did| did.0).collect(),
+                        })
+                        .collect(),
+                })
+            }
+            Value::SearchBreakdown(search) => {
+                let breakdown =
+                    self.with_col(|col| col.with_ctx(|ctx| search_breakdown(ctx, &search)))?;
+                OValue::SearchBreakdown(pb::SearchBreakdownOut {
+                    total: breakdown.total,
+                    terms: breakdown
+                        .terms
+                        .into_iter()
+                        .map(|(search, count)| pb::search_breakdown_out::Term { search, count })
+                        .collect(),
+                    truncated: breakdown.truncated,
+                })
+            }
+            Value::SchedulerVersion(_) => OValue::SchedulerVersion(
+                self.with_col(|col| col.with_ctx(|ctx| ctx.scheduler_version()))? as u32,
+            ),
+            Value::UpgradeScheduler(_) => OValue::UpgradeScheduler(self.upgrade_scheduler()?),
+            Value::CollectionDayNumber(_) => OValue::CollectionDayNumber(
+                self.with_col(|col| col.with_ctx(|ctx| ctx.day_number()))?,
+            ),
+            Value::PreviewNotetypeTemplates(input) => {
+                OValue::PreviewNotetypeTemplates(self.preview_notetype_templates(input)?)
+            }
+            Value::RebuildSortFields(_) => OValue::RebuildSortFields(self.rebuild_sort_fields()?),
+            Value::ConvertNoteType(input) => {
+                let nids: Vec<_> = input.note_ids.into_iter().map(NoteID).collect();
+                let added = self.with_col(|col| {
+                    col.transact(None, |ctx| {
+                        ctx.convert_notes(&nids, NoteTypeID(input.notetype_id))
+                    })
+                })?;
+                OValue::ConvertNoteType(pb::ConvertNoteTypeOut {
+                    note_ids: added.into_iter().map(|nid| nid.0).collect(),
+                })
+            }
+            Value::MarkLeeches(_) => {
+                let (cids, nids) =
+                    self.with_col(|col| col.transact(None, |ctx| ctx.mark_leeches()))?;
+                OValue::MarkLeeches(pb::MarkLeechesOut {
+                    card_ids: cids.into_iter().map(|cid| cid.0).collect(),
+                    note_ids: nids.into_iter().map(|nid| nid.0).collect(),
+                })
+            }
+            Value::SetDeckConfig(input) => {
+                self.set_deck_config(input)?;
+                OValue::SetDeckConfig(pb::Empty {})
+            }
+            Value::ExportRevlogCsv(input) => {
+                OValue::ExportRevlogCsv(self.export_revlog_csv(input)?)
+            }
+            Value::ImportRevlog(input) => OValue::ImportRevlog(self.import_revlog(input)?),
+            Value::ExportTsv(input) => OValue::ExportTsv(self.export_tsv(input)?),
+            Value::CardsSinceUsn(input) => OValue::CardsSinceUsn(self.cards_since_usn(input)?),
+            Value::UpdateCards(input) => {
+                self.update_cards(input)?;
+                OValue::UpdateCards(pb::Empty {})
+            }
+            Value::NotetypeCss(input) => OValue::NotetypeCss(self.notetype_css(input)?),
+            Value::TemplateFields(template) => {
+                OValue::TemplateFields(self.template_fields(&template)?)
+            }
+            Value::ValidateTemplate(input) => {
+                OValue::ValidateTemplate(self.validate_template(input)?)
+            }
+            Value::NotetypeKind(ntid) => OValue::NotetypeKind(self.notetype_kind(ntid)? as i32),
+            Value::MediaFilePath(fname) => OValue::MediaFilePath(self.media_file_path(&fname)?),
+            Value::MediaFilenameForBytes(input) => {
+                OValue::MediaFilenameForBytes(self.media_filename_for_bytes(input)?)
+            }
+            Value::RotateLog(_) => {
+                self.with_col(|col| col.rotate_log())?;
+                OValue::RotateLog(Empty {})
+            }
+            Value::SetLogLevel(level) => {
+                set_log_level(log_level_from_pb(level));
+                OValue::SetLogLevel(Empty {})
+            }
+            Value::SetLanguages(input) => {
+                self.set_languages(input);
+                OValue::SetLanguages(Empty {})
+            }
+            Value::AvailableLanguages(_) => OValue::AvailableLanguages(self.available_languages()?),
+            Value::TrashUnusedMedia(_) => OValue::TrashUnusedMedia(self.trash_unused_media()?),
+            Value::RenameMediaFile(input) => {
+                OValue::RenameMediaFile(self.rename_media_file(input)?)
+            }
+            Value::AbortMediaCheck(_) => {
+                self.media_check_aborted.store(true, Ordering::Relaxed);
+                OValue::AbortMediaCheck(Empty {})
+            }
+            Value::StartOperation(input) => OValue::StartOperation(self.start_operation(&input)?),
+            Value::OperationStatus(id) => OValue::OperationStatus(self.operation_status(id)?),
+            Value::OperationCancel(id) => {
+                lock(&self.operations).cancel(id);
+                OValue::OperationCancel(Empty {})
+            }
+        })
+    }
+
+    /// Switch to a different set of languages. Operations already holding
+    /// a clone of the previous I18n will continue to use it.
+    fn set_languages(&mut self, input: pb::I18nBackendInit) {
+        let i18n = I18n::new(
+            &input.preferred_langs,
+            input.locale_folder_path,
+            log::terminal(),
+        );
+        if let Some(col) = lock(&self.col).as_mut() {
+            col.i18n = i18n.clone();
+        }
+        self.i18n = i18n;
+    }
+
+    fn available_languages(&self) -> Result<pb::AvailableLanguagesOut> {
+        Ok(pb::AvailableLanguagesOut {
+            languages: self
+                .i18n
+                .available_languages()?
+                .into_iter()
+                .map(|lang| pb::AvailableLanguage {
+                    code: lang.code,
+                    percent_translated: lang.percent_translated,
+                })
+                .collect(),
         })
     }
 
-    fn open_collection(&self, input: pb::OpenCollectionIn) -> Result<()> {
-        let mut col = self.col.lock().unwrap();
+    fn open_collection(&self, input: pb::OpenCollectionIn) -> Result<pb::OpenCollectionOut> {
+        let mut col = lock(&self.col);
         if col.is_some() {
             return Err(AnkiError::CollectionAlreadyOpen);
         }
@@ -277,9 +822,14 @@ impl Backend {
             "" => None,
             path => Some(path),
         };
-        let logger = default_logger(log_path)?;
+        let log_rotate_keep = match input.log_rotate_keep {
+            0 => DEFAULT_LOG_ROTATE_KEEP,
+            n => n,
+        };
+        let (logger, log_guard) = default_logger(log_path, log_rotate_keep)?;
+        set_log_level(log_level_from_pb(input.log_level));
 
-        let new_col = open_collection(
+        let mut new_col = open_collection(
             input.collection_path,
             input.media_folder_path,
             input.media_db_path,
@@ -287,14 +837,25 @@ impl Backend {
             self.i18n.clone(),
             logger,
         )?;
+        new_col.log_path = log_path.map(Into::into);
+        new_col.log_rotate_keep = log_rotate_keep;
+        new_col.log_guard = log_guard;
+        new_col.media_overlays = input
+            .media_overlay_paths
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        let scheduler_version = new_col.with_ctx(|ctx| ctx.scheduler_version())?;
 
         *col = Some(new_col);
 
-        Ok(())
+        Ok(pb::OpenCollectionOut {
+            scheduler_version: scheduler_version as u32,
+        })
     }
 
     fn close_collection(&self) -> Result<()> {
-        let mut col = self.col.lock().unwrap();
+        let mut col = lock(&self.col);
         if col.is_none() {
             return Err(AnkiError::CollectionNotOpen);
         }
@@ -308,17 +869,71 @@ impl Backend {
         Ok(())
     }
 
+    /// Pass the progress to the client, unless an update was sent recently.
+    /// Returns false if the client asked for the operation to be aborted.
     fn fire_progress_callback(&self, progress: Progress) -> bool {
+        self.send_progress(progress, true)
+    }
+
+    /// Like fire_progress_callback(), but if `throttled` is false, the
+    /// update is always sent, for updates carrying data the client needs.
+    fn send_progress(&self, progress: Progress, throttled: bool) -> bool {
+        let mut operation_id = 0;
+        if let Some((id, cancelled)) = &self.operation {
+            if cancelled.load(Ordering::Relaxed) {
+                return false;
+            }
+            operation_id = *id;
+        }
         if let Some(cb) = &self.progress_callback {
-            let bytes = progress_to_proto_bytes(progress, &self.i18n);
-            cb(bytes)
+            let bytes = progress_to_proto_bytes(progress, &self.i18n, operation_id);
+            let to_send = if throttled {
+                lock(&self.progress_throttles)
+                    .entry(thread::current().id())
+                    .or_insert_with(|| ProgressThrottle::new(self.progress_interval))
+                    .throttle(bytes, Instant::now())
+            } else {
+                Some(bytes)
+            };
+            match to_send {
+                Some(bytes) => cb(bytes),
+                None => true,
+            }
         } else {
             true
         }
     }
 
+    /// Send the latest progress update held back from the command running
+    /// on this thread, if any, and reset its throttling so the next
+    /// command's first update is sent immediately.
+    fn flush_progress(&self) {
+        let pending = lock(&self.progress_throttles)
+            .remove(&thread::current().id())
+            .and_then(ProgressThrottle::finish);
+        if let (Some(cb), Some(bytes)) = (&self.progress_callback, pending) {
+            // the operation has already completed, so an abort is moot
+            let _ = cb(bytes);
+        }
+    }
+
+    /// Returns false if the check should be aborted, either because
+    /// AbortMediaCheck was called or the progress handler returned false.
+    fn media_check_progress(&self, checked: usize) -> bool {
+        if self.media_check_aborted.load(Ordering::Relaxed) {
+            return false;
+        }
+        self.fire_progress_callback(Progress::MediaCheck(checked as u32))
+    }
+
     pub fn set_progress_callback(&mut self, progress_cb: Option<ProtoProgressCallback>) {
-        self.progress_callback = progress_cb;
+        self.progress_callback = progress_cb.map(Arc::new);
+    }
+
+    /// Change how often progress updates may be sent. A zero interval sends
+    /// every update.
+    pub fn set_progress_interval(&mut self, interval: Duration) {
+        self.progress_interval = interval;
     }
 
     fn template_requirements(
@@ -330,6 +945,7 @@ impl Backend {
             .iter()
             .map(|(name, ord)| (name.as_str(), *ord as u16))
             .collect();
+        let cloze = input.cloze;
         // map each provided template into a requirements list
         use crate::backend_proto::template_requirement::Value;
         let all_reqs = input
@@ -339,7 +955,12 @@ impl Backend {
                 let normalized = without_legacy_template_directives(&template);
                 if let Ok(tmpl) = ParsedTemplate::from_text(normalized.as_ref()) {
                     // convert the rust structure into a protobuf one
-                    let val = match tmpl.requirements(&map) {
+                    let reqs = if cloze {
+                        tmpl.cloze_requirements(&map)
+                    } else {
+                        tmpl.requirements(&map)
+                    };
+                    let val = match reqs {
                         FieldRequirements::Any(ords) => Value::Any(pb::TemplateRequirementAny {
                             ords: ords_hash_to_set(ords),
                         }),
@@ -362,6 +983,46 @@ impl Backend {
         })
     }
 
+    /// Check a template's field and filter references without rendering it.
+    fn validate_template(&self, input: pb::ValidateTemplateIn) -> Result<pb::ValidateTemplateOut> {
+        let normalized = without_legacy_template_directives(&input.template);
+        let tmpl = ParsedTemplate::from_text(normalized.as_ref()).map_err(|e| {
+            AnkiError::TemplateError {
+                info: localized_template_error(&self.i18n, e),
+            }
+        })?;
+        let fields: HashSet<_> = input.field_names.iter().map(AsRef::as_ref).collect();
+        let registered_filters: HashSet<_> =
+            input.registered_filters.iter().map(AsRef::as_ref).collect();
+
+        Ok(pb::ValidateTemplateOut {
+            unknown_fields: tmpl
+                .unknown_fields(&fields)
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            unknown_filters: tmpl
+                .unknown_filters(&registered_filters)
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        })
+    }
+
+    /// The note fields a template refers to.
+    fn template_fields(&self, template: &str) -> Result<pb::TemplateFieldsOut> {
+        let normalized = without_legacy_template_directives(template);
+        let tmpl = ParsedTemplate::from_text(normalized.as_ref()).map_err(|e| {
+            AnkiError::TemplateError {
+                info: localized_template_error(&self.i18n, e),
+            }
+        })?;
+        let mut fields: Vec<String> = tmpl.fields().into_iter().map(Into::into).collect();
+        fields.sort();
+
+        Ok(pb::TemplateFieldsOut { fields })
+    }
+
     fn sched_timing_today(&self, input: pb::SchedTimingTodayIn) -> pb::SchedTimingTodayOut {
         let today = sched_timing_today(
             input.created_secs as i64,
@@ -370,19 +1031,63 @@ impl Backend {
             input.now_mins_west.map(|v| v.val),
             input.rollover_hour.map(|v| v.val as i8),
         );
+        let offset_next_day_at = if input.rollover_hour.is_some() {
+            next_day_at_after_days(
+                today.next_day_at,
+                input.day_offset as i32,
+                local_minutes_west_for_stamp,
+            )
+        } else {
+            // the v1 scheduler's days are always 24 hours
+            today.next_day_at + i64::from(input.day_offset) * 86_400
+        };
         pb::SchedTimingTodayOut {
             days_elapsed: today.days_elapsed,
             next_day_at: today.next_day_at,
+            offset_next_day_at,
+        }
+    }
+
+    fn week_boundaries(&self, input: pb::WeekBoundariesIn) -> pb::WeekBoundariesOut {
+        let today = self.sched_timing_today(input.timing.unwrap_or_default());
+        let offsets = LocalOffsetCache::local();
+        let weeks = week_boundaries(
+            today.next_day_at,
+            weekday_from_pb(input.first_weekday),
+            input.weeks.max(1),
+            |stamp| offsets.minutes_west(stamp),
+        );
+        pb::WeekBoundariesOut {
+            week_starts: weeks.week_starts,
+            next_week_at: weeks.next_week_at,
         }
     }
 
     fn render_template(&self, input: pb::RenderCardIn) -> Result<pb::RenderCardOut> {
-        // convert string map to &str
-        let fields: HashMap<_, _> = input
+        let normalized: HashMap<_, _> = input
             .fields
             .iter()
-            .map(|(k, v)| (k.as_ref(), v.as_ref()))
+            .map(|(k, v)| {
+                let v = if input.normalize_whitespace {
+                    normalize_whitespace(v)
+                } else {
+                    v.as_str().into()
+                };
+                (k.as_str(), v)
+            })
             .collect();
+        // convert string map to &str
+        let fields: HashMap<_, _> = normalized.iter().map(|(k, v)| (*k, v.as_ref())).collect();
+        let tags: Vec<_> = input.tags.iter().map(AsRef::as_ref).collect();
+        let registered_filters: HashSet<_> =
+            input.registered_filters.iter().map(AsRef::as_ref).collect();
+        let mut limits = RenderLimits::default();
+        if input.max_output_len > 0 {
+            limits.max_output_len = input.max_output_len as usize;
+        }
+        if input.max_depth > 0 {
+            limits.max_depth = input.max_depth as usize;
+        }
 
         // render
         let (qnodes, anodes) = render_card(
@@ -390,8 +1095,19 @@ impl Backend {
             &input.answer_template,
             &fields,
             input.card_ordinal as u16,
+            &tags,
+            if input.flag_unknown_filters {
+                Some(&registered_filters)
+            } else {
+                None
+            },
+            limits,
             &self.i18n,
-        )?;
+        )
+        .map_err(|err| {
+            self.log_render_error(&input, &err);
+            err
+        })?;
 
         // return
         Ok(pb::RenderCardOut {
@@ -400,6 +1116,20 @@ impl Backend {
         })
     }
 
+    /// Record the card that failed to render, so the problem note can be
+    /// found from the log.
+    fn log_render_error(&self, input: &pb::RenderCardIn, err: &AnkiError) {
+        // rendering doesn't require an open collection, so this may fail
+        let _ = self.with_col(|col| {
+            error!(col.log, "card template failed to render";
+                "nid" => input.note_id,
+                "ntid" => input.notetype_id,
+                "ord" => input.card_ordinal,
+                "err" => ?err);
+            Ok(())
+        });
+    }
+
     fn extract_av_tags(&self, input: pb::ExtractAvTagsIn) -> pb::ExtractAvTagsOut {
         let (text, tags) = extract_av_tags(&input.text, input.question_side);
         let pt_tags = tags
@@ -432,6 +1162,14 @@ impl Backend {
         }
     }
 
+    fn compare_answer(&self, input: pb::CompareAnswerIn) -> pb::CompareAnswerOut {
+        let cmp = compare_answer(&input.expected, &input.provided);
+        pb::CompareAnswerOut {
+            html: cmp.html,
+            match_percent: cmp.match_percent,
+        }
+    }
+
     fn extract_latex(&self, input: pb::ExtractLatexIn) -> pb::ExtractLatexOut {
         let func = if input.expand_clozes {
             extract_latex_expanding_clozes
@@ -452,31 +1190,137 @@ impl Backend {
         }
     }
 
-    fn add_media_file(&mut self, input: pb::AddMediaFileIn) -> Result<String> {
-        self.with_col(|col| {
-            let mgr = MediaManager::new(&col.media_folder, &col.media_db)?;
+    fn add_media_file(&mut self, input: pb::AddMediaFileIn) -> Result<pb::AddMediaFileOut> {
+        self.with_media(|_col, mgr| {
+            let mut ctx = mgr.dbctx();
+            let added = mgr.add_file(&mut ctx, &input.desired_name, &input.data)?;
+            Ok(pb::AddMediaFileOut {
+                name: added.fname.into(),
+                already_existed: added.already_existed,
+                size: added.size as u64,
+            })
+        })
+    }
+
+    fn add_media_files(&self, input: pb::AddMediaFilesIn) -> Result<pb::AddMediaFilesOut> {
+        let total = input.files.len() as u32;
+        let started = Instant::now();
+        let callback = |progress: usize| {
+            self.fire_progress_callback(Progress::MediaAdd {
+                added: progress as u32,
+                total,
+                started,
+            })
+        };
+
+        self.with_media(|_col, mgr| {
             let mut ctx = mgr.dbctx();
+            let files: Vec<_> = input
+                .files
+                .iter()
+                .map(|f| (f.desired_name.as_str(), f.data.as_slice()))
+                .collect();
+            let added = mgr.add_files(&mut ctx, &files, callback)?;
+            Ok(pb::AddMediaFilesOut {
+                names: added.into_iter().map(|f| f.fname.into()).collect(),
+            })
+        })
+    }
+
+    fn media_filename_for_bytes(&self, input: pb::MediaFilenameForBytesIn) -> Result<String> {
+        self.with_media(|_col, mgr| mgr.filename_for_data(&input.data, &input.extension))
+    }
+
+    /// Full path to a media file, searching the overlay folders if it is
+    /// not in the primary folder. Empty if the file can't be found.
+    fn media_file_path(&self, fname: &str) -> Result<String> {
+        self.with_media(|_col, mgr| {
             Ok(mgr
-                .add_file(&mut ctx, &input.desired_name, &input.data)?
-                .into())
+                .file_path(fname)
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_default())
         })
     }
 
-    // fixme: will block other db access
+    /// A copy of the backend for running an operation on another thread.
+    /// The collection, media lock and progress handler are shared; progress
+    /// throttling is tracked separately.
+    fn for_operation(&self, id: OperationID, cancelled: Arc<AtomicBool>) -> Backend {
+        Backend {
+            col: self.col.clone(),
+            media_lock: self.media_lock.clone(),
+            media_check_aborted: self.media_check_aborted.clone(),
+            progress_callback: self.progress_callback.clone(),
+            progress_interval: self.progress_interval,
+            progress_throttles: Mutex::new(HashMap::new()),
+            operations: self.operations.clone(),
+            operation: Some((id, cancelled)),
+            i18n: self.i18n.clone(),
+            server: self.server,
+        }
+    }
 
-    fn sync_media(&self, input: SyncMediaIn) -> Result<()> {
-        let mut guard = self.col.lock().unwrap();
+    /// Run an encoded BackendInput on a background thread, returning a
+    /// handle for OperationStatus. Only media sync and media check are
+    /// accepted. A sync leaves the collection usable while it runs; a check
+    /// keeps it locked until it completes, so other collection commands
+    /// wait for it, though status and cancel requests don't. A command that
+    /// panics finishes with an error.
+    fn start_operation(&self, input: &[u8]) -> Result<OperationID> {
+        let input = pb::BackendInput::decode(input)
+            .map_err(|_| AnkiError::invalid_input("couldn't decode operation"))?;
+        match input.value {
+            Some(Value::SyncMedia(_)) | Some(Value::CheckMedia(_)) => (),
+            _ => {
+                return Err(AnkiError::invalid_input(
+                    "command can't be run in the background",
+                ))
+            }
+        }
 
-        let col = guard.as_mut().unwrap();
-        col.set_media_sync_running()?;
+        let (id, cancelled) = lock(&self.operations).start();
+        let mut backend = self.for_operation(id, cancelled);
+        thread::spawn(move || {
+            let output = output_or_panic_error(|| backend.run_command(input));
+            let mut buf = vec![];
+            output.encode(&mut buf).expect("encode failed");
+            lock(&backend.operations).finish(id, buf);
+        });
 
-        let folder = col.media_folder.clone();
-        let db = col.media_db.clone();
-        let log = col.log.clone();
+        Ok(id)
+    }
 
-        drop(guard);
+    fn operation_status(&self, id: OperationID) -> Result<pb::OperationStatusOut> {
+        match lock(&self.operations).status(id) {
+            Some(OperationStatus::Running) => Ok(pb::OperationStatusOut {
+                finished: false,
+                output: vec![],
+            }),
+            Some(OperationStatus::Finished(output)) => Ok(pb::OperationStatusOut {
+                finished: true,
+                output,
+            }),
+            None => Err(AnkiError::invalid_input("unknown operation")),
+        }
+    }
 
-        let res = self.sync_media_inner(input, folder, db, log);
+    /// Sync media with AnkiWeb. The collection mutex is released while the
+    /// sync runs, so the collection remains usable; access to the media DB
+    /// is coordinated with the media lock instead.
+    fn sync_media(&self, input: SyncMediaIn) -> Result<()> {
+        let (folder, db, log) = self.with_col(|col| {
+            col.set_media_sync_running()?;
+            Ok((
+                col.media_folder.clone(),
+                col.media_db.clone(),
+                col.log.clone(),
+            ))
+        })?;
+
+        let res = {
+            let _media_guard = lock(&self.media_lock);
+            self.sync_media_inner(input, folder, db, log)
+        };
 
         self.with_col(|col| col.set_media_sync_finished())?;
 
@@ -494,19 +1338,33 @@ impl Backend {
             self.fire_progress_callback(Progress::MediaSync(progress))
         };
 
+        let mut settings = MediaSyncSettings::default();
+        if input.timeout_secs > 0 {
+            settings.timeout = Duration::from_secs(input.timeout_secs as u64);
+        }
+        if !input.proxy_url.is_empty() {
+            settings.proxy = Some(ProxySettings {
+                url: input.proxy_url.clone(),
+                credentials: if input.proxy_username.is_empty() {
+                    None
+                } else {
+                    Some((input.proxy_username.clone(), input.proxy_password.clone()))
+                },
+            });
+        }
+
         let mgr = MediaManager::new(&folder, &db)?;
         let mut rt = Runtime::new().unwrap();
-        rt.block_on(mgr.sync_media(callback, &input.endpoint, &input.hkey, log))
+        rt.block_on(mgr.sync_media(callback, &input.endpoint, &input.hkey, &settings, log))
     }
 
     fn check_media(&self) -> Result<pb::MediaCheckOut> {
-        let callback =
-            |progress: usize| self.fire_progress_callback(Progress::MediaCheck(progress as u32));
+        self.media_check_aborted.store(false, Ordering::Relaxed);
+        let callback = |progress: usize| self.media_check_progress(progress);
 
-        self.with_col(|col| {
-            let mgr = MediaManager::new(&col.media_folder, &col.media_db)?;
+        self.with_media(|col, mgr| {
             col.transact(None, |ctx| {
-                let mut checker = MediaChecker::new(ctx, &mgr, callback);
+                let mut checker = MediaChecker::new(ctx, mgr, callback);
                 let mut output = checker.check()?;
 
                 let report = checker.summarize_output(&mut output);
@@ -521,9 +1379,90 @@ impl Backend {
         })
     }
 
+    fn trash_unused_media(&self) -> Result<pb::TrashUnusedMediaOut> {
+        self.media_check_aborted.store(false, Ordering::Relaxed);
+        let callback = |progress: usize| self.media_check_progress(progress);
+
+        self.with_media(|col, mgr| {
+            col.transact(None, |ctx| {
+                let mut checker = MediaChecker::new(ctx, mgr, callback);
+                let (count, bytes) = checker.trash_unused()?;
+                Ok(pb::TrashUnusedMediaOut { count, bytes })
+            })
+        })
+    }
+
+    fn verify_media_checksums(&self) -> Result<pb::VerifyMediaChecksumsOut> {
+        self.media_check_aborted.store(false, Ordering::Relaxed);
+        let callback = |progress: usize| self.media_check_progress(progress);
+
+        self.with_media(|col, mgr| {
+            col.with_ctx(|ctx| {
+                let mut checker = MediaChecker::new(ctx, mgr, callback);
+                let mismatches = checker.verify_checksums()?;
+                Ok(pb::VerifyMediaChecksumsOut {
+                    corrupt: mismatches.corrupt,
+                    modified: mismatches.modified,
+                })
+            })
+        })
+    }
+
+    fn dedupe_media(&self) -> Result<pb::DedupeMediaOut> {
+        self.media_check_aborted.store(false, Ordering::Relaxed);
+        let callback = |progress: usize| self.media_check_progress(progress);
+
+        self.with_media(|col, mgr| {
+            col.transact(None, |ctx| {
+                let mut checker = MediaChecker::new(ctx, mgr, callback);
+                let (count, bytes) = checker.dedupe()?;
+                Ok(pb::DedupeMediaOut { count, bytes })
+            })
+        })
+    }
+
+    fn media_size_report(&self, largest_count: usize) -> Result<pb::MediaSizeReportOut> {
+        self.media_check_aborted.store(false, Ordering::Relaxed);
+        let callback = |progress: usize| self.media_check_progress(progress);
+
+        self.with_media(|col, mgr| {
+            col.with_ctx(|ctx| {
+                let mut checker = MediaChecker::new(ctx, mgr, callback);
+                let report = checker.size_report(largest_count)?;
+                Ok(pb::MediaSizeReportOut {
+                    total_files: report.total_files,
+                    total_bytes: report.total_bytes,
+                    extensions: report
+                        .extensions
+                        .into_iter()
+                        .map(|usage| pb::media_size_report_out::Extension {
+                            extension: usage.extension,
+                            files: usage.files,
+                            bytes: usage.bytes,
+                        })
+                        .collect(),
+                    largest: report
+                        .largest
+                        .into_iter()
+                        .map(|(fname, bytes)| pb::media_size_report_out::File { fname, bytes })
+                        .collect(),
+                })
+            })
+        })
+    }
+
+    fn rename_media_file(&self, input: pb::RenameMediaFileIn) -> Result<u32> {
+        self.with_media(|col, mgr| {
+            col.transact(None, |ctx| {
+                let mut checker = MediaChecker::new(ctx, mgr, |_n| true);
+                let updated = checker.rename_file(&input.old_name, &input.new_name)?;
+                Ok(updated as u32)
+            })
+        })
+    }
+
     fn remove_media_files(&self, fnames: &[String]) -> Result<()> {
-        self.with_col(|col| {
-            let mgr = MediaManager::new(&col.media_folder, &col.media_db)?;
+        self.with_media(|_col, mgr| {
             let mut ctx = mgr.dbctx();
             mgr.remove_files(&mut ctx, fnames)
         })
@@ -544,6 +1483,18 @@ impl Backend {
         self.i18n.trn(key, map)
     }
 
+    /// Translate multiple strings at once. Unknown keys are returned as
+    /// a placeholder, like translate_string().
+    fn translate_strings(&self, input: pb::TranslateStringsIn) -> pb::TranslateStringsOut {
+        pb::TranslateStringsOut {
+            strings: input
+                .strings
+                .into_iter()
+                .map(|s| self.translate_string(s))
+                .collect(),
+        }
+    }
+
     fn format_time_span(&self, input: pb::FormatTimeSpanIn) -> String {
         let context = match pb::format_time_span_in::Context::from_i32(input.context) {
             Some(context) => context,
@@ -561,13 +1512,12 @@ impl Backend {
     }
 
     fn empty_trash(&self) -> Result<()> {
-        let callback =
-            |progress: usize| self.fire_progress_callback(Progress::MediaCheck(progress as u32));
+        self.media_check_aborted.store(false, Ordering::Relaxed);
+        let callback = |progress: usize| self.media_check_progress(progress);
 
-        self.with_col(|col| {
-            let mgr = MediaManager::new(&col.media_folder, &col.media_db)?;
+        self.with_media(|col, mgr| {
             col.transact(None, |ctx| {
-                let mut checker = MediaChecker::new(ctx, &mgr, callback);
+                let mut checker = MediaChecker::new(ctx, mgr, callback);
 
                 checker.empty_trash()
             })
@@ -575,14 +1525,12 @@ impl Backend {
     }
 
     fn restore_trash(&self) -> Result<()> {
-        let callback =
-            |progress: usize| self.fire_progress_callback(Progress::MediaCheck(progress as u32));
-
-        self.with_col(|col| {
-            let mgr = MediaManager::new(&col.media_folder, &col.media_db)?;
+        self.media_check_aborted.store(false, Ordering::Relaxed);
+        let callback = |progress: usize| self.media_check_progress(progress);
 
+        self.with_media(|col, mgr| {
             col.transact(None, |ctx| {
-                let mut checker = MediaChecker::new(ctx, &mgr, callback);
+                let mut checker = MediaChecker::new(ctx, mgr, callback);
 
                 checker.restore_trash()
             })
@@ -602,6 +1550,7 @@ impl Backend {
                         Some(V::None(_)) => SortMode::NoOrder,
                         Some(V::Custom(s)) => SortMode::Custom(s),
                         Some(V::FromConfig(_)) => SortMode::FromConfig,
+                        Some(V::Relevance(_)) => SortMode::Relevance,
                         Some(V::Builtin(b)) => SortMode::Builtin {
                             kind: sort_kind_from_pb(b.kind),
                             reverse: b.reverse,
@@ -611,7 +1560,10 @@ impl Backend {
                 } else {
                     SortMode::FromConfig
                 };
-                let cids = search_cards(ctx, &input.search, order)?;
+                if input.unknown_field_error {
+                    check_search_fields(ctx, &input.search)?;
+                }
+                let cids = search_cards(ctx, &input.search, order, input.strip_clozes)?;
                 Ok(pb::SearchCardsOut {
                     card_ids: cids.into_iter().map(|v| v.0).collect(),
                 })
@@ -622,7 +1574,10 @@ impl Backend {
     fn search_notes(&self, input: pb::SearchNotesIn) -> Result<pb::SearchNotesOut> {
         self.with_col(|col| {
             col.with_ctx(|ctx| {
-                let nids = search_notes(ctx, &input.search)?;
+                if input.unknown_field_error {
+                    check_search_fields(ctx, &input.search)?;
+                }
+                let nids = search_notes(ctx, &input.search, input.strip_clozes)?;
                 Ok(pb::SearchNotesOut {
                     note_ids: nids.into_iter().map(|v| v.0).collect(),
                 })
@@ -630,26 +1585,570 @@ impl Backend {
         })
     }
 
-    fn get_card(&self, cid: i64) -> Result<pb::GetCardOut> {
-        let card = self.with_col(|col| col.with_ctx(|ctx| ctx.storage.get_card(CardID(cid))))?;
-        Ok(pb::GetCardOut {
-            card: card.map(card_to_pb),
-        })
+    /// Pass the matching notes to the progress handler in batches. The
+    /// collection is only locked while each batch is read, and each batch is
+    /// sent once it has been unlocked again, so the handler may call back
+    /// into the backend.
+    fn search_notes_in_batches(&self, input: pb::SearchNotesInBatchesIn) -> Result<u32> {
+        let mut found = 0;
+        search_notes_in_batches(
+            input.batch_size as usize,
+            |after, limit| {
+                self.with_col(|col| {
+                    col.with_ctx(|ctx| {
+                        search_notes_after(ctx, &input.search, input.strip_clozes, after, limit)
+                    })
+                })
+            },
+            |nids| {
+                found += nids.len() as u32;
+                self.send_progress(Progress::SearchBatch { nids, found }, false)
+            },
+        )
     }
 
-    fn update_card(&self, pbcard: pb::Card) -> Result<()> {
-        let mut card = pbcard_to_native(pbcard)?;
-        self.with_col(|col| col.transact(None, |ctx| ctx.update_card(&mut card)))
+    fn similar_notes(&self, input: pb::SimilarNotesIn) -> Result<pb::SimilarNotesOut> {
+        let limit = match input.limit {
+            0 => DEFAULT_SIMILAR_NOTES_LIMIT,
+            n => n as usize,
+        };
+        let similar = self.with_col(|col| {
+            col.with_ctx(|ctx| similar_notes(ctx.storage.db, NoteID(input.note_id), limit))
+        })?;
+        Ok(pb::SimilarNotesOut {
+            notes: similar
+                .into_iter()
+                .map(|(nid, similarity)| pb::SimilarNote {
+                    note_id: nid.0,
+                    similarity,
+                })
+                .collect(),
+        })
     }
 
-    fn add_card(&self, pbcard: pb::Card) -> Result<i64> {
-        let mut card = pbcard_to_native(pbcard)?;
-        self.with_col(|col| col.transact(None, |ctx| ctx.add_card(&mut card)))?;
-        Ok(card.id.0)
+    fn saved_searches(&self) -> Result<pb::SavedSearchesOut> {
+        let searches = self.with_col(|col| col.with_ctx(|ctx| ctx.saved_searches()))?;
+        Ok(pb::SavedSearchesOut {
+            searches: searches
+                .into_iter()
+                .map(|(name, search)| pb::SavedSearch { name, search })
+                .collect(),
+        })
     }
-}
 
-fn translate_arg_to_fluent_val(arg: &pb::TranslateArgValue) -> FluentValue {
+    /// A card with an out-of-range type or queue is an error, unless
+    /// `lenient` is set, in which case it's returned as a new card, as in
+    /// lenient updates, so the client can load and fix it.
+    fn get_card(&self, cid: i64, lenient: bool) -> Result<pb::GetCardOut> {
+        let cid = CardID(cid);
+        let card = self.with_col(|col| {
+            if lenient {
+                let log = col.log.clone();
+                col.with_ctx(|ctx| ctx.storage.get_card_lenient(cid, &log))
+            } else {
+                col.with_ctx(|ctx| ctx.storage.get_card(cid))
+            }
+        })?;
+        Ok(pb::GetCardOut {
+            card: card.map(card_to_pb),
+        })
+    }
+
+    fn update_card(&self, input: pb::UpdateCardIn) -> Result<()> {
+        let pbcard = input
+            .card
+            .ok_or_else(|| AnkiError::invalid_input("card missing"))?;
+        let expected_mtime = match input.expected_mtime {
+            0 => None,
+            mtime => Some(TimestampSecs(mtime)),
+        };
+        self.with_col(|col| {
+            let lenient_log = if input.lenient { Some(&col.log) } else { None };
+            let mut card = pbcard_to_native(pbcard, lenient_log)?;
+            col.transact(None, |ctx| match expected_mtime {
+                Some(mtime) => ctx.update_card_if_unchanged(&mut card, mtime),
+                None => ctx.update_card(&mut card),
+            })
+        })
+    }
+
+    fn update_cards(&self, input: pb::UpdateCardsIn) -> Result<()> {
+        let fields = input
+            .fields
+            .into_iter()
+            .map(card_field_from_pb)
+            .collect::<Result<Vec<_>>>()?;
+        let lenient = input.lenient;
+        let pbcards = input.cards;
+        self.with_col(|col| {
+            let lenient_log = if lenient { Some(&col.log) } else { None };
+            let cards = pbcards
+                .into_iter()
+                .map(|c| pbcard_to_native(c, lenient_log))
+                .collect::<Result<Vec<_>>>()?;
+            col.transact(None, |ctx| ctx.update_card_fields(&cards, &fields))
+        })
+    }
+
+    fn find_invalid_cards(&self) -> Result<pb::FindInvalidCardsOut> {
+        use pb::invalid_card::Reason;
+        let invalid = self.with_col(|col| col.with_ctx(|ctx| ctx.storage.invalid_cards()))?;
+        Ok(pb::FindInvalidCardsOut {
+            cards: invalid
+                .into_iter()
+                .map(|(cid, reason)| pb::InvalidCard {
+                    card_id: cid.0,
+                    reason: match reason {
+                        InvalidCardReason::InvalidType => Reason::InvalidType,
+                        InvalidCardReason::InvalidQueue => Reason::InvalidQueue,
+                        InvalidCardReason::ReviewWithoutInterval => Reason::ReviewWithoutInterval,
+                    } as i32,
+                })
+                .collect(),
+        })
+    }
+
+    fn reorder_new_cards(&self, input: pb::ReorderNewCardsIn) -> Result<u32> {
+        use pb::reorder_new_cards_in::Order;
+        let order = match input.order {
+            Some(Order::RandomSeed(seed)) => NewCardOrder::Random { seed },
+            Some(Order::NoteCreation(_)) => NewCardOrder::NoteCreation,
+            Some(Order::FieldName(name)) => NewCardOrder::Field(name),
+            None => return Err(AnkiError::invalid_input("order missing")),
+        };
+        let count = self.with_col(|col| {
+            col.transact(None, |ctx| ctx.reorder_new_cards(&input.search, order))
+        })?;
+        Ok(count as u32)
+    }
+
+    fn adjust_ease(&self, input: pb::AdjustEaseIn) -> Result<pb::AdjustEaseOut> {
+        use pb::adjust_ease_in::Adjustment;
+        let adjustment = match input.adjustment {
+            Some(Adjustment::Multiplier(multiplier)) => EaseAdjustment::Multiply(multiplier),
+            Some(Adjustment::Delta(delta)) => EaseAdjustment::Add(delta),
+            None => return Err(AnkiError::invalid_input("adjustment missing")),
+        };
+        let adjusted = self
+            .with_col(|col| col.transact(None, |ctx| ctx.adjust_ease(&input.search, adjustment)))?;
+        Ok(pb::AdjustEaseOut {
+            count: adjusted.count as u32,
+            min_factor: adjusted.min_factor as u32,
+            max_factor: adjusted.max_factor as u32,
+        })
+    }
+
+    fn deck_configs_list(&self) -> Result<pb::DeckConfigsListOut> {
+        let confs = self.with_col(|col| col.with_ctx(|ctx| ctx.deck_confs_with_decks()))?;
+        Ok(pb::DeckConfigsListOut {
+            configs: confs
+                .into_iter()
+                .map(|(conf, dids)| pb::DeckConfigUsage {
+                    config: Some(deck_conf_to_pb(conf)),
+                    shared: dids.len() > 1,
+                    deck_ids: dids.into_iter().map(|did| did.0).collect(),
+                })
+                .collect(),
+        })
+    }
+
+    fn set_deck_config(&self, input: pb::DeckConfig) -> Result<()> {
+        self.with_col(|col| {
+            col.transact(None, |ctx| {
+                let mut conf = ctx.get_deck_conf(DeckConfID(input.id))?;
+                conf.name = input.name;
+                conf.new.delays = input.learn_steps;
+                conf.lapse.delays = input.relearn_steps;
+                conf.new.per_day = input.new_per_day;
+                conf.rev.per_day = input.reviews_per_day;
+                conf.new.initial_factor = input.initial_ease;
+                conf.rev.easy_bonus = input.easy_bonus;
+                conf.rev.max_ivl = input.maximum_interval;
+                conf.lapse.mult = input.lapse_multiplier;
+                conf.lapse.min_int = input.minimum_lapse_interval;
+                conf.lapse.leech_fails = input.leech_threshold;
+                ctx.update_deck_conf(&mut conf)
+            })
+        })
+    }
+
+    fn all_tags(&self) -> Result<pb::AllTagsOut> {
+        let tree = self.with_col(|col| col.with_ctx(|ctx| ctx.all_tags_tree()))?;
+        Ok(pb::AllTagsOut {
+            tags: tree.into_iter().map(tag_tree_node_to_proto).collect(),
+        })
+    }
+
+    fn reposition_new_cards(&self, input: pb::RepositionNewCardsIn) -> Result<u32> {
+        let cids: Vec<_> = input.card_ids.into_iter().map(CardID).collect();
+        let count = self.with_col(|col| {
+            col.transact(None, |ctx| {
+                ctx.reposition_new_cards(
+                    &cids,
+                    input.start,
+                    input.step,
+                    input.randomize,
+                    input.shift,
+                )
+            })
+        })?;
+        Ok(count as u32)
+    }
+
+    fn export_revlog_csv(&self, input: pb::ExportRevlogCsvIn) -> Result<u32> {
+        let days = if input.limit_days {
+            Some((input.from_days_ago, input.to_days_ago))
+        } else {
+            None
+        };
+        let started = Instant::now();
+        let progress = |exported, total| {
+            self.fire_progress_callback(Progress::RevlogExport {
+                exported,
+                total,
+                started,
+            })
+        };
+        self.with_col(|col| {
+            col.with_ctx(|ctx| ctx.export_revlog_csv(Path::new(&input.path), days, progress))
+        })
+    }
+
+    fn export_tsv(&self, input: pb::ExportTsvIn) -> Result<u32> {
+        let options = TsvExportOptions {
+            fields: input.fields,
+            include_tags: input.include_tags,
+            include_notetype: input.include_notetype,
+            strip_html: input.strip_html,
+            markdown: input.markdown,
+        };
+        let started = Instant::now();
+        let progress = |exported, total| {
+            self.fire_progress_callback(Progress::NoteExport {
+                exported,
+                total,
+                started,
+            })
+        };
+        self.with_col(|col| {
+            col.with_ctx(|ctx| {
+                ctx.export_tsv(Path::new(&input.path), &input.search, &options, progress)
+            })
+        })
+    }
+
+    fn import_revlog(&self, input: pb::ImportRevlogIn) -> Result<pb::ImportRevlogOut> {
+        let card_map: HashMap<_, _> = input
+            .card_id_map
+            .into_iter()
+            .map(|(from, to)| (CardID(from), CardID(to)))
+            .collect();
+        let started = Instant::now();
+        let progress = |processed, total| {
+            self.fire_progress_callback(Progress::RevlogImport {
+                processed,
+                total,
+                started,
+            })
+        };
+        let counts = self.with_col(|col| {
+            col.transact(None, |ctx| {
+                ctx.import_revlog(Path::new(&input.path), &card_map, progress)
+            })
+        })?;
+        Ok(pb::ImportRevlogOut {
+            imported: counts.imported,
+            skipped: counts.skipped,
+            unmapped: counts.unmapped,
+        })
+    }
+
+    fn rebuild_sort_fields(&self) -> Result<u32> {
+        let started = Instant::now();
+        let progress = |processed, total| {
+            self.fire_progress_callback(Progress::SortFieldRebuild {
+                processed,
+                total,
+                started,
+            })
+        };
+        self.with_col(|col| col.transact(None, |ctx| ctx.rebuild_sort_fields(progress)))
+    }
+
+    fn upgrade_scheduler(&self) -> Result<bool> {
+        let started = Instant::now();
+        let progress = |steps_done, total| {
+            self.fire_progress_callback(Progress::SchedulerUpgrade {
+                steps_done,
+                total,
+                started,
+            })
+        };
+        self.with_col(|col| col.transact(None, |ctx| ctx.upgrade_scheduler(progress)))
+    }
+
+    fn add_card(&self, pbcard: pb::Card) -> Result<i64> {
+        let mut card = pbcard_to_native(pbcard, None)?;
+        self.with_col(|col| col.transact(None, |ctx| ctx.add_card(&mut card)))?;
+        Ok(card.id.0)
+    }
+
+    fn card_review_log(&self, cid: i64) -> Result<pb::CardReviewLogOut> {
+        let entries =
+            self.with_col(|col| col.with_ctx(|ctx| ctx.storage.card_revlog(CardID(cid))))?;
+        Ok(pb::CardReviewLogOut {
+            entries: entries
+                .into_iter()
+                .map(|e| revlog_entry_to_pb(e, &self.i18n))
+                .collect(),
+        })
+    }
+
+    fn card_info(&self, cid: i64) -> Result<pb::CardInfoOut> {
+        let stats = self.with_col(|col| col.with_ctx(|ctx| ctx.card_stats(CardID(cid))))?;
+        Ok(card_stats_to_pb(stats, &self.i18n))
+    }
+
+    fn cards_since_usn(&self, input: pb::CardsSinceUsnIn) -> Result<pb::CardsSinceUsnOut> {
+        let changes = self.with_col(|col| {
+            col.with_ctx(|ctx| {
+                ctx.cards_since_usn(Usn(input.usn), CardID(input.after_id), input.limit)
+            })
+        })?;
+        Ok(pb::CardsSinceUsnOut {
+            cards: changes.cards.into_iter().map(card_to_pb).collect(),
+            removed_card_ids: changes.removed.into_iter().map(|cid| cid.0).collect(),
+            next_after_id: changes.next.map(|cid| cid.0).unwrap_or_default(),
+        })
+    }
+
+    fn notetype_kind(&self, ntid: i64) -> Result<pb::NotetypeKind> {
+        let kind = self.with_col(|col| col.with_ctx(|ctx| ctx.note_type_kind(NoteTypeID(ntid))))?;
+        Ok(match kind {
+            NoteTypeKind::Standard => pb::NotetypeKind::Standard,
+            NoteTypeKind::Cloze => pb::NotetypeKind::Cloze,
+        })
+    }
+
+    fn add_notetype(&self, input: pb::AddNotetypeIn) -> Result<i64> {
+        let kind = match pb::NotetypeKind::from_i32(input.kind) {
+            Some(pb::NotetypeKind::Cloze) => NoteTypeKind::Cloze,
+            _ => NoteTypeKind::Standard,
+        };
+        let nt = NewNoteType {
+            name: input.name,
+            kind,
+            fields: input.fields,
+            templates: input
+                .templates
+                .into_iter()
+                .map(new_card_template_from_pb)
+                .collect(),
+            css: input.css,
+        };
+        let ntid = self.with_col(|col| col.transact(None, |ctx| ctx.add_note_type(nt)))?;
+        Ok(ntid.0)
+    }
+
+    fn add_notetype_field(&self, input: pb::AddNotetypeFieldIn) -> Result<u32> {
+        let ntid = NoteTypeID(input.notetype_id);
+        let count = self
+            .with_col(|col| col.transact(None, |ctx| ctx.add_note_type_field(ntid, &input.name)))?;
+        Ok(count as u32)
+    }
+
+    fn remove_notetype_field(&self, input: pb::RemoveNotetypeFieldIn) -> Result<u32> {
+        let ntid = NoteTypeID(input.notetype_id);
+        let ord = input.ord as u16;
+        let count =
+            self.with_col(|col| col.transact(None, |ctx| ctx.remove_note_type_field(ntid, ord)))?;
+        Ok(count as u32)
+    }
+
+    fn reposition_notetype_field(&self, input: pb::RepositionNotetypeFieldIn) -> Result<u32> {
+        let ntid = NoteTypeID(input.notetype_id);
+        let (ord, new_ord) = (input.ord as u16, input.new_ord as u16);
+        let count = self.with_col(|col| {
+            col.transact(None, |ctx| {
+                ctx.reposition_note_type_field(ntid, ord, new_ord)
+            })
+        })?;
+        Ok(count as u32)
+    }
+
+    fn add_notetype_template(&self, input: pb::AddNotetypeTemplateIn) -> Result<u32> {
+        let ntid = NoteTypeID(input.notetype_id);
+        let template = input
+            .template
+            .map(new_card_template_from_pb)
+            .ok_or_else(|| AnkiError::invalid_input("template missing"))?;
+        let count = self.with_col(|col| {
+            col.transact(None, |ctx| {
+                ctx.add_note_type_template(ntid, template, input.generate_cards)
+            })
+        })?;
+        Ok(count as u32)
+    }
+
+    fn remove_notetype_template(&self, input: pb::RemoveNotetypeTemplateIn) -> Result<u32> {
+        let ntid = NoteTypeID(input.notetype_id);
+        let ord = input.ord as u16;
+        let count = self
+            .with_col(|col| col.transact(None, |ctx| ctx.remove_note_type_template(ntid, ord)))?;
+        Ok(count as u32)
+    }
+
+    fn notetype_change_impact(
+        &self,
+        input: pb::NotetypeChangeImpactIn,
+    ) -> Result<pb::NotetypeChangeImpactOut> {
+        use pb::notetype_change_impact_in::Change;
+        let change = match input.change {
+            Some(Change::RemoveField(field)) => NoteTypeChange::RemoveField {
+                ntid: NoteTypeID(field.notetype_id),
+                ord: field.ord as u16,
+            },
+            Some(Change::RemoveTemplate(template)) => NoteTypeChange::RemoveTemplate {
+                ntid: NoteTypeID(template.notetype_id),
+                ord: template.ord as u16,
+            },
+            None => return Err(AnkiError::invalid_input("change missing")),
+        };
+        let impact =
+            self.with_col(|col| col.with_ctx(|ctx| ctx.note_type_change_impact(change)))?;
+        Ok(pb::NotetypeChangeImpactOut {
+            notes_losing_data: impact.notes_losing_data as u32,
+            cards_removed: impact.cards_removed as u32,
+            cards_emptied: impact.cards_emptied as u32,
+            notes_without_cards: impact.notes_without_cards as u32,
+        })
+    }
+
+    fn note_cards(&self, nid: i64) -> Result<pb::NoteCardsOut> {
+        let cards = self.with_col(|col| col.with_ctx(|ctx| ctx.note_cards(NoteID(nid))))?;
+        Ok(pb::NoteCardsOut {
+            cards: cards
+                .into_iter()
+                .map(|c| pb::NoteCard {
+                    card_id: c.card.id.0,
+                    ord: c.card.ord as u32,
+                    template_name: c.template_name,
+                    deck_id: c.card.did.0,
+                    due: c.card.due,
+                })
+                .collect(),
+        })
+    }
+
+    fn preview_notetype_templates(
+        &self,
+        input: pb::PreviewNotetypeTemplatesIn,
+    ) -> Result<pb::PreviewNotetypeTemplatesOut> {
+        let templates = input
+            .templates
+            .into_iter()
+            .map(|t| NewCardTemplate {
+                name: t.name,
+                front: t.front,
+                back: t.back,
+            })
+            .collect();
+        let nids: Vec<_> = input.note_ids.into_iter().map(NoteID).collect();
+        let previews = self.with_col(|col| {
+            col.with_ctx(|ctx| {
+                ctx.preview_templates(NoteTypeID(input.notetype_id), templates, &nids)
+            })
+        })?;
+        let css = input.css;
+        Ok(pb::PreviewNotetypeTemplatesOut {
+            cards: previews
+                .into_iter()
+                .map(|preview| {
+                    let (nodes, error) = match preview.rendered {
+                        Ok(nodes) => (nodes, String::new()),
+                        Err(error) => ((vec![], vec![]), error),
+                    };
+                    pb::preview_notetype_templates_out::CardPreview {
+                        note_id: preview.nid.0,
+                        card_ord: preview.ord as u32,
+                        question_nodes: rendered_nodes_to_proto(nodes.0),
+                        answer_nodes: rendered_nodes_to_proto(nodes.1),
+                        error,
+                        css: scope_css(&css, preview.ord),
+                    }
+                })
+                .collect(),
+        })
+    }
+
+    fn notetype_css(&self, input: pb::NotetypeCssIn) -> Result<String> {
+        let note_types = self.with_col(|col| col.with_ctx(|ctx| ctx.storage.all_note_types()))?;
+        let note_type = note_types
+            .get(&NoteTypeID(input.notetype_id))
+            .ok_or_else(|| AnkiError::invalid_input("no such notetype"))?;
+        Ok(if input.scoped {
+            scope_css(&note_type.css, input.card_ord as u16)
+        } else {
+            note_type.css.clone()
+        })
+    }
+}
+
+fn log_level_from_pb(level: i32) -> Option<Level> {
+    use pb::LogLevel as L;
+    match L::from_i32(level) {
+        Some(L::Error) => Some(Level::Error),
+        Some(L::Warning) => Some(Level::Warning),
+        Some(L::Info) => Some(Level::Info),
+        Some(L::Debug) => Some(Level::Debug),
+        Some(L::Trace) => Some(Level::Trace),
+        Some(L::Default) | None => None,
+    }
+}
+
+fn weekday_from_pb(day: i32) -> Weekday {
+    use pb::Weekday as W;
+    match W::from_i32(day) {
+        Some(W::Monday) => Weekday::Mon,
+        Some(W::Tuesday) => Weekday::Tue,
+        Some(W::Wednesday) => Weekday::Wed,
+        Some(W::Thursday) => Weekday::Thu,
+        Some(W::Friday) => Weekday::Fri,
+        Some(W::Saturday) => Weekday::Sat,
+        Some(W::Sunday) | None => Weekday::Sun,
+    }
+}
+
+fn new_card_template_from_pb(t: pb::NewCardTemplate) -> NewCardTemplate {
+    NewCardTemplate {
+        name: t.name,
+        front: t.front,
+        back: t.back,
+    }
+}
+
+fn card_field_from_pb(field: i32) -> Result<CardField> {
+    use pb::CardField as F;
+    Ok(match F::from_i32(field) {
+        Some(F::Deck) => CardField::Deck,
+        Some(F::Ordinal) => CardField::Ordinal,
+        Some(F::Type) => CardField::Type,
+        Some(F::Queue) => CardField::Queue,
+        Some(F::Due) => CardField::Due,
+        Some(F::Interval) => CardField::Interval,
+        Some(F::EaseFactor) => CardField::EaseFactor,
+        Some(F::Reps) => CardField::Reps,
+        Some(F::Lapses) => CardField::Lapses,
+        Some(F::RemainingSteps) => CardField::RemainingSteps,
+        Some(F::OriginalDue) => CardField::OriginalDue,
+        Some(F::OriginalDeck) => CardField::OriginalDeck,
+        Some(F::Flags) => CardField::Flags,
+        Some(F::Data) => CardField::Data,
+        None => return Err(AnkiError::invalid_input("invalid card field")),
+    })
+}
+
+fn translate_arg_to_fluent_val(arg: &pb::TranslateArgValue) -> FluentValue {
     use pb::translate_arg_value::Value as V;
     match &arg.value {
         Some(val) => match val {
@@ -688,15 +2187,184 @@ fn rendered_node_to_proto(node: RenderedNode) -> pb::rendered_template_node::Val
     }
 }
 
-fn progress_to_proto_bytes(progress: Progress, i18n: &I18n) -> Vec<u8> {
-    let proto = pb::Progress {
-        value: Some(match progress {
-            Progress::MediaSync(p) => pb::progress::Value::MediaSync(media_sync_progress(p, i18n)),
-            Progress::MediaCheck(n) => {
-                let s = i18n.trn(FString::MediaCheckChecked, tr_args!["count"=>n]);
-                pb::progress::Value::MediaCheck(s)
+fn deck_conf_to_pb(conf: DeckConf) -> pb::DeckConfig {
+    pb::DeckConfig {
+        id: conf.id.0,
+        name: conf.name,
+        learn_steps: conf.new.delays,
+        relearn_steps: conf.lapse.delays,
+        new_per_day: conf.new.per_day,
+        reviews_per_day: conf.rev.per_day,
+        initial_ease: conf.new.initial_factor,
+        easy_bonus: conf.rev.easy_bonus,
+        maximum_interval: conf.rev.max_ivl,
+        lapse_multiplier: conf.lapse.mult,
+        minimum_lapse_interval: conf.lapse.min_int,
+        leech_threshold: conf.lapse.leech_fails,
+    }
+}
+
+fn tag_tree_node_to_proto(node: TagTreeNode) -> pb::TagTreeNode {
+    pb::TagTreeNode {
+        name: node.name,
+        full_name: node.full_name,
+        children: node
+            .children
+            .into_iter()
+            .map(tag_tree_node_to_proto)
+            .collect(),
+    }
+}
+
+/// `operation_id` is 0 if the command is not running in the background.
+fn progress_to_proto_bytes(progress: Progress, i18n: &I18n, operation_id: OperationID) -> Vec<u8> {
+    use pb::progress::Value;
+    let proto = match progress {
+        Progress::MediaSync(p) => {
+            let val = media_sync_progress(p, i18n);
+            pb::Progress {
+                phase: pb::ProgressPhase::MediaSync as i32,
+                operation_id,
+                current: p.checked as u32,
+                total: 0,
+                percent: 0,
+                eta_secs: 0,
+                message: [&val.checked, &val.added, &val.removed]
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                value: Some(Value::MediaSync(val)),
             }
-        }),
+        }
+        Progress::MediaCheck(n) => {
+            let s = i18n.trn(FString::MediaCheckChecked, tr_args!["count"=>n]);
+            pb::Progress {
+                phase: pb::ProgressPhase::MediaCheck as i32,
+                operation_id,
+                current: n,
+                total: 0,
+                percent: 0,
+                eta_secs: 0,
+                message: s.clone(),
+                value: Some(Value::MediaCheck(s)),
+            }
+        }
+        Progress::MediaAdd {
+            added,
+            total,
+            started,
+        } => {
+            let s = i18n.trn(FString::ImportingAddedMediaCount, tr_args!["count"=>added]);
+            let (percent, eta_secs) = progress_estimates(added, total, started.elapsed());
+            pb::Progress {
+                phase: pb::ProgressPhase::MediaAdd as i32,
+                operation_id,
+                current: added,
+                total,
+                message: s.clone(),
+                value: Some(Value::MediaAdd(s)),
+                percent,
+                eta_secs,
+            }
+        }
+        Progress::RevlogExport {
+            exported,
+            total,
+            started,
+        } => {
+            let (percent, eta_secs) = progress_estimates(exported, total, started.elapsed());
+            pb::Progress {
+                phase: pb::ProgressPhase::Export as i32,
+                operation_id,
+                current: exported,
+                total,
+                message: String::new(),
+                value: None,
+                percent,
+                eta_secs,
+            }
+        }
+        Progress::NoteExport {
+            exported,
+            total,
+            started,
+        } => {
+            let (percent, eta_secs) = progress_estimates(exported, total, started.elapsed());
+            pb::Progress {
+                phase: pb::ProgressPhase::Export as i32,
+                operation_id,
+                current: exported,
+                total,
+                message: String::new(),
+                value: None,
+                percent,
+                eta_secs,
+            }
+        }
+        Progress::SortFieldRebuild {
+            processed,
+            total,
+            started,
+        } => {
+            let (percent, eta_secs) = progress_estimates(processed, total, started.elapsed());
+            pb::Progress {
+                phase: pb::ProgressPhase::Other as i32,
+                operation_id,
+                current: processed,
+                total,
+                message: String::new(),
+                value: None,
+                percent,
+                eta_secs,
+            }
+        }
+        Progress::SchedulerUpgrade {
+            steps_done,
+            total,
+            started,
+        } => {
+            let (percent, eta_secs) = progress_estimates(steps_done, total, started.elapsed());
+            pb::Progress {
+                phase: pb::ProgressPhase::Other as i32,
+                operation_id,
+                current: steps_done,
+                total,
+                message: String::new(),
+                value: None,
+                percent,
+                eta_secs,
+            }
+        }
+        Progress::SearchBatch { nids, found } => pb::Progress {
+            phase: pb::ProgressPhase::Search as i32,
+            operation_id,
+            current: found,
+            total: 0,
+            message: String::new(),
+            value: Some(Value::SearchBatch(pb::SearchBatch {
+                note_ids: nids.iter().map(|nid| nid.0).collect(),
+            })),
+            percent: 0,
+            eta_secs: 0,
+        },
+        Progress::RevlogImport {
+            processed,
+            total,
+            started,
+        } => {
+            let (percent, eta_secs) = progress_estimates(processed, total, started.elapsed());
+            pb::Progress {
+                phase: pb::ProgressPhase::Import as i32,
+                operation_id,
+                current: processed,
+                total,
+                message: String::new(),
+                value: None,
+                percent,
+                eta_secs,
+            }
+        }
     };
 
     let mut buf = vec![];
@@ -704,6 +2372,25 @@ fn progress_to_proto_bytes(progress: Progress, i18n: &I18n) -> Vec<u8> {
     buf
 }
 
+/// The percentage complete and estimated seconds remaining, given the
+/// time taken so far. Both are 0 if the total is not known.
+fn progress_estimates(current: u32, total: u32, elapsed: Duration) -> (u32, u32) {
+    if total == 0 {
+        return (0, 0);
+    }
+    let current = current.min(total);
+    let percent = (current as u64 * 100 / total as u64) as u32;
+    let eta_secs = if current == 0 {
+        // no rate to extrapolate from yet
+        0
+    } else {
+        let remaining = (total - current) as f64;
+        (elapsed.as_secs_f64() / current as f64 * remaining).round() as u32
+    };
+
+    (percent, eta_secs)
+}
+
 fn media_sync_progress(p: &MediaSyncProgress, i18n: &I18n) -> pb::MediaSyncProgress {
     pb::MediaSyncProgress {
         checked: i18n.trn(FString::SyncMediaCheckedCount, tr_args!["count"=>p.checked]),
@@ -730,6 +2417,7 @@ fn sort_kind_from_pb(kind: i32) -> SortKind {
             BuiltinSortKind::CardMod => SK::CardMod,
             BuiltinSortKind::CardReps => SK::CardReps,
             BuiltinSortKind::CardDue => SK::CardDue,
+            BuiltinSortKind::NewCardPosition => SK::NewCardPosition,
             BuiltinSortKind::CardEase => SK::CardEase,
             BuiltinSortKind::CardLapses => SK::CardLapses,
             BuiltinSortKind::CardInterval => SK::CardInterval,
@@ -740,6 +2428,68 @@ fn sort_kind_from_pb(kind: i32) -> SortKind {
     }
 }
 
+fn revlog_entry_to_pb(e: RevlogEntry, i18n: &I18n) -> pb::RevlogEntry {
+    use pb::revlog_entry::ReviewKind;
+    let interval_secs = e.interval_secs();
+    pb::RevlogEntry {
+        id: e.id.0,
+        ease: e.button_chosen as u32,
+        interval_secs,
+        interval: time_span(interval_secs as f32, i18n, false),
+        ease_factor: e.ease_factor,
+        taken_millis: e.taken_millis,
+        review_kind: match e.review_kind {
+            RevlogReviewKind::Learning => ReviewKind::Learning,
+            RevlogReviewKind::Review => ReviewKind::Review,
+            RevlogReviewKind::Relearning => ReviewKind::Relearning,
+            RevlogReviewKind::Cram => ReviewKind::Cram,
+        } as i32,
+    }
+}
+
+fn card_stats_to_pb(s: CardStats, i18n: &I18n) -> pb::CardInfoOut {
+    use pb::card_info_out::Due;
+    pb::CardInfoOut {
+        card_id: s.card_id.0,
+        note_id: s.note_id.0,
+        deck: s.deck,
+        original_deck: s.original_deck.unwrap_or_default(),
+        notetype: s.notetype,
+        template: s.template,
+        added: s.added.0,
+        first_review: s.first_review.map(|t| t.0).unwrap_or_default(),
+        latest_review: s.latest_review.map(|t| t.0).unwrap_or_default(),
+        due: s.due.map(|due| match due {
+            CardDue::Time(t) => Due::DueDate(t.0),
+            CardDue::Position(pos) => Due::DuePosition(pos),
+        }),
+        interval: s
+            .interval
+            .map(|days| time_span(days as f32 * 86_400.0, i18n, false))
+            .unwrap_or_default(),
+        ease: s.ease.map(u32::from).unwrap_or_default(),
+        reviews: s.reviews,
+        lapses: s.lapses,
+        average_time: s
+            .average_secs
+            .map(|secs| time_span(secs, i18n, true))
+            .unwrap_or_default(),
+        total_time: if s.average_secs.is_some() {
+            time_span(s.total_secs, i18n, true)
+        } else {
+            String::new()
+        },
+    }
+}
+
+fn study_counts_to_pb(counts: StudyCounts) -> pb::StudyStateOut {
+    pb::StudyStateOut {
+        new_count: counts.new,
+        learn_count: counts.learning,
+        review_count: counts.review,
+    }
+}
+
 fn card_to_pb(c: Card) -> pb::Card {
     pb::Card {
         id: c.id.0,
@@ -763,11 +2513,31 @@ fn card_to_pb(c: Card) -> pb::Card {
     }
 }
 
-fn pbcard_to_native(c: pb::Card) -> Result<Card> {
-    let ctype = CardType::try_from(c.ctype as u8)
-        .map_err(|_| AnkiError::invalid_input("invalid card type"))?;
-    let queue = CardQueue::try_from(c.queue as i8)
-        .map_err(|_| AnkiError::invalid_input("invalid card queue"))?;
+/// Convert a card provided by the client. An out-of-range card type or
+/// queue is an error, unless a logger is provided in `lenient_log`, in
+/// which case it is replaced with New and a warning is logged, so that
+/// repair tools can load and fix bad cards.
+fn pbcard_to_native(c: pb::Card, lenient_log: Option<&Logger>) -> Result<Card> {
+    let ctype = match (CardType::try_from(c.ctype as u8), lenient_log) {
+        (Ok(ctype), _) => ctype,
+        (Err(_), Some(log)) => {
+            warn!(log, "invalid card type replaced with new";
+                "cid" => c.id,
+                "type" => c.ctype);
+            CardType::New
+        }
+        (Err(_), None) => return Err(AnkiError::invalid_input("invalid card type")),
+    };
+    let queue = match (CardQueue::try_from(c.queue as i8), lenient_log) {
+        (Ok(queue), _) => queue,
+        (Err(_), Some(log)) => {
+            warn!(log, "invalid card queue replaced with new";
+                "cid" => c.id,
+                "queue" => c.queue);
+            CardQueue::New
+        }
+        (Err(_), None) => return Err(AnkiError::invalid_input("invalid card queue")),
+    };
     Ok(Card {
         id: CardID(c.id),
         nid: NoteID(c.nid),
@@ -789,3 +2559,555 @@ fn pbcard_to_native(c: pb::Card) -> Result<Card> {
         data: c.data,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::Backend;
+    use crate::backend_proto as pb;
+    use crate::err::Result;
+    use crate::i18n::I18n;
+    use crate::log;
+    use crate::media::check::test::MEDIACHECK_ANKI2;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::{tempdir, TempDir};
+
+    /// A backend with a copy of the test collection open, and an empty
+    /// media folder.
+    fn open_test_backend() -> Result<(TempDir, Backend)> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("col.anki2");
+        fs::write(&col_path, MEDIACHECK_ANKI2)?;
+        let media_folder = dir.path().join("media");
+        fs::create_dir(&media_folder)?;
+
+        let backend = Backend::new(I18n::new(&["zz"], "dummy", log::terminal()), false);
+        backend.open_collection(pb::OpenCollectionIn {
+            collection_path: col_path.to_string_lossy().into(),
+            media_folder_path: media_folder.to_string_lossy().into(),
+            media_db_path: dir.path().join("media.db").to_string_lossy().into(),
+            ..Default::default()
+        })?;
+
+        Ok((dir, backend))
+    }
+
+    #[test]
+    fn progress_throttling() {
+        use super::Progress;
+        use prost::Message;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let mut backend = Backend::new(I18n::new(&["zz"], "dummy", log::terminal()), false);
+        let received = Arc::new(Mutex::new(vec![]));
+        let received2 = received.clone();
+        backend.set_progress_callback(Some(Box::new(move |bytes| {
+            received2.lock().unwrap().push(bytes);
+            true
+        })));
+        // long enough that only the first update is sent immediately
+        backend.set_progress_interval(Duration::from_secs(3600));
+
+        for n in 1..=1000 {
+            assert!(backend.fire_progress_callback(Progress::MediaCheck(n)));
+        }
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        // the final update is sent when the command completes
+        backend.flush_progress();
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 2);
+            let last = pb::Progress::decode(received.last().unwrap().as_slice()).unwrap();
+            assert_eq!(last.current, 1000);
+        }
+
+        // nothing is held back after a flush
+        backend.flush_progress();
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn concurrent_progress_throttling() {
+        use super::Progress;
+        use prost::Message;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+
+        let mut backend = Backend::new(I18n::new(&["zz"], "dummy", log::terminal()), false);
+        let received = Arc::new(Mutex::new(vec![]));
+        let received2 = received.clone();
+        backend.set_progress_callback(Some(Box::new(move |bytes| {
+            let progress = pb::Progress::decode(bytes.as_slice()).unwrap();
+            received2.lock().unwrap().push(progress.current);
+            true
+        })));
+        backend.set_progress_interval(Duration::from_secs(3600));
+
+        // two commands running at once each send their first and final
+        // updates
+        let backend = Arc::new(backend);
+        let threads: Vec<_> = vec![1..=1000, 2001..=3000]
+            .into_iter()
+            .map(|range| {
+                let backend = backend.clone();
+                thread::spawn(move || {
+                    for n in range {
+                        assert!(backend.fire_progress_callback(Progress::MediaCheck(n)));
+                    }
+                    backend.flush_progress();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut received = received.lock().unwrap().clone();
+        received.sort();
+        assert_eq!(received, vec![1, 1000, 2001, 3000]);
+    }
+
+    #[test]
+    fn progress_eta() {
+        use super::progress_estimates;
+        use std::time::Duration;
+
+        let secs = Duration::from_secs;
+        assert_eq!(progress_estimates(5, 0, secs(10)), (0, 0));
+        assert_eq!(progress_estimates(0, 100, secs(10)), (0, 0));
+        assert_eq!(progress_estimates(25, 100, secs(10)), (25, 30));
+        assert_eq!(progress_estimates(1, 3, secs(2)), (33, 4));
+        assert_eq!(progress_estimates(100, 100, secs(10)), (100, 0));
+        assert_eq!(progress_estimates(120, 100, secs(10)), (100, 0));
+    }
+
+    #[test]
+    fn progress_phases() {
+        use super::{progress_to_proto_bytes, Progress};
+        use crate::media::sync::MediaSyncProgress;
+        use pb::progress::Value;
+        use prost::Message;
+        use std::time::Instant;
+
+        let i18n = I18n::new(&["zz"], "dummy", log::terminal());
+        let sync_progress = MediaSyncProgress {
+            checked: 3,
+            ..Default::default()
+        };
+        let decode = |progress| {
+            pb::Progress::decode(progress_to_proto_bytes(progress, &i18n, 7).as_slice()).unwrap()
+        };
+
+        // the media variants are still set, alongside the common fields
+        let progress = decode(Progress::MediaCheck(5));
+        assert_eq!(progress.phase, pb::ProgressPhase::MediaCheck as i32);
+        assert_eq!((progress.current, progress.total), (5, 0));
+        assert_eq!(progress.operation_id, 7);
+        assert_eq!(
+            progress.value,
+            Some(Value::MediaCheck(progress.message.clone()))
+        );
+
+        let progress = decode(Progress::MediaSync(&sync_progress));
+        assert_eq!(progress.phase, pb::ProgressPhase::MediaSync as i32);
+        assert_eq!(progress.current, 3);
+        match &progress.value {
+            Some(Value::MediaSync(sync)) => assert_eq!(
+                progress.message,
+                format!("{}\n{}\n{}", sync.checked, sync.added, sync.removed)
+            ),
+            other => panic!("unexpected value: {:?}", other),
+        }
+
+        // other operations only use the common fields
+        let progress = decode(Progress::NoteExport {
+            exported: 25,
+            total: 100,
+            started: Instant::now(),
+        });
+        assert_eq!(progress.phase, pb::ProgressPhase::Export as i32);
+        assert_eq!((progress.current, progress.total), (25, 100));
+        assert_eq!(progress.percent, 25);
+        assert_eq!(progress.value, None);
+    }
+
+    #[test]
+    fn set_languages() {
+        let mut ftl_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        ftl_dir.push("tests/support/ftl");
+        let ftl_dir = ftl_dir.to_string_lossy().to_string();
+
+        let mut backend = Backend::new(I18n::new(&["zz"], &ftl_dir, log::terminal()), false);
+        let i18n = backend.i18n().clone();
+        assert_eq!(backend.i18n().tr_("valid-key", None), "a valid key");
+
+        backend.set_languages(pb::I18nBackendInit {
+            preferred_langs: vec!["ja_JP".into()],
+            locale_folder_path: ftl_dir,
+        });
+        assert_eq!(backend.i18n().tr_("valid-key", None), "キー");
+
+        // existing clones are unaffected
+        assert_eq!(i18n.tr_("valid-key", None), "a valid key");
+    }
+
+    #[test]
+    fn media_sync_progress_plurals() {
+        use super::media_sync_progress;
+        use crate::media::sync::MediaSyncProgress;
+
+        let mut ftl_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        ftl_dir.push("tests/support/ftl");
+        // Polish has separate forms for one, a few and many
+        let i18n = I18n::new(&["pl_PL"], &ftl_dir, log::terminal());
+        let checked = |count| {
+            let progress = MediaSyncProgress {
+                checked: count,
+                ..Default::default()
+            };
+            media_sync_progress(&progress, &i18n).checked
+        };
+        assert_eq!(checked(1), "Sprawdzono: 1 plik");
+        assert_eq!(checked(3), "Sprawdzono: 3 pliki");
+        assert_eq!(checked(5), "Sprawdzono: 5 plików");
+        assert_eq!(checked(22), "Sprawdzono: 22 pliki");
+    }
+
+    #[test]
+    fn search_during_media_sync() -> Result<()> {
+        use std::sync::{Arc, Barrier};
+        use std::thread;
+
+        let (_dir, backend) = open_test_backend()?;
+        let backend = Arc::new(backend);
+
+        // mimic sync_media() without contacting the server
+        backend.with_col(|col| col.set_media_sync_running())?;
+        let media_guard = backend.media_lock.lock().unwrap();
+
+        // the collection remains usable while the sync is in progress, by
+        // more than one caller at once
+        let barrier = Arc::new(Barrier::new(2));
+        let searches: Vec<_> = (0..2)
+            .map(|_| {
+                let backend = backend.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    backend.search_cards(pb::SearchCardsIn {
+                        search: "".into(),
+                        ..Default::default()
+                    })
+                })
+            })
+            .collect();
+        for search in searches {
+            let out = search.join().unwrap()?;
+            assert!(!out.card_ids.is_empty());
+        }
+        assert!(backend.close_collection().is_err());
+
+        drop(media_guard);
+        backend.with_col(|col| col.set_media_sync_finished())?;
+        backend.close_collection()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn search_batches_sent_after_unlocking() -> Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        let (_dir, mut backend) = open_test_backend()?;
+        let col = backend.col.clone();
+        let batches = Arc::new(Mutex::new(0));
+        let batches2 = batches.clone();
+        backend.set_progress_callback(Some(Box::new(move |_| {
+            // the handler could call into the backend
+            assert!(col.try_lock().is_ok());
+            *batches2.lock().unwrap() += 1;
+            true
+        })));
+
+        let found = backend.search_notes_in_batches(pb::SearchNotesInBatchesIn {
+            search: "".into(),
+            strip_clozes: false,
+            batch_size: 2,
+        })?;
+        assert_eq!(found, 3);
+        assert_eq!(*batches.lock().unwrap(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn card_data_round_trip() -> Result<()> {
+        let (_dir, backend) = open_test_backend()?;
+        let cid = 1581236445527;
+
+        // another client stores a key we know nothing about
+        let mut card = backend.get_card(cid, false)?.card.unwrap();
+        card.data = r#"{"other_client":{"a":[1,2]}}"#.into();
+        backend.update_card(pb::UpdateCardIn {
+            card: Some(card),
+            ..Default::default()
+        })?;
+
+        // we modify an unrelated field and write the card back
+        let mut card = backend.get_card(cid, false)?.card.unwrap();
+        card.flags = 1;
+        backend.update_card(pb::UpdateCardIn {
+            card: Some(card),
+            ..Default::default()
+        })?;
+
+        let card = backend.get_card(cid, false)?.card.unwrap();
+        assert_eq!(card.flags, 1);
+        assert_eq!(card.data, r#"{"other_client":{"a":[1,2]}}"#);
+
+        backend.close_collection()?;
+        Ok(())
+    }
+
+    #[test]
+    fn lenient_card_decoding() -> Result<()> {
+        let (_dir, backend) = open_test_backend()?;
+        let cid = 1581236445527;
+
+        let mut card = backend.get_card(cid, false)?.card.unwrap();
+        card.queue = 7;
+        card.flags = 2;
+
+        // strict by default
+        assert!(backend
+            .update_card(pb::UpdateCardIn {
+                card: Some(card.clone()),
+                ..Default::default()
+            })
+            .is_err());
+        assert_eq!(backend.get_card(cid, false)?.card.unwrap().queue, 1);
+
+        backend.update_card(pb::UpdateCardIn {
+            card: Some(card.clone()),
+            lenient: true,
+            ..Default::default()
+        })?;
+        let stored = backend.get_card(cid, false)?.card.unwrap();
+        assert_eq!(stored.queue, 0);
+        assert_eq!(stored.ctype, card.ctype);
+        assert_eq!(stored.flags, 2);
+
+        // bulk updates too
+        card.ctype = 9;
+        let fields = vec![pb::CardField::Type as i32];
+        assert!(backend
+            .update_cards(pb::UpdateCardsIn {
+                cards: vec![card.clone()],
+                fields: fields.clone(),
+                lenient: false,
+            })
+            .is_err());
+        backend.update_cards(pb::UpdateCardsIn {
+            cards: vec![card],
+            fields,
+            lenient: true,
+        })?;
+        assert_eq!(backend.get_card(cid, false)?.card.unwrap().ctype, 0);
+
+        // invalid values already in the database are an error when reading,
+        // unless the caller asks for them to be read as new
+        backend.with_col(|col| {
+            col.transact(None, |ctx| {
+                ctx.storage
+                    .db
+                    .execute("update cards set type = 9, queue = 7 where id = ?", &[cid])?;
+                Ok(())
+            })
+        })?;
+        assert!(backend.get_card(cid, false).is_err());
+        let card = backend.get_card(cid, true)?.card.unwrap();
+        assert_eq!((card.ctype, card.queue), (0, 0));
+
+        // reading leniently doesn't change what's stored
+        let stored: (u8, i8) = backend.with_col(|col| {
+            col.with_ctx(|ctx| {
+                Ok(ctx.storage.db.query_row(
+                    "select type, queue from cards where id = ?",
+                    &[cid],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?)
+            })
+        })?;
+        assert_eq!(stored, (9, 7));
+
+        backend.close_collection()?;
+        Ok(())
+    }
+
+    #[test]
+    fn operation_panics() -> Result<()> {
+        use super::output_or_panic_error;
+        use std::thread;
+
+        let output = output_or_panic_error(|| panic!("boom"));
+        match output.value {
+            Some(pb::backend_output::Value::Error(err)) => {
+                assert_eq!(
+                    err.value,
+                    Some(pb::backend_error::Value::Fatal(pb::Empty {}))
+                );
+                assert!(err.localized.ends_with("boom"));
+            }
+            other => panic!("unexpected output: {:?}", other),
+        }
+
+        // a panic while the collection is locked doesn't lock out later
+        // commands
+        let (_dir, backend) = open_test_backend()?;
+        let col = backend.col.clone();
+        assert!(thread::spawn(move || {
+            let _guard = col.lock();
+            panic!("boom");
+        })
+        .join()
+        .is_err());
+        assert!(backend
+            .with_col(|col| col.with_ctx(|ctx| ctx.storage.usn()))
+            .is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn background_operation() -> Result<()> {
+        use prost::Message;
+        use std::{thread, time::Duration};
+
+        let (_dir, backend) = open_test_backend()?;
+
+        let encode = |value| {
+            let mut buf = vec![];
+            pb::BackendInput { value: Some(value) }
+                .encode(&mut buf)
+                .unwrap();
+            buf
+        };
+
+        // only some commands can be run in the background
+        assert!(backend
+            .start_operation(&encode(pb::backend_input::Value::CloseCollection(
+                pb::Empty {}
+            )))
+            .is_err());
+
+        let id =
+            backend.start_operation(&encode(pb::backend_input::Value::CheckMedia(pb::Empty {})))?;
+        let mut status = backend.operation_status(id)?;
+        for _ in 0..500 {
+            if status.finished {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            status = backend.operation_status(id)?;
+        }
+        assert!(status.finished);
+        let output = pb::BackendOutput::decode(status.output.as_slice()).unwrap();
+        match output.value {
+            Some(pb::backend_output::Value::CheckMedia(out)) => {
+                assert_eq!(out.missing, vec!["foo[.jpg", "normal.jpg", "ぱぱ.jpg"]);
+            }
+            other => panic!("unexpected output: {:?}", other),
+        }
+
+        // the output is only returned once
+        assert!(backend.operation_status(id).is_err());
+
+        backend.close_collection()?;
+        Ok(())
+    }
+
+    #[test]
+    fn abort_media_check() -> Result<()> {
+        use pb::backend_input::Value;
+        use prost::Message;
+        use std::sync::{mpsc, Mutex};
+        use std::{thread, time::Duration};
+
+        let (dir, mut backend) = open_test_backend()?;
+
+        // hold the check at its first progress update until it has been
+        // aborted, then give it time to report progress again
+        let (progress_tx, progress_rx) = mpsc::channel();
+        let (resume_tx, resume_rx) = mpsc::channel();
+        let (progress_tx, resume_rx) = (Mutex::new(progress_tx), Mutex::new(resume_rx));
+        backend.set_progress_callback(Some(Box::new(move |_| {
+            let _ = progress_tx.lock().unwrap().send(());
+            let _ = resume_rx.lock().unwrap().recv();
+            thread::sleep(Duration::from_millis(200));
+            true
+        })));
+        backend.set_progress_interval(Duration::from_secs(0));
+
+        let encode = |value| {
+            let mut buf = vec![];
+            pb::BackendInput { value: Some(value) }
+                .encode(&mut buf)
+                .unwrap();
+            buf
+        };
+
+        // the checker reports progress at most every 0.15 secs, so add files
+        // until a check is still running when it does
+        let media_folder = dir.path().join("media");
+        let mut added = 0;
+        let mut interrupted = false;
+        for _ in 0..50 {
+            for _ in 0..1000 {
+                added += 1;
+                fs::write(media_folder.join(format!("{}.txt", added)), "x")?;
+            }
+
+            let id = backend.start_operation(&encode(Value::CheckMedia(pb::Empty {})))?;
+            let status = loop {
+                if progress_rx.try_recv().is_ok() {
+                    backend.run_command(pb::BackendInput {
+                        value: Some(Value::AbortMediaCheck(pb::Empty {})),
+                    });
+                    resume_tx.send(()).unwrap();
+                }
+                let status = backend.operation_status(id)?;
+                if status.finished {
+                    break status;
+                }
+                thread::sleep(Duration::from_millis(1));
+            };
+
+            let output = pb::BackendOutput::decode(status.output.as_slice()).unwrap();
+            match output.value {
+                Some(pb::backend_output::Value::Error(err)) => {
+                    assert_eq!(
+                        err.value,
+                        Some(pb::backend_error::Value::Interrupted(pb::Empty {}))
+                    );
+                    interrupted = true;
+                    break;
+                }
+                // finished before it could be aborted
+                Some(pb::backend_output::Value::CheckMedia(_)) => (),
+                other => panic!("unexpected output: {:?}", other),
+            }
+        }
+        assert!(interrupted);
+
+        // a later check isn't affected by the abort
+        backend.set_progress_callback(None);
+        let out = backend.check_media()?;
+        assert_eq!(out.unused.len(), added);
+
+        backend.close_collection()?;
+        Ok(())
+    }
+}
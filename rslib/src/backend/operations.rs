@@ -0,0 +1,99 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub(super) type OperationID = u32;
+
+/// Commands started with StartOperation, which run on a background
+/// thread. An operation is forgotten once the client has collected its
+/// output.
+#[derive(Default)]
+pub(super) struct Operations {
+    last_id: OperationID,
+    active: HashMap<OperationID, Operation>,
+}
+
+struct Operation {
+    cancelled: Arc<AtomicBool>,
+    /// The encoded BackendOutput, once the command has completed.
+    output: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(super) enum OperationStatus {
+    Running,
+    Finished(Vec<u8>),
+}
+
+impl Operations {
+    /// Register a new operation, returning its id, and a flag that is set
+    /// when the client asks for it to be cancelled.
+    pub(super) fn start(&mut self) -> (OperationID, Arc<AtomicBool>) {
+        // 0 is used in progress updates for commands run in the foreground
+        self.last_id = self.last_id.wrapping_add(1).max(1);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.active.insert(
+            self.last_id,
+            Operation {
+                cancelled: cancelled.clone(),
+                output: None,
+            },
+        );
+        (self.last_id, cancelled)
+    }
+
+    pub(super) fn finish(&mut self, id: OperationID, output: Vec<u8>) {
+        if let Some(op) = self.active.get_mut(&id) {
+            op.output = Some(output);
+        }
+    }
+
+    /// Returns None if the operation is unknown. A finished operation's
+    /// output is only returned once.
+    pub(super) fn status(&mut self, id: OperationID) -> Option<OperationStatus> {
+        let finished = self.active.get(&id)?.output.is_some();
+        if finished {
+            let op = self.active.remove(&id).unwrap();
+            Some(OperationStatus::Finished(op.output.unwrap()))
+        } else {
+            Some(OperationStatus::Running)
+        }
+    }
+
+    /// Ask the operation to stop at the next opportunity. Unknown
+    /// operations are ignored, as they may have already been collected.
+    pub(super) fn cancel(&self, id: OperationID) {
+        if let Some(op) = self.active.get(&id) {
+            op.cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OperationStatus, Operations};
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn operation_lifecycle() {
+        let mut ops = Operations::default();
+        let (id, cancelled) = ops.start();
+        let (id2, _) = ops.start();
+        assert_eq!((id, id2), (1, 2));
+
+        assert_eq!(ops.status(id), Some(OperationStatus::Running));
+        ops.cancel(id);
+        assert!(cancelled.load(Ordering::Relaxed));
+
+        ops.finish(id, vec![1, 2, 3]);
+        assert_eq!(
+            ops.status(id),
+            Some(OperationStatus::Finished(vec![1, 2, 3]))
+        );
+        assert_eq!(ops.status(id), None);
+        assert_eq!(ops.status(id2), Some(OperationStatus::Running));
+    }
+}
@@ -1,13 +1,18 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use crate::decks::DeckID;
+use crate::decks::{Deck, DeckID, DEFAULT_DECK_ID};
 use crate::define_newtype;
-use crate::err::{AnkiError, Result};
-use crate::notes::NoteID;
+use crate::err::{AnkiError, DBErrorKind, Result};
+use crate::notes::{get_note, NoteID};
+use crate::notetypes::{NoteType, NoteTypeID, NoteTypeKind};
+use crate::search::search_notes;
 use crate::{collection::RequestContext, timestamp::TimestampSecs, types::Usn};
 use num_enum::TryFromPrimitive;
+use rusqlite::NO_PARAMS;
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 
 define_newtype!(CardID, i64);
 
@@ -58,6 +63,9 @@ pub struct Card {
     pub(crate) odue: i32,
     pub(crate) odid: DeckID,
     pub(crate) flags: u8,
+    /// A JSON object that clients may store extra info in. It is written
+    /// back as provided, so keys not understood by the caller are retained
+    /// only if the caller passes them through unchanged.
     pub(crate) data: String,
 }
 
@@ -86,23 +94,712 @@ impl Default for Card {
     }
 }
 
+/// A column of the cards table that can be updated on its own.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum CardField {
+    Deck,
+    Ordinal,
+    Type,
+    Queue,
+    Due,
+    Interval,
+    EaseFactor,
+    Reps,
+    Lapses,
+    RemainingSteps,
+    OriginalDue,
+    OriginalDeck,
+    Flags,
+    Data,
+}
+
+impl Card {
+    /// Return the card to its original deck and due date, restoring the
+    /// queue of cards that are not suspended or buried.
+    fn remove_from_filtered_deck(&mut self) {
+        self.did = self.odid;
+        if self.odue != 0 {
+            self.due = self.odue;
+        }
+        self.odid = DeckID(0);
+        self.odue = 0;
+        if (self.queue as i8) >= 0 {
+            self.queue = match self.ctype {
+                CardType::New => CardQueue::New,
+                CardType::Review => CardQueue::Review,
+                // learning steps are due in seconds, relearning days
+                CardType::Learn | CardType::Relearn => {
+                    if self.due > 1_000_000_000 {
+                        CardQueue::Learn
+                    } else {
+                        CardQueue::DayLearn
+                    }
+                }
+            };
+        }
+    }
+
+    fn copy_field_from(&mut self, other: &Card, field: CardField) {
+        match field {
+            CardField::Deck => self.did = other.did,
+            CardField::Ordinal => self.ord = other.ord,
+            CardField::Type => self.ctype = other.ctype,
+            CardField::Queue => self.queue = other.queue,
+            CardField::Due => self.due = other.due,
+            CardField::Interval => self.ivl = other.ivl,
+            CardField::EaseFactor => self.factor = other.factor,
+            CardField::Reps => self.reps = other.reps,
+            CardField::Lapses => self.lapses = other.lapses,
+            CardField::RemainingSteps => self.left = other.left,
+            CardField::OriginalDue => self.odue = other.odue,
+            CardField::OriginalDeck => self.odid = other.odid,
+            CardField::Flags => self.flags = other.flags,
+            CardField::Data => self.data = other.data.clone(),
+        }
+    }
+}
+
+/// The most cards returned by a single cards_since_usn() call.
+const CARD_CHANGES_BATCH_LIMIT: u32 = 1000;
+
+/// A batch of changes returned by cards_since_usn().
+pub(crate) struct CardChanges {
+    pub cards: Vec<Card>,
+    /// Only included in the first batch.
+    pub removed: Vec<CardID>,
+    /// If set, more changes remain, and should be fetched by passing this
+    /// as `after`.
+    pub next: Option<CardID>,
+}
+
+/// A card along with the name of the template that generated it.
+pub(crate) struct NoteCard {
+    pub card: Card,
+    pub template_name: String,
+}
+
+/// Why a card in the database can't be used as is.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum InvalidCardReason {
+    InvalidType,
+    InvalidQueue,
+    ReviewWithoutInterval,
+}
+
+/// Check the raw type, queue and interval columns of a card, returning the
+/// first problem found. A column that is not an integer is invalid.
+pub(crate) fn invalid_card_reason(
+    ctype: Option<i64>,
+    queue: Option<i64>,
+    ivl: Option<i64>,
+) -> Option<InvalidCardReason> {
+    let ctype = ctype
+        .and_then(|n| u8::try_from(n).ok())
+        .and_then(|n| CardType::try_from(n).ok());
+    let ctype = match ctype {
+        Some(ctype) => ctype,
+        None => return Some(InvalidCardReason::InvalidType),
+    };
+    let queue = queue
+        .and_then(|n| i8::try_from(n).ok())
+        .and_then(|n| CardQueue::try_from(n).ok());
+    if queue.is_none() {
+        return Some(InvalidCardReason::InvalidQueue);
+    }
+    if ctype == CardType::Review && ivl.unwrap_or_default() <= 0 {
+        return Some(InvalidCardReason::ReviewWithoutInterval);
+    }
+
+    None
+}
+
+/// Ensure the card's deck and original deck exist. A missing deck id
+/// is replaced with the default deck; odid may be 0 if the card is not
+/// in a filtered deck.
+fn check_card_decks(card: &mut Card, decks: &HashMap<DeckID, Deck>) -> Result<()> {
+    if card.did.0 == 0 {
+        card.did = DEFAULT_DECK_ID;
+    }
+    if !decks.contains_key(&card.did) {
+        return Err(AnkiError::invalid_input(format!(
+            "deck {} not found",
+            card.did
+        )));
+    }
+    if card.odid.0 != 0 && !decks.contains_key(&card.odid) {
+        return Err(AnkiError::invalid_input(format!(
+            "original deck {} not found",
+            card.odid
+        )));
+    }
+
+    Ok(())
+}
+
 impl RequestContext<'_> {
+    /// Write the card back to the database. Every column is replaced,
+    /// including `data`, so callers must start from the stored card rather
+    /// than constructing one, or unknown `data` keys will be lost.
     pub(crate) fn update_card(&mut self, card: &mut Card) -> Result<()> {
+        let note_types = self.storage.all_note_types()?;
+        let decks = self.storage.all_decks()?;
+        self.update_card_checked(card, &note_types, &decks)
+    }
+
+    /// update_card(), validating against notetypes and decks the caller has
+    /// already loaded.
+    fn update_card_checked(
+        &mut self,
+        card: &mut Card,
+        note_types: &HashMap<NoteTypeID, NoteType>,
+        decks: &HashMap<DeckID, Deck>,
+    ) -> Result<()> {
         if card.id.0 == 0 {
             return Err(AnkiError::invalid_input("card id not set"));
         }
+        self.check_card_ord(card, note_types)?;
+        check_card_decks(card, decks)?;
         card.mtime = TimestampSecs::now();
         card.usn = self.storage.usn()?;
         self.storage.update_card(card)
     }
 
-    #[allow(dead_code)]
+    /// Like update_card(), but fails with a conflict error if the stored
+    /// card's mtime doesn't match expected_mtime, indicating it was modified
+    /// after the caller fetched it. As mtime has a granularity of one
+    /// second, changes made within the same second are not detected.
+    pub(crate) fn update_card_if_unchanged(
+        &mut self,
+        card: &mut Card,
+        expected_mtime: TimestampSecs,
+    ) -> Result<()> {
+        let stored = self
+            .storage
+            .get_card(card.id)?
+            .ok_or_else(|| AnkiError::invalid_input(format!("card {} not found", card.id)))?;
+        if stored.mtime != expected_mtime {
+            return Err(AnkiError::Conflict {
+                info: format!("card {}", card.id),
+            });
+        }
+        self.update_card(card)
+    }
+
+    /// Update only the provided fields of each card, leaving the rest of
+    /// the stored card untouched. The notetypes and decks are loaded once,
+    /// and each card is validated against them. Should be called inside a
+    /// transaction.
+    pub(crate) fn update_card_fields(
+        &mut self,
+        cards: &[Card],
+        fields: &[CardField],
+    ) -> Result<()> {
+        if fields.is_empty() {
+            return Err(AnkiError::invalid_input("no card fields provided"));
+        }
+        let note_types = self.storage.all_note_types()?;
+        let decks = self.storage.all_decks()?;
+        for updated in cards {
+            let mut card = self.storage.get_card(updated.id)?.ok_or_else(|| {
+                AnkiError::invalid_input(format!("card {} not found", updated.id))
+            })?;
+            for field in fields {
+                card.copy_field_from(updated, *field);
+            }
+            self.update_card_checked(&mut card, &note_types, &decks)?;
+        }
+
+        Ok(())
+    }
+
+    /// Give new cards contiguous positions starting at 1, in their current
+    /// order, and make the next added card follow them. Returns the number
+    /// of cards that were changed.
+    pub(crate) fn compact_new_positions(&mut self) -> Result<usize> {
+        let usn = self.storage.usn()?;
+        let (changed, positions) = self.storage.compact_new_positions(usn)?;
+        self.storage.set_config_value("nextPos", &(positions + 1))?;
+        Ok(changed)
+    }
+
     pub(crate) fn add_card(&mut self, card: &mut Card) -> Result<()> {
         if card.id.0 != 0 {
             return Err(AnkiError::invalid_input("card id already set"));
         }
+        let note_types = self.storage.all_note_types()?;
+        self.check_card_ord(card, &note_types)?;
+        check_card_decks(card, &self.storage.all_decks()?)?;
         card.mtime = TimestampSecs::now();
         card.usn = self.storage.usn()?;
         self.storage.add_card(card)
     }
+
+    /// Cards modified after the provided USN, including unsynced changes,
+    /// and the ids of cards removed since then. Cards are returned in
+    /// batches of up to `limit`; pass CardID(0) for the first batch.
+    pub(crate) fn cards_since_usn(
+        &mut self,
+        usn: Usn,
+        after: CardID,
+        limit: u32,
+    ) -> Result<CardChanges> {
+        let limit = match limit {
+            0 => CARD_CHANGES_BATCH_LIMIT,
+            n => n.min(CARD_CHANGES_BATCH_LIMIT),
+        };
+        let cards = self.storage.cards_since_usn(usn, after, limit)?;
+        let next = if cards.len() == limit as usize {
+            cards.last().map(|c| c.id)
+        } else {
+            None
+        };
+        let removed = if after.0 == 0 {
+            self.storage.removed_cards_since_usn(usn)?
+        } else {
+            vec![]
+        };
+
+        Ok(CardChanges {
+            cards,
+            removed,
+            next,
+        })
+    }
+
+    /// Ensure the card's ordinal refers to one of its notetype's templates,
+    /// and that no other card of the note already uses it.
+    fn check_card_ord(
+        &mut self,
+        card: &Card,
+        note_types: &HashMap<NoteTypeID, NoteType>,
+    ) -> Result<()> {
+        let note = get_note(self.storage.db, card.nid)?
+            .ok_or_else(|| AnkiError::invalid_input(format!("note {} not found", card.nid)))?;
+        let note_type = note_types
+            .get(&note.ntid)
+            .ok_or_else(|| AnkiError::DBError {
+                info: format!("missing note type {}", note.ntid),
+                kind: DBErrorKind::MissingEntity,
+            })?;
+        // cloze cards are numbered by cloze deletion, not by template
+        if note_type.kind != NoteTypeKind::Cloze && card.ord as usize >= note_type.templates.len() {
+            return Err(AnkiError::invalid_input(format!(
+                "card ordinal {} out of range for note type {}",
+                card.ord, note_type.name
+            )));
+        }
+
+        let (existing, _) = self.storage.note_cards(card.nid)?;
+        if existing
+            .iter()
+            .any(|c| c.ord == card.ord && c.id != card.id)
+        {
+            return Err(AnkiError::invalid_input(format!(
+                "note {} already has a card with ordinal {}",
+                card.nid, card.ord
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The cards of a note, in ordinal order.
+    pub(crate) fn note_cards(&mut self, nid: NoteID) -> Result<Vec<NoteCard>> {
+        let (cards, ntid) = self.storage.note_cards(nid)?;
+        let ntid = match ntid {
+            Some(ntid) => ntid,
+            None => return Ok(vec![]),
+        };
+        let note_types = self.storage.all_note_types()?;
+        let note_type = note_types.get(&ntid).ok_or_else(|| AnkiError::DBError {
+            info: format!("missing note type {}", ntid),
+            kind: DBErrorKind::MissingEntity,
+        })?;
+
+        Ok(cards
+            .into_iter()
+            .map(|card| NoteCard {
+                template_name: note_type.template_name(card.ord).to_string(),
+                card,
+            })
+            .collect())
+    }
+
+    /// Notes whose cards are in more than one deck, with the decks, in
+    /// note id and deck id order. Cards in a filtered deck count as being
+    /// in their original deck.
+    pub(crate) fn notes_with_split_decks(&self) -> Result<Vec<(NoteID, Vec<DeckID>)>> {
+        let mut stmt = self.storage.db.prepare(
+            "select distinct nid, (case when odid != 0 then odid else did end) as home
+             from cards where nid in (
+               select nid from cards group by nid
+               having count(distinct (case when odid != 0 then odid else did end)) > 1
+             )
+             order by nid, home",
+        )?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+
+        let mut notes: Vec<(NoteID, Vec<DeckID>)> = vec![];
+        while let Some(row) = rows.next()? {
+            let (nid, did): (NoteID, DeckID) = (row.get(0)?, row.get(1)?);
+            match notes.last_mut() {
+                Some((last_nid, decks)) if *last_nid == nid => decks.push(did),
+                _ => notes.push((nid, vec![did])),
+            }
+        }
+
+        Ok(notes)
+    }
+
+    /// Move the cards of each note matching `search` into a single deck:
+    /// `deck` if provided, or otherwise the deck most of the note's cards
+    /// are in, preferring the lowest deck id on a tie. Cards in a filtered
+    /// deck count as being in their original deck, and are returned from
+    /// the filtered deck if they need to move. Returns the number of cards
+    /// moved.
+    pub(crate) fn consolidate_note_decks(
+        &mut self,
+        search: &str,
+        deck: Option<DeckID>,
+    ) -> Result<u32> {
+        if let Some(did) = deck {
+            match self.storage.all_decks()?.get(&did) {
+                None => return Err(AnkiError::invalid_input(format!("deck {} not found", did))),
+                Some(deck) if deck.conf.is_none() => {
+                    return Err(AnkiError::invalid_input(
+                        "cards can't be moved into a filtered deck",
+                    ))
+                }
+                _ => (),
+            }
+        }
+        let mut nids = search_notes(self, search, false)?;
+        nids.sort();
+        nids.dedup();
+        let mtime = TimestampSecs::now();
+        let usn = self.storage.usn()?;
+
+        let mut moved = 0;
+        for nid in nids {
+            let (cards, _) = self.storage.note_cards(nid)?;
+            let home = |card: &Card| {
+                if card.odid.0 != 0 {
+                    card.odid
+                } else {
+                    card.did
+                }
+            };
+            let target = match deck {
+                Some(did) => did,
+                None => {
+                    let mut counts: HashMap<DeckID, usize> = HashMap::new();
+                    for card in &cards {
+                        *counts.entry(home(card)).or_default() += 1;
+                    }
+                    match counts
+                        .into_iter()
+                        .max_by_key(|&(did, count)| (count, std::cmp::Reverse(did.0)))
+                    {
+                        Some((did, _)) => did,
+                        None => continue,
+                    }
+                }
+            };
+
+            for mut card in cards {
+                if home(&card) == target {
+                    continue;
+                }
+                if card.odid.0 != 0 {
+                    card.remove_from_filtered_deck();
+                }
+                card.did = target;
+                card.mtime = mtime;
+                card.usn = usn;
+                self.storage.update_card(&card)?;
+                moved += 1;
+            }
+        }
+
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Card, CardField};
+    use crate::card::{CardID, CardQueue};
+    use crate::decks::DeckID;
+    use crate::err::{AnkiError, Result};
+    use crate::notes::NoteID;
+    use crate::timestamp::TimestampSecs;
+    use crate::types::Usn;
+    use crate::{collection::test::open_test_collection, collection::RequestContext};
+
+    #[test]
+    fn card_ordinals() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.with_ctx(|ctx| {
+            // the note uses the Basic notetype, and already has a card 1
+            let mut card = Card::default();
+            card.nid = NoteID(1581236386334);
+            card.did = DeckID(1);
+
+            // duplicate ordinal
+            card.ord = 0;
+            let res = ctx.add_card(&mut card);
+            assert!(matches!(res, Err(AnkiError::InvalidInput { .. })));
+
+            // out of range
+            card.ord = 1;
+            let res = ctx.add_card(&mut card);
+            assert!(matches!(res, Err(AnkiError::InvalidInput { .. })));
+
+            // updating the existing card with its own ordinal is fine
+            let mut card = ctx.storage.get_card(CardID(1581236445527))?.unwrap();
+            ctx.update_card(&mut card)?;
+            card.ord = 1;
+            let res = ctx.update_card(&mut card);
+            assert!(matches!(res, Err(AnkiError::InvalidInput { .. })));
+
+            // missing note
+            let mut card = Card::default();
+            card.nid = NoteID(1);
+            let res = ctx.add_card(&mut card);
+            assert!(matches!(res, Err(AnkiError::InvalidInput { .. })));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn card_decks() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.with_ctx(|ctx| {
+            // nonexistent decks
+            let mut card = ctx.storage.get_card(CardID(1581236445527))?.unwrap();
+            card.did = DeckID(5);
+            let res = ctx.update_card(&mut card);
+            assert!(matches!(res, Err(AnkiError::InvalidInput { .. })));
+            card.did = DeckID(1);
+            card.odid = DeckID(5);
+            let res = ctx.update_card(&mut card);
+            assert!(matches!(res, Err(AnkiError::InvalidInput { .. })));
+
+            // no deck falls back on the default deck
+            card.did = DeckID(0);
+            card.odid = DeckID(0);
+            ctx.update_card(&mut card)?;
+            assert_eq!(card.did, DeckID(1));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn update_card_fields() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.with_ctx(|ctx| {
+            // partial updates only touch the requested fields
+            let mut card = ctx.storage.get_card(CardID(1581236445527))?.unwrap();
+            let original = card.clone();
+            card.flags = 2;
+            card.due = 1234;
+            card.did = DeckID(5);
+            let mut other = ctx.storage.get_card(CardID(1581236461565))?.unwrap();
+            other.flags = 1;
+            ctx.update_card_fields(&[card.clone(), other], &[CardField::Flags])?;
+            let stored = ctx.storage.get_card(CardID(1581236445527))?.unwrap();
+            assert_eq!(stored.flags, 2);
+            assert_eq!(stored.due, original.due);
+            assert_eq!(stored.did, original.did);
+            let stored = ctx.storage.get_card(CardID(1581236461565))?.unwrap();
+            assert_eq!(stored.flags, 1);
+
+            // each card is still validated
+            let res = ctx.update_card_fields(&[card], &[CardField::Deck]);
+            assert!(matches!(res, Err(AnkiError::InvalidInput { .. })));
+
+            // at least one field is required
+            let card = ctx.storage.get_card(CardID(1581236445527))?.unwrap();
+            let res = ctx.update_card_fields(&[card], &[]);
+            assert!(matches!(res, Err(AnkiError::InvalidInput { .. })));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn update_card_if_unchanged() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.with_ctx(|ctx| {
+            // a stale update is rejected
+            let mut card = ctx.storage.get_card(CardID(1581236445527))?.unwrap();
+            let fetched_mtime = card.mtime;
+            card.mtime = TimestampSecs(fetched_mtime.0 - 10);
+            ctx.storage.update_card(&card)?;
+            card.flags = 3;
+            let res = ctx.update_card_if_unchanged(&mut card, fetched_mtime);
+            assert!(matches!(res, Err(AnkiError::Conflict { .. })));
+            let stored = ctx.storage.get_card(card.id)?.unwrap();
+            assert_ne!(stored.flags, 3);
+            ctx.update_card_if_unchanged(&mut card, stored.mtime)?;
+            assert_eq!(ctx.storage.get_card(card.id)?.unwrap().flags, 3);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn cards_since_usn() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.with_ctx(|ctx| {
+            // changes are returned in batches
+            let changes = ctx.cards_since_usn(Usn(0), CardID(0), 2)?;
+            assert_eq!(changes.cards.len(), 2);
+            assert_eq!(changes.removed, vec![]);
+            let changes = ctx.cards_since_usn(Usn(0), changes.next.unwrap(), 2)?;
+            assert_eq!(changes.cards.len(), 1);
+            assert_eq!(changes.next, None);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn compact_new_positions() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        fn position(ctx: &mut RequestContext, cid: CardID) -> (i32, i32) {
+            let card = ctx.storage.get_card(cid).unwrap().unwrap();
+            (card.due, card.odue)
+        }
+
+        col.transact(None, |ctx| {
+            // positions 500, 7000 and 20, with the last card in a filtered
+            // deck; the learning card is not affected
+            ctx.storage.db.execute_batch(
+                "update cards set type = 0, queue = 0, due = 500 where id = 1581236461565;
+                 update cards set due = 7000 where id = 1581236488474;",
+            )?;
+            let mut card = ctx.storage.get_card(CardID(1581236461565))?.unwrap();
+            card.id = CardID(0);
+            card.nid = NoteID(1581236386334);
+            card.ord = 0;
+            card.odid = DeckID(1);
+            card.due = -100_000;
+            card.odue = 20;
+            ctx.storage.add_card(&mut card)?;
+            let filtered_cid = card.id;
+
+            assert_eq!(ctx.compact_new_positions()?, 3);
+            assert_eq!(position(ctx, filtered_cid), (-100_000, 1));
+            assert_eq!(position(ctx, CardID(1581236461565)), (2, 0));
+            assert_eq!(position(ctx, CardID(1581236488474)), (3, 0));
+            assert_eq!(position(ctx, CardID(1581236445527)), (1581236553, 0));
+            assert_eq!(ctx.storage.get_config_value::<i32>("nextPos")?, Some(4));
+
+            // already compact
+            assert_eq!(ctx.compact_new_positions()?, 0);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn notes_with_split_decks() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            assert!(ctx.notes_with_split_decks()?.is_empty());
+
+            // a second card of the first note in another deck, and a card of
+            // the second note in a filtered deck
+            let mut card = ctx.storage.get_card(CardID(1581236445527))?.unwrap();
+            card.id = CardID(0);
+            card.ord = 1;
+            card.did = DeckID(5);
+            ctx.storage.add_card(&mut card)?;
+            ctx.storage
+                .db
+                .execute_batch("update cards set did = 6, odid = 1 where id = 1581236461565")?;
+
+            assert_eq!(
+                ctx.notes_with_split_decks()?,
+                vec![(NoteID(1581236386334), vec![DeckID(1), DeckID(5)])]
+            );
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn consolidate_note_decks() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                r#"update col set decks = '{
+                    "1": {"id": 1, "name": "Default", "conf": 1},
+                    "2": {"id": 2, "name": "Other", "conf": 1},
+                    "3": {"id": 3, "name": "Filtered"}
+                }';
+                update cards set usn = 0;"#,
+            )?;
+
+            // the first note has one card from Default in a filtered deck,
+            // and two from Other, one of which is also in the filtered deck
+            let c1 = CardID(1581236445527);
+            let mut card = ctx.storage.get_card(c1)?.unwrap();
+            card.id = CardID(0);
+            card.ord = 1;
+            card.did = DeckID(2);
+            ctx.storage.add_card(&mut card)?;
+            card.id = CardID(0);
+            card.ord = 2;
+            card.did = DeckID(3);
+            card.odid = DeckID(2);
+            ctx.storage.add_card(&mut card)?;
+            let filtered_cid = card.id;
+            ctx.storage.db.execute_batch(
+                "update cards set did = 3, odid = 1, due = -100000, odue = 1600000000
+                 where id = 1581236445527",
+            )?;
+
+            // the card from Default moves to Other, leaving the filtered
+            // deck; the card already from Other stays filtered
+            assert_eq!(ctx.consolidate_note_decks("", None)?, 1);
+            let card = ctx.storage.get_card(c1)?.unwrap();
+            assert_eq!(
+                (card.did, card.odid, card.due, card.odue, card.queue),
+                (DeckID(2), DeckID(0), 1600000000, 0, CardQueue::Learn)
+            );
+            assert_eq!(card.usn, Usn(-1));
+            let card = ctx.storage.get_card(filtered_cid)?.unwrap();
+            assert_eq!((card.did, card.odid), (DeckID(3), DeckID(2)));
+            assert!(ctx.notes_with_split_decks()?.is_empty());
+            assert_eq!(ctx.consolidate_note_decks("", None)?, 0);
+
+            // a specified deck
+            assert_eq!(
+                ctx.consolidate_note_decks("nid:1581236445532", Some(DeckID(2)))?,
+                1
+            );
+            let card = ctx.storage.get_card(CardID(1581236461565))?.unwrap();
+            assert_eq!(card.did, DeckID(2));
+
+            // filtered and missing decks can't be targets
+            for did in &[3, 9] {
+                let res = ctx.consolidate_note_decks("", Some(DeckID(*did)));
+                assert!(matches!(res, Err(AnkiError::InvalidInput { .. })));
+            }
+
+            Ok(())
+        })
+    }
 }
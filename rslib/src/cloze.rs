@@ -109,6 +109,11 @@ pub fn expand_clozes_to_reveal_latex(text: &str) -> String {
     buf
 }
 
+/// Replace cloze deletions with the text they contain, dropping any hints.
+pub(crate) fn strip_clozes(text: &str) -> Cow<str> {
+    CLOZE.replace_all(text, format!("${}", cloze_caps::TEXT).as_str())
+}
+
 pub fn cloze_numbers_in_string(html: &str) -> HashSet<u16> {
     let mut hash = HashSet::with_capacity(4);
     for cap in CLOZE.captures_iter(html) {
@@ -119,6 +124,20 @@ pub fn cloze_numbers_in_string(html: &str) -> HashSet<u16> {
     hash
 }
 
+/// The cloze numbers between the lowest and highest used in `texts` that
+/// are not used by any cloze, in ascending order. Notes with such gaps
+/// generate fewer cards than their highest number suggests.
+pub(crate) fn cloze_gaps<'a>(texts: impl IntoIterator<Item = &'a str>) -> Vec<u16> {
+    let mut used = HashSet::new();
+    for text in texts {
+        used.extend(cloze_numbers_in_string(text));
+    }
+    match (used.iter().min(), used.iter().max()) {
+        (Some(&min), Some(&max)) => (min..max).filter(|n| !used.contains(n)).collect(),
+        _ => vec![],
+    }
+}
+
 fn strip_html_inside_mathjax(text: &str) -> Cow<str> {
     MATHJAX.replace_all(text, |caps: &Captures| -> String {
         format!(
@@ -140,7 +159,9 @@ pub(crate) fn cloze_filter<'a>(text: &'a str, context: &RenderContext) -> Cow<'a
 
 #[cfg(test)]
 mod test {
-    use crate::cloze::{cloze_numbers_in_string, expand_clozes_to_reveal_latex};
+    use crate::cloze::{
+        cloze_gaps, cloze_numbers_in_string, expand_clozes_to_reveal_latex, strip_clozes,
+    };
     use crate::text::strip_html;
     use std::collections::HashSet;
 
@@ -165,5 +186,23 @@ mod test {
         assert!(expanded.contains("foo [baz]"));
         assert!(expanded.contains("[...] bar"));
         assert!(expanded.contains("foo bar"));
+
+        assert_eq!(
+            strip_clozes("{{c1::Paris}} is the capital of {{c2::France::country}}"),
+            "Paris is the capital of France"
+        );
+    }
+
+    #[test]
+    fn gaps() {
+        assert!(cloze_gaps(vec!["no clozes"]).is_empty());
+        assert!(cloze_gaps(vec!["{{c1::a}} {{c2::b}}", "{{c2::c}}"]).is_empty());
+        assert_eq!(cloze_gaps(vec!["{{c1::a}} {{c3::b}}"]), vec![2]);
+        // numbers used in other fields fill gaps, and gaps below the
+        // lowest number are not reported
+        assert_eq!(
+            cloze_gaps(vec!["{{c6::a}} {{c2::b}}", "{{c4::c::hint}}"]),
+            vec![3, 5]
+        );
     }
 }
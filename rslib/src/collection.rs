@@ -3,8 +3,11 @@
 
 use crate::err::{AnkiError, Result};
 use crate::i18n::I18n;
-use crate::log::Logger;
+use crate::log::{self, Logger, DEFAULT_LOG_ROTATE_KEEP};
+use crate::media::MediaManager;
+use crate::sched::queue::StudyQueues;
 use crate::storage::{SqliteStorage, StorageContext};
+use slog_async::AsyncGuard;
 use std::path::PathBuf;
 
 pub fn open_collection<P: Into<PathBuf>>(
@@ -23,9 +26,15 @@ pub fn open_collection<P: Into<PathBuf>>(
         col_path,
         media_folder: media_folder.into(),
         media_db: media_db.into(),
+        media_overlays: vec![],
         server,
         i18n,
         log,
+        log_path: None,
+        log_rotate_keep: DEFAULT_LOG_ROTATE_KEEP,
+        log_guard: None,
+        media_mgr: None,
+        study_queues: None,
         state: CollectionState::Normal,
     };
 
@@ -45,9 +54,21 @@ pub struct Collection {
     pub(crate) col_path: PathBuf,
     pub(crate) media_folder: PathBuf,
     pub(crate) media_db: PathBuf,
+    /// Read-only folders consulted when a file is not in media_folder.
+    pub(crate) media_overlays: Vec<PathBuf>,
     pub(crate) server: bool,
     pub(crate) i18n: I18n,
     pub(crate) log: Logger,
+    /// Set if logging to a file.
+    pub(crate) log_path: Option<String>,
+    /// The number of rotated log files to keep.
+    pub(crate) log_rotate_keep: u32,
+    /// Set if logging to a file; writes out buffered messages when dropped.
+    pub(crate) log_guard: Option<AsyncGuard>,
+    /// Opened on first use by with_media(), and closed with the collection.
+    media_mgr: Option<MediaManager>,
+    /// The current study session, kept until the collection is closed.
+    study_queues: Option<StudyQueues>,
     state: CollectionState,
 }
 
@@ -107,6 +128,34 @@ impl Collection {
         })
     }
 
+    /// Call the provided closure with the collection's media manager,
+    /// opening the media DB if it's not already open.
+    pub(crate) fn with_media<F, R>(&mut self, func: F) -> Result<R>
+    where
+        F: FnOnce(&Collection, &MediaManager) -> Result<R>,
+    {
+        let mgr = match self.media_mgr.take() {
+            Some(mgr) => mgr,
+            None => MediaManager::new(&self.media_folder, &self.media_db)?
+                .with_overlays(self.media_overlays.clone()),
+        };
+        let res = func(self, &mgr);
+        self.media_mgr = Some(mgr);
+        res
+    }
+
+    /// Call the provided closure with a RequestContext and the queues of
+    /// the current study session, which it may build or replace.
+    pub(crate) fn with_study_queues<F, R>(&mut self, func: F) -> Result<R>
+    where
+        F: FnOnce(&mut RequestContext, &mut Option<StudyQueues>) -> Result<R>,
+    {
+        let mut queues = self.study_queues.take();
+        let res = self.with_ctx(|ctx| func(ctx, &mut queues));
+        self.study_queues = queues;
+        res
+    }
+
     pub(crate) fn set_media_sync_running(&mut self) -> Result<()> {
         if self.state == CollectionState::Normal {
             self.state = CollectionState::MediaSyncRunning;
@@ -128,4 +177,58 @@ impl Collection {
     pub(crate) fn can_close(&self) -> bool {
         self.state == CollectionState::Normal
     }
+
+    /// Start a new log file, keeping the current one as a backup.
+    pub(crate) fn rotate_log(&mut self) -> Result<()> {
+        let path = match &self.log_path {
+            Some(path) => path.clone(),
+            // logging to the terminal
+            None => return Ok(()),
+        };
+        if !self.can_close() {
+            // a media sync holds a copy of the logger, which would stop
+            // writing to the file
+            return Err(AnkiError::invalid_input("media sync running"));
+        }
+
+        // write out buffered messages before the file is moved
+        self.log = Logger::root(slog::Discard, slog::o!());
+        self.log_guard = None;
+        log::rotate_log(&path, self.log_rotate_keep)?;
+        let (log, guard) = log::default_logger(Some(&path), self.log_rotate_keep)?;
+        self.log = log;
+        self.log_guard = guard;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{open_collection, Collection};
+    use crate::err::Result;
+    use crate::media::check::test::MEDIACHECK_ANKI2;
+    use crate::{i18n::I18n, log};
+    use std::{fs, path::PathBuf};
+    use tempfile::{tempdir, TempDir};
+
+    /// Open a copy of the test collection, without a media folder. The
+    /// copy is removed when the returned folder is dropped.
+    pub(crate) fn open_test_collection() -> Result<(TempDir, Collection)> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("col.anki2");
+        fs::write(&col_path, MEDIACHECK_ANKI2)?;
+
+        let i18n = I18n::new(&[""], "", log::terminal());
+        let col = open_collection(
+            &col_path,
+            &PathBuf::new(),
+            &PathBuf::new(),
+            false,
+            i18n,
+            log::terminal(),
+        )?;
+
+        Ok((dir, col))
+    }
 }
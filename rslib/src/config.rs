@@ -7,6 +7,11 @@ use serde_aux::field_attributes::deserialize_number_from_string;
 use serde_derive::Deserialize;
 use serde_json::Value;
 
+/// The config key holding how many seconds early learning cards may be
+/// shown when nothing else is due.
+pub(crate) const COLLAPSE_TIME_KEY: &str = "collapseTime";
+pub(crate) const DEFAULT_COLLAPSE_TIME: i64 = 1200;
+
 pub(crate) fn default_on_invalid<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
     T: Default + DeTrait<'de>,
@@ -46,6 +51,7 @@ pub enum SortKind {
     CardMod,
     CardReps,
     CardDue,
+    NewCardPosition,
     CardEase,
     CardLapses,
     #[serde(rename = "cardIvl")]
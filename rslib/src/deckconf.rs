@@ -0,0 +1,396 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::collection::RequestContext;
+use crate::decks::{DeckConfID, DeckID};
+use crate::err::{AnkiError, Result};
+use crate::timestamp::{TimestampMillis, TimestampSecs};
+use crate::types::Usn;
+use serde_aux::field_attributes::deserialize_number_from_string;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use unicase::UniCase;
+
+/// The study options shared by a group of decks. Keys we don't use are
+/// kept in `other`, so a config can be written back without losing them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct DeckConf {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub id: DeckConfID,
+    pub name: String,
+    #[serde(rename = "mod")]
+    pub mtime: TimestampSecs,
+    pub usn: Usn,
+    pub new: NewConf,
+    pub rev: RevConf,
+    pub lapse: LapseConf,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NewConf {
+    /// Learning steps in minutes.
+    pub delays: Vec<f32>,
+    pub per_day: u32,
+    /// In permille, eg 2500 = 250%.
+    pub initial_factor: u32,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RevConf {
+    pub per_day: u32,
+    #[serde(rename = "ease4")]
+    pub easy_bonus: f32,
+    pub max_ivl: u32,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LapseConf {
+    /// Relearning steps in minutes.
+    pub delays: Vec<f32>,
+    /// The proportion of the old interval kept after a lapse.
+    pub mult: f32,
+    pub min_int: u32,
+    pub leech_fails: u32,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// The config every collection has, which can't be removed.
+pub(crate) const DEFAULT_DECK_CONF_ID: DeckConfID = DeckConfID(1);
+
+/// The lowest starting ease the desktop client allows.
+const MINIMUM_INITIAL_FACTOR: u32 = 1300;
+
+impl DeckConf {
+    fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(AnkiError::invalid_input("deck config name is empty"));
+        }
+        if self.new.delays.is_empty() {
+            return Err(AnkiError::invalid_input(
+                "at least one learning step is required",
+            ));
+        }
+        check_steps(&self.new.delays)?;
+        check_steps(&self.lapse.delays)?;
+        if self.new.initial_factor < MINIMUM_INITIAL_FACTOR {
+            return Err(AnkiError::invalid_input(format!(
+                "starting ease must be at least {}",
+                MINIMUM_INITIAL_FACTOR
+            )));
+        }
+        if self.rev.easy_bonus < 1.0 {
+            return Err(AnkiError::invalid_input("easy bonus must be at least 1"));
+        }
+        if self.rev.max_ivl == 0 || self.lapse.min_int == 0 {
+            return Err(AnkiError::invalid_input("intervals must be at least 1 day"));
+        }
+        if !(0.0..=1.0).contains(&self.lapse.mult) {
+            return Err(AnkiError::invalid_input(
+                "lapse multiplier must be between 0 and 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Steps are a positive number of minutes.
+fn check_steps(steps: &[f32]) -> Result<()> {
+    if let Some(step) = steps.iter().find(|s| !s.is_finite() || **s <= 0.0) {
+        return Err(AnkiError::invalid_input(format!("invalid step: {}", step)));
+    }
+    Ok(())
+}
+
+impl RequestContext<'_> {
+    /// Add a config with the default study options, returning its id.
+    pub(crate) fn add_deck_conf(&mut self, name: &str) -> Result<DeckConfID> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(AnkiError::invalid_input("deck config name is empty"));
+        }
+
+        let existing = self.storage.all_deck_conf()?;
+        let mut dcid = DeckConfID(TimestampMillis::now().0);
+        while existing.contains_key(&dcid) {
+            dcid.0 += 1;
+        }
+
+        let conf: DeckConf = serde_json::from_value(json!({
+            "id": dcid,
+            "name": name,
+            "mod": TimestampSecs::now(),
+            "usn": self.storage.usn()?,
+            "new": {
+                "delays": [1, 10],
+                "ints": [1, 4, 7],
+                "initialFactor": 2500,
+                "separate": true,
+                "order": 1,
+                "perDay": 20,
+                "bury": false,
+            },
+            "lapse": {
+                "delays": [10],
+                "mult": 0,
+                "minInt": 1,
+                "leechFails": 8,
+                "leechAction": 0,
+            },
+            "rev": {
+                "perDay": 200,
+                "ease4": 1.3,
+                "fuzz": 0.05,
+                "minSpace": 1,
+                "ivlFct": 1,
+                "maxIvl": 36500,
+                "bury": false,
+                "hardFactor": 1.2,
+            },
+            "maxTaken": 60,
+            "timer": 0,
+            "autoplay": true,
+            "replayq": true,
+            "dyn": false,
+        }))?;
+        self.storage.update_deck_conf(&conf)?;
+
+        Ok(dcid)
+    }
+
+    /// Make the provided decks use a config. Filtered decks can't be
+    /// given one.
+    pub(crate) fn set_deck_conf_for_decks(
+        &mut self,
+        dcid: DeckConfID,
+        dids: &[DeckID],
+    ) -> Result<()> {
+        self.get_deck_conf(dcid)?;
+        let decks = self.storage.all_decks()?;
+        for did in dids {
+            match decks.get(did) {
+                None => return Err(AnkiError::invalid_input(format!("deck {} not found", did))),
+                Some(deck) if deck.conf.is_none() => {
+                    return Err(AnkiError::invalid_input("filtered decks have no config"))
+                }
+                Some(_) => (),
+            }
+        }
+
+        let usn = self.storage.usn()?;
+        self.storage.set_deck_conf_id(dids, dcid, usn)
+    }
+
+    /// Remove a config, moving the decks that used it to the default one.
+    /// As with the desktop client, this requires a full sync.
+    pub(crate) fn remove_deck_conf(&mut self, dcid: DeckConfID) -> Result<()> {
+        if dcid == DEFAULT_DECK_CONF_ID {
+            return Err(AnkiError::invalid_input(
+                "the default deck config can't be removed",
+            ));
+        }
+        self.get_deck_conf(dcid)?;
+
+        let dids: Vec<_> = self
+            .storage
+            .all_decks()?
+            .values()
+            .filter(|deck| deck.conf == Some(dcid))
+            .map(|deck| deck.id)
+            .collect();
+        let usn = self.storage.usn()?;
+        self.storage
+            .set_deck_conf_id(&dids, DEFAULT_DECK_CONF_ID, usn)?;
+        self.storage.remove_deck_conf(dcid)?;
+        self.storage.set_schema_modified()
+    }
+
+    pub(crate) fn get_deck_conf(&self, dcid: DeckConfID) -> Result<DeckConf> {
+        self.storage
+            .all_deck_conf()?
+            .remove(&dcid)
+            .ok_or_else(|| AnkiError::invalid_input(format!("deck config {} not found", dcid)))
+    }
+
+    /// The config used by a deck. Filtered decks don't have one.
+    pub(crate) fn deck_conf_for_deck(&self, did: DeckID) -> Result<DeckConf> {
+        let deck = self
+            .storage
+            .all_decks()?
+            .remove(&did)
+            .ok_or_else(|| AnkiError::invalid_input(format!("deck {} not found", did)))?;
+        let dcid = deck
+            .conf
+            .ok_or_else(|| AnkiError::invalid_input("filtered decks have no config"))?;
+        self.get_deck_conf(dcid)
+    }
+
+    /// Every deck config, with the decks that use it, ordered by name.
+    pub(crate) fn deck_confs_with_decks(&self) -> Result<Vec<(DeckConf, Vec<DeckID>)>> {
+        let all_decks = self.storage.all_decks()?;
+        let mut decks: Vec<_> = all_decks.values().collect();
+        decks.sort_by(|a, b| UniCase::new(&a.name).cmp(&UniCase::new(&b.name)));
+
+        let mut confs: Vec<_> = self
+            .storage
+            .all_deck_conf()?
+            .into_iter()
+            .map(|(dcid, conf)| {
+                let dids = decks
+                    .iter()
+                    .filter(|deck| deck.conf == Some(dcid))
+                    .map(|deck| deck.id)
+                    .collect();
+                (conf, dids)
+            })
+            .collect();
+        confs.sort_by(|a, b| UniCase::new(&a.0.name).cmp(&UniCase::new(&b.0.name)));
+
+        Ok(confs)
+    }
+
+    /// Save changes to an existing deck config, after checking they are
+    /// valid.
+    pub(crate) fn update_deck_conf(&mut self, conf: &mut DeckConf) -> Result<()> {
+        if !self.storage.all_deck_conf()?.contains_key(&conf.id) {
+            return Err(AnkiError::invalid_input(format!(
+                "deck config {} not found",
+                conf.id
+            )));
+        }
+        conf.validate()?;
+        conf.mtime = TimestampSecs::now();
+        conf.usn = self.storage.usn()?;
+        self.storage.update_deck_conf(conf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::collection::test::open_test_collection;
+    use crate::decks::{DeckConfID, DeckID};
+    use crate::err::Result;
+
+    #[test]
+    fn update() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            let mut conf = ctx.deck_conf_for_deck(DeckID(1))?;
+            assert_eq!(conf.id, DeckConfID(1));
+            assert_eq!(conf.new.delays, vec![1.0, 10.0]);
+            assert_eq!(conf.new.per_day, 20);
+            assert_eq!(conf.rev.per_day, 200);
+
+            conf.new.per_day = 5;
+            conf.lapse.delays = vec![];
+            ctx.update_deck_conf(&mut conf)?;
+            let conf = ctx.get_deck_conf(DeckConfID(1))?;
+            assert_eq!(conf.new.per_day, 5);
+            assert!(conf.lapse.delays.is_empty());
+            // keys we don't use are preserved
+            assert_eq!(conf.other["maxTaken"], 60);
+            assert_eq!(conf.new.other["ints"], serde_json::json!([1, 4, 7]));
+
+            let mut bad = conf.clone();
+            bad.new.delays = vec![];
+            assert!(ctx.update_deck_conf(&mut bad).is_err());
+            let mut bad = conf.clone();
+            bad.lapse.delays = vec![10.0, -1.0];
+            assert!(ctx.update_deck_conf(&mut bad).is_err());
+            let mut bad = conf.clone();
+            bad.new.initial_factor = 1000;
+            assert!(ctx.update_deck_conf(&mut bad).is_err());
+            let mut bad = conf;
+            bad.id = DeckConfID(5);
+            assert!(ctx.update_deck_conf(&mut bad).is_err());
+
+            assert!(ctx.deck_conf_for_deck(DeckID(5)).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn decks_using_conf() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            let mut other = ctx.get_deck_conf(DeckConfID(1))?;
+            other.id = DeckConfID(2);
+            other.name = "another".into();
+            ctx.storage.update_deck_conf(&other)?;
+            // two decks sharing the default config, and a filtered deck
+            ctx.storage.db.execute_batch(
+                r#"update col set decks = '{
+                    "1": {"id": 1, "name": "Default", "conf": 1},
+                    "2": {"id": 2, "name": "Blue", "conf": 1},
+                    "3": {"id": 3, "name": "Filtered", "dyn": 1}
+                }'"#,
+            )?;
+
+            let confs: Vec<_> = ctx
+                .deck_confs_with_decks()?
+                .into_iter()
+                .map(|(conf, dids)| (conf.name, dids))
+                .collect();
+            assert_eq!(
+                confs,
+                vec![
+                    ("another".to_string(), vec![]),
+                    ("Default".into(), vec![DeckID(2), DeckID(1)]),
+                ]
+            );
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn add_assign_remove() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                r#"update col set decks = '{
+                    "1": {"id": 1, "name": "Default", "conf": 1},
+                    "2": {"id": 2, "name": "Blue", "conf": 1},
+                    "3": {"id": 3, "name": "Filtered", "dyn": 1}
+                }'"#,
+            )?;
+
+            let dcid = ctx.add_deck_conf(" Fast ")?;
+            let conf = ctx.get_deck_conf(dcid)?;
+            assert_eq!(conf.name, "Fast");
+            assert_eq!(conf.new.per_day, 20);
+            assert_eq!(conf.other["maxTaken"], 60);
+            assert!(ctx.add_deck_conf("").is_err());
+
+            ctx.set_deck_conf_for_decks(dcid, &[DeckID(2)])?;
+            assert_eq!(ctx.deck_conf_for_deck(DeckID(2))?.id, dcid);
+            assert_eq!(ctx.deck_conf_for_deck(DeckID(1))?.id, DeckConfID(1));
+            assert!(ctx.set_deck_conf_for_decks(dcid, &[DeckID(3)]).is_err());
+            assert!(ctx.set_deck_conf_for_decks(dcid, &[DeckID(4)]).is_err());
+            assert!(ctx
+                .set_deck_conf_for_decks(DeckConfID(5), &[DeckID(1)])
+                .is_err());
+
+            // removing a config moves its decks back to the default
+            assert!(ctx.remove_deck_conf(DeckConfID(1)).is_err());
+            ctx.remove_deck_conf(dcid)?;
+            assert!(ctx.get_deck_conf(dcid).is_err());
+            assert_eq!(ctx.deck_conf_for_deck(DeckID(2))?.id, DeckConfID(1));
+
+            Ok(())
+        })
+    }
+}
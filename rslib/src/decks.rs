@@ -8,11 +8,23 @@ use serde_derive::Deserialize;
 define_newtype!(DeckID, i64);
 define_newtype!(DeckConfID, i64);
 
+/// The 'Default' deck, which every collection has.
+pub(crate) const DEFAULT_DECK_ID: DeckID = DeckID(1);
+
 #[derive(Deserialize)]
 pub struct Deck {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub(crate) id: DeckID,
     pub(crate) name: String,
+    /// The study options of a normal deck. Filtered decks have none.
+    #[serde(default)]
+    pub(crate) conf: Option<DeckConfID>,
+    /// The day and the number of new cards studied on it.
+    #[serde(default, rename = "newToday")]
+    pub(crate) new_today: (u32, i32),
+    /// The day and the number of reviews done on it.
+    #[serde(default, rename = "revToday")]
+    pub(crate) review_today: (u32, i32),
 }
 
 pub(crate) fn child_ids<'a>(decks: &'a [Deck], name: &str) -> impl Iterator<Item = DeckID> + 'a {
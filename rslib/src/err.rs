@@ -39,6 +39,9 @@ pub enum AnkiError {
 
     #[fail(display = "Close the existing collection first.")]
     CollectionAlreadyOpen,
+
+    #[fail(display = "Modified elsewhere: {}", info)]
+    Conflict { info: String },
 }
 
 // error helpers
@@ -104,6 +107,10 @@ pub enum TemplateError {
         filters: String,
         field: String,
     },
+    /// Conditionals nested deeper than the provided limit.
+    NestedTooDeeply(usize),
+    /// A side rendered to more than the provided number of bytes.
+    OutputTooLarge(usize),
 }
 
 impl From<io::Error> for AnkiError {
@@ -206,6 +213,9 @@ fn error_for_status_code(info: String, code: StatusCode) -> AnkiError {
 fn guess_reqwest_error(info: String) -> AnkiError {
     let kind = if info.contains("unreachable") || info.contains("dns") {
         NetworkErrorKind::Offline
+    } else if info.contains("proxy authentication required") {
+        // an https proxy rejecting the tunnel request
+        NetworkErrorKind::ProxyAuth
     } else {
         NetworkErrorKind::Other
     };
@@ -231,3 +241,25 @@ pub enum DBErrorKind {
     MissingEntity,
     Other,
 }
+
+#[cfg(test)]
+mod test {
+    use super::{guess_reqwest_error, AnkiError, NetworkErrorKind};
+
+    #[test]
+    fn reqwest_error_kinds() {
+        let kind = |info: &str| match guess_reqwest_error(info.to_string()) {
+            AnkiError::NetworkError { kind, .. } => kind,
+            err => panic!("unexpected error: {:?}", err),
+        };
+        assert_eq!(
+            kind("error trying to connect: proxy authentication required"),
+            NetworkErrorKind::ProxyAuth
+        );
+        assert_eq!(
+            kind("error trying to connect: dns error"),
+            NetworkErrorKind::Offline
+        );
+        assert_eq!(kind("connection reset"), NetworkErrorKind::Other);
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::collection::RequestContext;
+use crate::err::{AnkiError, DBErrorKind, Result};
+use crate::markdown::html_to_markdown;
+use crate::notetypes::{NoteType, NoteTypeID};
+use crate::search::search_notes;
+use crate::tags::split_tags;
+use crate::text::strip_html;
+use rusqlite::params;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// How many notes are written between progress updates.
+const EXPORT_PROGRESS_INTERVAL: u32 = 1000;
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct TsvExportOptions {
+    /// Names of the fields to write, in order. Notes without a field get an
+    /// empty column. If empty, all of each note's fields are written.
+    pub fields: Vec<String>,
+    /// Add the note's tags as a last column, separated by spaces.
+    pub include_tags: bool,
+    /// Add the name of the note's note type as a first column.
+    pub include_notetype: bool,
+    pub strip_html: bool,
+    /// Convert field HTML to Markdown. Takes precedence over strip_html.
+    pub markdown: bool,
+}
+
+impl TsvExportOptions {
+    fn columns<'a>(&self, nt: &'a NoteType, fields: &'a str, tags: &str) -> Vec<Cow<'a, str>> {
+        let fields: Vec<_> = fields.split('\x1f').collect();
+        let mut columns = vec![];
+        if self.include_notetype {
+            columns.push(nt.name.as_str().into());
+        }
+        if self.fields.is_empty() {
+            columns.extend(fields.iter().map(|&text| self.field_text(text)));
+        } else {
+            for name in &self.fields {
+                let text = nt
+                    .fields
+                    .iter()
+                    .position(|field| &field.name == name)
+                    .and_then(|idx| fields.get(idx))
+                    .copied()
+                    .unwrap_or_default();
+                columns.push(self.field_text(text));
+            }
+        }
+        if self.include_tags {
+            columns.push(split_tags(tags).collect::<Vec<_>>().join(" ").into());
+        }
+        columns
+    }
+
+    fn field_text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.markdown {
+            html_to_markdown(text).into()
+        } else if self.strip_html {
+            strip_html(text)
+        } else {
+            text.into()
+        }
+    }
+}
+
+/// Escape backslashes, tabs and line breaks with a backslash, so that each
+/// note is written on a single line and the text can be restored exactly.
+fn escape_tsv(text: &str) -> Cow<str> {
+    if !text.contains(|c| matches!(c, '\\' | '\t' | '\n' | '\r')) {
+        return text.into();
+    }
+    let mut out = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out.into()
+}
+
+impl RequestContext<'_> {
+    /// Write the notes matching `search` to a tab-separated file, one note
+    /// per line in creation order, returning the number of notes written.
+    /// There is no header row.
+    ///
+    /// `progress` is called with the notes written so far and the total;
+    /// if it returns false, the export is aborted.
+    pub(crate) fn export_tsv<F>(
+        &mut self,
+        path: &Path,
+        search: &str,
+        options: &TsvExportOptions,
+        mut progress: F,
+    ) -> Result<u32>
+    where
+        F: FnMut(u32, u32) -> bool,
+    {
+        let mut nids = search_notes(self, search, false)?;
+        // one entry is returned for each matching card
+        nids.sort();
+        nids.dedup();
+        let total = nids.len() as u32;
+        let note_types = self.storage.all_note_types()?;
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut stmt = self
+            .storage
+            .db
+            .prepare("select mid, flds, tags from notes where id = ?")?;
+        let mut written = 0;
+        for nid in nids {
+            let (ntid, fields, tags): (NoteTypeID, String, String) = stmt
+                .query_row(params![nid], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?;
+            let nt = note_types.get(&ntid).ok_or_else(|| AnkiError::DBError {
+                info: "missing note type".to_string(),
+                kind: DBErrorKind::MissingEntity,
+            })?;
+
+            let line = options
+                .columns(nt, &fields, &tags)
+                .iter()
+                .map(|column| escape_tsv(column))
+                .collect::<Vec<_>>()
+                .join("\t");
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\n")?;
+
+            written += 1;
+            if written % EXPORT_PROGRESS_INTERVAL == 0 && !progress(written, total) {
+                return Err(AnkiError::Interrupted);
+            }
+        }
+        writer.flush()?;
+        progress(written, total);
+
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape_tsv, TsvExportOptions};
+    use crate::collection::test::open_test_collection;
+    use crate::err::Result;
+    use crate::notetypes::NoteTypeID;
+    use std::fs;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape_tsv("plain"), "plain");
+        assert_eq!(escape_tsv("a\tb\r\nc\\n"), "a\\tb\\r\\nc\\\\n");
+    }
+
+    #[test]
+    fn export_tsv() -> Result<()> {
+        let (dir, col) = open_test_collection()?;
+
+        let out_path = dir.path().join("notes.txt");
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                "update notes set flds = 'front' || char(31) || 'back', tags = '';
+                 update notes set flds = '<b>a&amp;b</b>' || char(9) || 'c' || char(31)
+                   || 'line' || char(10) || 'break',
+                   tags = ' one two '
+                 where id = 1581236445532;",
+            )?;
+            let nt_name = ctx.get_note_type(NoteTypeID(1581236385347))?.name;
+
+            let mut options = TsvExportOptions {
+                include_tags: true,
+                ..Default::default()
+            };
+            let mut calls = vec![];
+            let progress = |written, total| {
+                calls.push((written, total));
+                true
+            };
+            assert_eq!(ctx.export_tsv(&out_path, "", &options, progress)?, 3);
+            assert_eq!(calls, vec![(3, 3)]);
+            assert_eq!(
+                fs::read_to_string(&out_path)?,
+                "front\tback\t\n\
+                 <b>a&amp;b</b>\\tc\tline\\nbreak\tone two\n\
+                 front\tback\t\n"
+            );
+
+            // selected fields in the given order, with HTML stripped
+            options.fields = vec!["Back".into(), "Missing".into(), "Front".into()];
+            options.include_tags = false;
+            options.include_notetype = true;
+            options.strip_html = true;
+            ctx.export_tsv(&out_path, "nid:1581236445532", &options, |_, _| true)?;
+            assert_eq!(
+                fs::read_to_string(&out_path)?,
+                format!("{}\tline\\nbreak\t\ta&b\\tc\n", nt_name)
+            );
+
+            options.markdown = true;
+            ctx.export_tsv(&out_path, "nid:1581236445532", &options, |_, _| true)?;
+            assert_eq!(
+                fs::read_to_string(&out_path)?,
+                format!("{}\tline break\t\t**a&b** c\n", nt_name)
+            );
+
+            Ok(())
+        })
+    }
+}
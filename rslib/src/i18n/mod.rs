@@ -5,8 +5,11 @@ use crate::err::Result;
 use crate::log::{error, Logger};
 use fluent::{FluentArgs, FluentBundle, FluentResource, FluentValue};
 use intl_memoizer::IntlLangMemoizer;
+use lazy_static::lazy_static;
 use num_format::Locale;
+use regex::Regex;
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
@@ -158,14 +161,30 @@ fn ftl_external_text(folder: &Path) -> Result<String> {
     Ok(buf)
 }
 
+lazy_static! {
+    static ref MESSAGE_ID: Regex = Regex::new(r"(?m)^([a-zA-Z][a-zA-Z0-9_-]*) *=").unwrap();
+}
+
+/// The ids of the messages defined in the provided resource text.
+fn message_ids(text: &str) -> HashSet<&str> {
+    MESSAGE_ID
+        .captures_iter(text)
+        .map(|caps| caps.get(1).unwrap().as_str())
+        .collect()
+}
+
 /// Parse resource text into an AST for inclusion in a bundle.
 /// Returns None if text contains errors.
 /// extra_text may contain resources loaded from the filesystem
 /// at runtime. If it contains errors, they will not prevent a
 /// bundle from being returned.
+/// The bundle's plural rules are taken from bundle_lang, the language
+/// the text is written in. Numbers are formatted according to the
+/// user's locales.
 fn get_bundle(
     text: String,
     extra_text: String,
+    bundle_lang: &LanguageIdentifier,
     locales: &[LanguageIdentifier],
     log: &Logger,
 ) -> Option<FluentBundle<FluentResource>> {
@@ -175,7 +194,9 @@ fn get_bundle(
         })
         .ok()?;
 
-    let mut bundle: FluentBundle<FluentResource> = FluentBundle::new(locales);
+    let mut bundle_locales = vec![bundle_lang.clone()];
+    bundle_locales.extend(locales.iter().filter(|l| *l != bundle_lang).cloned());
+    let mut bundle: FluentBundle<FluentResource> = FluentBundle::new(&bundle_locales);
     bundle
         .add_resource(res)
         .map_err(|e| {
@@ -202,6 +223,7 @@ fn get_bundle(
 }
 
 /// Get a bundle that includes any filesystem overrides.
+/// lang is None for the English template.
 fn get_bundle_with_extra(
     text: String,
     lang: Option<&LanguageIdentifier>,
@@ -221,12 +243,24 @@ fn get_bundle_with_extra(
         "".into()
     };
 
-    get_bundle(text, extra_text, locales, log)
+    let template_lang: LanguageIdentifier = "en-US".parse().unwrap();
+    let bundle_lang = lang.unwrap_or(&template_lang);
+
+    get_bundle(text, extra_text, bundle_lang, locales, log)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AvailableLanguage {
+    /// The name of the language's folder, eg ja or pt_BR.
+    pub code: String,
+    /// 0-100
+    pub percent_translated: f32,
 }
 
 #[derive(Clone)]
 pub struct I18n {
     inner: Arc<Mutex<I18nInner>>,
+    ftl_folder: PathBuf,
     log: Logger,
 }
 
@@ -279,10 +313,54 @@ impl I18n {
 
         Self {
             inner: Arc::new(Mutex::new(I18nInner { bundles })),
+            ftl_folder,
             log,
         }
     }
 
+    /// The languages that have a folder in the ftl folder, and the
+    /// proportion of the English template each one translates.
+    pub fn available_languages(&self) -> Result<Vec<AvailableLanguage>> {
+        let mut template_text = ftl_template_text();
+        if let Some(path) = lang_folder(None, &self.ftl_folder) {
+            template_text += &ftl_external_text(&path)?;
+        }
+        let template_ids = message_ids(&template_text);
+
+        let mut langs = vec![];
+        for entry in fs::read_dir(&self.ftl_folder)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let code = match entry.file_name().into_string() {
+                Ok(code) if code != "templates" => code,
+                _ => continue,
+            };
+            let lang: LanguageIdentifier = match code.parse() {
+                Ok(lang) => lang,
+                Err(_) => continue,
+            };
+
+            let mut text = ftl_localized_text(&lang).unwrap_or_default();
+            text += &ftl_external_text(&entry.path())?;
+            let translated = message_ids(&text).intersection(&template_ids).count();
+            let percent_translated = if template_ids.is_empty() {
+                0.0
+            } else {
+                translated as f32 / template_ids.len() as f32 * 100.0
+            };
+
+            langs.push(AvailableLanguage {
+                code,
+                percent_translated,
+            });
+        }
+        langs.sort_by(|a, b| a.code.cmp(&b.code));
+
+        Ok(langs)
+    }
+
     /// Get translation with zero arguments.
     pub fn tr(&self, key: FString) -> Cow<str> {
         let key = FLUENT_KEYS[key as usize];
@@ -295,7 +373,7 @@ impl I18n {
         self.tr_(key, Some(args)).into()
     }
 
-    fn tr_<'a>(&'a self, key: &str, args: Option<FluentArgs>) -> Cow<'a, str> {
+    pub(crate) fn tr_<'a>(&'a self, key: &str, args: Option<FluentArgs>) -> Cow<'a, str> {
         for bundle in &self.inner.lock().unwrap().bundles {
             let msg = match bundle.get_message(key) {
                 Some(msg) => msg,
@@ -403,6 +481,49 @@ mod test {
     use std::path::PathBuf;
     use unic_langid::langid;
 
+    #[test]
+    fn available_languages() {
+        let mut ftl_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        ftl_dir.push("tests/support/ftl");
+
+        let i18n = I18n::new(&["zz"], &ftl_dir, log::terminal());
+        let langs = i18n.available_languages().unwrap();
+        let codes: Vec<_> = langs.iter().map(|l| l.code.as_str()).collect();
+        assert_eq!(codes, vec!["ja", "pl"]);
+        for lang in langs {
+            assert!(lang.percent_translated > 0.0);
+            assert!(lang.percent_translated <= 100.0);
+        }
+    }
+
+    #[test]
+    fn plurals() {
+        let mut ftl_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        ftl_dir.push("tests/support/ftl");
+        let log = log::terminal();
+
+        // English fallback text uses English plural rules, even when the
+        // preferred language has none
+        let i18n = I18n::new(&["ja_JP"], &ftl_dir, log.clone());
+        assert_eq!(
+            i18n.tr_("plural", Some(tr_args!["hats"=>1])),
+            "You have 1 hat."
+        );
+        assert_eq!(
+            i18n.tr_("plural", Some(tr_args!["hats"=>2])),
+            "You have 2 hats."
+        );
+
+        // Polish has separate few and many forms, which must be selected
+        // with Polish rules even if it's not the first preference
+        let i18n = I18n::new(&["ja_JP", "pl_PL"], &ftl_dir, log);
+        let hats = |n: u32| i18n.tr_("plural", Some(tr_args!["hats"=>n])).to_string();
+        assert_eq!(hats(1), "Masz 1 kapelusz.");
+        assert_eq!(hats(2), "Masz 2 kapelusze.");
+        assert_eq!(hats(5), "Masz 5 kapeluszy.");
+        assert_eq!(hats(22), "Masz 22 kapelusze.");
+    }
+
     #[test]
     fn numbers() {
         let fmter = NumberFormatter::new(&[langid!("pl-PL")]);
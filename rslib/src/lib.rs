@@ -15,19 +15,27 @@ pub mod card;
 pub mod cloze;
 pub mod collection;
 pub mod config;
+pub mod deckconf;
 pub mod decks;
 pub mod err;
+pub mod export;
 pub mod i18n;
 pub mod latex;
 pub mod log;
+pub mod markdown;
 pub mod media;
 pub mod notes;
 pub mod notetypes;
+pub mod revlog;
+pub mod sanitize;
 pub mod sched;
 pub mod search;
+pub mod stats;
 pub mod storage;
+pub mod tags;
 pub mod template;
 pub mod template_filters;
 pub mod text;
 pub mod timestamp;
+pub mod typeanswer;
 pub mod types;
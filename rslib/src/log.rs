@@ -1,18 +1,74 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-pub use slog::{debug, error, Logger};
-use slog::{slog_o, Drain};
-use slog_async::OverflowStrategy;
+pub use slog::{debug, error, warn, Level, Logger};
+use slog::{slog_o, Drain, FilterLevel, OwnedKVList, Record};
+use slog_async::{AsyncGuard, OverflowStrategy};
 use std::fs::OpenOptions;
-use std::{fs, io};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, io};
 
 const LOG_ROTATE_BYTES: u64 = 50 * 1024 * 1024;
+/// Number of rotated log files kept if the caller doesn't ask for another
+/// number.
+pub(crate) const DEFAULT_LOG_ROTATE_KEEP: u32 = 2;
+
+/// The most verbose level that will be logged, if it has been changed at
+/// runtime. 0 means not set.
+static LEVEL_OVERRIDE: AtomicUsize = AtomicUsize::new(0);
+
+/// Change the maximum level logged by all loggers. If None, the default
+/// level is restored. When RUST_LOG is set, it continues to limit output.
+pub fn set_log_level(level: Option<Level>) {
+    LEVEL_OVERRIDE.store(level.map(Level::as_usize).unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Drops records that are more verbose than the current level.
+struct RuntimeLevelFilter<D> {
+    drain: D,
+    /// Level used when no override has been set. If None, all records are
+    /// passed through, as RUST_LOG is doing the filtering.
+    default_level: Option<Level>,
+}
+
+impl<D: Drain> Drain for RuntimeLevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let max_level = match LEVEL_OVERRIDE.load(Ordering::Relaxed) {
+            0 => self.default_level,
+            n => Level::from_usize(n),
+        };
+        if max_level.map_or(true, |max| record.level().is_at_least(max)) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Filter records with RUST_LOG if it is set, allowing the level to be
+/// changed at runtime with set_log_level().
+fn level_filter<D: Drain>(drain: D) -> RuntimeLevelFilter<slog_envlogger::EnvLogger<D>> {
+    let builder = slog_envlogger::LogBuilder::new(drain);
+    match env::var("RUST_LOG") {
+        Ok(directives) => RuntimeLevelFilter {
+            drain: builder.parse(&directives).build(),
+            default_level: None,
+        },
+        Err(_) => RuntimeLevelFilter {
+            // the override may ask for more than the usual default
+            drain: builder.filter(None, FilterLevel::Trace).build(),
+            default_level: Some(Level::Error),
+        },
+    }
+}
 
 pub(crate) fn terminal() -> Logger {
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = slog_envlogger::new(drain);
+    let drain = level_filter(drain);
     let drain = slog_async::Async::new(drain)
         .chan_size(1_024)
         .overflow_strategy(OverflowStrategy::Block)
@@ -21,22 +77,25 @@ pub(crate) fn terminal() -> Logger {
     Logger::root(drain, slog_o!())
 }
 
-fn file(path: &str) -> io::Result<Logger> {
-    maybe_rotate_log(path)?;
+/// A logger that writes to path, after rotating the file if it has grown
+/// too large. Dropping the returned guard writes out any buffered messages
+/// and waits for them to be written; anything logged through the logger
+/// after that is discarded.
+fn file(path: &str, rotate_keep: u32) -> io::Result<(Logger, AsyncGuard)> {
+    maybe_rotate_log(path, rotate_keep)?;
     let file = OpenOptions::new().create(true).append(true).open(path)?;
 
     let decorator = slog_term::PlainSyncDecorator::new(file);
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = slog_envlogger::new(drain);
-    let drain = slog_async::Async::new(drain)
+    let drain = level_filter(drain);
+    let (drain, guard) = slog_async::Async::new(drain)
         .chan_size(1_024)
         .overflow_strategy(OverflowStrategy::Block)
-        .build()
-        .fuse();
-    Ok(Logger::root(drain, slog_o!()))
+        .build_with_guard();
+    Ok((Logger::root(drain.ignore_res(), slog_o!()), guard))
 }
 
-fn maybe_rotate_log(path: &str) -> io::Result<()> {
+fn maybe_rotate_log(path: &str, rotate_keep: u32) -> io::Result<()> {
     let current_bytes = match fs::metadata(path) {
         Ok(meta) => meta.len(),
         Err(e) => {
@@ -51,24 +110,99 @@ fn maybe_rotate_log(path: &str) -> io::Result<()> {
         return Ok(());
     }
 
-    let path2 = format!("{}.1", path);
-    let path3 = format!("{}.2", path);
+    rotate_log(path, rotate_keep)
+}
 
-    // if a rotated file already exists, rename it
-    if let Err(e) = fs::rename(&path2, &path3) {
-        if e.kind() != io::ErrorKind::NotFound {
-            return Err(e);
-        }
+/// Move the log to path.1, path.1 to path.2 and so on up to
+/// path.`keep`, discarding the oldest file. The guard of any logger writing
+/// to path should be dropped first, so that buffered messages are written.
+pub(crate) fn rotate_log(path: &str, keep: u32) -> io::Result<()> {
+    // if rotated files already exist, rename them
+    for num in (1..keep).rev() {
+        rename_if_exists(
+            &format!("{}.{}", path, num),
+            &format!("{}.{}", path, num + 1),
+        )?;
     }
 
     // and rotate the primary log
-    fs::rename(path, path2)
+    rename_if_exists(path, &format!("{}.1", path))
+}
+
+fn rename_if_exists(from: &str, to: &str) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
 }
 
 /// Get a logger, logging to a file if a path was provided, otherwise terminal.
-pub(crate) fn default_logger(path: Option<&str>) -> io::Result<Logger> {
+/// File loggers come with a guard that flushes them; see file().
+pub(crate) fn default_logger(
+    path: Option<&str>,
+    rotate_keep: u32,
+) -> io::Result<(Logger, Option<AsyncGuard>)> {
     Ok(match path {
-        Some(path) => file(path)?,
-        None => terminal(),
+        Some(path) => {
+            let (log, guard) = file(path, rotate_keep)?;
+            (log, Some(guard))
+        }
+        None => (terminal(), None),
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::{error, file, rotate_log};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn rotation() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("col.log");
+        let path_str = path.to_str().unwrap();
+
+        // nothing to rotate
+        rotate_log(path_str, 2).unwrap();
+
+        for text in &["one", "two", "three"] {
+            fs::write(&path, text).unwrap();
+            rotate_log(path_str, 2).unwrap();
+        }
+        assert!(!path.exists());
+        assert_eq!(
+            fs::read_to_string(format!("{}.1", path_str)).unwrap(),
+            "three"
+        );
+        assert_eq!(
+            fs::read_to_string(format!("{}.2", path_str)).unwrap(),
+            "two"
+        );
+        assert!(!dir.path().join("col.log.3").exists());
+
+        // keeping more files
+        fs::write(&path, "four").unwrap();
+        rotate_log(path_str, 3).unwrap();
+        assert_eq!(
+            fs::read_to_string(format!("{}.3", path_str)).unwrap(),
+            "two"
+        );
+    }
+
+    #[test]
+    fn flushing() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("col.log");
+        let (log, guard) = file(path.to_str().unwrap(), 2).unwrap();
+
+        error!(log, "before");
+        drop(guard);
+        assert!(fs::read_to_string(&path).unwrap().contains("before"));
+
+        // the logger is still usable, but its messages are discarded
+        error!(log, "after");
+        drop(log);
+        assert!(!fs::read_to_string(&path).unwrap().contains("after"));
+    }
+}
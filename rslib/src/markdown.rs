@@ -0,0 +1,397 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::text::decode_entities;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref TOKEN: Regex = Regex::new(
+        r#"(?xsi)
+            # ignored entirely
+            <!--.*?-->
+            |
+            <(?:style|script)\b.*?</(?:style|script)>
+            |
+            # a tag
+            <
+                (/?)                # 1 - closing?
+                ([a-z][a-z0-9]*)    # 2 - name
+                ([^>]*)             # 3 - attributes
+            >
+            |
+            # text
+            ([^<]+|<)               # 4
+            "#
+    )
+    .unwrap();
+    static ref ATTR: Regex = Regex::new(
+        r#"(?xsi)
+            ([a-z-]+)\s*=\s*
+            (?:
+                "([^"]*)"       # 2 - double-quoted
+                |
+                '([^']*)'       # 3 - single-quoted
+                |
+                ([^\s>]+)       # 4 - unquoted
+            )
+            "#
+    )
+    .unwrap();
+    static ref WHITESPACE: Regex = Regex::new(r"\s+").unwrap();
+    static ref EXTRA_NEWLINES: Regex = Regex::new(r"\n{3,}").unwrap();
+}
+
+/// The value of the named attribute in a tag's attribute text.
+fn attribute(attrs: &str, name: &str) -> Option<String> {
+    ATTR.captures_iter(attrs)
+        .find(|caps| caps[1].eq_ignore_ascii_case(name))
+        .and_then(|caps| caps.get(2).or_else(|| caps.get(3)).or_else(|| caps.get(4)))
+        .map(|value| decode_entities(value.as_str()).into_owned())
+}
+
+/// Characters that would otherwise be read as formatting.
+fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Wrap inline code in enough backticks that those inside don't end it.
+fn inline_code(code: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in code.chars() {
+        if c == '`' {
+            run += 1;
+            longest = longest.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    let fence = "`".repeat(longest + 1);
+    if code.starts_with('`') || code.ends_with('`') {
+        format!("{} {} {}", fence, code, fence)
+    } else {
+        format!("{}{}{}", fence, code, fence)
+    }
+}
+
+struct List {
+    ordered: bool,
+    next_number: u32,
+    /// The column the text of the current item starts at, which nested
+    /// lists are indented to.
+    content_column: usize,
+}
+
+#[derive(Default)]
+struct MarkdownWriter {
+    out: String,
+    lists: Vec<List>,
+    /// The target of each open link, or None for links without one.
+    links: Vec<Option<String>>,
+    /// Text inside <code> or <pre>, which is written when the tag closes.
+    code: Option<String>,
+    in_pre: bool,
+}
+
+impl MarkdownWriter {
+    fn text(&mut self, text: &str) {
+        let text = decode_entities(text);
+        if let Some(code) = &mut self.code {
+            if self.in_pre {
+                code.push_str(&text);
+            } else {
+                code.push_str(&WHITESPACE.replace_all(&text, " "));
+            }
+            return;
+        }
+        let text = WHITESPACE.replace_all(&text, " ");
+        // like a browser, ignore whitespace after whitespace or a new line
+        let text = if self.out.is_empty() || self.out.ends_with(char::is_whitespace) {
+            text.trim_start()
+        } else {
+            &text
+        };
+        self.out.push_str(&escape_markdown(text));
+    }
+
+    /// Begin a new line, leaving `blank_lines` empty lines before it.
+    fn new_line(&mut self, blank_lines: usize) {
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+        if self.out.is_empty() {
+            return;
+        }
+        let wanted = blank_lines + 1;
+        let existing = self.out.len() - self.out.trim_end_matches('\n').len();
+        for _ in existing..wanted {
+            self.out.push('\n');
+        }
+    }
+
+    /// The indent for a block inside the current list item, if any.
+    fn indent(&self) -> String {
+        " ".repeat(self.lists.last().map(|l| l.content_column).unwrap_or(0))
+    }
+
+    fn open_tag(&mut self, name: &str, attrs: &str) {
+        if self.in_pre {
+            if name == "br" {
+                self.text("\n");
+            }
+            return;
+        }
+        if self.code.is_some() {
+            // no formatting inside inline code
+            return;
+        }
+        match name {
+            "b" | "strong" => self.out.push_str("**"),
+            "i" | "em" => self.out.push_str("*"),
+            "code" => {
+                if self.code.is_none() {
+                    self.code = Some(String::new());
+                }
+            }
+            "pre" => {
+                self.new_line(1);
+                self.in_pre = true;
+                self.code = Some(String::new());
+            }
+            "a" => {
+                let href = attribute(attrs, "href");
+                if href.is_some() {
+                    self.out.push('[');
+                }
+                self.links.push(href);
+            }
+            "img" => {
+                let alt = attribute(attrs, "alt").unwrap_or_default();
+                let src = attribute(attrs, "src").unwrap_or_default();
+                self.out
+                    .push_str(&format!("![{}]({})", escape_markdown(&alt), src));
+            }
+            "br" => {
+                self.new_line(0);
+                let indent = self.indent();
+                self.out.push_str(&indent);
+            }
+            "p" | "div" | "blockquote" | "table" | "tr" => {
+                self.new_line(if name == "p" { 1 } else { 0 });
+                let indent = self.indent();
+                self.out.push_str(&indent);
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.new_line(1);
+                let level = name[1..].parse().unwrap_or(1);
+                self.out.push_str(&"#".repeat(level));
+                self.out.push(' ');
+            }
+            "hr" => {
+                self.new_line(1);
+                self.out.push_str("---");
+                self.new_line(1);
+            }
+            "ul" | "ol" => {
+                if self.lists.is_empty() {
+                    self.new_line(1);
+                }
+                let content_column = self.lists.last().map(|l| l.content_column).unwrap_or(0);
+                let start = if name == "ol" {
+                    attribute(attrs, "start")
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or(1)
+                } else {
+                    1
+                };
+                self.lists.push(List {
+                    ordered: name == "ol",
+                    next_number: start,
+                    content_column,
+                });
+            }
+            "li" => {
+                self.new_line(0);
+                let depth = self.lists.len();
+                // the start of the parent item's text, if nested
+                let column = if depth > 1 {
+                    self.lists[depth - 2].content_column
+                } else {
+                    0
+                };
+                let marker = match self.lists.last_mut() {
+                    Some(list) if list.ordered => {
+                        list.next_number += 1;
+                        format!("{}. ", list.next_number - 1)
+                    }
+                    _ => "- ".to_string(),
+                };
+                self.out.push_str(&" ".repeat(column));
+                self.out.push_str(&marker);
+                if let Some(list) = self.lists.last_mut() {
+                    list.content_column = column + marker.len();
+                }
+            }
+            // other tags are dropped, keeping their text
+            _ => (),
+        }
+    }
+
+    fn close_tag(&mut self, name: &str) {
+        let in_inline_code = !self.in_pre && self.code.is_some();
+        if (self.in_pre && name != "pre") || (in_inline_code && name != "code") {
+            return;
+        }
+        match name {
+            "b" | "strong" => self.out.push_str("**"),
+            "i" | "em" => self.out.push_str("*"),
+            "code" => {
+                if let Some(code) = self.code.take() {
+                    self.out.push_str(&inline_code(&code));
+                }
+            }
+            "pre" => {
+                let code = self.code.take().unwrap_or_default();
+                self.in_pre = false;
+                self.out.push_str("```\n");
+                self.out.push_str(code.trim_matches('\n'));
+                self.out.push_str("\n```");
+                self.new_line(1);
+            }
+            "a" => {
+                if let Some(Some(href)) = self.links.pop() {
+                    self.out.push_str(&format!("]({})", href));
+                }
+            }
+            "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => self.new_line(1),
+            "div" | "blockquote" | "table" | "tr" => self.new_line(0),
+            "ul" | "ol" => {
+                self.lists.pop();
+                if self.lists.is_empty() {
+                    self.new_line(1);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn finish(mut self) -> String {
+        if let Some(code) = self.code.take() {
+            // unclosed code; keep its text
+            self.out.push_str(&escape_markdown(&code));
+        }
+        let out = EXTRA_NEWLINES.replace_all(&self.out, "\n\n");
+        out.trim().to_string()
+    }
+}
+
+/// Convert field HTML to Markdown. Bold, italics, links, images, code,
+/// lists, headings and paragraphs are converted; other tags are dropped,
+/// leaving their text.
+pub fn html_to_markdown(html: &str) -> String {
+    let mut writer = MarkdownWriter::default();
+    for caps in TOKEN.captures_iter(html) {
+        if let Some(name) = caps.get(2) {
+            let name = name.as_str().to_ascii_lowercase();
+            if caps[1].is_empty() {
+                writer.open_tag(&name, &caps[3]);
+            } else {
+                writer.close_tag(&name);
+            }
+        } else if let Some(text) = caps.get(4) {
+            writer.text(text.as_str());
+        }
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::html_to_markdown;
+
+    #[test]
+    fn inline() {
+        assert_eq!(html_to_markdown("plain text"), "plain text");
+        assert_eq!(
+            html_to_markdown("<b>bold</b> and <em>italic</em>, <STRONG>x</STRONG>"),
+            "**bold** and *italic*, **x**"
+        );
+        assert_eq!(
+            html_to_markdown(r#"<a href="https://example.com/?a=1&amp;b=2">link</a>"#),
+            "[link](https://example.com/?a=1&b=2)"
+        );
+        assert_eq!(
+            html_to_markdown("<img src='cat.jpg' alt=\"a cat\"> <img src=dog.jpg>"),
+            "![a cat](cat.jpg) ![](dog.jpg)"
+        );
+        assert_eq!(
+            html_to_markdown("1 &lt; 2 *not bold*<br>next&nbsp;line"),
+            "1 < 2 \\*not bold\\*\nnext line"
+        );
+        // unknown tags keep their text
+        assert_eq!(
+            html_to_markdown("<span class=x>red</span> <font>x</font><style>b {}</style>"),
+            "red x"
+        );
+    }
+
+    #[test]
+    fn code() {
+        assert_eq!(
+            html_to_markdown("run <code>ls  -l *.txt</code> now"),
+            "run `ls -l *.txt` now"
+        );
+        assert_eq!(html_to_markdown("<code>a`b</code>"), "``a`b``");
+        assert_eq!(html_to_markdown("<code><b>x</b>_y</code>"), "`x_y`");
+        assert_eq!(html_to_markdown("<code>`</code>"), "`` ` ``");
+        assert_eq!(
+            html_to_markdown("before<pre><code>fn main() {\n    &lt;b&gt;\n}</code></pre>after"),
+            "before\n\n```\nfn main() {\n    <b>\n}\n```\n\nafter"
+        );
+    }
+
+    #[test]
+    fn blocks() {
+        assert_eq!(
+            html_to_markdown("<h2>Title</h2><p>one</p><p>two</p><div>three</div>"),
+            "## Title\n\none\n\ntwo\n\nthree"
+        );
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            html_to_markdown("<ul><li>a</li><li>b</li></ul>"),
+            "- a\n- b"
+        );
+        assert_eq!(
+            html_to_markdown(
+                "intro
+                <ul>
+                  <li>a
+                    <ul>
+                      <li><b>b</b></li>
+                      <li>c
+                        <ol><li>d</li><li>e</li></ol>
+                      </li>
+                    </ul>
+                  </li>
+                  <li>f</li>
+                </ul>
+                outro"
+            ),
+            "intro\n\n- a\n  - **b**\n  - c\n    1. d\n    2. e\n- f\n\noutro"
+        );
+        assert_eq!(
+            html_to_markdown("<ol start=9><li>x<ul><li>y</li></ul></li><li>z</li></ol>"),
+            "9. x\n   - y\n10. z"
+        );
+    }
+}
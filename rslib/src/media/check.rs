@@ -6,10 +6,11 @@ use crate::err::{AnkiError, DBErrorKind, Result};
 use crate::i18n::{tr_args, tr_strs, FString};
 use crate::latex::extract_latex_expanding_clozes;
 use crate::log::debug;
+use crate::media::changetracker::ChangeTracker;
 use crate::media::database::MediaDatabaseContext;
 use crate::media::files::{
-    data_for_file, filename_if_normalized, normalize_nfc_filename, trash_folder,
-    MEDIA_SYNC_FILESIZE_LIMIT,
+    data_for_file, filename_if_normalized, mtime_as_i64, normalize_filename,
+    normalize_nfc_filename, sha1_of_file, trash_folder, MEDIA_SYNC_FILESIZE_LIMIT,
 };
 use crate::notes::{for_every_note, set_note, Note};
 use crate::text::{normalize_to_nfc, MediaRef};
@@ -36,6 +37,38 @@ pub struct MediaCheckOutput {
     pub trash_bytes: u64,
 }
 
+/// Media files whose contents no longer match the media database, sorted
+/// by name.
+#[derive(Debug, PartialEq, Default)]
+pub struct ChecksumMismatches {
+    /// The modification time is unchanged, so the file has likely been
+    /// damaged.
+    pub corrupt: Vec<String>,
+    /// The file was modified after the database last saw it, so the change
+    /// was probably intended, and will be picked up by the next check or
+    /// sync.
+    pub modified: Vec<String>,
+}
+
+/// The space used by the media folder.
+#[derive(Debug, PartialEq, Default)]
+pub struct MediaSizeReport {
+    pub total_files: u64,
+    pub total_bytes: u64,
+    /// Largest total first.
+    pub extensions: Vec<ExtensionUsage>,
+    /// Filenames and sizes, largest first.
+    pub largest: Vec<(String, u64)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExtensionUsage {
+    /// In lowercase, without the dot; empty for files without one.
+    pub extension: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
 #[derive(Debug, PartialEq, Default)]
 struct MediaFolderCheck {
     files: Vec<String>,
@@ -78,7 +111,8 @@ where
 
         let folder_check = self.check_media_folder(&mut ctx)?;
         let referenced_files = self.check_media_references(&folder_check.renamed)?;
-        let (unused, missing) = find_unused_and_missing(folder_check.files, referenced_files);
+        let (unused, mut missing) = find_unused_and_missing(folder_check.files, referenced_files);
+        missing.retain(|fname| self.mgr.overlay_file_path(fname).is_none());
         let (trash_count, trash_bytes) = self.files_in_trash()?;
         Ok(MediaCheckOutput {
             unused,
@@ -203,13 +237,14 @@ where
         buf
     }
 
-    /// Check all the files in the media folder.
-    ///
-    /// - Renames files with invalid names
-    /// - Notes folders/oversized files
-    /// - Gathers a list of all files
-    fn check_media_folder(&mut self, ctx: &mut MediaDatabaseContext) -> Result<MediaFolderCheck> {
-        let mut out = MediaFolderCheck::default();
+    /// Call `func` with the name of each entry in the media folder,
+    /// firing the progress callback every 10 entries; callers that do
+    /// slow work on each entry can fire it more often. Entries whose names
+    /// are not valid unicode are skipped.
+    fn scan_media_folder<F>(&mut self, mut func: F) -> Result<()>
+    where
+        F: FnMut(&mut Self, &str, &fs::DirEntry) -> Result<()>,
+    {
         for dentry in self.mgr.media_folder.read_dir()? {
             let dentry = dentry?;
 
@@ -220,36 +255,52 @@ where
 
             // if the filename is not valid unicode, skip it
             let fname_os = dentry.file_name();
-            let disk_fname = match fname_os.to_str() {
+            let fname = match fname_os.to_str() {
                 Some(s) => s,
                 None => continue,
             };
 
+            func(self, fname, &dentry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check all the files in the media folder.
+    ///
+    /// - Renames files with invalid names
+    /// - Notes folders/oversized files
+    /// - Gathers a list of all files
+    fn check_media_folder(&mut self, ctx: &mut MediaDatabaseContext) -> Result<MediaFolderCheck> {
+        let mut out = MediaFolderCheck::default();
+        self.scan_media_folder(|checker, disk_fname, dentry| {
             // skip folders
             if dentry.file_type()?.is_dir() {
                 out.dirs.push(disk_fname.to_string());
-                continue;
+                return Ok(());
             }
 
             // ignore large files and zero byte files
             let metadata = dentry.metadata()?;
             if metadata.len() > MEDIA_SYNC_FILESIZE_LIMIT as u64 {
                 out.oversize.push(disk_fname.to_string());
-                continue;
+                return Ok(());
             }
             if metadata.len() == 0 {
-                continue;
+                return Ok(());
             }
 
             // rename if required
-            let (norm_name, renamed_on_disk) = self.normalize_and_maybe_rename(ctx, &disk_fname)?;
+            let (norm_name, renamed_on_disk) =
+                checker.normalize_and_maybe_rename(ctx, &disk_fname)?;
             if renamed_on_disk {
                 out.renamed
                     .insert(disk_fname.to_string(), norm_name.to_string());
             }
 
             out.files.push(norm_name.into_owned());
-        }
+            Ok(())
+        })?;
 
         Ok(out)
     }
@@ -271,7 +322,7 @@ where
                 info: "file disappeared".into(),
             }
         })?;
-        let fname = self.mgr.add_file(ctx, disk_fname, &data)?;
+        let fname = self.mgr.add_file(ctx, disk_fname, &data)?.fname;
         debug!(self.ctx.log, "renamed"; "from"=>disk_fname, "to"=>&fname.as_ref());
         assert_ne!(fname.as_ref(), disk_fname);
 
@@ -321,6 +372,22 @@ where
         Ok((total_files, total_bytes))
     }
 
+    /// Run a check, and move any unused files into the trash.
+    /// Returns the number of files moved and their total size.
+    pub fn trash_unused(&mut self) -> Result<(u64, u64)> {
+        let output = self.check()?;
+
+        let mut total_bytes = 0;
+        for fname in &output.unused {
+            total_bytes += fs::metadata(self.mgr.media_folder.join(fname))?.len();
+        }
+
+        let mut ctx = self.mgr.dbctx();
+        self.mgr.remove_files(&mut ctx, &output.unused)?;
+
+        Ok((output.unused.len() as u64, total_bytes))
+    }
+
     pub fn empty_trash(&mut self) -> Result<()> {
         let trash = trash_folder(&self.mgr.media_folder)?;
 
@@ -362,9 +429,9 @@ where
                 let fname_os = dentry.file_name();
                 let fname = fname_os.to_string_lossy();
                 if let Some(data) = data_for_file(&trash, fname.as_ref())? {
-                    let _new_fname =
-                        self.mgr
-                            .add_file(&mut self.mgr.dbctx(), fname.as_ref(), &data)?;
+                    let _added = self
+                        .mgr
+                        .add_file(&mut self.mgr.dbctx(), fname.as_ref(), &data)?;
                 } else {
                     debug!(self.ctx.log, "file disappeared while restoring trash"; "fname"=>fname.as_ref());
                 }
@@ -375,6 +442,220 @@ where
         Ok(())
     }
 
+    /// Recompute the checksum of every file in the media folder, and return
+    /// the files whose contents no longer match the media database. Files
+    /// the database doesn't know about yet are skipped.
+    ///
+    /// Unlike check(), this reads every file in full, so it is much slower.
+    pub fn verify_checksums(&mut self) -> Result<ChecksumMismatches> {
+        let mut ctx = self.mgr.dbctx();
+        let mut out = ChecksumMismatches::default();
+
+        self.scan_media_folder(|checker, fname, dentry| {
+            // hashing large files is slow, so check on every file
+            checker.maybe_fire_progress_cb()?;
+
+            if dentry.file_type()?.is_dir() {
+                return Ok(());
+            }
+            let entry = match ctx.get_entry(fname)? {
+                Some(entry) => entry,
+                None => return Ok(()),
+            };
+            let expected = match entry.sha1 {
+                Some(sha1) => sha1,
+                None => return Ok(()),
+            };
+            if sha1_of_file(&dentry.path())? != expected {
+                if mtime_as_i64(dentry.path())? == entry.mtime {
+                    out.corrupt.push(fname.to_string());
+                } else {
+                    out.modified.push(fname.to_string());
+                }
+            }
+            Ok(())
+        })?;
+
+        out.corrupt.sort();
+        out.modified.sort();
+        Ok(out)
+    }
+
+    /// Add up the space used by the files in the media folder, in total
+    /// and by extension, and find the `largest_count` largest files.
+    /// Folders, such as the trash, are not included.
+    pub fn size_report(&mut self, largest_count: usize) -> Result<MediaSizeReport> {
+        let mut report = MediaSizeReport::default();
+        let mut extensions: HashMap<String, ExtensionUsage> = HashMap::new();
+        let mut files = vec![];
+
+        self.scan_media_folder(|_checker, fname, dentry| {
+            let metadata = dentry.metadata()?;
+            if metadata.is_dir() {
+                return Ok(());
+            }
+            let bytes = metadata.len();
+            report.total_files += 1;
+            report.total_bytes += bytes;
+
+            let extension = Path::new(fname)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .unwrap_or_default()
+                .to_lowercase();
+            let usage = extensions
+                .entry(extension.clone())
+                .or_insert_with(|| ExtensionUsage {
+                    extension,
+                    files: 0,
+                    bytes: 0,
+                });
+            usage.files += 1;
+            usage.bytes += bytes;
+
+            files.push((fname.to_string(), bytes));
+            Ok(())
+        })?;
+
+        report.extensions = extensions.into_iter().map(|(_, usage)| usage).collect();
+        report.extensions.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.extension.cmp(&b.extension))
+        });
+        files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        files.truncate(largest_count);
+        report.largest = files;
+
+        Ok(report)
+    }
+
+    /// Find files in the media folder with the same contents, and keep only
+    /// the first name of each set in sort order, updating notes that
+    /// reference the other names and moving those files to the trash.
+    /// Returns the number of files moved and their total size.
+    ///
+    /// The media database is brought up to date first, and its checksums
+    /// are used to find the duplicates, so only new or changed files are
+    /// read. Files starting with an underscore may be referenced by
+    /// templates, so they are left alone, as are files with names the media
+    /// check would change, which the database doesn't track.
+    pub fn dedupe(&mut self) -> Result<(u64, u64)> {
+        let mut ctx = self.mgr.dbctx();
+        ChangeTracker::new(&self.mgr.media_folder, &mut self.progress_cb, self.ctx.log)
+            .register_changes(&mut ctx)?;
+
+        let mut renamed = HashMap::new();
+        let mut total_bytes = 0;
+        for mut files in ctx.files_with_duplicate_checksums()? {
+            files.retain(|fname| !fname.starts_with('_'));
+            if files.len() < 2 {
+                continue;
+            }
+            let canonical = &files[0];
+            for fname in &files[1..] {
+                total_bytes += fs::metadata(self.mgr.media_folder.join(fname))?.len();
+                renamed.insert(fname.clone(), canonical.clone());
+            }
+        }
+        if renamed.is_empty() {
+            self.ctx.should_commit = false;
+            return Ok((0, 0));
+        }
+
+        // notes are updated first, in the caller's transaction; if the files
+        // can't all be moved, the ones already moved are put back, and the
+        // error rolls the notes back
+        self.check_media_references(&renamed)?;
+        let mut duplicates: Vec<_> = renamed.keys().cloned().collect();
+        duplicates.sort();
+        self.trash_files_or_restore(&mut ctx, &duplicates)?;
+
+        Ok((duplicates.len() as u64, total_bytes))
+    }
+
+    /// Move the provided files to the trash. If one of them can't be moved,
+    /// the files moved before it are restored, and the error is returned.
+    fn trash_files_or_restore(
+        &mut self,
+        ctx: &mut MediaDatabaseContext,
+        fnames: &[String],
+    ) -> Result<()> {
+        for (idx, fname) in fnames.iter().enumerate() {
+            if let Err(err) = self.mgr.remove_files(ctx, &[fname]) {
+                let trash = trash_folder(&self.mgr.media_folder)?;
+                for moved in &fnames[..idx] {
+                    if let Some(data) = data_for_file(&trash, moved)? {
+                        self.mgr.add_file(ctx, moved, &data)?;
+                        fs::remove_file(trash.join(moved))?;
+                    }
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Rename a file in the media folder, and update any notes that
+    /// reference it. Returns the number of notes that were updated.
+    ///
+    /// Notes are updated first, so they will be rolled back if the file
+    /// can't be moved. The old file is moved to the trash.
+    pub fn rename_file(&mut self, old_name: &str, new_name: &str) -> Result<usize> {
+        let mgr = self.mgr;
+        let new_name = normalize_filename(new_name);
+        if mgr.media_folder.join(new_name.as_ref()).exists() {
+            return Err(AnkiError::invalid_input(format!(
+                "{} already exists",
+                new_name
+            )));
+        }
+        let data = data_for_file(&mgr.media_folder, old_name)?
+            .ok_or_else(|| AnkiError::invalid_input(format!("{} not found", old_name)))?;
+
+        let updated = self.rename_file_references(old_name, &new_name)?;
+
+        let mut ctx = mgr.dbctx();
+        mgr.add_file(&mut ctx, &new_name, &data)?;
+        mgr.remove_files(&mut ctx, &[old_name])?;
+
+        Ok(updated)
+    }
+
+    fn rename_file_references(&mut self, old_name: &str, new_name: &str) -> Result<usize> {
+        let note_types = self.ctx.storage.all_note_types()?;
+        let mut updated = 0;
+
+        for_every_note(&self.ctx.storage.db, |note| {
+            let mut modified = false;
+            for idx in 0..note.fields().len() {
+                if let Some(field) =
+                    rename_file_refs_in_field(&note.fields()[idx], old_name, new_name)
+                {
+                    note.set_field(idx, field)?;
+                    modified = true;
+                }
+            }
+            if modified {
+                let nt = note_types
+                    .get(&note.ntid)
+                    .ok_or_else(|| AnkiError::DBError {
+                        info: "missing note type".to_string(),
+                        kind: DBErrorKind::MissingEntity,
+                    })?;
+                set_note(&self.ctx.storage.db, note, nt)?;
+                updated += 1;
+            }
+            Ok(())
+        })?;
+
+        if updated == 0 {
+            self.ctx.should_commit = false;
+        }
+
+        Ok(updated)
+    }
+
     /// Find all media references in notes, fixing as necessary.
     fn check_media_references(
         &mut self,
@@ -493,12 +774,27 @@ fn normalize_and_maybe_rename_files<'a>(
     field
 }
 
+/// Returns the updated field if it referenced old_name.
+fn rename_file_refs_in_field(field: &str, old_name: &str, new_name: &str) -> Option<String> {
+    let mut updated: Cow<str> = field.into();
+    for media_ref in extract_media_refs(field) {
+        if normalize_to_nfc(media_ref.fname) == old_name {
+            updated = rename_media_ref_in_field(updated.as_ref(), &media_ref, new_name).into();
+        }
+    }
+
+    match updated {
+        Cow::Owned(field) => Some(field),
+        Cow::Borrowed(_) => None,
+    }
+}
+
 fn rename_media_ref_in_field(field: &str, media_ref: &MediaRef, new_name: &str) -> String {
     let updated_tag = media_ref.full_ref.replace(media_ref.fname, new_name);
     field.replace(media_ref.full_ref, &updated_tag)
 }
 
-/// Returns (unused, missing)
+/// Returns (unused, missing), each sorted by filename.
 fn find_unused_and_missing(
     files: Vec<String>,
     mut references: HashSet<String>,
@@ -513,7 +809,11 @@ fn find_unused_and_missing(
         }
     }
 
-    (unused, references.into_iter().collect())
+    let mut missing: Vec<_> = references.into_iter().collect();
+    unused.sort();
+    missing.sort();
+
+    (unused, missing)
 }
 
 fn extract_latex_refs(note: &Note, seen_files: &mut HashSet<String>, svg: bool) {
@@ -534,8 +834,10 @@ pub(crate) mod test {
     use crate::err::Result;
     use crate::i18n::I18n;
     use crate::log;
-    use crate::media::check::{MediaCheckOutput, MediaChecker};
-    use crate::media::files::trash_folder;
+    use crate::media::check::{
+        ChecksumMismatches, ExtensionUsage, MediaCheckOutput, MediaChecker, MediaSizeReport,
+    };
+    use crate::media::files::{mtime_as_i64, trash_folder};
     use crate::media::MediaManager;
     use std::path::Path;
     use std::{fs, io};
@@ -685,6 +987,126 @@ Unused: unused.jpg
         Ok(())
     }
 
+    #[test]
+    fn stable_ordering() -> Result<()> {
+        let (_dir, mgr, col) = common_setup()?;
+
+        for fname in &["c.jpg", "a.jpg", "b.jpg"] {
+            fs::write(&mgr.media_folder.join(fname), "foo")?;
+        }
+
+        let run_check = || {
+            col.transact(None, |ctx| {
+                let mut checker = MediaChecker::new(ctx, &mgr, |_n| true);
+                checker.check()
+            })
+        };
+        let first = run_check()?;
+        let second = run_check()?;
+
+        assert_eq!(first.unused, vec!["a.jpg", "b.jpg", "c.jpg"]);
+        assert_eq!(first.missing, vec!["foo[.jpg", "normal.jpg", "ぱぱ.jpg"]);
+        assert_eq!(first, second);
+
+        Ok(())
+    }
+
+    #[test]
+    fn overlay_folders() -> Result<()> {
+        let (dir, mgr, col) = common_setup()?;
+        let overlay = dir.path().join("shared");
+        fs::create_dir(&overlay)?;
+        fs::write(&overlay.join("normal.jpg"), "normal")?;
+        fs::write(&overlay.join("unused.jpg"), "foo")?;
+        let mgr = mgr.with_overlays(vec![overlay.clone()]);
+
+        let output = col.transact(None, |ctx| {
+            let mut checker = MediaChecker::new(ctx, &mgr, |_n| true);
+            checker.check()
+        })?;
+
+        // files in the overlay are present, and never reported as unused
+        assert_eq!(output.missing, vec!["foo[.jpg", "ぱぱ.jpg"]);
+        assert!(output.unused.is_empty());
+
+        // the primary folder takes precedence
+        assert_eq!(
+            mgr.file_path("normal.jpg"),
+            Some(overlay.join("normal.jpg"))
+        );
+        fs::write(&mgr.media_folder.join("normal.jpg"), "normal")?;
+        assert_eq!(
+            mgr.file_path("normal.jpg"),
+            Some(mgr.media_folder.join("normal.jpg"))
+        );
+        assert_eq!(mgr.file_path("ぱぱ.jpg"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn trash_unused() -> Result<()> {
+        let (_dir, mgr, col) = common_setup()?;
+
+        fs::write(&mgr.media_folder.join("normal.jpg"), "normal")?;
+        fs::write(&mgr.media_folder.join("unused1.jpg"), "foo")?;
+        fs::write(&mgr.media_folder.join("unused2.jpg"), "foobar")?;
+
+        let progress = |_n| true;
+        let (count, bytes) = col.transact(None, |ctx| {
+            let mut checker = MediaChecker::new(ctx, &mgr, progress);
+            checker.trash_unused()
+        })?;
+        assert_eq!((count, bytes), (2, 9));
+
+        assert!(fs::metadata(&mgr.media_folder.join("normal.jpg")).is_ok());
+        assert!(fs::metadata(&mgr.media_folder.join("unused1.jpg")).is_err());
+        let trash = trash_folder(&mgr.media_folder)?;
+        assert!(fs::metadata(trash.join("unused2.jpg")).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rename_file() -> Result<()> {
+        let (_dir, mgr, col) = common_setup()?;
+
+        fs::write(&mgr.media_folder.join("normal.jpg"), "normal")?;
+        fs::write(&mgr.media_folder.join("taken.jpg"), "taken")?;
+
+        let rename = |old: &str, new: &str| {
+            col.transact(None, |ctx| {
+                let mut checker = MediaChecker::new(ctx, &mgr, |_n| true);
+                checker.rename_file(old, new)
+            })
+        };
+
+        // existing files are not overwritten
+        assert!(rename("normal.jpg", "taken.jpg").is_err());
+        assert_eq!(
+            fs::read_to_string(mgr.media_folder.join("taken.jpg"))?,
+            "taken"
+        );
+        assert!(rename("missing.jpg", "new.jpg").is_err());
+
+        assert_eq!(rename("normal.jpg", "renamed.jpg")?, 1);
+        assert!(fs::metadata(&mgr.media_folder.join("normal.jpg")).is_err());
+        assert_eq!(
+            fs::read_to_string(mgr.media_folder.join("renamed.jpg"))?,
+            "normal"
+        );
+
+        // the reference was updated, so the file is no longer missing
+        let output = col.transact(None, |ctx| {
+            let mut checker = MediaChecker::new(ctx, &mgr, |_n| true);
+            checker.check()
+        })?;
+        assert_eq!(output.missing, vec!["foo[.jpg", "ぱぱ.jpg"]);
+        assert_eq!(output.unused, vec!["taken.jpg"]);
+
+        Ok(())
+    }
+
     #[test]
     fn unicode_normalization() -> Result<()> {
         let (_dir, mgr, col) = common_setup()?;
@@ -698,8 +1120,6 @@ Unused: unused.jpg
             checker.check()
         })?;
 
-        output.missing.sort();
-
         if cfg!(target_vendor = "apple") {
             // on a Mac, the file should not have been renamed, but the returned name
             // should be in NFC format
@@ -738,4 +1158,160 @@ Unused: unused.jpg
 
         Ok(())
     }
+
+    #[test]
+    fn verify_checksums() -> Result<()> {
+        let (_dir, mgr, col) = common_setup()?;
+
+        let mut ctx = mgr.dbctx();
+        mgr.add_file(&mut ctx, "intact.jpg", b"intact")?;
+        mgr.add_file(&mut ctx, "damaged.jpg", b"damaged")?;
+        mgr.add_file(&mut ctx, "edited.jpg", b"edited")?;
+        drop(ctx);
+        // not in the media database yet
+        fs::write(&mgr.media_folder.join("untracked.jpg"), "untracked")?;
+
+        let mismatches =
+            col.with_ctx(|ctx| MediaChecker::new(ctx, &mgr, |_n| true).verify_checksums())?;
+        assert_eq!(mismatches, ChecksumMismatches::default());
+
+        // flip some bytes, as bit rot would, leaving the mtime alone
+        let damaged = mgr.media_folder.join("damaged.jpg");
+        fs::write(&damaged, b"dbmaged")?;
+        // and edit another file, which updates its mtime
+        let edited = mgr.media_folder.join("edited.jpg");
+        fs::write(&edited, b"edited again")?;
+        let mut ctx = mgr.dbctx();
+        ctx.transact(|ctx| {
+            let mut entry = ctx.get_entry("damaged.jpg")?.unwrap();
+            entry.mtime = mtime_as_i64(&damaged)?;
+            ctx.set_entry(&entry)?;
+            let mut entry = ctx.get_entry("edited.jpg")?.unwrap();
+            entry.mtime = mtime_as_i64(&edited)? - 60;
+            ctx.set_entry(&entry)
+        })?;
+        drop(ctx);
+
+        let mismatches =
+            col.with_ctx(|ctx| MediaChecker::new(ctx, &mgr, |_n| true).verify_checksums())?;
+        assert_eq!(
+            mismatches,
+            ChecksumMismatches {
+                corrupt: vec!["damaged.jpg".into()],
+                modified: vec!["edited.jpg".into()],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn size_report() -> Result<()> {
+        let (_dir, mgr, col) = common_setup()?;
+        fs::write(&mgr.media_folder.join("a.jpg"), "12345")?;
+        fs::write(&mgr.media_folder.join("b.JPG"), "123")?;
+        fs::write(&mgr.media_folder.join("c.mp3"), "1234567")?;
+        fs::write(&mgr.media_folder.join("noext"), "1")?;
+        fs::create_dir(&mgr.media_folder.join("folder"))?;
+        fs::write(&mgr.media_folder.join("folder").join("d.jpg"), "123456789")?;
+
+        let report = col.with_ctx(|ctx| MediaChecker::new(ctx, &mgr, |_n| true).size_report(2))?;
+        assert_eq!(
+            report,
+            MediaSizeReport {
+                total_files: 4,
+                total_bytes: 16,
+                extensions: vec![
+                    ExtensionUsage {
+                        extension: "jpg".into(),
+                        files: 2,
+                        bytes: 8
+                    },
+                    ExtensionUsage {
+                        extension: "mp3".into(),
+                        files: 1,
+                        bytes: 7
+                    },
+                    ExtensionUsage {
+                        extension: "".into(),
+                        files: 1,
+                        bytes: 1
+                    },
+                ],
+                largest: vec![("c.mp3".into(), 7), ("a.jpg".into(), 5)],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedupe() -> Result<()> {
+        let (_dir, mgr, col) = common_setup()?;
+
+        fs::write(&mgr.media_folder.join("normal.jpg"), "same")?;
+        fs::write(&mgr.media_folder.join("dupe.jpg"), "same")?;
+        fs::write(&mgr.media_folder.join("_under.jpg"), "same")?;
+        fs::write(&mgr.media_folder.join("other.jpg"), "other")?;
+
+        let dedupe = || {
+            col.transact(None, |ctx| {
+                let mut checker = MediaChecker::new(ctx, &mgr, |_n| true);
+                checker.dedupe()
+            })
+        };
+        assert_eq!(dedupe()?, (1, 4));
+        assert_eq!(
+            files_in_dir(&mgr.media_folder),
+            vec!["_under.jpg", "dupe.jpg", "other.jpg"]
+        );
+        assert_eq!(
+            files_in_dir(&trash_folder(&mgr.media_folder)?),
+            vec!["normal.jpg"]
+        );
+
+        // the note that used normal.jpg now uses dupe.jpg
+        let output = col.transact(None, |ctx| {
+            let mut checker = MediaChecker::new(ctx, &mgr, |_n| true);
+            checker.check()
+        })?;
+        assert_eq!(output.missing, vec!["foo[.jpg", "ぱぱ.jpg"]);
+        assert_eq!(output.unused, vec!["other.jpg"]);
+
+        // nothing left to do
+        assert_eq!(dedupe()?, (0, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedupe_restores_files_on_failure() -> Result<()> {
+        let (_dir, mgr, col) = common_setup()?;
+
+        fs::write(&mgr.media_folder.join("a.jpg"), "same")?;
+        fs::write(&mgr.media_folder.join("b.jpg"), "same")?;
+        fs::write(&mgr.media_folder.join("c.jpg"), "same")?;
+        // a folder in the trash with the same name as the last duplicate
+        // stops it from being moved, after b.jpg has been
+        let trash = trash_folder(&mgr.media_folder)?;
+        fs::create_dir(trash.join("c.jpg"))?;
+        fs::write(trash.join("c.jpg").join("file"), "")?;
+
+        let result = col.transact(None, |ctx| {
+            let mut checker = MediaChecker::new(ctx, &mgr, |_n| true);
+            checker.dedupe()
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            files_in_dir(&mgr.media_folder),
+            vec!["a.jpg", "b.jpg", "c.jpg"]
+        );
+        assert_eq!(files_in_dir(&trash), vec!["c.jpg"]);
+
+        // the media database still knows about the restored file
+        let mut ctx = mgr.dbctx();
+        assert!(ctx.get_entry("b.jpg")?.unwrap().sha1.is_some());
+
+        Ok(())
+    }
 }
@@ -221,6 +221,29 @@ delete from media where fname=?"
         Ok(map?)
     }
 
+    /// Names of the files that share their checksum with another file,
+    /// grouped by checksum, with each group in sorted order.
+    pub(super) fn files_with_duplicate_checksums(&mut self) -> Result<Vec<Vec<String>>> {
+        let mut stmt = self.db.prepare(
+            "select csum, fname from media where csum in (
+               select csum from media where csum is not null
+               group by csum having count(*) > 1)
+             order by csum, fname",
+        )?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+        let mut groups: Vec<Vec<String>> = vec![];
+        let mut last_csum: Option<String> = None;
+        while let Some(row) = rows.next()? {
+            let csum: String = row.get(0)?;
+            if last_csum.as_ref() != Some(&csum) {
+                groups.push(vec![]);
+                last_csum = Some(csum);
+            }
+            groups.last_mut().unwrap().push(row.get(1)?);
+        }
+        Ok(groups)
+    }
+
     pub(super) fn force_resync(&mut self) -> Result<()> {
         self.db
             .execute_batch("delete from media; update meta set lastUsn = 0, dirMod = 0")
@@ -149,28 +149,40 @@ pub fn add_data_to_folder_uniquely<'a, P>(
 where
     P: AsRef<Path>,
 {
-    let normalized_name = normalize_filename(desired_name);
-
-    let mut target_path = folder.as_ref().join(normalized_name.as_ref());
+    let (fname, already_exists) = unique_filename(folder.as_ref(), desired_name, sha1)?;
 
-    let existing_file_hash = existing_file_sha1(&target_path)?;
-    if existing_file_hash.is_none() {
-        // no file with that name exists yet
-        fs::write(&target_path, data)?;
-        return Ok(normalized_name);
+    if !already_exists {
+        fs::write(folder.as_ref().join(fname.as_ref()), data)?;
     }
 
-    if existing_file_hash.unwrap() == sha1 {
-        // existing file has same checksum, nothing to do
-        return Ok(normalized_name);
-    }
+    Ok(fname)
+}
 
-    // give it a unique name based on its hash
-    let hashed_name = add_hash_suffix_to_file_stem(normalized_name.as_ref(), &sha1);
-    target_path.set_file_name(&hashed_name);
+/// Determine the name data with the provided hash should be stored under.
+/// If a file with the desired name but different content exists, the hash
+/// is appended to the name.
+///
+/// Returns the name, and true if a file with that name and content already
+/// exists in the folder.
+pub(super) fn unique_filename<'a>(
+    folder: &Path,
+    desired_name: &'a str,
+    sha1: [u8; 20],
+) -> io::Result<(Cow<'a, str>, bool)> {
+    let normalized_name = normalize_filename(desired_name);
 
-    fs::write(&target_path, data)?;
-    Ok(hashed_name.into())
+    match existing_file_sha1(&folder.join(normalized_name.as_ref()))? {
+        // no file with that name exists yet
+        None => Ok((normalized_name, false)),
+        // existing file has same checksum, nothing to do
+        Some(existing) if existing == sha1 => Ok((normalized_name, true)),
+        Some(_) => {
+            // give it a unique name based on its hash
+            let hashed_name = add_hash_suffix_to_file_stem(normalized_name.as_ref(), &sha1);
+            let already_exists = existing_file_sha1(&folder.join(&hashed_name))? == Some(sha1);
+            Ok((hashed_name.into(), already_exists))
+        }
+    }
 }
 
 /// Convert foo.jpg into foo-abcde12345679.jpg
@@ -415,7 +427,7 @@ pub(super) fn data_for_file(media_folder: &Path, fname: &str) -> Result<Option<V
 mod test {
     use crate::media::files::{
         add_data_to_folder_uniquely, add_hash_suffix_to_file_stem, normalize_filename,
-        remove_files, sha1_of_data, truncate_filename, MAX_FILENAME_LENGTH,
+        remove_files, sha1_of_data, truncate_filename, unique_filename, MAX_FILENAME_LENGTH,
     };
     use std::borrow::Cow;
     use tempfile::tempdir;
@@ -472,6 +484,20 @@ mod test {
             "test-88fdd585121a4ccb3d1540527aee53a77c77abb8.mp3"
         );
 
+        // the name and existence can be checked without writing
+        assert_eq!(
+            unique_filename(dpath, "test.mp3", h2).unwrap(),
+            (
+                Cow::Borrowed("test-88fdd585121a4ccb3d1540527aee53a77c77abb8.mp3"),
+                true
+            )
+        );
+        let h3 = sha1_of_data("hello2".as_bytes());
+        assert_eq!(
+            unique_filename(dpath, "new.mp3", h3).unwrap(),
+            (Cow::Borrowed("new.mp3"), false)
+        );
+
         let mut written_files = std::fs::read_dir(dpath)
             .unwrap()
             .map(|d| d.unwrap().file_name().to_string_lossy().into_owned())
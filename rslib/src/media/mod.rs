@@ -1,13 +1,15 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use crate::err::Result;
+use crate::err::{AnkiError, Result};
 use crate::media::database::{open_or_create, MediaDatabaseContext, MediaEntry};
-use crate::media::files::{add_data_to_folder_uniquely, mtime_as_i64, remove_files, sha1_of_data};
-use crate::media::sync::{MediaSyncProgress, MediaSyncer};
+use crate::media::files::{mtime_as_i64, remove_files, sha1_of_data, unique_filename};
+use crate::media::sync::{MediaSyncProgress, MediaSyncSettings, MediaSyncer};
+use coarsetime::Instant;
 use rusqlite::Connection;
 use slog::Logger;
 use std::borrow::Cow;
+use std::fs;
 use std::path::{Path, PathBuf};
 
 pub mod changetracker;
@@ -16,9 +18,24 @@ pub mod database;
 pub mod files;
 pub mod sync;
 
+/// The result of adding a file with MediaManager::add_file().
+#[derive(Debug, PartialEq)]
+pub struct AddedMediaFile<'a> {
+    /// The name the file was stored under.
+    pub fname: Cow<'a, str>,
+    /// True if a file with the same name and content was already present,
+    /// so nothing was written.
+    pub already_existed: bool,
+    /// Size of the stored file in bytes.
+    pub size: usize,
+}
+
 pub struct MediaManager {
     db: Connection,
     media_folder: PathBuf,
+    /// Additional read-only folders searched for files that are not in
+    /// the primary folder. Nothing is ever written to them.
+    overlay_folders: Vec<PathBuf>,
 }
 
 impl MediaManager {
@@ -31,9 +48,33 @@ impl MediaManager {
         Ok(MediaManager {
             db,
             media_folder: media_folder.into(),
+            overlay_folders: vec![],
         })
     }
 
+    pub fn with_overlays(mut self, overlay_folders: Vec<PathBuf>) -> Self {
+        self.overlay_folders = overlay_folders;
+        self
+    }
+
+    /// Locate a file in the primary folder, falling back on the overlay
+    /// folders in the order they were provided.
+    pub fn file_path(&self, fname: &str) -> Option<PathBuf> {
+        let primary = self.media_folder.join(fname);
+        if primary.is_file() {
+            return Some(primary);
+        }
+        self.overlay_file_path(fname)
+    }
+
+    /// Locate a file in the overlay folders only.
+    pub(crate) fn overlay_file_path(&self, fname: &str) -> Option<PathBuf> {
+        self.overlay_folders
+            .iter()
+            .map(|folder| folder.join(fname))
+            .find(|path| path.is_file())
+    }
+
     /// Add a file to the media folder.
     ///
     /// If a file with differing contents already exists, a hash will be
@@ -45,13 +86,16 @@ impl MediaManager {
         ctx: &mut MediaDatabaseContext,
         desired_name: &'a str,
         data: &[u8],
-    ) -> Result<Cow<'a, str>> {
+    ) -> Result<AddedMediaFile<'a>> {
         let pre_add_folder_mtime = mtime_as_i64(&self.media_folder)?;
 
         // add file to folder
         let data_hash = sha1_of_data(data);
-        let chosen_fname =
-            add_data_to_folder_uniquely(&self.media_folder, desired_name, data, data_hash)?;
+        let (chosen_fname, already_existed) =
+            unique_filename(&self.media_folder, desired_name, data_hash)?;
+        if !already_existed {
+            fs::write(self.media_folder.join(chosen_fname.as_ref()), data)?;
+        }
         let file_mtime = mtime_as_i64(self.media_folder.join(chosen_fname.as_ref()))?;
         let post_add_folder_mtime = mtime_as_i64(&self.media_folder)?;
 
@@ -88,7 +132,61 @@ impl MediaManager {
             Ok(())
         })?;
 
-        Ok(chosen_fname)
+        Ok(AddedMediaFile {
+            fname: chosen_fname,
+            already_existed,
+            size: data.len(),
+        })
+    }
+
+    /// Add multiple files to the media folder, returning the results in
+    /// the same order as the input.
+    ///
+    /// progress_cb is called periodically with the number of files added so
+    /// far; if it returns false, the operation is aborted. Files added prior
+    /// to the abort are kept.
+    pub fn add_files<'a, F>(
+        &self,
+        ctx: &mut MediaDatabaseContext,
+        files: &[(&'a str, &[u8])],
+        mut progress_cb: F,
+    ) -> Result<Vec<AddedMediaFile<'a>>>
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let mut added = Vec::with_capacity(files.len());
+        let mut progress_updated = Instant::now();
+
+        for &(desired_name, data) in files {
+            added.push(self.add_file(ctx, desired_name, data)?);
+
+            let now = Instant::now();
+            if now.duration_since(progress_updated).as_f64() >= 0.15 {
+                progress_updated = now;
+                if !progress_cb(added.len()) {
+                    return Err(AnkiError::Interrupted);
+                }
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// The filename add_file() would use for data with the provided extension,
+    /// based on the data's checksum. The data is not written.
+    pub fn filename_for_data(&self, data: &[u8], extension: &str) -> Result<String> {
+        let data_hash = sha1_of_data(data);
+        let desired_name = if extension.is_empty() {
+            hex::encode(data_hash)
+        } else {
+            format!(
+                "{}.{}",
+                hex::encode(data_hash),
+                extension.trim_start_matches('.')
+            )
+        };
+        let (fname, _) = unique_filename(&self.media_folder, &desired_name, data_hash)?;
+        Ok(fname.into_owned())
     }
 
     pub fn remove_files<S>(&self, ctx: &mut MediaDatabaseContext, filenames: &[S]) -> Result<()>
@@ -132,12 +230,13 @@ impl MediaManager {
         progress: F,
         endpoint: &'a str,
         hkey: &'a str,
+        settings: &MediaSyncSettings,
         log: Logger,
     ) -> Result<()>
     where
         F: Fn(&MediaSyncProgress) -> bool,
     {
-        let mut syncer = MediaSyncer::new(self, progress, endpoint, log);
+        let mut syncer = MediaSyncer::new(self, progress, endpoint, settings, log)?;
         syncer.sync(hkey).await
     }
 
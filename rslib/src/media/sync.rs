@@ -1,7 +1,7 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use crate::err::{AnkiError, Result, SyncErrorKind};
+use crate::err::{AnkiError, NetworkErrorKind, Result, SyncErrorKind};
 use crate::media::changetracker::ChangeTracker;
 use crate::media::database::{MediaDatabaseContext, MediaDatabaseMetadata, MediaEntry};
 use crate::media::files::{
@@ -11,12 +11,13 @@ use crate::media::MediaManager;
 use crate::version;
 use bytes::Bytes;
 use coarsetime::Instant;
-use reqwest::{multipart, Client, Response};
+use reqwest::{multipart, Client, Proxy, Response};
 use serde_derive::{Deserialize, Serialize};
 use serde_tuple::Serialize_tuple;
 use slog::{debug, Logger};
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::future::Future;
 use std::io::{Read, Write};
 use std::path::Path;
 use std::{io, time};
@@ -24,6 +25,53 @@ use std::{io, time};
 static SYNC_MAX_FILES: usize = 25;
 static SYNC_MAX_BYTES: usize = (2.5 * 1024.0 * 1024.0) as usize;
 static SYNC_SINGLE_FILE_MAX_BYTES: usize = 100 * 1024 * 1024;
+static SYNC_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+/// The longest a request may take if no timeout is provided.
+static SYNC_DEFAULT_TIMEOUT: time::Duration = time::Duration::from_secs(120);
+/// How many times a request that times out is attempted.
+static SYNC_MAX_ATTEMPTS: u32 = 3;
+/// The wait before retrying a request that timed out, which doubles after
+/// each retry.
+static SYNC_RETRY_DELAY: time::Duration = time::Duration::from_secs(5);
+
+/// Network settings for a media sync.
+#[derive(Debug, Clone)]
+pub struct MediaSyncSettings {
+    /// The longest a single request may take, including reading the reply.
+    pub timeout: time::Duration,
+    /// If not provided, the proxies in the HTTP_PROXY and HTTPS_PROXY
+    /// environment variables are used.
+    pub proxy: Option<ProxySettings>,
+}
+
+impl Default for MediaSyncSettings {
+    fn default() -> Self {
+        MediaSyncSettings {
+            timeout: SYNC_DEFAULT_TIMEOUT,
+            proxy: None,
+        }
+    }
+}
+
+/// A proxy to send all sync requests through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxySettings {
+    pub url: String,
+    /// A username and password, if the proxy requires them.
+    pub credentials: Option<(String, String)>,
+}
+
+impl ProxySettings {
+    fn to_proxy(&self) -> Result<Proxy> {
+        // the url may contain a password, so it is not included
+        let proxy =
+            Proxy::all(&self.url).map_err(|_| AnkiError::invalid_input("invalid proxy url"))?;
+        Ok(match &self.credentials {
+            Some((username, password)) => proxy.basic_auth(username, password),
+            None => proxy,
+        })
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct MediaSyncProgress {
@@ -140,15 +188,20 @@ where
         mgr: &'a MediaManager,
         progress_cb: P,
         endpoint: &'a str,
+        settings: &MediaSyncSettings,
         log: Logger,
-    ) -> MediaSyncer<'a, P> {
-        let client = Client::builder()
-            .connect_timeout(time::Duration::from_secs(30))
-            .build()
-            .unwrap();
+    ) -> Result<MediaSyncer<'a, P>> {
+        let mut builder = Client::builder()
+            .connect_timeout(SYNC_CONNECT_TIMEOUT.min(settings.timeout))
+            .timeout(settings.timeout);
+        if let Some(proxy) = &settings.proxy {
+            // replaces the proxies from the environment
+            builder = builder.proxy(proxy.to_proxy()?);
+        }
+        let client = builder.build()?;
         let ctx = mgr.dbctx();
 
-        MediaSyncer {
+        Ok(MediaSyncer {
             mgr,
             ctx,
             skey: None,
@@ -158,7 +211,7 @@ where
             progress_updated: Instant::now(),
             endpoint,
             log,
-        }
+        })
     }
 
     fn skey(&self) -> &str {
@@ -180,7 +233,8 @@ where
         let client_usn = meta.last_sync_usn;
 
         debug!(self.log, "begin media sync");
-        let (sync_key, server_usn) = self.sync_begin(hkey).await?;
+        let (sync_key, server_usn) =
+            with_retries(&self.log, SYNC_RETRY_DELAY, || self.sync_begin(hkey)).await?;
         self.skey = Some(sync_key);
         debug!(self.log, "server usn was {}", server_usn);
 
@@ -265,7 +319,10 @@ where
                  "start_usn"=>last_usn
             );
 
-            let batch = self.fetch_record_batch(last_usn).await?;
+            let batch = with_retries(&self.log, SYNC_RETRY_DELAY, || {
+                self.fetch_record_batch(last_usn)
+            })
+            .await?;
             if batch.is_empty() {
                 debug!(self.log, "empty batch, done");
                 break;
@@ -292,7 +349,10 @@ where
                     .take(SYNC_MAX_FILES)
                     .map(ToOwned::to_owned)
                     .collect();
-                let zip_data = self.fetch_zip(batch.as_slice()).await?;
+                let zip_data = with_retries(&self.log, SYNC_RETRY_DELAY, || {
+                    self.fetch_zip(batch.as_slice())
+                })
+                .await?;
                 let download_batch = extract_into_media_folder(
                     self.mgr.media_folder.as_path(),
                     zip_data,
@@ -334,15 +394,21 @@ where
             }
 
             let zip_data = zip_files(&mut self.ctx, &self.mgr.media_folder, &pending, &self.log)?;
-            if zip_data.is_none() {
-                self.progress.checked += pending.len();
-                self.maybe_fire_progress_cb()?;
-                // discard zip info and retry batch - not particularly efficient,
-                // but this is a corner case
-                continue;
-            }
+            let zip_data = match zip_data {
+                Some(data) => data,
+                None => {
+                    self.progress.checked += pending.len();
+                    self.maybe_fire_progress_cb()?;
+                    // discard zip info and retry batch - not particularly efficient,
+                    // but this is a corner case
+                    continue;
+                }
+            };
 
-            let reply = self.send_zip_data(zip_data.unwrap()).await?;
+            let reply = with_retries(&self.log, SYNC_RETRY_DELAY, || {
+                self.send_zip_data(&zip_data)
+            })
+            .await?;
 
             let (processed_files, processed_deletions): (Vec<_>, Vec<_>) = pending
                 .iter()
@@ -382,12 +448,11 @@ where
     }
 
     async fn finalize_sync(&mut self) -> Result<()> {
-        let url = format!("{}mediaSanity", self.endpoint);
         let local = self.ctx.count()?;
-
-        let obj = FinalizeRequest { local };
-        let resp = ankiweb_json_request(&self.client, &url, &obj, self.skey()).await?;
-        let resp: FinalizeResponse = resp.json().await?;
+        let resp = with_retries(&self.log, SYNC_RETRY_DELAY, || {
+            self.fetch_sanity_check(local)
+        })
+        .await?;
 
         if let Some(data) = resp.data {
             if data == "OK" {
@@ -404,6 +469,14 @@ where
         }
     }
 
+    async fn fetch_sanity_check(&self, local: u32) -> Result<FinalizeResponse> {
+        let url = format!("{}mediaSanity", self.endpoint);
+
+        let obj = FinalizeRequest { local };
+        let resp = ankiweb_json_request(&self.client, &url, &obj, self.skey()).await?;
+        resp.json().await.map_err(Into::into)
+    }
+
     fn fire_progress_cb(&self) -> Result<()> {
         if (self.progress_cb)(&self.progress) {
             Ok(())
@@ -445,10 +518,10 @@ where
         resp.bytes().await.map_err(Into::into)
     }
 
-    async fn send_zip_data(&self, data: Vec<u8>) -> Result<UploadReply> {
+    async fn send_zip_data(&self, data: &[u8]) -> Result<UploadReply> {
         let url = format!("{}uploadChanges", self.endpoint);
 
-        let resp = ankiweb_bytes_request(&self.client, &url, data, self.skey()).await?;
+        let resp = ankiweb_bytes_request(&self.client, &url, data.to_vec(), self.skey()).await?;
         let res: UploadResult = resp.json().await?;
 
         if let Some(reply) = res.data {
@@ -540,6 +613,34 @@ fn determine_required_changes<'a>(
     Ok((to_download, to_delete, to_remove_pending))
 }
 
+/// Run a request, trying it again after a delay if it times out, up to
+/// SYNC_MAX_ATTEMPTS times in total. Other errors are returned immediately.
+async fn with_retries<T, F, Fut>(
+    log: &Logger,
+    mut delay: time::Duration,
+    mut request: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(AnkiError::NetworkError {
+                kind: NetworkErrorKind::Timeout,
+                ..
+            }) if attempt < SYNC_MAX_ATTEMPTS => {
+                debug!(log, "request timed out, retrying"; "attempt"=>attempt);
+                tokio::time::delay_for(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
 async fn ankiweb_json_request<T>(
     client: &Client,
     url: &str,
@@ -810,11 +911,14 @@ fn version_string() -> String {
 
 #[cfg(test)]
 mod test {
-    use crate::err::Result;
+    use crate::err::{AnkiError, NetworkErrorKind, Result};
     use crate::media::sync::{
-        determine_required_change, LocalState, MediaSyncProgress, RequiredChange,
+        determine_required_change, with_retries, LocalState, MediaSyncProgress, ProxySettings,
+        RequiredChange,
     };
     use crate::media::MediaManager;
+    use std::cell::Cell;
+    use std::time::Duration;
     use tempfile::tempdir;
     use tokio::runtime::Runtime;
 
@@ -834,8 +938,14 @@ mod test {
         let log = crate::log::terminal();
 
         let mgr = MediaManager::new(&media_dir, &media_db)?;
-        mgr.sync_media(progress, "https://sync.ankiweb.net/msync/", hkey, log)
-            .await?;
+        mgr.sync_media(
+            progress,
+            "https://sync.ankiweb.net/msync/",
+            hkey,
+            &Default::default(),
+            log,
+        )
+        .await?;
 
         Ok(())
     }
@@ -868,4 +978,58 @@ mod test {
         assert_eq!(d("a", "b", L::InDBAndPending), R::Download);
         assert_eq!(d("a", "b", L::InDBNotPending), R::Download);
     }
+
+    #[test]
+    fn retries() {
+        let log = crate::log::terminal();
+        let mut rt = Runtime::new().unwrap();
+        let attempts = Cell::new(0);
+        let request = |timeouts| {
+            let attempts = &attempts;
+            move || {
+                attempts.set(attempts.get() + 1);
+                let result = if attempts.get() <= timeouts {
+                    Err(AnkiError::NetworkError {
+                        info: "".into(),
+                        kind: NetworkErrorKind::Timeout,
+                    })
+                } else {
+                    Ok(attempts.get())
+                };
+                async move { result }
+            }
+        };
+
+        // timeouts are retried
+        let delay = Duration::from_millis(1);
+        let result = rt.block_on(with_retries(&log, delay, request(2)));
+        assert_eq!(result.unwrap(), 3);
+
+        // until the last attempt
+        attempts.set(0);
+        let result = rt.block_on(with_retries(&log, delay, request(3)));
+        assert!(matches!(
+            result,
+            Err(AnkiError::NetworkError {
+                kind: NetworkErrorKind::Timeout,
+                ..
+            })
+        ));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn proxies() {
+        let mut proxy = ProxySettings {
+            url: "http://proxy.example.com:3128".into(),
+            credentials: Some(("user".into(), "pass".into())),
+        };
+        assert!(proxy.to_proxy().is_ok());
+
+        proxy.url = "not a url".into();
+        assert!(matches!(
+            proxy.to_proxy(),
+            Err(AnkiError::InvalidInput { .. })
+        ));
+    }
 }
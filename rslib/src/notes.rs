@@ -3,12 +3,16 @@
 
 /// At the moment, this is just basic note reading/updating functionality for
 /// the media DB check.
+use crate::collection::RequestContext;
 use crate::err::{AnkiError, DBErrorKind, Result};
 use crate::notetypes::NoteTypeID;
-use crate::text::strip_html_preserving_image_filenames;
-use crate::timestamp::TimestampSecs;
+use crate::search::ids_to_string;
+use crate::text::{strip_html_and_media, strip_html_preserving_image_filenames};
+use crate::timestamp::{TimestampMillis, TimestampSecs};
 use crate::{define_newtype, notetypes::NoteType, types::Usn};
 use rusqlite::{params, Connection, Row, NO_PARAMS};
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::convert::TryInto;
 
 define_newtype!(NoteID, i64);
@@ -47,8 +51,27 @@ pub(crate) fn field_checksum(text: &str) -> u32 {
     u32::from_be_bytes(digest[..4].try_into().unwrap())
 }
 
-#[allow(dead_code)]
-fn get_note(db: &Connection, nid: NoteID) -> Result<Option<Note>> {
+/// The digits of base91 guids: printable characters other than quotes,
+/// backslash and the field separator.
+const BASE91_TABLE: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789\
+    !#$%&()*+,-./:;<=>?@[]^_`{|}~";
+
+/// A random guid in the format the desktop client uses.
+pub(crate) fn guid() -> String {
+    to_base91(rand::random())
+}
+
+fn to_base91(mut num: u64) -> String {
+    let mut buf = vec![];
+    while num > 0 {
+        buf.push(BASE91_TABLE[(num % BASE91_TABLE.len() as u64) as usize]);
+        num /= BASE91_TABLE.len() as u64;
+    }
+    buf.reverse();
+    String::from_utf8(buf).unwrap()
+}
+
+pub(crate) fn get_note(db: &Connection, nid: NoteID) -> Result<Option<Note>> {
     let mut stmt = db.prepare_cached("select id, mid, mod, usn, flds from notes where id=?")?;
     let note = stmt.query_and_then(params![nid], row_to_note)?.next();
 
@@ -69,6 +92,93 @@ pub(super) fn for_every_note<F: FnMut(&mut Note) -> Result<()>>(
     Ok(())
 }
 
+pub(crate) fn notes_of_type(db: &Connection, ntid: NoteTypeID) -> Result<Vec<Note>> {
+    let mut stmt = db.prepare("select id, mid, mod, usn, flds from notes where mid = ?")?;
+    stmt.query_and_then(params![ntid], row_to_note)?.collect()
+}
+
+/// Apply `func` to the fields of every note using the provided note type,
+/// and save the changes. Returns the number of notes updated.
+pub(crate) fn transform_note_fields<F: FnMut(&mut Vec<String>)>(
+    db: &Connection,
+    note_type: &NoteType,
+    mut func: F,
+) -> Result<usize> {
+    let notes = notes_of_type(db, note_type.id)?;
+    let count = notes.len();
+    for mut note in notes {
+        func(&mut note.fields);
+        set_note(db, &mut note, note_type)?;
+    }
+    Ok(count)
+}
+
+/// Notes are compared by overlapping runs of this many characters.
+const SHINGLE_LEN: usize = 3;
+
+/// Other notes whose first field is most similar to that of `nid`, as
+/// (note id, similarity), best first. Similarity is from 0 to 1, and is
+/// the proportion of character runs the fields have in common. Notes with
+/// nothing in common are excluded, and at most `limit` are returned.
+pub(crate) fn similar_notes(
+    db: &Connection,
+    nid: NoteID,
+    limit: usize,
+) -> Result<Vec<(NoteID, f32)>> {
+    let note = get_note(db, nid)?
+        .ok_or_else(|| AnkiError::invalid_input(format!("note {} not found", nid)))?;
+    let target = shingles(&note.fields()[0]);
+    if target.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut similar = vec![];
+    for_every_note(db, |other| {
+        if other.id != nid {
+            let similarity = jaccard_similarity(&target, &shingles(&other.fields()[0]));
+            if similarity > 0.0 {
+                similar.push((other.id, similarity));
+            }
+        }
+        Ok(())
+    })?;
+    similar.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+    similar.truncate(limit);
+
+    Ok(similar)
+}
+
+/// The runs of SHINGLE_LEN characters in a field, ignoring HTML, case and
+/// repeated whitespace. Text shorter than that is a single run.
+fn shingles(field: &str) -> HashSet<String> {
+    let text = strip_html_preserving_image_filenames(field).to_lowercase();
+    let chars: Vec<_> = text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .collect();
+    if chars.is_empty() {
+        HashSet::new()
+    } else if chars.len() <= SHINGLE_LEN {
+        std::iter::once(chars.into_iter().collect()).collect()
+    } else {
+        chars
+            .windows(SHINGLE_LEN)
+            .map(|w| w.iter().collect())
+            .collect()
+    }
+}
+
+fn jaccard_similarity(a: &HashSet<String>, b: &HashSet<String>) -> f32 {
+    let union = a.union(b).count();
+    if union == 0 {
+        0.0
+    } else {
+        a.intersection(b).count() as f32 / union as f32
+    }
+}
+
 fn row_to_note(row: &Row) -> Result<Note> {
     Ok(Note {
         id: row.get(0)?,
@@ -84,24 +194,83 @@ fn row_to_note(row: &Row) -> Result<Note> {
     })
 }
 
+/// Add a note with a new guid, returning its id. `tags` is in the format
+/// notes store them. No cards are generated.
+pub(crate) fn add_note(
+    db: &Connection,
+    note_type: &NoteType,
+    fields: Vec<String>,
+    tags: &str,
+) -> Result<NoteID> {
+    if fields.len() != note_type.fields.len() {
+        return Err(AnkiError::invalid_input(format!(
+            "note type {} has {} fields",
+            note_type.id,
+            note_type.fields.len()
+        )));
+    }
+    db.prepare_cached(
+        "insert into notes (id, guid, mid, mod, usn, tags, flds, sfld, csum, flags, data)
+        values (
+          (case when ?1 in (select id from notes) then (select max(id) + 1 from notes)
+           else ?1 end),
+          ?2, ?3, 0, -1, ?4, '', '', 0, 0, '')",
+    )?
+    .execute(params![
+        TimestampMillis::now().0,
+        guid(),
+        note_type.id,
+        tags
+    ])?;
+
+    let mut note = Note {
+        id: NoteID(db.last_insert_rowid()),
+        ntid: note_type.id,
+        mtime: TimestampSecs(0),
+        usn: Usn(-1),
+        fields,
+    };
+    set_note(db, &mut note, note_type)?;
+    Ok(note.id)
+}
+
+/// The text stored in the notes table for sorting by and searching the
+/// sort field, without HTML.
+pub(crate) fn sort_field_text<'a>(
+    fields: &'a [String],
+    note_type: &NoteType,
+) -> Result<Cow<'a, str>> {
+    let text = fields
+        .get(note_type.sort_field_idx as usize)
+        .ok_or_else(|| AnkiError::DBError {
+            info: "sort field out of range".to_string(),
+            kind: DBErrorKind::MissingEntity,
+        })?;
+    Ok(if note_type.sort_field_strip_media {
+        strip_html_and_media(text)
+    } else {
+        strip_html_preserving_image_filenames(text)
+    })
+}
+
 pub(super) fn set_note(db: &Connection, note: &mut Note, note_type: &NoteType) -> Result<()> {
-    note.mtime = TimestampSecs::now();
     // hard-coded for now
-    note.usn = Usn(-1);
+    set_note_with_usn(db, note, note_type, Usn(-1))
+}
+
+/// Like set_note(), but marks the note with the provided USN, so changes
+/// made on a server are recorded with the server's USN.
+fn set_note_with_usn(
+    db: &Connection,
+    note: &mut Note,
+    note_type: &NoteType,
+    usn: Usn,
+) -> Result<()> {
+    note.mtime = TimestampSecs::now();
+    note.usn = usn;
     let field1_nohtml = strip_html_preserving_image_filenames(&note.fields()[0]);
     let csum = field_checksum(field1_nohtml.as_ref());
-    let sort_field = if note_type.sort_field_idx == 0 {
-        field1_nohtml
-    } else {
-        strip_html_preserving_image_filenames(
-            note.fields()
-                .get(note_type.sort_field_idx as usize)
-                .ok_or_else(|| AnkiError::DBError {
-                    info: "sort field out of range".to_string(),
-                    kind: DBErrorKind::MissingEntity,
-                })?,
-        )
-    };
+    let sort_field = sort_field_text(note.fields(), note_type)?;
 
     let mut stmt =
         db.prepare_cached("update notes set mod=?,usn=?,flds=?,sfld=?,csum=? where id=?")?;
@@ -116,3 +285,297 @@ pub(super) fn set_note(db: &Connection, note: &mut Note, note_type: &NoteType) -
 
     Ok(())
 }
+
+/// Notes are processed this many at a time when rebuilding sort fields.
+const SORT_FIELD_BATCH_SIZE: usize = 1000;
+
+impl RequestContext<'_> {
+    /// Recalculate the stored sort field of every note from its note
+    /// type's current sort field and settings. Notes whose sort field
+    /// changes are marked as modified. Returns the number of notes changed.
+    ///
+    /// `progress` is called with the notes processed so far and the total
+    /// after each batch; if it returns false, the rebuild is aborted.
+    pub(crate) fn rebuild_sort_fields<F>(&mut self, mut progress: F) -> Result<u32>
+    where
+        F: FnMut(u32, u32) -> bool,
+    {
+        let note_types = self.storage.all_note_types()?;
+        let nids: Vec<NoteID> = self
+            .storage
+            .db
+            .prepare("select id from notes order by id")?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        let total = nids.len() as u32;
+        let usn = self.storage.usn()?;
+        let mtime = TimestampSecs::now();
+
+        let mut update = self
+            .storage
+            .db
+            .prepare("update notes set sfld = ?, mod = ?, usn = ? where id = ?")?;
+        let mut changed = 0;
+        let mut processed = 0;
+        for batch in nids.chunks(SORT_FIELD_BATCH_SIZE) {
+            // numeric sort fields are stored as numbers
+            let mut sql =
+                "select id, mid, flds, cast(sfld as text) from notes where id in ".to_string();
+            ids_to_string(&mut sql, batch);
+            let mut stmt = self.storage.db.prepare(&sql)?;
+            let mut rows = stmt.query(NO_PARAMS)?;
+            while let Some(row) = rows.next()? {
+                let ntid: NoteTypeID = row.get(1)?;
+                let nt = note_types.get(&ntid).ok_or_else(|| AnkiError::DBError {
+                    info: "missing note type".to_string(),
+                    kind: DBErrorKind::MissingEntity,
+                })?;
+                let fields: Vec<String> = row
+                    .get_raw(2)
+                    .as_str()?
+                    .split('\x1f')
+                    .map(Into::into)
+                    .collect();
+                let sort_field = sort_field_text(&fields, nt)?;
+                if row.get_raw(3).as_str()? != sort_field {
+                    let nid: NoteID = row.get(0)?;
+                    update.execute(params![sort_field, mtime, usn, nid])?;
+                    changed += 1;
+                }
+            }
+
+            processed += batch.len() as u32;
+            if !progress(processed, total) {
+                return Err(AnkiError::Interrupted);
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Make each note's field count match its note type, adding empty
+    /// fields to notes with too few, and joining the extra fields of notes
+    /// with too many onto their last field with "; ", so no text is lost.
+    /// Notes whose note type is missing are skipped. Returns the ids of the
+    /// notes that were fixed, in ascending order.
+    pub(crate) fn fix_field_counts(&mut self) -> Result<Vec<NoteID>> {
+        let note_types = self.storage.all_note_types()?;
+        let usn = self.storage.usn()?;
+        let db = self.storage.db;
+        let mut fixed = vec![];
+        for_every_note(db, |note| {
+            let nt = match note_types.get(&note.ntid) {
+                Some(nt) => nt,
+                None => return Ok(()),
+            };
+            let wanted = nt.fields.len();
+            if note.fields.len() == wanted || wanted == 0 {
+                return Ok(());
+            }
+            if note.fields.len() < wanted {
+                note.fields.resize(wanted, String::new());
+            } else {
+                let extra = note.fields.split_off(wanted - 1).join("; ");
+                note.fields.push(extra);
+            }
+            set_note_with_usn(db, note, nt, usn)?;
+            fixed.push(note.id);
+            Ok(())
+        })?;
+        fixed.sort();
+
+        Ok(fixed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{get_note, guid, set_note, similar_notes, to_base91, NoteID, BASE91_TABLE};
+    use crate::collection::test::open_test_collection;
+    use crate::collection::RequestContext;
+    use crate::err::{AnkiError, Result};
+    use crate::notetypes::{NoteType, NoteTypeID};
+    use crate::types::Usn;
+
+    #[test]
+    fn similar() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            let db = ctx.storage.db;
+            let nids: Vec<NoteID> = db
+                .prepare("select id from notes order by id")?
+                .query_map(rusqlite::NO_PARAMS, |row| row.get(0))?
+                .collect::<std::result::Result<_, _>>()?;
+            for (nid, first) in nids.iter().zip(&[
+                "The <b>quick</b> brown fox",
+                "the quick  brown foxes",
+                "unrelated",
+            ]) {
+                db.execute(
+                    "update notes set flds = ? || char(31) where id = ?",
+                    rusqlite::params![first, nid],
+                )?;
+            }
+
+            let similar = similar_notes(db, nids[0], 10)?;
+            assert_eq!(similar.len(), 1);
+            assert_eq!(similar[0].0, nids[1]);
+            // the extra "es" adds two runs to the 17 they share
+            assert!((similar[0].1 - 17.0 / 19.0).abs() < 0.001);
+
+            assert!(similar_notes(db, nids[0], 0)?.is_empty());
+            assert!(similar_notes(db, NoteID(1), 10).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn sort_field() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            let db = ctx.storage.db;
+            let mut nt = ctx.storage.all_note_types()?[&NoteTypeID(1581236385347)].clone();
+            let nid = NoteID(1581236386334);
+            let sfld = |text: &str, nt: &NoteType| -> Result<String> {
+                let mut note = get_note(db, nid)?.unwrap();
+                note.set_field(0, text)?;
+                set_note(db, &mut note, nt)?;
+                Ok(db.query_row(
+                    "select sfld from notes where id = ?",
+                    rusqlite::params![nid],
+                    |row| row.get(0),
+                )?)
+            };
+
+            assert_eq!(sfld("<img src=\"cat.jpg\">", &nt)?, " cat.jpg ");
+            nt.sort_field_strip_media = true;
+            assert_eq!(sfld("<img src=\"cat.jpg\">", &nt)?, "");
+            assert_eq!(sfld("<img src=\"cat.jpg\" alt=\"a cat\">", &nt)?, " a cat ");
+            assert_eq!(sfld("[sound:meow.mp3]<b>meow</b>", &nt)?, "meow");
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn rebuild_sort_fields() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        fn sort_fields(ctx: &RequestContext) -> Vec<String> {
+            ctx.storage
+                .db
+                .prepare("select cast(sfld as text) from notes order by id")
+                .unwrap()
+                .query_map(rusqlite::NO_PARAMS, |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap()
+        }
+
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                "update notes set flds = 'a' || char(31) || '<b>z</b>', sfld = 'a', usn = 0;
+                 update notes set flds = '3' || char(31) || '<i>10</i>', sfld = 3
+                 where id = 1581236445532;",
+            )?;
+            let mut calls = 0;
+            let mut progress = |processed, total| {
+                assert_eq!((processed, total), (3, 3));
+                calls += 1;
+                true
+            };
+
+            // nothing changes until the sort field does
+            assert_eq!(ctx.rebuild_sort_fields(&mut progress)?, 0);
+            let mut nt = ctx.get_note_type(NoteTypeID(1581236385347))?;
+            nt.sort_field_idx = 1;
+            ctx.storage.update_note_type(&nt)?;
+            assert_eq!(ctx.rebuild_sort_fields(&mut progress)?, 3);
+            assert_eq!(sort_fields(ctx), vec!["z", "10", "z"]);
+            assert_eq!(calls, 2);
+            let usns: Vec<i32> = ctx
+                .storage
+                .db
+                .prepare("select usn from notes")?
+                .query_map(rusqlite::NO_PARAMS, |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            assert_eq!(usns, vec![-1; 3]);
+
+            assert!(matches!(
+                ctx.rebuild_sort_fields(|_, _| false),
+                Err(AnkiError::Interrupted)
+            ));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn fix_field_counts() -> Result<()> {
+        let (_dir, mut col) = open_test_collection()?;
+        // fixed notes are marked with the server's USN
+        col.server = true;
+
+        col.transact(None, |ctx| {
+            // the note type has two fields
+            ctx.storage.db.execute_batch(
+                "update col set usn = 5;
+                 update notes set flds = 'front' where id = 1581236386334;
+                 update notes set flds = 'a' || char(31) || 'b' || char(31) || 'c'
+                 where id = 1581236461568;",
+            )?;
+
+            assert_eq!(
+                ctx.fix_field_counts()?,
+                vec![NoteID(1581236386334), NoteID(1581236461568)]
+            );
+            let fields = |nid| {
+                get_note(ctx.storage.db, NoteID(nid))
+                    .unwrap()
+                    .unwrap()
+                    .fields
+            };
+            assert_eq!(fields(1581236386334), vec!["front", ""]);
+            assert_eq!(fields(1581236461568), vec!["a", "b; c"]);
+            let usn = |nid| get_note(ctx.storage.db, NoteID(nid)).unwrap().unwrap().usn;
+            assert_eq!(usn(1581236386334), Usn(5));
+            assert_eq!(usn(1581236445532), Usn(-1));
+
+            assert!(ctx.fix_field_counts()?.is_empty());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn base91() {
+        assert_eq!(to_base91(0), "");
+        assert_eq!(to_base91(1), "b");
+        assert_eq!(to_base91(90), "~");
+        assert_eq!(to_base91(91), "ba");
+        assert_eq!(to_base91(u64::max_value()).len(), 10);
+    }
+
+    #[test]
+    fn guids() {
+        let from_base91 = |text: &str| {
+            text.bytes().try_fold(0u64, |num, byte| {
+                let digit = BASE91_TABLE.iter().position(|&b| b == byte)?;
+                num.checked_mul(BASE91_TABLE.len() as u64)?
+                    .checked_add(digit as u64)
+            })
+        };
+
+        let guids: Vec<_> = (0..10_000).map(|_| guid()).collect();
+        for text in &guids {
+            assert!(!text.is_empty() && text.len() <= 10, "{}", text);
+            let num = from_base91(text).unwrap();
+            assert_eq!(&to_base91(num), text);
+        }
+        let unique: std::collections::HashSet<_> = guids.iter().collect();
+        assert_eq!(unique.len(), guids.len());
+    }
+}
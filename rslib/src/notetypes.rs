@@ -1,41 +1,1700 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+use crate::card::{Card, CardType};
+use crate::cloze::{cloze_numbers_in_string, reveal_cloze_text};
+use crate::collection::RequestContext;
+use crate::decks::{DeckID, DEFAULT_DECK_ID};
 use crate::define_newtype;
+use crate::err::{AnkiError, Result};
+use crate::notes::{add_note, get_note, notes_of_type, transform_note_fields, NoteID};
+use crate::template::{
+    field_is_empty, localized_template_error, render_card, without_legacy_template_directives,
+    FieldMap, FieldRequirements, ParsedTemplate, RenderedNode,
+};
+use crate::timestamp::{TimestampMillis, TimestampSecs};
+use crate::types::Usn;
+use lazy_static::lazy_static;
+use regex::Regex;
+use rusqlite::params;
 use serde_aux::field_attributes::deserialize_number_from_string;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::collections::{HashMap, HashSet};
 
 define_newtype!(NoteTypeID, i64);
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub(crate) enum NoteTypeKind {
+    Standard = 0,
+    Cloze = 1,
+}
+
+impl Default for NoteTypeKind {
+    fn default() -> Self {
+        NoteTypeKind::Standard
+    }
+}
+
+/// Keys we don't use are kept in `other`, so a note type can be written
+/// back without losing them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct NoteType {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub id: NoteTypeID,
     pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: NoteTypeKind,
+    #[serde(rename = "mod")]
+    pub mtime: TimestampSecs,
+    pub usn: Usn,
     #[serde(rename = "sortf")]
     pub sort_field_idx: u16,
+    /// If set, sound tags and image filenames are left out of the stored
+    /// sort field, and images are replaced with their alt text.
+    #[serde(rename = "sortfStripMedia", default)]
+    pub sort_field_strip_media: bool,
     #[serde(rename = "latexsvg", default)]
     pub latex_svg: bool,
     #[serde(rename = "tmpls")]
     pub templates: Vec<CardTemplate>,
     #[serde(rename = "flds")]
     pub fields: Vec<NoteField>,
+    #[serde(default)]
+    pub css: String,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct CardTemplate {
     pub name: String,
     pub ord: u16,
+    #[serde(rename = "qfmt", default)]
+    pub front: String,
+    #[serde(rename = "afmt", default)]
+    pub back: String,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct NoteField {
     pub name: String,
     pub ord: u16,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
 }
 
 impl NoteType {
     pub fn latex_uses_svg(&self) -> bool {
         self.latex_svg
     }
+
+    /// The name of the template used by cards with the given ordinal.
+    pub fn template_name(&self, card_ord: u16) -> &str {
+        self.templates
+            .iter()
+            .find(|t| t.ord == card_ord)
+            // cloze note types use a single template for all cards
+            .or_else(|| self.templates.first())
+            .map(|t| t.name.as_str())
+            .unwrap_or_default()
+    }
+
+    /// The kind of note type, treating it as cloze if any template uses the
+    /// cloze filter, even when the stored type flag says otherwise.
+    pub fn detected_kind(&self) -> NoteTypeKind {
+        let uses_cloze = self.templates.iter().any(|t| {
+            [&t.front, &t.back].iter().any(|text| {
+                let normalized = without_legacy_template_directives(text);
+                ParsedTemplate::from_text(&normalized)
+                    .map(|tmpl| tmpl.uses_cloze())
+                    .unwrap_or_default()
+            })
+        });
+        if uses_cloze {
+            NoteTypeKind::Cloze
+        } else {
+            self.kind
+        }
+    }
+
+    /// Renumber fields and templates to match their positions, and
+    /// recalculate the cached card requirements.
+    fn prepare_for_save(&mut self) {
+        for (ord, field) in self.fields.iter_mut().enumerate() {
+            field.ord = ord as u16;
+        }
+        for (ord, template) in self.templates.iter_mut().enumerate() {
+            template.ord = ord as u16;
+        }
+        self.update_requirements();
+    }
+
+    /// Recalculate the cached card requirements after the fields or
+    /// templates have changed.
+    pub(crate) fn update_requirements(&mut self) {
+        let fields: Vec<_> = self.fields.iter().map(|f| f.name.as_str()).collect();
+        let fronts: Vec<_> = self.templates.iter().map(|t| t.front.as_str()).collect();
+        let req = legacy_requirements(self.kind, &fields, &fronts);
+        self.other.insert("req".into(), req);
+    }
+}
+
+// Adding
+//----------------------------------------
+
+/// The parts of a new note type that the caller provides; other settings
+/// take their default values.
+pub(crate) struct NewNoteType {
+    pub name: String,
+    pub kind: NoteTypeKind,
+    /// Field names, in ordinal order.
+    pub fields: Vec<String>,
+    pub templates: Vec<NewCardTemplate>,
+    pub css: String,
+}
+
+pub(crate) struct NewCardTemplate {
+    pub name: String,
+    pub front: String,
+    pub back: String,
+}
+
+static DEFAULT_LATEX_HEADER: &str = r"\documentclass[12pt]{article}
+\special{papersize=3in,5in}
+\usepackage[utf8]{inputenc}
+\usepackage{amssymb,amsmath}
+\pagestyle{empty}
+\setlength{\parindent}{0in}
+\begin{document}
+";
+
+static DEFAULT_LATEX_FOOTER: &str = r"\end{document}";
+
+impl RequestContext<'_> {
+    /// Validate and add the provided note type, returning its id.
+    pub(crate) fn add_note_type(&mut self, nt: NewNoteType) -> Result<NoteTypeID> {
+        self.check_new_note_type(&nt)?;
+
+        let ntid = self.unused_note_type_id()?;
+
+        let fields: Vec<_> = nt
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(ord, name)| {
+                json!({
+                    "name": name,
+                    "ord": ord,
+                    "sticky": false,
+                    "rtl": false,
+                    "font": "Arial",
+                    "size": 20,
+                    "media": [],
+                })
+            })
+            .collect();
+        let templates: Vec<_> = nt
+            .templates
+            .iter()
+            .enumerate()
+            .map(|(ord, t)| {
+                json!({
+                    "name": t.name,
+                    "ord": ord,
+                    "qfmt": t.front,
+                    "afmt": t.back,
+                    "did": null,
+                    "bqfmt": "",
+                    "bafmt": "",
+                })
+            })
+            .collect();
+
+        let legacy = json!({
+            "id": ntid,
+            "name": nt.name,
+            "type": nt.kind as u8,
+            "mod": TimestampSecs::now(),
+            "usn": self.storage.usn()?,
+            "sortf": 0,
+            "did": DEFAULT_DECK_ID,
+            "tmpls": templates,
+            "flds": fields,
+            "css": nt.css,
+            "latexPre": DEFAULT_LATEX_HEADER,
+            "latexPost": DEFAULT_LATEX_FOOTER,
+            "latexsvg": false,
+            "tags": [],
+            "vers": [],
+            "req": legacy_requirements(
+                nt.kind,
+                &nt.fields.iter().map(String::as_str).collect::<Vec<_>>(),
+                &nt.templates.iter().map(|t| t.front.as_str()).collect::<Vec<_>>(),
+            ),
+        });
+        self.storage.set_legacy_note_type(ntid, legacy)?;
+
+        Ok(ntid)
+    }
+
+    fn unused_note_type_id(&self) -> Result<NoteTypeID> {
+        let existing = self.storage.all_note_types()?;
+        let mut ntid = NoteTypeID(TimestampMillis::now().0);
+        while existing.contains_key(&ntid) {
+            ntid.0 += 1;
+        }
+        Ok(ntid)
+    }
+
+    /// Copy an existing note type's fields, templates and styling into a
+    /// new note type with the given name, returning its id. No notes are
+    /// moved to the copy.
+    pub(crate) fn clone_note_type(&mut self, ntid: NoteTypeID, name: &str) -> Result<NoteTypeID> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(AnkiError::invalid_input("note type name is empty"));
+        }
+        let mut nt = self.get_note_type(ntid)?;
+        nt.id = self.unused_note_type_id()?;
+        nt.name = name.into();
+        nt.prepare_for_save();
+        nt.mtime = TimestampSecs::now();
+        nt.usn = self.storage.usn()?;
+        self.storage.update_note_type(&nt)?;
+
+        Ok(nt.id)
+    }
+
+    fn check_new_note_type(&self, nt: &NewNoteType) -> Result<()> {
+        if nt.name.trim().is_empty() {
+            return Err(AnkiError::invalid_input("note type name is empty"));
+        }
+        if nt.fields.is_empty() {
+            return Err(AnkiError::invalid_input("note type has no fields"));
+        }
+        if nt.templates.is_empty() {
+            return Err(AnkiError::invalid_input("note type has no templates"));
+        }
+        if nt.kind == NoteTypeKind::Cloze && nt.templates.len() > 1 {
+            return Err(AnkiError::invalid_input(
+                "cloze note types must have a single template",
+            ));
+        }
+
+        let mut field_names = HashSet::new();
+        for name in &nt.fields {
+            if name.trim().is_empty() {
+                return Err(AnkiError::invalid_input("field name is empty"));
+            }
+            if !field_names.insert(name.as_str()) {
+                return Err(AnkiError::invalid_input(format!(
+                    "duplicate field name: {}",
+                    name
+                )));
+            }
+        }
+
+        for template in &nt.templates {
+            self.check_template(template, &field_names)?;
+        }
+
+        Ok(())
+    }
+
+    /// Ensure both sides of the template parse, and only refer to existing
+    /// fields.
+    fn check_template(
+        &self,
+        template: &NewCardTemplate,
+        field_names: &HashSet<&str>,
+    ) -> Result<()> {
+        for side in &[&template.front, &template.back] {
+            let text = without_legacy_template_directives(side);
+            let parsed = ParsedTemplate::from_text(&text).map_err(|e| {
+                AnkiError::invalid_input(format!(
+                    "{}: {}",
+                    template.name,
+                    localized_template_error(self.i18n, e)
+                ))
+            })?;
+            if let Some(field) = parsed.first_unknown_field(field_names) {
+                return Err(AnkiError::invalid_input(format!(
+                    "{}: no such field: {}",
+                    template.name, field
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The fields each template requires to generate a card, in the
+/// [ord, "any"/"all"/"none", [field ords]] format older clients expect.
+pub(crate) fn legacy_requirements(
+    nt_kind: NoteTypeKind,
+    field_names: &[&str],
+    fronts: &[&str],
+) -> Value {
+    let field_map: FieldMap = field_names
+        .iter()
+        .enumerate()
+        .map(|(ord, name)| (*name, ord as u16))
+        .collect();
+    let reqs: Vec<_> = fronts
+        .iter()
+        .enumerate()
+        .map(|(ord, front)| {
+            let normalized = without_legacy_template_directives(front);
+            let reqs = ParsedTemplate::from_text(&normalized).map(|tmpl| {
+                if nt_kind == NoteTypeKind::Cloze {
+                    tmpl.cloze_requirements(&field_map)
+                } else {
+                    tmpl.requirements(&field_map)
+                }
+            });
+            let (kind, ords) = match reqs {
+                Ok(reqs) => match reqs {
+                    FieldRequirements::Any(ords) => ("any", ords),
+                    FieldRequirements::All(ords) => ("all", ords),
+                    FieldRequirements::None => ("none", Default::default()),
+                },
+                // template parsing failures make card unsatisfiable
+                Err(_) => ("none", Default::default()),
+            };
+            let mut ords: Vec<_> = ords.into_iter().collect();
+            ords.sort_unstable();
+            json!([ord, kind, ords])
+        })
+        .collect();
+    json!(reqs)
+}
+
+// Editing fields
+//----------------------------------------
+
+impl RequestContext<'_> {
+    pub(crate) fn get_note_type(&self, ntid: NoteTypeID) -> Result<NoteType> {
+        self.storage
+            .all_note_types()?
+            .remove(&ntid)
+            .ok_or_else(|| AnkiError::invalid_input(format!("note type {} not found", ntid)))
+    }
+
+    pub(crate) fn note_type_kind(&self, ntid: NoteTypeID) -> Result<NoteTypeKind> {
+        Ok(self.get_note_type(ntid)?.detected_kind())
+    }
+
+    /// Save a modified note type. As it affects the schema, the next sync
+    /// will need to be a full sync.
+    fn save_note_type(&mut self, nt: &mut NoteType) -> Result<()> {
+        nt.prepare_for_save();
+        nt.mtime = TimestampSecs::now();
+        nt.usn = self.storage.usn()?;
+        self.storage.set_schema_modified()?;
+        self.storage.update_note_type(nt)
+    }
+
+    /// Add a new field to the end of the note type's fields. Returns the
+    /// number of notes updated.
+    pub(crate) fn add_note_type_field(&mut self, ntid: NoteTypeID, name: &str) -> Result<usize> {
+        let mut nt = self.get_note_type(ntid)?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(AnkiError::invalid_input("field name is empty"));
+        }
+        if nt.fields.iter().any(|f| f.name == name) {
+            return Err(AnkiError::invalid_input(format!(
+                "duplicate field name: {}",
+                name
+            )));
+        }
+
+        let mut other = Map::new();
+        other.insert("sticky".into(), json!(false));
+        other.insert("rtl".into(), json!(false));
+        other.insert("font".into(), json!("Arial"));
+        other.insert("size".into(), json!(20));
+        other.insert("media".into(), json!([]));
+        nt.fields.push(NoteField {
+            name: name.into(),
+            ord: 0,
+            other,
+        });
+        self.save_note_type(&mut nt)?;
+
+        transform_note_fields(self.storage.db, &nt, |fields| fields.push("".into()))
+    }
+
+    /// Remove the field with the given ordinal, deleting its content from
+    /// every note and removing references to it from the templates. Returns
+    /// the number of notes updated.
+    pub(crate) fn remove_note_type_field(&mut self, ntid: NoteTypeID, ord: u16) -> Result<usize> {
+        let mut nt = self.get_note_type(ntid)?;
+        let idx = ord as usize;
+        if idx >= nt.fields.len() {
+            return Err(AnkiError::invalid_input(format!(
+                "field ordinal {} out of range",
+                ord
+            )));
+        }
+        if nt.fields.len() == 1 {
+            return Err(AnkiError::invalid_input("can't remove the last field"));
+        }
+
+        let removed = nt.fields.remove(idx);
+        nt.sort_field_idx = match nt.sort_field_idx {
+            sortf if sortf == ord => 0,
+            sortf if sortf > ord => sortf - 1,
+            sortf => sortf,
+        };
+        for template in &mut nt.templates {
+            template.front = without_field_references(&template.front, &removed.name);
+            template.back = without_field_references(&template.back, &removed.name);
+        }
+        self.save_note_type(&mut nt)?;
+
+        transform_note_fields(self.storage.db, &nt, |fields| {
+            if idx < fields.len() {
+                fields.remove(idx);
+            }
+        })
+    }
+
+    /// Move the field with the given ordinal to `new_ord`, shifting the
+    /// fields in between. Returns the number of notes updated.
+    pub(crate) fn reposition_note_type_field(
+        &mut self,
+        ntid: NoteTypeID,
+        ord: u16,
+        new_ord: u16,
+    ) -> Result<usize> {
+        let mut nt = self.get_note_type(ntid)?;
+        let (old_idx, new_idx) = (ord as usize, new_ord as usize);
+        if old_idx >= nt.fields.len() || new_idx >= nt.fields.len() {
+            return Err(AnkiError::invalid_input(format!(
+                "field ordinal {} or {} out of range",
+                ord, new_ord
+            )));
+        }
+        if old_idx == new_idx {
+            return Ok(0);
+        }
+
+        let sort_field_name = nt.fields[nt.sort_field_idx as usize].name.clone();
+        let field = nt.fields.remove(old_idx);
+        nt.fields.insert(new_idx, field);
+        nt.sort_field_idx = nt
+            .fields
+            .iter()
+            .position(|f| f.name == sort_field_name)
+            .unwrap_or_default() as u16;
+        self.save_note_type(&mut nt)?;
+
+        transform_note_fields(self.storage.db, &nt, |fields| {
+            if old_idx < fields.len() && new_idx < fields.len() {
+                let val = fields.remove(old_idx);
+                fields.insert(new_idx, val);
+            }
+        })
+    }
+}
+
+// Editing templates
+//----------------------------------------
+
+impl RequestContext<'_> {
+    /// Add a template to the end of the note type's templates. If
+    /// generate_cards is true, cards are created for the notes that have
+    /// the fields the template requires. Returns the number of cards added.
+    pub(crate) fn add_note_type_template(
+        &mut self,
+        ntid: NoteTypeID,
+        template: NewCardTemplate,
+        generate_cards: bool,
+    ) -> Result<usize> {
+        let mut nt = self.get_note_type(ntid)?;
+        if nt.kind == NoteTypeKind::Cloze {
+            return Err(AnkiError::invalid_input(
+                "cloze note types must have a single template",
+            ));
+        }
+        if template.name.trim().is_empty() {
+            return Err(AnkiError::invalid_input("template name is empty"));
+        }
+        if nt.templates.iter().any(|t| t.name == template.name) {
+            return Err(AnkiError::invalid_input(format!(
+                "duplicate template name: {}",
+                template.name
+            )));
+        }
+        let field_names: HashSet<_> = nt.fields.iter().map(|f| f.name.as_str()).collect();
+        self.check_template(&template, &field_names)?;
+
+        let mut other = Map::new();
+        other.insert("did".into(), Value::Null);
+        other.insert("bqfmt".into(), json!(""));
+        other.insert("bafmt".into(), json!(""));
+        nt.templates.push(CardTemplate {
+            name: template.name,
+            ord: 0,
+            front: template.front,
+            back: template.back,
+            other,
+        });
+        self.save_note_type(&mut nt)?;
+
+        if generate_cards {
+            self.generate_cards_for_template(&nt, (nt.templates.len() - 1) as u16)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Add a card for the given template to each note whose fields are
+    /// sufficient to render it. Cards go into the deck of the note's
+    /// existing cards, and share their position if they are new.
+    fn generate_cards_for_template(&mut self, nt: &NoteType, ord: u16) -> Result<usize> {
+        let front = without_legacy_template_directives(&nt.templates[ord as usize].front);
+        let parsed = match ParsedTemplate::from_text(&front) {
+            Ok(parsed) => parsed,
+            Err(_) => return Ok(0),
+        };
+
+        let mut added = 0;
+        for note in notes_of_type(self.storage.db, nt.id)? {
+            let nonempty: HashSet<_> = nt
+                .fields
+                .iter()
+                .zip(note.fields())
+                .filter(|(_, text)| !field_is_empty(text))
+                .map(|(field, _)| field.name.as_str())
+                .collect();
+            if !parsed.renders_with_fields(&nonempty) {
+                continue;
+            }
+
+            let (siblings, _) = self.storage.note_cards(note.id)?;
+            if siblings.iter().any(|c| c.ord == ord) {
+                continue;
+            }
+            let did = siblings
+                .first()
+                .map(|c| if c.odid.0 != 0 { c.odid } else { c.did })
+                .unwrap_or(DEFAULT_DECK_ID);
+            let due = match siblings.iter().find(|c| c.ctype == CardType::New) {
+                Some(c) if c.odid.0 != 0 => c.odue,
+                Some(c) => c.due,
+                None => self.storage.next_new_position()?,
+            };
+
+            let mut card = Card::default();
+            card.nid = note.id;
+            card.did = did;
+            card.ord = ord;
+            card.due = due;
+            card.mtime = TimestampSecs::now();
+            card.usn = self.storage.usn()?;
+            self.storage.add_card(&mut card)?;
+            added += 1;
+        }
+
+        Ok(added)
+    }
+
+    /// Remove the template with the given ordinal, deleting its cards and
+    /// renumbering the cards of later templates. Fails if it would leave
+    /// any notes without cards. Returns the number of cards removed.
+    pub(crate) fn remove_note_type_template(
+        &mut self,
+        ntid: NoteTypeID,
+        ord: u16,
+    ) -> Result<usize> {
+        let mut nt = self.get_note_type(ntid)?;
+        if ord as usize >= nt.templates.len() {
+            return Err(AnkiError::invalid_input(format!(
+                "template ordinal {} out of range",
+                ord
+            )));
+        }
+        if nt.templates.len() == 1 {
+            return Err(AnkiError::invalid_input("can't remove the last template"));
+        }
+        let orphaned = self.storage.notes_only_using_template(ntid, ord)?;
+        if orphaned > 0 {
+            return Err(AnkiError::invalid_input(format!(
+                "removing the template would leave {} notes without cards",
+                orphaned
+            )));
+        }
+
+        nt.templates.remove(ord as usize);
+        self.save_note_type(&mut nt)?;
+
+        let usn = self.storage.usn()?;
+        let removed = self.storage.remove_template_cards(ntid, ord, usn)?;
+        self.storage.shift_template_cards(ntid, ord, usn)?;
+
+        Ok(removed)
+    }
+}
+
+// Previewing changes
+//----------------------------------------
+
+/// A destructive note type edit that can be previewed before applying it.
+pub(crate) enum NoteTypeChange {
+    RemoveField { ntid: NoteTypeID, ord: u16 },
+    RemoveTemplate { ntid: NoteTypeID, ord: u16 },
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct NoteTypeChangeImpact {
+    /// Notes with content in a field that would be removed.
+    pub notes_losing_data: usize,
+    /// Cards that would be deleted.
+    pub cards_removed: usize,
+    /// Cards that would remain, but whose front side would become empty.
+    pub cards_emptied: usize,
+    /// Notes that would be left without cards, preventing the change.
+    pub notes_without_cards: usize,
+}
+
+impl RequestContext<'_> {
+    /// Calculate how the change would affect existing notes and cards,
+    /// without modifying anything.
+    pub(crate) fn note_type_change_impact(
+        &mut self,
+        change: NoteTypeChange,
+    ) -> Result<NoteTypeChangeImpact> {
+        match change {
+            NoteTypeChange::RemoveField { ntid, ord } => self.remove_field_impact(ntid, ord),
+            NoteTypeChange::RemoveTemplate { ntid, ord } => {
+                let nt = self.get_note_type(ntid)?;
+                if ord as usize >= nt.templates.len() {
+                    return Err(AnkiError::invalid_input(format!(
+                        "template ordinal {} out of range",
+                        ord
+                    )));
+                }
+                Ok(NoteTypeChangeImpact {
+                    cards_removed: self.storage.template_card_count(ntid, ord)? as usize,
+                    notes_without_cards: self.storage.notes_only_using_template(ntid, ord)?
+                        as usize,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+
+    fn remove_field_impact(&mut self, ntid: NoteTypeID, ord: u16) -> Result<NoteTypeChangeImpact> {
+        let nt = self.get_note_type(ntid)?;
+        let idx = ord as usize;
+        let removed = match nt.fields.get(idx) {
+            Some(field) => field.name.as_str(),
+            None => {
+                return Err(AnkiError::invalid_input(format!(
+                    "field ordinal {} out of range",
+                    ord
+                )))
+            }
+        };
+
+        // the front of each template, before and after the field's
+        // references are removed
+        let fronts: Vec<_> = nt
+            .templates
+            .iter()
+            .map(|t| {
+                let before = without_legacy_template_directives(&t.front).into_owned();
+                let after = without_field_references(&before, removed);
+                (before, after)
+            })
+            .collect();
+        let parsed: Vec<_> = fronts
+            .iter()
+            .map(|(before, after)| {
+                (
+                    ParsedTemplate::from_text(before).ok(),
+                    ParsedTemplate::from_text(after).ok(),
+                )
+            })
+            .collect();
+
+        let mut impact = NoteTypeChangeImpact::default();
+        for note in notes_of_type(self.storage.db, ntid)? {
+            let nonempty_before: HashSet<_> = nt
+                .fields
+                .iter()
+                .zip(note.fields())
+                .filter(|(_, text)| !field_is_empty(text))
+                .map(|(field, _)| field.name.as_str())
+                .collect();
+            if !nonempty_before.contains(removed) {
+                // no content in the field, so nothing changes
+                continue;
+            }
+            impact.notes_losing_data += 1;
+            let mut nonempty_after = nonempty_before.clone();
+            nonempty_after.remove(removed);
+
+            // cloze cards depend on the cloze deletions, not the template
+            if nt.kind == NoteTypeKind::Cloze {
+                continue;
+            }
+            let (cards, _) = self.storage.note_cards(note.id)?;
+            for card in cards {
+                if let Some((Some(before), Some(after))) = parsed.get(card.ord as usize) {
+                    if before.renders_with_fields(&nonempty_before)
+                        && !after.renders_with_fields(&nonempty_after)
+                    {
+                        impact.cards_emptied += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(impact)
+    }
+
+    /// Render sample notes of a note type with draft templates, without
+    /// saving anything. Each template is rendered for standard notes, and
+    /// each cloze number for cloze notes. Cards that fail to render are
+    /// returned with the error, so the other cards can still be shown.
+    pub(crate) fn preview_templates(
+        &mut self,
+        ntid: NoteTypeID,
+        templates: Vec<NewCardTemplate>,
+        nids: &[NoteID],
+    ) -> Result<Vec<TemplatePreview>> {
+        if templates.is_empty() {
+            return Err(AnkiError::invalid_input("no templates provided"));
+        }
+        let mut nt = self.get_note_type(ntid)?;
+        nt.templates = templates
+            .into_iter()
+            .enumerate()
+            .map(|(ord, t)| CardTemplate {
+                name: t.name,
+                ord: ord as u16,
+                front: t.front,
+                back: t.back,
+                other: Map::new(),
+            })
+            .collect();
+        let kind = nt.detected_kind();
+        let decks = self.storage.all_decks()?;
+
+        let mut previews = vec![];
+        for nid in nids {
+            let note = get_note(self.storage.db, *nid)?
+                .ok_or_else(|| AnkiError::invalid_input(format!("note {} not found", nid)))?;
+            if note.ntid != ntid {
+                return Err(AnkiError::invalid_input(format!(
+                    "note {} does not use note type {}",
+                    nid, ntid
+                )));
+            }
+            let tags: String = self.storage.db.query_row(
+                "select tags from notes where id = ?",
+                params![nid],
+                |row| row.get(0),
+            )?;
+            let tags: Vec<_> = tags.split_whitespace().collect();
+            let (cards, _) = self.storage.note_cards(*nid)?;
+
+            let ords: Vec<u16> = if kind == NoteTypeKind::Cloze {
+                let mut numbers: Vec<_> = note
+                    .fields()
+                    .iter()
+                    .map(String::as_str)
+                    .flat_map(cloze_numbers_in_string)
+                    .collect();
+                numbers.sort();
+                numbers.dedup();
+                let ords: Vec<_> = numbers.into_iter().map(|n| n.saturating_sub(1)).collect();
+                if ords.is_empty() {
+                    vec![0]
+                } else {
+                    ords
+                }
+            } else {
+                nt.templates.iter().map(|t| t.ord).collect()
+            };
+
+            for ord in ords {
+                let template = if kind == NoteTypeKind::Cloze {
+                    &nt.templates[0]
+                } else {
+                    &nt.templates[ord as usize]
+                };
+                // use the existing card for the deck and flag, if any
+                let card = cards
+                    .iter()
+                    .find(|c| c.ord == ord)
+                    .or_else(|| cards.first());
+                let deck = card
+                    .and_then(|c| decks.get(if c.odid.0 != 0 { &c.odid } else { &c.did }))
+                    .map(|d| d.name.as_str())
+                    .unwrap_or_default();
+                let flag = card
+                    .map(|c| c.flags & 0b111)
+                    .filter(|&flag| flag > 0)
+                    .map(|flag| format!("flag{}", flag))
+                    .unwrap_or_default();
+                let tags_text = tags.join(" ");
+                let cloze_key = format!("c{}", ord + 1);
+
+                let mut fields: HashMap<&str, &str> = nt
+                    .fields
+                    .iter()
+                    .map(|f| f.name.as_str())
+                    .zip(note.fields().iter().map(AsRef::as_ref))
+                    .collect();
+                fields.insert("Tags", &tags_text);
+                fields.insert("Type", &nt.name);
+                fields.insert("Deck", deck);
+                fields.insert("Subdeck", deck.rsplit("::").next().unwrap_or_default());
+                fields.insert("Card", &template.name);
+                fields.insert("CardFlag", &flag);
+                fields.insert(&cloze_key, "1");
+
+                let rendered = render_card(
+                    &template.front,
+                    &template.back,
+                    &fields,
+                    ord,
+                    &tags,
+                    None,
+                    Default::default(),
+                    self.i18n,
+                )
+                .map_err(|err| err.localized_description(self.i18n));
+                previews.push(TemplatePreview {
+                    nid: *nid,
+                    ord,
+                    rendered,
+                });
+            }
+        }
+
+        Ok(previews)
+    }
+}
+
+/// A card rendered by preview_templates().
+#[derive(Debug)]
+pub(crate) struct TemplatePreview {
+    pub nid: NoteID,
+    pub ord: u16,
+    /// The question and answer nodes, or why the card couldn't be rendered.
+    pub rendered: std::result::Result<(Vec<RenderedNode>, Vec<RenderedNode>), String>,
+}
+
+/// Remove any {{field}}, {{filter:field}}, {{#field}} etc references to the
+/// named field from a template; see ParsedTemplate::without_field(). A
+/// template that can't be parsed is returned unchanged.
+fn without_field_references(template: &str, field: &str) -> String {
+    let text = without_legacy_template_directives(template);
+    match ParsedTemplate::from_text(&text) {
+        Ok(parsed) => parsed.without_field(field),
+        Err(_) => template.to_string(),
+    }
+}
+
+// Converting notes
+//----------------------------------------
+
+impl RequestContext<'_> {
+    /// Add notes of the note type `target` based on the provided notes,
+    /// returning the ids of the added notes. The original notes are left
+    /// in place, so the caller can remove them once the result has been
+    /// checked. Only cloze notes to a standard note type is supported at
+    /// the moment; see cloze_note_to_basic().
+    pub(crate) fn convert_notes(
+        &mut self,
+        nids: &[NoteID],
+        target: NoteTypeID,
+    ) -> Result<Vec<NoteID>> {
+        let note_types = self.storage.all_note_types()?;
+        let target = note_types
+            .get(&target)
+            .ok_or_else(|| AnkiError::invalid_input(format!("note type {} not found", target)))?;
+        if target.detected_kind() != NoteTypeKind::Standard || target.fields.len() < 2 {
+            return Err(AnkiError::invalid_input(
+                "notes can only be converted to a standard note type with 2 or more fields",
+            ));
+        }
+
+        let mut added = vec![];
+        for nid in nids {
+            let note = get_note(self.storage.db, *nid)?
+                .ok_or_else(|| AnkiError::invalid_input(format!("note {} not found", nid)))?;
+            let source = note_types.get(&note.ntid).ok_or_else(|| {
+                AnkiError::invalid_input(format!("note type {} not found", note.ntid))
+            })?;
+            if source.detected_kind() != NoteTypeKind::Cloze {
+                return Err(AnkiError::invalid_input(format!(
+                    "note {} is not a cloze note",
+                    nid
+                )));
+            }
+            let tags: String = self.storage.db.query_row(
+                "select tags from notes where id = ?",
+                params![nid],
+                |row| row.get(0),
+            )?;
+            let (cards, _) = self.storage.note_cards(*nid)?;
+
+            for (ord, fields) in cloze_note_to_basic(source, note.fields(), target.fields.len()) {
+                let did = cards
+                    .iter()
+                    .find(|c| c.ord + 1 == ord)
+                    .map(|c| if c.odid.0 != 0 { c.odid } else { c.did })
+                    .unwrap_or(DEFAULT_DECK_ID);
+                let new_nid = add_note(self.storage.db, target, fields.clone(), &tags)?;
+                if self.add_cards_for_new_note(target, new_nid, &fields, did)? == 0 {
+                    return Err(AnkiError::invalid_input(format!(
+                        "note type {} would not generate any cards",
+                        target.id
+                    )));
+                }
+                added.push(new_nid);
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Add a new card to the deck for each of a standard note type's
+    /// templates that the fields can render, returning the number added.
+    fn add_cards_for_new_note(
+        &mut self,
+        nt: &NoteType,
+        nid: NoteID,
+        fields: &[String],
+        did: DeckID,
+    ) -> Result<usize> {
+        let nonempty: HashSet<_> = nt
+            .fields
+            .iter()
+            .zip(fields)
+            .filter(|(_, text)| !field_is_empty(text))
+            .map(|(field, _)| field.name.as_str())
+            .collect();
+        let mut due = None;
+        let mut added = 0;
+        for template in &nt.templates {
+            let front = without_legacy_template_directives(&template.front);
+            match ParsedTemplate::from_text(&front) {
+                Ok(parsed) if parsed.renders_with_fields(&nonempty) => (),
+                _ => continue,
+            }
+            // all of a note's new cards share a position
+            if due.is_none() {
+                due = Some(self.storage.next_new_position()?);
+            }
+
+            let mut card = Card::default();
+            card.nid = nid;
+            card.did = did;
+            card.ord = template.ord;
+            card.due = due.unwrap();
+            card.mtime = TimestampSecs::now();
+            card.usn = self.storage.usn()?;
+            self.storage.add_card(&mut card)?;
+            added += 1;
+        }
+        Ok(added)
+    }
+}
+
+/// The fields of a basic note for each cloze number in a cloze note, as
+/// (cloze number, fields). The first field has the cloze hidden, and the
+/// second has it revealed, followed by any non-empty fields that are not
+/// used for clozes, such as Back Extra. Other fields are left empty.
+fn cloze_note_to_basic(
+    source: &NoteType,
+    fields: &[String],
+    target_field_count: usize,
+) -> Vec<(u16, Vec<String>)> {
+    let cloze_fields: HashSet<String> = source
+        .templates
+        .iter()
+        .filter_map(|t| {
+            ParsedTemplate::from_text(&without_legacy_template_directives(&t.front)).ok()
+        })
+        .flat_map(|parsed| {
+            parsed
+                .cloze_fields()
+                .into_iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let (cloze_texts, extra): (Vec<_>, Vec<_>) = source
+        .fields
+        .iter()
+        .zip(fields)
+        .partition(|(field, _)| cloze_fields.contains(&field.name));
+    let extra: Vec<_> = extra
+        .into_iter()
+        .map(|(_, text)| text.as_str())
+        .filter(|text| !field_is_empty(text))
+        .collect();
+
+    let mut ords: Vec<_> = cloze_texts
+        .iter()
+        .flat_map(|(_, text)| cloze_numbers_in_string(text))
+        .collect();
+    ords.sort();
+    ords.dedup();
+
+    ords.into_iter()
+        .map(|ord| {
+            let side = |question| {
+                cloze_texts
+                    .iter()
+                    .map(|(_, text)| reveal_cloze_text(text, ord, question))
+                    .filter(|text| !text.is_empty())
+                    .collect::<Vec<_>>()
+                    .join("<br>")
+            };
+            let mut back = vec![side(false)];
+            back.extend(extra.iter().map(ToString::to_string));
+
+            let mut fields = vec![String::new(); target_field_count];
+            fields[0] = side(true);
+            fields[1] = back.join("<br><br>");
+            (ord, fields)
+        })
+        .collect()
+}
+
+// CSS scoping
+//----------------------------------------
+
+lazy_static! {
+    static ref CSS_COMMENT: Regex = Regex::new(r"(?s)/\*.*?\*/").unwrap();
+}
+
+/// Prefix every selector in the provided stylesheet with `.card-<ord>`, so
+/// that multiple cards can be shown on one page without their styles
+/// affecting each other. Selectors targeting the page itself (html, body
+/// and .card) are replaced with the wrapper, which also applies to .card
+/// with more classes, such as .card.nightMode.
+pub(crate) fn scope_css(css: &str, card_ord: u16) -> String {
+    let wrapper = format!(".card-{}", card_ord);
+    let css = CSS_COMMENT.replace_all(css, "");
+    let mut out = String::with_capacity(css.len());
+    scope_rules(&css, &wrapper, &mut out);
+    out
+}
+
+fn scope_rules(mut css: &str, wrapper: &str, out: &mut String) {
+    loop {
+        let trimmed = css.trim_start();
+        out.push_str(&css[..css.len() - trimmed.len()]);
+        css = trimmed;
+        if css.is_empty() {
+            break;
+        }
+
+        let prelude_end = match css.find(|c| c == '{' || c == ';') {
+            Some(idx) => idx,
+            None => {
+                // trailing junk
+                out.push_str(css);
+                break;
+            }
+        };
+        let prelude = &css[..prelude_end];
+        if css[prelude_end..].starts_with(';') {
+            // a statement like @import
+            out.push_str(&css[..=prelude_end]);
+            css = &css[prelude_end + 1..];
+            continue;
+        }
+
+        let body_end = matching_brace(css, prelude_end);
+        let body = &css[prelude_end + 1..body_end];
+        if prelude.starts_with("@media") || prelude.starts_with("@supports") {
+            out.push_str(prelude);
+            out.push('{');
+            scope_rules(body, wrapper, out);
+            out.push('}');
+        } else if prelude.starts_with('@') {
+            // @font-face, @keyframes, etc
+            out.push_str(&css[..body_end.min(css.len() - 1) + 1]);
+        } else {
+            out.push_str(&scope_selectors(prelude, wrapper));
+            out.push('{');
+            out.push_str(body);
+            out.push('}');
+        }
+        css = css.get(body_end + 1..).unwrap_or("");
+    }
+}
+
+/// Return the index of the brace closing the one at `open`, or the end of the
+/// string if it is not closed.
+fn matching_brace(css: &str, open: usize) -> usize {
+    let mut depth = 0;
+    for (idx, c) in css[open..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return open + idx;
+                }
+            }
+            _ => (),
+        }
+    }
+    css.len()
+}
+
+fn scope_selectors(selectors: &str, wrapper: &str) -> String {
+    let scoped: Vec<_> = selectors
+        .split(',')
+        .map(|selector| {
+            let selector = selector.trim();
+            match selector {
+                "html" | "body" => wrapper.to_string(),
+                // .card, or .card with more classes such as .card.nightMode
+                _ if starts_with_card_class(selector) => {
+                    format!("{}{}", wrapper, &selector[".card".len()..])
+                }
+                _ => format!("{} {}", wrapper, selector),
+            }
+        })
+        .collect();
+    format!("{} ", scoped.join(", "))
+}
+
+/// True if the selector's first compound selector includes the .card class,
+/// and not just a class starting with "card", like .cards or .card-1.
+fn starts_with_card_class(selector: &str) -> bool {
+    selector.starts_with(".card")
+        && !selector[".card".len()..]
+            .starts_with(|c: char| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        scope_css, without_field_references, NewCardTemplate, NewNoteType, NoteTypeChange,
+        NoteTypeChangeImpact, NoteTypeID, NoteTypeKind,
+    };
+    use crate::collection::test::open_test_collection;
+    use crate::collection::RequestContext;
+    use crate::err::{AnkiError, Result};
+    use crate::notes::{get_note, notes_of_type, NoteID};
+    use crate::template::RenderedNode;
+
+    #[test]
+    fn scoping() {
+        assert_eq!(
+            scope_css("b { color: red; }", 1),
+            ".card-1 b { color: red; }"
+        );
+        assert_eq!(
+            scope_css(".card {\n font-size: 20px;\n}\n\ni, .foo > u{x:y}", 2),
+            ".card-2 {\n font-size: 20px;\n}\n\n.card-2 i, .card-2 .foo > u {x:y}"
+        );
+        assert_eq!(
+            scope_css(
+                "/* c */ @import url(x.css); @media (max-width: 10px) { b {} }",
+                0
+            ),
+            " @import url(x.css); @media (max-width: 10px) { .card-0 b {} }"
+        );
+        assert_eq!(
+            scope_css("@font-face { font-family: x; }", 0),
+            "@font-face { font-family: x; }"
+        );
+        // the night mode classes are added to the card itself
+        assert_eq!(
+            scope_css(
+                ".card.nightMode {x:y} .card.night_mode b, .cards, .card-3 {x:y}",
+                1
+            ),
+            ".card-1.nightMode {x:y} .card-1.night_mode b, .card-1 .cards, .card-1 .card-3 {x:y}"
+        );
+    }
+
+    #[test]
+    fn removing_field_references() {
+        // only references to the exact field are removed
+        assert_eq!(
+            without_field_references(
+                "{{Back}}{{FrontBack}}{{Extra Back}}{{text:Back}}{{cloze:Extra Back}}",
+                "Back"
+            ),
+            "{{FrontBack}}{{Extra Back}}{{cloze:Extra Back}}"
+        );
+        // a conditional section is removed with its contents, and a negated
+        // one is kept
+        assert_eq!(
+            without_field_references(
+                "{{#Back}}<hr>{{Back}}{{/Back}}{{^Back}}none{{/Back}}\
+                 {{#Extra Back}}x{{/Extra Back}}",
+                "Back"
+            ),
+            "none{{#Extra Back}}x{{/Extra Back}}"
+        );
+        assert_eq!(
+            without_field_references("{{#Front}}a{{#Back}}b{{/Back}}c{{/Front}}", "Back"),
+            "{{#Front}}ac{{/Front}}"
+        );
+        // unparsable templates are left as they are
+        assert_eq!(without_field_references("{{#Back}}", "Back"), "{{#Back}}");
+    }
+
+    #[test]
+    fn add_note_type() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        let new_nt = |fields: &[&str], front: &str| NewNoteType {
+            name: "Test".into(),
+            kind: NoteTypeKind::Standard,
+            fields: fields.iter().map(ToString::to_string).collect(),
+            templates: vec![NewCardTemplate {
+                name: "Card 1".into(),
+                front: front.into(),
+                back: "{{FrontSide}}<hr id=answer>{{Back}}".into(),
+            }],
+            css: ".card {}".into(),
+        };
+        let is_invalid = |res: Result<_>| matches!(res, Err(AnkiError::InvalidInput { .. }));
+
+        col.transact(None, |ctx| {
+            let ntid =
+                ctx.add_note_type(new_nt(&["Front", "Back"], "{{#Front}}{{Front}}{{/Front}}"))?;
+            let note_types = ctx.storage.all_note_types()?;
+            let nt = &note_types[&ntid];
+            assert_eq!(nt.name, "Test");
+            assert_eq!(nt.kind, NoteTypeKind::Standard);
+            assert_eq!(
+                nt.fields
+                    .iter()
+                    .map(|f| f.name.as_str())
+                    .collect::<Vec<_>>(),
+                vec!["Front", "Back"]
+            );
+            assert_eq!(nt.template_name(0), "Card 1");
+            assert_eq!(nt.css, ".card {}");
+            let req: serde_json::Value = ctx.storage.db.query_row_and_then(
+                "select models from col",
+                rusqlite::NO_PARAMS,
+                |row| -> Result<_> {
+                    let v: serde_json::Value = serde_json::from_str(row.get_raw(0).as_str()?)?;
+                    Ok(v[ntid.to_string()]["req"].clone())
+                },
+            )?;
+            assert_eq!(req, serde_json::json!([[0, "any", [0]]]));
+
+            // duplicate field
+            assert!(is_invalid(
+                ctx.add_note_type(new_nt(&["Back", "Back"], "{{Back}}"))
+            ));
+            // missing field
+            assert!(is_invalid(
+                ctx.add_note_type(new_nt(&["Front", "Back"], "{{Missing}}"))
+            ));
+            assert!(is_invalid(ctx.add_note_type(new_nt(
+                &["Front", "Back"],
+                "{{#Missing}}{{/Missing}}"
+            ))));
+            // invalid template
+            assert!(is_invalid(
+                ctx.add_note_type(new_nt(&["Front", "Back"], "{{#Front}}"))
+            ));
+
+            // cloze note types may refer to cloze numbers
+            let mut nt = new_nt(&["Text", "Back"], "{{cloze:Text}}{{#c1}}x{{/c1}}");
+            nt.kind = NoteTypeKind::Cloze;
+            let ntid = ctx.add_note_type(nt)?;
+            assert_eq!(
+                ctx.storage.all_note_types()?[&ntid].kind,
+                NoteTypeKind::Cloze
+            );
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn edit_fields() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        // the Basic note type has three notes
+        let ntid = NoteTypeID(1581236385347);
+        let nid = NoteID(1581236386334);
+        col.transact(None, |ctx| {
+            let fields = |ctx: &mut RequestContext| -> Result<Vec<String>> {
+                Ok(get_note(ctx.storage.db, nid)?.unwrap().fields().clone())
+            };
+            let field_names = |ctx: &mut RequestContext| -> Result<Vec<String>> {
+                Ok(ctx
+                    .get_note_type(ntid)?
+                    .fields
+                    .into_iter()
+                    .map(|f| f.name)
+                    .collect())
+            };
+            let original = fields(ctx)?;
+
+            assert_eq!(ctx.add_note_type_field(ntid, "Extra")?, 3);
+            assert_eq!(field_names(ctx)?, vec!["Front", "Back", "Extra"]);
+            assert_eq!(
+                fields(ctx)?,
+                vec![original[0].as_str(), original[1].as_str(), ""]
+            );
+            assert!(ctx.add_note_type_field(ntid, "Extra").is_err());
+
+            assert_eq!(ctx.reposition_note_type_field(ntid, 2, 0)?, 3);
+            assert_eq!(field_names(ctx)?, vec!["Extra", "Front", "Back"]);
+            assert_eq!(
+                fields(ctx)?,
+                vec!["", original[0].as_str(), original[1].as_str()]
+            );
+            // the sort field follows the field it pointed to
+            assert_eq!(ctx.get_note_type(ntid)?.sort_field_idx, 1);
+
+            assert_eq!(ctx.remove_note_type_field(ntid, 2)?, 3);
+            assert_eq!(field_names(ctx)?, vec!["Extra", "Front"]);
+            assert_eq!(fields(ctx)?, vec!["", original[0].as_str()]);
+            let nt = ctx.get_note_type(ntid)?;
+            assert_eq!(nt.templates[0].back, "{{FrontSide}}\n\n<hr id=answer>\n\n");
+            assert_eq!(nt.fields[1].ord, 1);
+
+            assert!(ctx.remove_note_type_field(ntid, 5).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn edit_templates() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        // the Basic note type has three notes with one card each
+        let ntid = NoteTypeID(1581236385347);
+        let template = |name: &str, front: &str| NewCardTemplate {
+            name: name.into(),
+            front: front.into(),
+            back: "{{FrontSide}}".into(),
+        };
+        col.transact(None, |ctx| {
+            assert_eq!(
+                ctx.add_note_type_template(ntid, template("Card 2", "{{Back}}"), true)?,
+                3
+            );
+            // a new card shares its sibling's position
+            let (cards, _) = ctx.storage.note_cards(NoteID(1581236461568))?;
+            assert_eq!(cards.iter().map(|c| c.ord).collect::<Vec<_>>(), vec![0, 1]);
+            assert_eq!(cards[1].due, cards[0].due);
+
+            assert!(ctx
+                .add_note_type_template(ntid, template("Card 3", "{{Missing}}"), true)
+                .is_err());
+            assert!(ctx
+                .add_note_type_template(ntid, template("Card 2", "{{Front}}"), true)
+                .is_err());
+            assert_eq!(
+                ctx.add_note_type_template(ntid, template("Card 3", "{{Front}}"), true)?,
+                3
+            );
+
+            assert_eq!(ctx.remove_note_type_template(ntid, 1)?, 3);
+            let nt = ctx.get_note_type(ntid)?;
+            assert_eq!(nt.templates[1].name, "Card 3");
+            assert_eq!(nt.templates[1].ord, 1);
+            // cards of later templates have been shifted down
+            let (cards, _) = ctx.storage.note_cards(NoteID(1581236461568))?;
+            assert_eq!(cards.iter().map(|c| c.ord).collect::<Vec<_>>(), vec![0, 1]);
+
+            // every note would be left without cards
+            assert!(ctx.remove_note_type_template(ntid, 0).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn change_impact() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        let ntid = NoteTypeID(1581236385347);
+        col.with_ctx(|ctx| {
+            // the Basic card fronts only show the Front field
+            let impact =
+                ctx.note_type_change_impact(NoteTypeChange::RemoveField { ntid, ord: 0 })?;
+            assert_eq!(
+                impact,
+                NoteTypeChangeImpact {
+                    notes_losing_data: 3,
+                    cards_emptied: 3,
+                    ..Default::default()
+                }
+            );
+            let impact =
+                ctx.note_type_change_impact(NoteTypeChange::RemoveField { ntid, ord: 1 })?;
+            assert_eq!(impact.notes_losing_data, 3);
+            assert_eq!(impact.cards_emptied, 0);
+
+            let impact =
+                ctx.note_type_change_impact(NoteTypeChange::RemoveTemplate { ntid, ord: 0 })?;
+            assert_eq!(impact.cards_removed, 3);
+            assert_eq!(impact.notes_without_cards, 3);
+
+            assert!(ctx
+                .note_type_change_impact(NoteTypeChange::RemoveField { ntid, ord: 2 })
+                .is_err());
+
+            Ok(())
+        })?;
+
+        // nothing was changed
+        col.with_ctx(|ctx| {
+            assert_eq!(ctx.get_note_type(ntid)?.fields.len(), 2);
+            assert_eq!(ctx.storage.template_card_count(ntid, 0)?, 3);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn clone_note_type() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        let ntid = NoteTypeID(1581236385347);
+        col.transact(None, |ctx| {
+            let new_id = ctx.clone_note_type(ntid, "Basic copy")?;
+            assert_ne!(new_id, ntid);
+
+            let original = ctx.get_note_type(ntid)?;
+            let copy = ctx.get_note_type(new_id)?;
+            assert_eq!(copy.id, new_id);
+            assert_eq!(copy.name, "Basic copy");
+            assert_eq!(copy.css, original.css);
+            assert_eq!(copy.templates[0].front, original.templates[0].front);
+            assert_eq!(
+                copy.fields.iter().map(|f| &f.name).collect::<Vec<_>>(),
+                original.fields.iter().map(|f| &f.name).collect::<Vec<_>>()
+            );
+            assert_eq!(copy.other["latexPre"], original.other["latexPre"]);
+            assert!(notes_of_type(ctx.storage.db, new_id)?.is_empty());
+
+            assert!(ctx.clone_note_type(ntid, " ").is_err());
+            assert!(ctx.clone_note_type(NoteTypeID(1), "x").is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn note_type_kind() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        let new_nt = |kind: NoteTypeKind, front: &str| NewNoteType {
+            name: "Test".into(),
+            kind,
+            fields: vec!["Text".into(), "Extra".into()],
+            templates: vec![NewCardTemplate {
+                name: "Cloze".into(),
+                front: front.into(),
+                back: "{{Extra}}".into(),
+            }],
+            css: "".into(),
+        };
+
+        col.transact(None, |ctx| {
+            // standard
+            assert_eq!(
+                ctx.note_type_kind(NoteTypeID(1581236385347))?,
+                NoteTypeKind::Standard
+            );
+
+            // cloze, by the stored flag
+            let ntid = ctx.add_note_type(new_nt(NoteTypeKind::Cloze, "{{Text}}"))?;
+            assert_eq!(ctx.note_type_kind(ntid)?, NoteTypeKind::Cloze);
+
+            // cloze, by the template
+            let ntid = ctx.add_note_type(new_nt(NoteTypeKind::Standard, "{{cloze:Text}}"))?;
+            assert_eq!(ctx.get_note_type(ntid)?.kind, NoteTypeKind::Standard);
+            assert_eq!(ctx.note_type_kind(ntid)?, NoteTypeKind::Cloze);
+
+            assert!(ctx.note_type_kind(NoteTypeID(1)).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn convert_cloze_to_basic() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        let basic = NoteTypeID(1581236385347);
+        let is_invalid = |res: Result<_>| matches!(res, Err(AnkiError::InvalidInput { .. }));
+
+        col.transact(None, |ctx| {
+            let cloze = ctx.add_note_type(NewNoteType {
+                name: "Cloze".into(),
+                kind: NoteTypeKind::Cloze,
+                fields: vec!["Text".into(), "Extra".into()],
+                templates: vec![NewCardTemplate {
+                    name: "Cloze".into(),
+                    front: "{{cloze:Text}}".into(),
+                    back: "{{cloze:Text}}<br>{{Extra}}".into(),
+                }],
+                css: "".into(),
+            })?;
+            ctx.storage.db.execute(
+                "update notes set mid = ?, tags = ' geo ',
+                 flds = '{{c1::Paris}} is in {{c3::France::country}}' || char(31) || 'extra'
+                 where id = 1581236386334",
+                rusqlite::params![cloze],
+            )?;
+
+            let nids = ctx.convert_notes(&[NoteID(1581236386334)], basic)?;
+            assert_eq!(nids.len(), 2);
+            let fields: Vec<_> = nids
+                .iter()
+                .map(|nid| {
+                    get_note(ctx.storage.db, *nid)
+                        .unwrap()
+                        .unwrap()
+                        .fields()
+                        .clone()
+                })
+                .collect();
+            assert_eq!(
+                fields,
+                vec![
+                    vec![
+                        "<span class=cloze>[...]</span> is in France".to_string(),
+                        "<span class=cloze>Paris</span> is in France<br><br>extra".into()
+                    ],
+                    vec![
+                        "Paris is in <span class=cloze>[country]</span>".into(),
+                        "Paris is in <span class=cloze>France</span><br><br>extra".into()
+                    ],
+                ]
+            );
+            for nid in &nids {
+                let (cards, ntid) = ctx.storage.note_cards(*nid)?;
+                assert_eq!(ntid, Some(basic));
+                assert_eq!(cards.len(), 1);
+                assert_eq!(cards[0].did.0, 1);
+                let tags: String = ctx.storage.db.query_row(
+                    "select tags from notes where id = ?",
+                    rusqlite::params![nid],
+                    |row| row.get(0),
+                )?;
+                assert_eq!(tags, " geo ");
+            }
+            // the original note is kept
+            assert!(get_note(ctx.storage.db, NoteID(1581236386334))?.is_some());
+
+            // only cloze notes can be converted, and only to standard types
+            assert!(is_invalid(
+                ctx.convert_notes(&[NoteID(1581236445532)], basic)
+            ));
+            assert!(is_invalid(
+                ctx.convert_notes(&[NoteID(1581236386334)], cloze)
+            ));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn preview_templates() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        let basic = NoteTypeID(1581236385347);
+        let text = |nodes: &[RenderedNode]| {
+            nodes
+                .iter()
+                .map(|node| match node {
+                    RenderedNode::Text { text } => text.as_str(),
+                    _ => "?",
+                })
+                .collect::<String>()
+        };
+
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                "update notes set flds = 'q' || char(31) || 'a', tags = ' one two '
+                 where id = 1581236386334;
+                 update notes set flds = 'q2' || char(31) || '' where id = 1581236445532;",
+            )?;
+            let before = ctx.get_note_type(basic)?;
+
+            let templates = vec![
+                NewCardTemplate {
+                    name: "Forward".into(),
+                    front: "{{Front}} in {{Card}} [{{Tags}}]".into(),
+                    back: "{{Back}}".into(),
+                },
+                NewCardTemplate {
+                    name: "Reverse".into(),
+                    front: "{{Back}}".into(),
+                    back: "{{Front}}".into(),
+                },
+            ];
+            let previews = ctx.preview_templates(
+                basic,
+                templates,
+                &[NoteID(1581236386334), NoteID(1581236445532)],
+            )?;
+            let summary: Vec<_> = previews
+                .iter()
+                .map(|p| {
+                    (
+                        p.nid.0,
+                        p.ord,
+                        p.rendered
+                            .as_ref()
+                            .map(|(q, a)| (text(q), text(a)))
+                            .map_err(|_| ()),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                summary,
+                vec![
+                    (
+                        1581236386334,
+                        0,
+                        Ok(("q in Forward [one two]".to_string(), "a".to_string()))
+                    ),
+                    (1581236386334, 1, Ok(("a".into(), "q".into()))),
+                    (1581236445532, 0, Ok(("q2 in Forward []".into(), "".into()))),
+                    // the empty field leaves the front blank
+                    (1581236445532, 1, Err(())),
+                ]
+            );
+
+            // nothing was saved
+            let after = ctx.get_note_type(basic)?;
+            assert_eq!(after.templates.len(), before.templates.len());
+            assert_eq!(after.templates[0].front, before.templates[0].front);
+
+            // at least one template is required
+            assert!(matches!(
+                ctx.preview_templates(basic, vec![], &[NoteID(1581236386334)]),
+                Err(AnkiError::InvalidInput { .. })
+            ));
+
+            Ok(())
+        })
+    }
 }
@@ -0,0 +1,322 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::card::CardID;
+use crate::collection::RequestContext;
+use crate::define_newtype;
+use crate::err::{AnkiError, Result};
+use crate::sched::cutoff::next_day_at_after_days;
+use crate::storage::revlog_entries_in_file;
+use crate::types::Usn;
+use num_enum::TryFromPrimitive;
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+define_newtype!(RevlogID, i64);
+
+#[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, TryFromPrimitive, Clone, Copy)]
+#[repr(u8)]
+pub enum RevlogReviewKind {
+    Learning = 0,
+    Review = 1,
+    Relearning = 2,
+    /// Reviewed early in a filtered deck.
+    Cram = 3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RevlogEntry {
+    /// Timestamp in milliseconds.
+    pub id: RevlogID,
+    pub cid: CardID,
+    pub usn: Usn,
+    /// The button that was pressed, 1-4.
+    pub button_chosen: u8,
+    /// Positive values are in days, negative values in seconds.
+    pub interval: i32,
+    /// Positive values are in days, negative values in seconds.
+    pub last_interval: i32,
+    /// Card's ease after answering, stored as 10x the %, eg 2500 represents
+    /// 250%.
+    pub ease_factor: u32,
+    /// Amount of milliseconds taken to answer the card.
+    pub taken_millis: u32,
+    pub review_kind: RevlogReviewKind,
+}
+
+impl RevlogEntry {
+    /// The interval in seconds.
+    pub(crate) fn interval_secs(&self) -> i64 {
+        interval_to_secs(self.interval)
+    }
+
+    /// The entry as a line of CSV, with the values as they are stored.
+    fn csv_line(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{}\n",
+            self.id,
+            self.cid,
+            self.button_chosen,
+            self.interval,
+            self.last_interval,
+            self.ease_factor,
+            self.taken_millis,
+            self.review_kind as u8
+        )
+    }
+}
+
+static REVLOG_CSV_HEADER: &str = "timestamp,cid,ease,ivl,last_ivl,factor,time,type\n";
+
+/// How many entries are processed between progress updates.
+const REVLOG_PROGRESS_INTERVAL: u32 = 1000;
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct RevlogImportCounts {
+    pub imported: u32,
+    /// Entries whose id was already present in this collection.
+    pub skipped: u32,
+    /// Entries for cards missing from the card map, which were not imported.
+    pub unmapped: u32,
+}
+
+impl RequestContext<'_> {
+    /// Write the review log to a CSV file, oldest first, returning the
+    /// number of entries written.
+    ///
+    /// If `days` is provided as (from_days_ago, to_days_ago), only reviews
+    /// from those days are included, where today is 0 and days begin at the
+    /// collection's rollover hour. Both ends are inclusive.
+    ///
+    /// `progress` is called with the entries written so far and the total;
+    /// if it returns false, the export is aborted.
+    pub(crate) fn export_revlog_csv<F>(
+        &mut self,
+        path: &Path,
+        days: Option<(u32, u32)>,
+        mut progress: F,
+    ) -> Result<u32>
+    where
+        F: FnMut(u32, u32) -> bool,
+    {
+        let range = match days {
+            Some((from_days_ago, to_days_ago)) => {
+                Some(self.revlog_day_range(from_days_ago, to_days_ago)?)
+            }
+            None => None,
+        };
+        let total = self.storage.revlog_count(range)?;
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(REVLOG_CSV_HEADER.as_bytes())?;
+        let mut written = 0;
+        self.storage.for_each_revlog_entry(range, |entry| {
+            writer.write_all(entry.csv_line().as_bytes())?;
+            written += 1;
+            if written % REVLOG_PROGRESS_INTERVAL == 0 && !progress(written, total) {
+                return Err(AnkiError::Interrupted);
+            }
+            Ok(())
+        })?;
+        writer.flush()?;
+        progress(written, total);
+
+        Ok(written)
+    }
+
+    /// Merge the review log of the collection at `path` into this one.
+    ///
+    /// Entries with an id that already exists here are skipped. Card ids
+    /// are replaced with their id in `card_map`, and entries for cards that
+    /// aren't in it are skipped, so no entry refers to a card that doesn't
+    /// exist here. Imported entries are marked as modified, so they'll be sent
+    /// on the next sync. The caller should run this in a transaction, so an
+    /// aborted import leaves no partial changes.
+    ///
+    /// `progress` is called with the entries processed so far and the total;
+    /// if it returns false, the import is aborted.
+    pub(crate) fn import_revlog<F>(
+        &mut self,
+        path: &Path,
+        card_map: &HashMap<CardID, CardID>,
+        mut progress: F,
+    ) -> Result<RevlogImportCounts>
+    where
+        F: FnMut(u32, u32) -> bool,
+    {
+        let entries = revlog_entries_in_file(path)?;
+        let total = entries.len() as u32;
+        let usn = self.storage.usn()?;
+        let mut counts = RevlogImportCounts::default();
+
+        for (idx, mut entry) in entries.into_iter().enumerate() {
+            match card_map.get(&entry.cid) {
+                Some(cid) => {
+                    entry.cid = *cid;
+                    entry.usn = usn;
+                    if self.storage.add_revlog_entry_if_missing(&entry)? {
+                        counts.imported += 1;
+                    } else {
+                        counts.skipped += 1;
+                    }
+                }
+                None => counts.unmapped += 1,
+            }
+
+            let processed = idx as u32 + 1;
+            if processed % REVLOG_PROGRESS_INTERVAL == 0 && !progress(processed, total) {
+                return Err(AnkiError::Interrupted);
+            }
+        }
+        progress(total, total);
+
+        Ok(counts)
+    }
+
+    /// The revlog ids from the start of `from_days_ago` up to the end of
+    /// `to_days_ago`.
+    fn revlog_day_range(
+        &mut self,
+        from_days_ago: u32,
+        to_days_ago: u32,
+    ) -> Result<(RevlogID, RevlogID)> {
+        if to_days_ago > from_days_ago {
+            return Err(AnkiError::invalid_input("date range ends before it starts"));
+        }
+        let next_day_at = self.storage.timing_today()?.next_day_at;
+        // the start of the day after the given day, in milliseconds
+        let storage = &self.storage;
+        let day_end = |days_ago: u32| {
+            next_day_at_after_days(next_day_at, -(days_ago as i32), |stamp| {
+                storage.local_minutes_west(stamp)
+            }) * 1000
+        };
+
+        Ok((
+            RevlogID(day_end(from_days_ago + 1)),
+            RevlogID(day_end(to_days_ago)),
+        ))
+    }
+}
+
+fn interval_to_secs(interval: i32) -> i64 {
+    if interval < 0 {
+        -i64::from(interval)
+    } else {
+        i64::from(interval) * 86_400
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RevlogImportCounts;
+    use crate::card::CardID;
+    use crate::collection::test::open_test_collection;
+    use crate::err::{AnkiError, Result};
+    use crate::media::check::test::MEDIACHECK_ANKI2;
+    use crate::timestamp::TimestampSecs;
+    use rusqlite::params;
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[test]
+    fn export_csv() -> Result<()> {
+        let (dir, col) = open_test_collection()?;
+        let csv_path = dir.path().join("revlog.csv");
+
+        col.transact(None, |ctx| {
+            // the existing entries are from 2020; add one from today
+            let now_millis = TimestampSecs::now().0 * 1000;
+            ctx.storage.db.execute(
+                "insert into revlog (id, cid, usn, ease, ivl, lastIvl, factor, time, type)
+                 values (?, 1581236445527, -1, 3, 4, -600, 2500, 8000, 1)",
+                params![now_millis],
+            )?;
+
+            let mut updates = vec![];
+            let written = ctx.export_revlog_csv(&csv_path, None, |n, total| {
+                updates.push((n, total));
+                true
+            })?;
+            assert_eq!(written, 3);
+            assert_eq!(updates, vec![(3, 3)]);
+            let csv = fs::read_to_string(&csv_path)?;
+            let lines: Vec<_> = csv.lines().collect();
+            assert_eq!(lines[0], "timestamp,cid,ease,ivl,last_ivl,factor,time,type");
+            assert_eq!(lines.len(), 4);
+            assert!(lines[1].starts_with("1581236491843,1581236445527,"));
+            assert_eq!(
+                lines[3],
+                format!("{},1581236445527,3,4,-600,2500,8000,1", now_millis)
+            );
+
+            // only today
+            let written = ctx.export_revlog_csv(&csv_path, Some((0, 0)), |_, _| true)?;
+            assert_eq!(written, 1);
+            // yesterday and the day before
+            let written = ctx.export_revlog_csv(&csv_path, Some((2, 1)), |_, _| true)?;
+            assert_eq!(written, 0);
+            assert_eq!(
+                fs::read_to_string(&csv_path)?,
+                "timestamp,cid,ease,ivl,last_ivl,factor,time,type\n"
+            );
+
+            assert!(matches!(
+                ctx.export_revlog_csv(&csv_path, Some((1, 2)), |_, _| true),
+                Err(AnkiError::InvalidInput { .. })
+            ));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn import_revlog() -> Result<()> {
+        let (dir, col) = open_test_collection()?;
+        let other_path = dir.path().join("other.anki2");
+        fs::write(&other_path, MEDIACHECK_ANKI2)?;
+
+        {
+            // give the other collection a review that isn't in ours
+            let other = rusqlite::Connection::open(&other_path)?;
+            other.execute_batch(
+                "insert into revlog (id, cid, usn, ease, ivl, lastIvl, factor, time, type)
+                 values (1600000000000, 1581236461565, 5, 3, 4, -600, 2500, 8000, 1)",
+            )?;
+        }
+
+        let mut card_map = HashMap::new();
+        card_map.insert(CardID(1581236461565), CardID(1581236445527));
+        col.transact(None, |ctx| {
+            let mut updates = vec![];
+            let counts = ctx.import_revlog(&other_path, &card_map, |n, total| {
+                updates.push((n, total));
+                true
+            })?;
+            // the first entry's card isn't mapped
+            assert_eq!(
+                counts,
+                RevlogImportCounts {
+                    imported: 1,
+                    skipped: 1,
+                    unmapped: 1,
+                }
+            );
+            assert_eq!(updates, vec![(3, 3)]);
+
+            let entries = ctx.storage.card_revlog(CardID(1581236445527))?;
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[1].id.0, 1600000000000);
+            assert_eq!(entries[1].usn, ctx.storage.usn()?);
+
+            // importing again adds nothing
+            let counts = ctx.import_revlog(&other_path, &card_map, |_, _| true)?;
+            assert_eq!(counts.imported, 0);
+
+            Ok(())
+        })
+    }
+}
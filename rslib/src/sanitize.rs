@@ -0,0 +1,518 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::collection::RequestContext;
+use crate::err::{AnkiError, DBErrorKind, Result};
+use crate::notes::{get_note, set_note, NoteID};
+use crate::search::search_notes;
+use crate::text::decode_entities;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+lazy_static! {
+    static ref TOKEN: Regex = Regex::new(
+        r#"(?xsi)
+            # removed along with their content
+            <!--.*?-->
+            |
+            <script\b.*?</script\s*>
+            |
+            # a tag
+            <
+                (/?)                    # 1 - closing?
+                ([a-z][a-z0-9:-]*)      # 2 - name
+                # 3 - attributes, which may contain quoted '>'
+                ((?:[^>"']|"[^"]*"|'[^']*')*)
+            >
+            "#
+    )
+    .unwrap();
+    static ref ATTR: Regex = Regex::new(
+        r#"(?xsi)
+            ([^\s"'>/=]+)               # 1 - name
+            (?:
+                \s*=\s*
+                (?:
+                    "([^"]*)"           # 2 - double-quoted value
+                    |
+                    '([^']*)'           # 3 - single-quoted value
+                    |
+                    ([^\s>]+)           # 4 - unquoted value
+                )
+            )?
+            "#
+    )
+    .unwrap();
+    static ref MARKUP: Regex = Regex::new(
+        r#"(?xsi)
+            # ignored
+            <!--.*?-->
+            |
+            <script\b.*?</script\s*>
+            |
+            <style\b.*?</style\s*>
+            |
+            # a tag
+            <
+                (/?)                    # 1 - closing?
+                ([a-z][a-z0-9:-]*)      # 2 - name
+                ((?:[^>"']|"[^"]*"|'[^']*')*) # 3 - attributes
+            >
+            |
+            # the start of a tag or comment that wasn't matched above
+            (<[a-z/!])                  # 4
+            "#
+    )
+    .unwrap();
+}
+
+/// Elements that have no closing tag.
+static VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// Elements whose closing tag may be left out.
+static OPTIONAL_END_ELEMENTS: &[&str] = &[
+    "dd", "dt", "li", "option", "p", "rp", "rt", "tbody", "td", "tfoot", "th", "thead", "tr",
+];
+
+static DEFAULT_ALLOWED_TAGS: &[&str] = &[
+    "a",
+    "b",
+    "big",
+    "blockquote",
+    "br",
+    "center",
+    "code",
+    "del",
+    "div",
+    "em",
+    "font",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "ins",
+    "li",
+    "mark",
+    "ol",
+    "p",
+    "pre",
+    "rp",
+    "rt",
+    "ruby",
+    "s",
+    "small",
+    "span",
+    "strike",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "u",
+    "ul",
+];
+
+static DEFAULT_ALLOWED_ATTRIBUTES: &[&str] = &[
+    "align", "alt", "class", "color", "colspan", "dir", "face", "height", "href", "lang",
+    "rowspan", "size", "src", "start", "style", "title", "width",
+];
+
+/// The elements and attributes that sanitize_html() keeps. Names are
+/// compared case-insensitively.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HtmlAllowlist {
+    tags: HashSet<String>,
+    attributes: HashSet<String>,
+}
+
+impl Default for HtmlAllowlist {
+    fn default() -> Self {
+        HtmlAllowlist::new(DEFAULT_ALLOWED_TAGS, DEFAULT_ALLOWED_ATTRIBUTES)
+    }
+}
+
+impl HtmlAllowlist {
+    pub(crate) fn new<T: AsRef<str>>(tags: &[T], attributes: &[T]) -> Self {
+        let lowercase = |names: &[T]| {
+            names
+                .iter()
+                .map(|name| name.as_ref().to_ascii_lowercase())
+                .collect()
+        };
+        HtmlAllowlist {
+            tags: lowercase(tags),
+            attributes: lowercase(attributes),
+        }
+    }
+
+    fn allows_tag(&self, name: &str) -> bool {
+        self.tags.contains(&name.to_ascii_lowercase())
+    }
+
+    /// Event handlers and script URLs are never allowed.
+    fn allows_attribute(&self, name: &str, value: Option<&str>) -> bool {
+        let name = name.to_ascii_lowercase();
+        !name.starts_with("on")
+            && self.attributes.contains(&name)
+            && !value.map(value_runs_script).unwrap_or_default()
+    }
+}
+
+/// True if an attribute value could run a script, such as a
+/// `javascript:` link.
+fn value_runs_script(value: &str) -> bool {
+    let value: String = decode_entities(value)
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    ["javascript:", "vbscript:", "expression("]
+        .iter()
+        .any(|unsafe_text| value.contains(unsafe_text))
+}
+
+/// Remove scripts, comments, tags not in the allowlist and attributes not
+/// in the allowlist from HTML. The text inside removed tags is kept,
+/// except for scripts. Tags that need no changes are left as they were.
+///
+/// Any `<` that doesn't start a tag that could be parsed, such as an
+/// unterminated tag or one with an unbalanced quote, is escaped, so a
+/// browser can't read a tag into it that was never checked.
+pub(crate) fn sanitize_html<'a>(html: &'a str, allowlist: &HtmlAllowlist) -> Cow<'a, str> {
+    let mut out = String::with_capacity(html.len());
+    let mut last = 0;
+    for caps in TOKEN.captures_iter(html) {
+        let whole = caps.get(0).unwrap();
+        out.push_str(&html[last..whole.start()].replace('<', "&lt;"));
+        out.push_str(&sanitize_tag(&caps, allowlist));
+        last = whole.end();
+    }
+    out.push_str(&html[last..].replace('<', "&lt;"));
+
+    if out == html {
+        Cow::Borrowed(html)
+    } else {
+        Cow::Owned(out)
+    }
+}
+
+/// The replacement for a script, comment or tag matched by TOKEN.
+fn sanitize_tag<'t>(caps: &regex::Captures<'t>, allowlist: &HtmlAllowlist) -> Cow<'t, str> {
+    let name = match caps.get(2) {
+        Some(name) => name.as_str(),
+        // a script or comment
+        None => return "".into(),
+    };
+    if !allowlist.allows_tag(name) {
+        return "".into();
+    }
+    if !caps[1].is_empty() {
+        // closing tags have no attributes to check
+        return format!("</{}>", name).into();
+    }
+
+    let attr_text = caps.get(3).unwrap().as_str();
+    let attrs: Vec<_> = ATTR.captures_iter(attr_text).collect();
+    let allowed: Vec<_> = attrs
+        .iter()
+        .filter(|attr| {
+            let value = attr
+                .get(2)
+                .or_else(|| attr.get(3))
+                .or_else(|| attr.get(4))
+                .map(|value| value.as_str());
+            allowlist.allows_attribute(&attr[1], value)
+        })
+        .map(|attr| attr.get(0).unwrap().as_str())
+        .collect();
+    // anything between the attributes other than spacing and slashes was
+    // not understood, so the tag is rebuilt without it
+    let only_attrs = ATTR
+        .split(attr_text)
+        .all(|between| between.chars().all(|c| c.is_whitespace() || c == '/'));
+
+    if allowed.len() == attrs.len() && only_attrs {
+        caps.get(0).unwrap().as_str().into()
+    } else if allowed.is_empty() {
+        format!("<{}>", name).into()
+    } else {
+        format!("<{} {}>", name, allowed.join(" ")).into()
+    }
+}
+
+/// A short description of the first problem in some HTML that a browser
+/// would have to guess how to fix, or None if it is well-formed. Closing
+/// tags that HTML allows to be left out are not required.
+pub(crate) fn html_problem(html: &str) -> Option<String> {
+    let mut open: Vec<String> = vec![];
+    for caps in MARKUP.captures_iter(html) {
+        if caps.get(4).is_some() {
+            return Some("unterminated tag".to_string());
+        }
+        let name = match caps.get(2) {
+            Some(name) => name.as_str().to_ascii_lowercase(),
+            None => continue,
+        };
+        if VOID_ELEMENTS.contains(&name.as_str()) {
+            continue;
+        }
+        if caps[1].is_empty() {
+            if !caps[3].trim_end().ends_with('/') {
+                open.push(name);
+            }
+            continue;
+        }
+        let idx = match open.iter().rposition(|open_name| open_name == &name) {
+            Some(idx) => idx,
+            None => return Some(format!("unexpected </{}>", name)),
+        };
+        if let Some(inner) = open[idx + 1..]
+            .iter()
+            .find(|inner| !OPTIONAL_END_ELEMENTS.contains(&inner.as_str()))
+        {
+            return Some(format!("<{}> not closed before </{}>", inner, name));
+        }
+        open.truncate(idx);
+    }
+    open.iter()
+        .find(|name| !OPTIONAL_END_ELEMENTS.contains(&name.as_str()))
+        .map(|name| format!("unclosed <{}>", name))
+}
+
+/// A field found by find_broken_html().
+#[derive(Debug, PartialEq)]
+pub(crate) struct BrokenHtml {
+    pub nid: NoteID,
+    pub field: String,
+    pub reason: String,
+}
+
+impl RequestContext<'_> {
+    /// Sanitize the fields of the notes matching `search` with
+    /// sanitize_html(), saving notes that change. Returns the number of
+    /// notes changed.
+    pub(crate) fn sanitize_fields(
+        &mut self,
+        search: &str,
+        allowlist: &HtmlAllowlist,
+    ) -> Result<u32> {
+        let mut nids = search_notes(self, search, false)?;
+        nids.sort();
+        nids.dedup();
+        let note_types = self.storage.all_note_types()?;
+
+        let mut changed = 0;
+        for nid in nids {
+            let mut note = match get_note(self.storage.db, nid)? {
+                Some(note) => note,
+                None => continue,
+            };
+            let mut note_changed = false;
+            for idx in 0..note.fields().len() {
+                if let Cow::Owned(text) = sanitize_html(&note.fields()[idx], allowlist) {
+                    if text != note.fields()[idx] {
+                        note.set_field(idx, text)?;
+                        note_changed = true;
+                    }
+                }
+            }
+            if note_changed {
+                let nt = note_types
+                    .get(&note.ntid)
+                    .ok_or_else(|| AnkiError::DBError {
+                        info: "missing note type".to_string(),
+                        kind: DBErrorKind::MissingEntity,
+                    })?;
+                set_note(self.storage.db, &mut note, nt)?;
+                changed += 1;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// The fields of notes matching `search` whose HTML is malformed,
+    /// ordered by note id and field. Nothing is changed.
+    pub(crate) fn find_broken_html(&mut self, search: &str) -> Result<Vec<BrokenHtml>> {
+        let mut nids = search_notes(self, search, false)?;
+        nids.sort();
+        nids.dedup();
+        let note_types = self.storage.all_note_types()?;
+
+        let mut broken = vec![];
+        for nid in nids {
+            let note = match get_note(self.storage.db, nid)? {
+                Some(note) => note,
+                None => continue,
+            };
+            let nt = note_types.get(&note.ntid);
+            for (idx, text) in note.fields().iter().enumerate() {
+                if let Some(reason) = html_problem(text) {
+                    let field = nt
+                        .and_then(|nt| nt.fields.get(idx))
+                        .map(|field| field.name.clone())
+                        .unwrap_or_else(|| (idx + 1).to_string());
+                    broken.push(BrokenHtml { nid, field, reason });
+                }
+            }
+        }
+
+        Ok(broken)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{html_problem, sanitize_html, BrokenHtml, HtmlAllowlist};
+    use crate::collection::test::open_test_collection;
+    use crate::err::Result;
+    use crate::notes::{get_note, NoteID};
+
+    #[test]
+    fn sanitizing() {
+        let allowlist = HtmlAllowlist::default();
+        let s = |html| sanitize_html(html, &allowlist).into_owned();
+
+        // safe markup is left alone
+        let safe = r#"<b>bold</b><br><img src=cat.jpg alt='a cat'><a href="x.html">x</a>
+            <div style="color: red" class=x>y</div><span title="a > b">z</span>"#;
+        assert_eq!(s(safe), safe);
+
+        assert_eq!(s("a<script>alert(1)</script>b"), "ab");
+        assert_eq!(s("a<SCRIPT type=x>alert(1)</SCRIPT >b"), "ab");
+        assert_eq!(s("a<!-- hidden -->b"), "ab");
+        assert_eq!(
+            s(r#"<div onclick="alert(1)" class="x">x</div>"#),
+            r#"<div class="x">x</div>"#
+        );
+        assert_eq!(s("<img src=x.jpg onerror=alert(1)>"), "<img src=x.jpg>");
+        assert_eq!(s(r#"<a href=" JavaScript:alert(1)">x</a>"#), "<a>x</a>");
+        assert_eq!(s(r#"<a href="jav&#x61;script:alert(1)">x</a>"#), "<a>x</a>");
+        assert_eq!(s("<iframe src=x></iframe>text<o:p></o:p>"), "text");
+        assert_eq!(s("<div data-x=1 hidden>x</div>"), "<div>x</div>");
+
+        // tags that can't be parsed are escaped instead of passed through
+        assert_eq!(
+            s(r#"<img src=x" onerror=alert(1)>"#),
+            r#"&lt;img src=x" onerror=alert(1)>"#
+        );
+        assert_eq!(
+            s("<img src=x onerror=alert(1) title='>"),
+            "&lt;img src=x onerror=alert(1) title='>"
+        );
+        assert_eq!(s("<svg/onload=alert(1)"), "&lt;svg/onload=alert(1)");
+        assert_eq!(s("<b>a</b><b"), "<b>a</b>&lt;b");
+        assert_eq!(s("a < b"), "a &lt; b");
+        // as is text in a tag that wasn't understood
+        assert_eq!(s(r#"<img src=x "onerror=y">"#), "<img src=x>");
+
+        // a custom allowlist
+        let allowlist = HtmlAllowlist::new(&["B"], &["title"]);
+        assert_eq!(
+            sanitize_html("<b title=x>a</b><i>b</i>", &allowlist),
+            "<b title=x>a</b>b"
+        );
+    }
+
+    #[test]
+    fn broken_html() {
+        for ok in &[
+            "plain text, a < b",
+            "<b>a</b><br><IMG src=x.jpg><hr/><span/>",
+            "<ul><li>a<li>b</ul><p>one<p>two",
+            "<table><tr><td>a<td>b</table>",
+            "<div title='a>b' class=x>y</DIV>",
+            "<!-- <b> --><script>if (a<b) {}</script><style>b {}</style>",
+        ] {
+            assert_eq!(html_problem(ok), None, "{}", ok);
+        }
+
+        assert_eq!(html_problem("<b>a"), Some("unclosed <b>".into()));
+        assert_eq!(
+            html_problem("<b><i>a</b></i>"),
+            Some("<i> not closed before </b>".into())
+        );
+        assert_eq!(html_problem("a</div>"), Some("unexpected </div>".into()));
+        assert_eq!(html_problem("<b>a</b"), Some("unterminated tag".into()));
+        assert_eq!(
+            html_problem("<span title=\"a>b</span>"),
+            Some("unterminated tag".into())
+        );
+        assert_eq!(html_problem("a<!-- b"), Some("unterminated tag".into()));
+    }
+
+    #[test]
+    fn find_broken_html() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                "update notes set flds = '<b>ok</b>' || char(31) || 'fine';
+                 update notes set flds = '<b>a' || char(31) || '<i>b</div>'
+                 where id = 1581236445532;",
+            )?;
+
+            assert_eq!(
+                ctx.find_broken_html("")?,
+                vec![
+                    BrokenHtml {
+                        nid: NoteID(1581236445532),
+                        field: "Front".into(),
+                        reason: "unclosed <b>".into(),
+                    },
+                    BrokenHtml {
+                        nid: NoteID(1581236445532),
+                        field: "Back".into(),
+                        reason: "unexpected </div>".into(),
+                    },
+                ]
+            );
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn sanitize_fields() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                "update notes set flds = '<b>ok</b>' || char(31) || 'fine', usn = 0;
+                 update notes set flds = '<i onclick=x()>a</i>' || char(31)
+                   || 'b<script>x()</script>'
+                 where id = 1581236445532;",
+            )?;
+
+            let allowlist = HtmlAllowlist::default();
+            assert_eq!(ctx.sanitize_fields("", &allowlist)?, 1);
+            let note = get_note(ctx.storage.db, NoteID(1581236445532))?.unwrap();
+            assert_eq!(note.fields(), &vec!["<i>a</i>".to_string(), "b".into()]);
+            let usn: i32 = ctx.storage.db.query_row(
+                "select usn from notes where id = 1581236445532",
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )?;
+            assert_eq!(usn, -1);
+
+            assert_eq!(ctx.sanitize_fields("", &allowlist)?, 0);
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use super::timespan::learning_congrats;
+use crate::collection::RequestContext;
+use crate::config::{COLLAPSE_TIME_KEY, DEFAULT_COLLAPSE_TIME};
+use crate::decks::{child_ids, DeckID};
+use crate::err::{AnkiError, Result};
+use crate::i18n::FString;
+use crate::timestamp::TimestampSecs;
+
+/// What is left in a deck and its subdecks once nothing more can be
+/// studied today.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct CongratsInfo {
+    /// Learning cards due later today.
+    pub(crate) learn_count: u32,
+    /// When the first of them is due.
+    pub(crate) next_learn_due: Option<TimestampSecs>,
+    /// Reviews that are due but over the daily limit.
+    pub(crate) review_remaining: bool,
+    /// New cards that are over the daily limit.
+    pub(crate) new_remaining: bool,
+    pub(crate) have_buried: bool,
+    /// Other decks have reviews or learning cards due today.
+    pub(crate) other_decks_due: bool,
+}
+
+impl RequestContext<'_> {
+    /// The message shown when the provided deck has been finished for now,
+    /// as HTML.
+    pub(crate) fn deck_congrats(&mut self, did: DeckID) -> Result<String> {
+        let info = self.congrats_info(did)?;
+        let collapse_time = self
+            .storage
+            .get_config_value(COLLAPSE_TIME_KEY)?
+            .unwrap_or(DEFAULT_COLLAPSE_TIME);
+
+        let mut lines = vec![];
+        if let Some(due) = info.next_learn_due {
+            // learning cards can be shown up to collapse_time early
+            let secs = (due.0 - collapse_time - TimestampSecs::now().0).max(0);
+            let msg = learning_congrats(info.learn_count as usize, secs as f32, self.i18n);
+            if !msg.is_empty() {
+                lines.push(msg);
+            }
+        }
+        if info.review_remaining {
+            lines.push(
+                self.i18n
+                    .tr(FString::SchedulingTodayReviewLimitReached)
+                    .into(),
+            );
+        }
+        if info.new_remaining {
+            lines.push(self.i18n.tr(FString::SchedulingTodayNewLimitReached).into());
+        }
+        if info.have_buried {
+            lines.push(self.i18n.tr(FString::SchedulingBuriedCardsFound).into());
+        }
+        if info.other_decks_due {
+            lines.push(self.i18n.tr(FString::SchedulingOtherDecksDue).into());
+        }
+
+        let mut msg = format!(
+            "<b>{}</b>",
+            self.i18n.tr(FString::SchedulingCongratulationsFinished)
+        );
+        if !lines.is_empty() {
+            msg.push_str("<br><br>");
+            msg.push_str(&lines.join("<p>"));
+        }
+        Ok(msg)
+    }
+
+    pub(crate) fn congrats_info(&mut self, did: DeckID) -> Result<CongratsInfo> {
+        let all_decks: Vec<_> = self
+            .storage
+            .all_decks()?
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect();
+        let deck = all_decks
+            .iter()
+            .find(|d| d.id == did)
+            .ok_or_else(|| AnkiError::invalid_input(format!("deck {} not found", did)))?;
+        let mut dids = vec![did];
+        dids.extend(child_ids(&all_decks, &deck.name));
+
+        let timing = self.storage.timing_today()?;
+        let (learn_count, next_learn_due, review_remaining, new_remaining, have_buried, other) =
+            self.storage
+                .congrats_counts(&dids, timing.days_elapsed, timing.next_day_at)?;
+        Ok(CongratsInfo {
+            learn_count,
+            next_learn_due: next_learn_due.map(TimestampSecs),
+            review_remaining,
+            new_remaining,
+            have_buried,
+            other_decks_due: other,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CongratsInfo;
+    use crate::collection::test::open_test_collection;
+    use crate::decks::DeckID;
+    use crate::err::Result;
+    use crate::timestamp::TimestampSecs;
+
+    #[test]
+    fn congrats() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            // a learning card in Default, a review over the limit in its
+            // child, and a buried card in another deck
+            let timing = ctx.storage.timing_today()?;
+            ctx.storage.db.execute_batch(
+                r#"update col set decks = '{
+                    "1": {"id": 1, "name": "Default", "conf": 1},
+                    "2": {"id": 2, "name": "Default::Child", "conf": 1},
+                    "3": {"id": 3, "name": "Other", "conf": 1}
+                }';
+                update cards set did = 3, queue = -2 where id = 1581236488474;"#,
+            )?;
+            ctx.storage.db.execute(
+                "update cards set queue = 1, due = ? where id = 1581236445527",
+                &[timing.next_day_at - 1],
+            )?;
+            ctx.storage.db.execute(
+                "update cards set did = 2, queue = 2, due = ? where id = 1581236461565",
+                &[timing.days_elapsed],
+            )?;
+
+            assert_eq!(
+                ctx.congrats_info(DeckID(1))?,
+                CongratsInfo {
+                    learn_count: 1,
+                    next_learn_due: Some(TimestampSecs(timing.next_day_at - 1)),
+                    review_remaining: true,
+                    ..Default::default()
+                }
+            );
+            assert_eq!(
+                ctx.congrats_info(DeckID(3))?,
+                CongratsInfo {
+                    have_buried: true,
+                    other_decks_due: true,
+                    ..Default::default()
+                }
+            );
+
+            let msg = ctx.deck_congrats(DeckID(3))?;
+            assert!(msg.starts_with("<b>Congratulations!"));
+            assert!(msg.contains("buried"));
+            assert!(msg.contains("Other decks"));
+
+            // nothing is left anywhere once the other cards are suspended
+            ctx.storage
+                .db
+                .execute_batch("update cards set queue = -1")?;
+            assert_eq!(ctx.congrats_info(DeckID(1))?, CongratsInfo::default());
+            assert!(!ctx.deck_congrats(DeckID(1))?.contains("<br>"));
+
+            assert!(ctx.deck_congrats(DeckID(5)).is_err());
+
+            Ok(())
+        })
+    }
+}
@@ -1,7 +1,11 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use chrono::{Date, Duration, FixedOffset, Local, TimeZone};
+use crate::collection::RequestContext;
+use crate::err::Result;
+use chrono::{Date, Datelike, Duration, FixedOffset, Local, TimeZone, Weekday};
+use std::cell::RefCell;
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct SchedTimingToday {
@@ -91,6 +95,113 @@ pub fn local_minutes_west_for_stamp(stamp: i64) -> i32 {
     Local.timestamp(stamp, 0).offset().utc_minus_local() / 60
 }
 
+/// Remembers the UTC offset for each UTC day, as looking up the local
+/// timezone for thousands of stamps is slow. A day in which the offset
+/// changes, such as when daylight savings starts or ends, is not cached,
+/// and is looked up each time. This assumes the offset doesn't change and
+/// then change back within the same day.
+pub(crate) struct OffsetCache<F: Fn(i64) -> i32> {
+    mins_west_for_stamp: F,
+    /// None for days the offset changes in.
+    days: RefCell<HashMap<i64, Option<i32>>>,
+}
+
+/// An OffsetCache for the local timezone. Create one per operation, so a
+/// change to the system timezone is picked up by the next one.
+pub(crate) type LocalOffsetCache = OffsetCache<fn(i64) -> i32>;
+
+impl LocalOffsetCache {
+    pub(crate) fn local() -> Self {
+        OffsetCache::new(local_minutes_west_for_stamp)
+    }
+}
+
+impl<F: Fn(i64) -> i32> OffsetCache<F> {
+    pub(crate) fn new(mins_west_for_stamp: F) -> Self {
+        OffsetCache {
+            mins_west_for_stamp,
+            days: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The same as mins_west_for_stamp(stamp).
+    pub(crate) fn minutes_west(&self, stamp: i64) -> i32 {
+        let day = stamp.div_euclid(86_400);
+        let cached = *self.days.borrow_mut().entry(day).or_insert_with(|| {
+            let start = (self.mins_west_for_stamp)(day * 86_400);
+            let end = (self.mins_west_for_stamp)(day * 86_400 + 86_399);
+            if start == end {
+                Some(start)
+            } else {
+                None
+            }
+        });
+        cached.unwrap_or_else(|| (self.mins_west_for_stamp)(stamp))
+    }
+}
+
+/// The timestamp of the day rollover `days` days after next_day_at, or
+/// before it if negative.
+///
+/// If the UTC offset changes in between (eg due to daylight savings), the
+/// rollover stays at the same local time, so some days will be 23 or 25
+/// hours long. mins_west_for_stamp should return the offset in effect at
+/// the provided time, such as local_minutes_west_for_stamp().
+pub(crate) fn next_day_at_after_days<F>(next_day_at: i64, days: i32, mins_west_for_stamp: F) -> i64
+where
+    F: Fn(i64) -> i32,
+{
+    let start_west = mins_west_for_stamp(next_day_at);
+    let naive = next_day_at + i64::from(days) * 86_400;
+    let adjusted = naive + i64::from(mins_west_for_stamp(naive) - start_west) * 60;
+    // the offset may differ again if the change happens close to the rollover
+    naive + i64::from(mins_west_for_stamp(adjusted) - start_west) * 60
+}
+
+#[derive(Debug, PartialEq)]
+pub struct WeekBoundaries {
+    /// The rollover at the start of each week, oldest first. The last
+    /// entry is the start of the current week.
+    pub week_starts: Vec<i64>,
+    /// The rollover at the end of the current week.
+    pub next_week_at: i64,
+}
+
+/// The start of the current week and the `weeks - 1` weeks before it,
+/// where weeks start on first_weekday. Boundaries are aligned to the day
+/// rollover, so the current day is the one ending at next_day_at.
+pub(crate) fn week_boundaries<F>(
+    next_day_at: i64,
+    first_weekday: Weekday,
+    weeks: u32,
+    mins_west_for_stamp: F,
+) -> WeekBoundaries
+where
+    F: Fn(i64) -> i32,
+{
+    let rollover_date = fixed_offset_from_minutes(mins_west_for_stamp(next_day_at))
+        .timestamp(next_day_at, 0)
+        .date();
+    let today = rollover_date.weekday().pred();
+    let days_into_week =
+        (7 + today.num_days_from_sunday() as i32 - first_weekday.num_days_from_sunday() as i32) % 7;
+
+    // days are relative to next_day_at, so today started at -1
+    let week_start = -1 - days_into_week;
+    let week_starts = (0..weeks as i32)
+        .rev()
+        .map(|week| {
+            next_day_at_after_days(next_day_at, week_start - week * 7, &mins_west_for_stamp)
+        })
+        .collect();
+    let next_week_at = next_day_at_after_days(next_day_at, week_start + 7, &mins_west_for_stamp);
+
+    WeekBoundaries {
+        week_starts,
+        next_week_at,
+    }
+}
+
 // Legacy code
 // ----------------------------------
 
@@ -161,16 +272,31 @@ pub(crate) fn sched_timing_today(
     }
 }
 
+impl RequestContext<'_> {
+    /// The number of days since the collection was created, taking the
+    /// rollover hour into account. Review cards' due numbers are relative
+    /// to this.
+    pub(crate) fn day_number(&mut self) -> Result<u32> {
+        Ok(self.storage.timing_today()?.days_elapsed)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::SchedTimingToday;
+    use crate::collection::test::open_test_collection;
+    use crate::err::Result;
     use crate::sched::cutoff::sched_timing_today_v1;
     use crate::sched::cutoff::sched_timing_today_v2_legacy;
     use crate::sched::cutoff::{
-        fixed_offset_from_minutes, local_minutes_west_for_stamp, normalized_rollover_hour,
-        sched_timing_today_v2_new,
+        fixed_offset_from_minutes, local_minutes_west_for_stamp, next_day_at_after_days,
+        normalized_rollover_hour, sched_timing_today_v2_new, week_boundaries, OffsetCache,
+        WeekBoundaries,
     };
-    use chrono::{FixedOffset, Local, TimeZone, Utc};
+    use crate::timestamp::TimestampSecs;
+    use chrono::{FixedOffset, Local, TimeZone, Utc, Weekday};
+    use rusqlite::params;
+    use serde_json::Value;
 
     #[test]
     fn rollover() {
@@ -365,4 +491,216 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn next_day_at_across_dst() {
+        // New York springs forward at 2am local on 2020-03-08 (07:00 UTC),
+        // and falls back at 2am local on 2020-11-01 (06:00 UTC)
+        let spring = Utc.ymd(2020, 3, 8).and_hms(7, 0, 0).timestamp();
+        let fall = Utc.ymd(2020, 11, 1).and_hms(6, 0, 0).timestamp();
+        let ny_west = |stamp: i64| {
+            if stamp >= spring && stamp < fall {
+                240
+            } else {
+                300
+            }
+        };
+
+        // 4am EST on the 7th
+        let next_day_at = Utc.ymd(2020, 3, 7).and_hms(9, 0, 0).timestamp();
+        assert_eq!(next_day_at_after_days(next_day_at, 0, ny_west), next_day_at);
+        // the 8th is only 23 hours long
+        let on_8th = next_day_at_after_days(next_day_at, 1, ny_west);
+        assert_eq!(on_8th, Utc.ymd(2020, 3, 8).and_hms(8, 0, 0).timestamp());
+        assert_eq!(on_8th - next_day_at, 23 * 3600);
+        let on_10th = next_day_at_after_days(next_day_at, 3, ny_west);
+        assert_eq!(on_10th, Utc.ymd(2020, 3, 10).and_hms(8, 0, 0).timestamp());
+
+        // a rollover at 3am on the day the clocks change
+        let next_day_at = Utc.ymd(2020, 3, 7).and_hms(8, 0, 0).timestamp();
+        assert_eq!(
+            next_day_at_after_days(next_day_at, 1, ny_west),
+            Utc.ymd(2020, 3, 8).and_hms(7, 0, 0).timestamp()
+        );
+
+        // and a 25 hour day when they go back
+        let next_day_at = Utc.ymd(2020, 10, 31).and_hms(8, 0, 0).timestamp();
+        assert_eq!(
+            next_day_at_after_days(next_day_at, 1, ny_west),
+            Utc.ymd(2020, 11, 1).and_hms(9, 0, 0).timestamp()
+        );
+    }
+
+    #[test]
+    fn weeks() {
+        let utc = |_stamp: i64| 0;
+        // 2020-01-01 is a Wednesday; the day ends at 4am on Thursday
+        let next_day_at = Utc.ymd(2020, 1, 2).and_hms(4, 0, 0).timestamp();
+        let at = |d: u32| Utc.ymd(2020, 1, d).and_hms(4, 0, 0).timestamp();
+        let dec = |d: u32| Utc.ymd(2019, 12, d).and_hms(4, 0, 0).timestamp();
+
+        assert_eq!(
+            week_boundaries(next_day_at, Weekday::Sun, 2, utc),
+            WeekBoundaries {
+                week_starts: vec![dec(22), dec(29)],
+                next_week_at: at(5),
+            }
+        );
+        assert_eq!(
+            week_boundaries(next_day_at, Weekday::Mon, 1, utc),
+            WeekBoundaries {
+                week_starts: vec![dec(30)],
+                next_week_at: at(6),
+            }
+        );
+        // the current day is the first day of the week
+        assert_eq!(
+            week_boundaries(next_day_at, Weekday::Wed, 1, utc),
+            WeekBoundaries {
+                week_starts: vec![at(1)],
+                next_week_at: at(8),
+            }
+        );
+        // or the last
+        assert_eq!(
+            week_boundaries(next_day_at, Weekday::Thu, 1, utc).next_week_at,
+            at(2)
+        );
+    }
+
+    #[test]
+    fn offset_cache() {
+        // as in next_day_at_across_dst()
+        let spring = Utc.ymd(2020, 3, 8).and_hms(7, 0, 0).timestamp();
+        let fall = Utc.ymd(2020, 11, 1).and_hms(6, 0, 0).timestamp();
+        let ny_west = |stamp: i64| {
+            if stamp >= spring && stamp < fall {
+                240
+            } else {
+                300
+            }
+        };
+
+        // every half hour for a year, including both changes
+        let cache = OffsetCache::new(ny_west);
+        let start = Utc.ymd(2020, 1, 1).and_hms(0, 0, 0).timestamp();
+        for stamp in (start..start + 366 * 86_400).step_by(1800) {
+            assert_eq!(cache.minutes_west(stamp), ny_west(stamp), "{}", stamp);
+        }
+        // right at the changes
+        for &stamp in &[spring - 1, spring, fall - 1, fall] {
+            assert_eq!(cache.minutes_west(stamp), ny_west(stamp));
+        }
+        assert_eq!(
+            cache.days.borrow().values().filter(|o| o.is_none()).count(),
+            2
+        );
+
+        // rollovers after the changes are the same as without the cache
+        let next_day_at = Utc.ymd(2020, 3, 7).and_hms(9, 0, 0).timestamp();
+        for days in -10..300 {
+            assert_eq!(
+                next_day_at_after_days(next_day_at, days, |stamp| cache.minutes_west(stamp)),
+                next_day_at_after_days(next_day_at, days, ny_west)
+            );
+        }
+    }
+
+    #[test]
+    fn offset_cache_lookups() {
+        use std::cell::Cell;
+
+        let spring = Utc.ymd(2020, 3, 8).and_hms(7, 0, 0).timestamp();
+        let fall = Utc.ymd(2020, 11, 1).and_hms(6, 0, 0).timestamp();
+        let lookups = Cell::new(0);
+        let ny_west = |stamp: i64| {
+            lookups.set(lookups.get() + 1);
+            if stamp >= spring && stamp < fall {
+                240
+            } else {
+                300
+            }
+        };
+
+        // due dates for rescheduling 10,000 cards over the next year, as in
+        // the reschedule bench
+        let next_day_at = Utc.ymd(2020, 1, 1).and_hms(9, 0, 0).timestamp();
+        let reschedule = |mins_west_for_stamp: &dyn Fn(i64) -> i32| -> Vec<i64> {
+            (0..10_000)
+                .map(|card| next_day_at_after_days(next_day_at, card % 365, mins_west_for_stamp))
+                .collect()
+        };
+
+        let uncached = reschedule(&ny_west);
+        assert_eq!(lookups.replace(0), 30_000);
+
+        // each day's offset is looked up once, at its start and end, and the
+        // two days the offset changes in are looked up per stamp
+        let cache = OffsetCache::new(&ny_west);
+        assert_eq!(reschedule(&|stamp| cache.minutes_west(stamp)), uncached);
+        assert!(lookups.get() <= 2 * 366 + 2 * 3 * (10_000 / 365 + 1));
+    }
+
+    #[test]
+    fn day_number() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            // without a rollover hour, whole days since creation are counted
+            let now = TimestampSecs::now().0;
+            ctx.storage
+                .db
+                .execute("update col set crt = ?", params![now - 3 * 86_400 - 3_600])?;
+            ctx.storage.set_config_value("rollover", &Value::Null)?;
+            assert_eq!(ctx.day_number()?, 3);
+
+            // 2020-01-01 00:00 UTC
+            let crt = 1_577_836_800;
+            ctx.storage
+                .db
+                .execute("update col set crt = ?", params![crt])?;
+            ctx.storage.set_config_value("rollover", &4)?;
+            ctx.storage.set_config_value("creationOffset", &0)?;
+            ctx.storage.set_config_value("localOffset", &-600)?;
+
+            // a server uses the client's stored offset, UTC+10 here, instead
+            // of its own timezone; 20:00 UTC on the 11th is 06:00 on the 12th
+            let server = col.storage.context(true);
+            let evening = crt + 10 * 86_400 + 20 * 3_600;
+            assert_eq!(server.timing_at(evening)?.days_elapsed, 11);
+            // and 16:00 UTC is 02:00, before the day rolls over
+            let afternoon = crt + 10 * 86_400 + 16 * 3_600;
+            assert_eq!(server.timing_at(afternoon)?.days_elapsed, 10);
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(all(feature = "unstable", test))]
+mod bench {
+    extern crate test;
+    use super::{local_minutes_west_for_stamp, next_day_at_after_days, LocalOffsetCache};
+    use test::Bencher;
+
+    /// Due dates for rescheduling 10,000 cards over the next year.
+    fn reschedule<F: Fn(i64) -> i32>(mins_west_for_stamp: F) -> i64 {
+        let next_day_at = 1_600_000_000;
+        (0..10_000)
+            .map(|card| next_day_at_after_days(next_day_at, card % 365, &mins_west_for_stamp))
+            .sum()
+    }
+
+    #[bench]
+    fn bench_reschedule_uncached(b: &mut Bencher) {
+        b.iter(|| reschedule(local_minutes_west_for_stamp));
+    }
+
+    #[bench]
+    fn bench_reschedule_cached(b: &mut Bencher) {
+        b.iter(|| {
+            let cache = LocalOffsetCache::local();
+            reschedule(|stamp| cache.minutes_west(stamp))
+        });
+    }
 }
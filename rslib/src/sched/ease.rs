@@ -0,0 +1,168 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::card::CardType;
+use crate::collection::RequestContext;
+use crate::err::{AnkiError, Result};
+use crate::search::{search_cards, SortMode};
+use crate::timestamp::TimestampSecs;
+
+/// The lowest ease a card can have, in permille.
+pub(crate) const MINIMUM_EASE_FACTOR: u16 = 1300;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum EaseAdjustment {
+    /// Multiply the ease, eg 1.1 to raise it by 10%.
+    Multiply(f32),
+    /// Add to the ease, in permille, eg 200 for 20 percentage points.
+    Add(i32),
+}
+
+impl EaseAdjustment {
+    fn apply(self, factor: u16) -> u16 {
+        let adjusted = match self {
+            EaseAdjustment::Multiply(multiplier) => (factor as f32 * multiplier).round() as i64,
+            EaseAdjustment::Add(delta) => factor as i64 + delta as i64,
+        };
+        adjusted
+            .max(MINIMUM_EASE_FACTOR as i64)
+            .min(u16::max_value() as i64) as u16
+    }
+}
+
+/// The result of adjusting ease in bulk.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct EaseAdjusted {
+    /// The number of cards whose ease changed.
+    pub(crate) count: usize,
+    /// The lowest and highest ease of the changed cards.
+    pub(crate) min_factor: u16,
+    pub(crate) max_factor: u16,
+}
+
+impl RequestContext<'_> {
+    /// Adjust the ease of the review and relearning cards matching
+    /// `search`. Ease can't drop below the minimum the scheduler allows.
+    /// New and learning cards have no ease yet, so are left alone.
+    pub(crate) fn adjust_ease(
+        &mut self,
+        search: &str,
+        adjustment: EaseAdjustment,
+    ) -> Result<EaseAdjusted> {
+        if let EaseAdjustment::Multiply(multiplier) = adjustment {
+            if !multiplier.is_finite() || multiplier <= 0.0 {
+                return Err(AnkiError::invalid_input(format!(
+                    "invalid ease multiplier: {}",
+                    multiplier
+                )));
+            }
+        }
+
+        let cids = search_cards(self, search, SortMode::NoOrder, false)?;
+        let usn = self.storage.usn()?;
+        let mtime = TimestampSecs::now();
+        let mut result = EaseAdjusted::default();
+        for cid in cids {
+            let mut card = match self.storage.get_card(cid)? {
+                Some(card) => card,
+                None => continue,
+            };
+            if card.ctype != CardType::Review && card.ctype != CardType::Relearn {
+                continue;
+            }
+            let factor = adjustment.apply(card.factor);
+            if factor == card.factor {
+                continue;
+            }
+
+            card.factor = factor;
+            card.mtime = mtime;
+            card.usn = usn;
+            self.storage.update_card(&card)?;
+
+            if result.count == 0 {
+                result.min_factor = factor;
+                result.max_factor = factor;
+            } else {
+                result.min_factor = result.min_factor.min(factor);
+                result.max_factor = result.max_factor.max(factor);
+            }
+            result.count += 1;
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EaseAdjusted, EaseAdjustment};
+    use crate::card::CardID;
+    use crate::collection::test::open_test_collection;
+    use crate::collection::RequestContext;
+    use crate::err::{AnkiError, Result};
+
+    #[test]
+    fn adjust_ease() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        fn factors(ctx: &mut RequestContext) -> Result<Vec<u16>> {
+            let mut factors = vec![];
+            for cid in &[1581236445527, 1581236461565, 1581236488474] {
+                factors.push(ctx.storage.get_card(CardID(*cid))?.unwrap().factor);
+            }
+            Ok(factors)
+        }
+
+        col.transact(None, |ctx| {
+            // two review cards and a new one
+            ctx.storage.db.execute_batch(
+                "update cards set type = 2, queue = 2;
+                 update cards set factor = 2500 where id = 1581236445527;
+                 update cards set factor = 1400 where id = 1581236461565;
+                 update cards set type = 0, queue = 0, factor = 0 where id = 1581236488474;",
+            )?;
+
+            assert_eq!(
+                ctx.adjust_ease("", EaseAdjustment::Multiply(1.1))?,
+                EaseAdjusted {
+                    count: 2,
+                    min_factor: 1540,
+                    max_factor: 2750,
+                }
+            );
+            assert_eq!(factors(ctx)?, vec![2750, 1540, 0]);
+
+            // ease is clamped to the minimum, and cards already there are
+            // unchanged
+            assert_eq!(
+                ctx.adjust_ease("", EaseAdjustment::Add(-300))?,
+                EaseAdjusted {
+                    count: 2,
+                    min_factor: 1300,
+                    max_factor: 2450,
+                }
+            );
+            assert_eq!(
+                ctx.adjust_ease("", EaseAdjustment::Multiply(0.5))?,
+                EaseAdjusted {
+                    count: 1,
+                    min_factor: 1300,
+                    max_factor: 1300,
+                }
+            );
+            assert_eq!(factors(ctx)?, vec![1300, 1300, 0]);
+
+            // only matching cards are changed
+            ctx.adjust_ease("cid:1581236445527", EaseAdjustment::Add(1200))?;
+            assert_eq!(factors(ctx)?, vec![2500, 1300, 0]);
+
+            assert!(matches!(
+                ctx.adjust_ease("", EaseAdjustment::Multiply(0.0)),
+                Err(AnkiError::InvalidInput { .. })
+            ));
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use super::ease::MINIMUM_EASE_FACTOR;
+use crate::card::{CardID, CardQueue, CardType};
+use crate::collection::RequestContext;
+use crate::deckconf::{LapseConf, DEFAULT_DECK_CONF_ID};
+use crate::decks::DeckID;
+use crate::err::{AnkiError, Result};
+use crate::notes::NoteID;
+use crate::tags::split_tags;
+use crate::timestamp::TimestampSecs;
+use rusqlite::{params, NO_PARAMS};
+use std::collections::HashMap;
+use unicase::UniCase;
+
+/// How much each lapse reduces the ease by, in permille.
+const LAPSE_EASE_PENALTY: u16 = 200;
+/// The leech action that suspends the card, instead of only tagging it.
+const LEECH_SUSPEND: u64 = 0;
+const LEECH_TAG: &str = "leech";
+
+/// What happens to a review card if it is answered with Again, under its
+/// current config.
+#[derive(Debug, PartialEq)]
+pub(crate) struct LapsePreview {
+    /// The interval in days the card gets once it finishes relearning, or
+    /// immediately if there are no relearning steps.
+    pub(crate) interval: u32,
+    pub(crate) ease_factor: u16,
+    pub(crate) lapses: u32,
+    /// The delay in seconds of the first relearning step, if the card
+    /// enters relearning.
+    pub(crate) relearning_delay: Option<u32>,
+    pub(crate) leech: bool,
+    /// Set when the card is a leech and leeches are suspended.
+    pub(crate) suspended: bool,
+}
+
+impl LapseConf {
+    /// True if a card with this many lapses is a leech: when it reaches
+    /// the threshold, and every half threshold after that.
+    pub(crate) fn is_leech(&self, lapses: u32) -> bool {
+        let threshold = self.leech_fails;
+        threshold > 0 && lapses >= threshold && (lapses - threshold) % (threshold / 2).max(1) == 0
+    }
+
+    fn suspends_leeches(&self) -> bool {
+        self.other
+            .get("leechAction")
+            .and_then(|action| action.as_u64())
+            .unwrap_or(LEECH_SUSPEND)
+            == LEECH_SUSPEND
+    }
+}
+
+impl RequestContext<'_> {
+    /// What answering a review card with Again would do to it, as the v2
+    /// scheduler does it, without changing the card. Cards in a filtered
+    /// deck use the config of their original deck.
+    pub(crate) fn preview_lapse(&mut self, cid: CardID) -> Result<LapsePreview> {
+        let card = self
+            .storage
+            .get_card(cid)?
+            .ok_or_else(|| AnkiError::invalid_input(format!("card {} not found", cid)))?;
+        if card.ctype != CardType::Review {
+            return Err(AnkiError::invalid_input("only review cards can lapse"));
+        }
+        let did = if card.odid.0 != 0 {
+            card.odid
+        } else {
+            card.did
+        };
+        let conf = self.deck_conf_for_deck(did)?.lapse;
+
+        let lapses = card.lapses + 1;
+        let leech = conf.is_leech(lapses);
+        let suspended = leech && conf.suspends_leeches();
+        let relearning_delay = match conf.delays.first() {
+            Some(minutes) if !suspended => Some((minutes * 60.0) as u32),
+            _ => None,
+        };
+        let interval = ((card.ivl as f32 * conf.mult) as u32)
+            .max(conf.min_int)
+            .max(1);
+
+        Ok(LapsePreview {
+            interval,
+            ease_factor: card
+                .factor
+                .saturating_sub(LAPSE_EASE_PENALTY)
+                .max(MINIMUM_EASE_FACTOR),
+            lapses,
+            relearning_delay,
+            leech,
+            suspended,
+        })
+    }
+
+    /// Tag the notes of cards that have reached their config's leech
+    /// threshold with "leech", and suspend the cards if the config's leech
+    /// action says so. Notes already tagged as leeches are left alone, so
+    /// cards the user has unsuspended stay that way. Cards in a filtered
+    /// deck use the config of their original deck. Returns the cards and
+    /// notes newly marked.
+    pub(crate) fn mark_leeches(&mut self) -> Result<(Vec<CardID>, Vec<NoteID>)> {
+        let decks = self.storage.all_decks()?;
+        let confs = self.storage.all_deck_conf()?;
+        let lapse_conf = |did: DeckID| {
+            decks
+                .get(&did)
+                .and_then(|deck| deck.conf)
+                .and_then(|dcid| {
+                    confs
+                        .get(&dcid)
+                        .or_else(|| confs.get(&DEFAULT_DECK_CONF_ID))
+                })
+                .map(|conf| &conf.lapse)
+        };
+
+        let lapsed: Vec<(CardID, NoteID, DeckID, u32)> = self
+            .storage
+            .db
+            .prepare(
+                "select id, nid, (case when odid = 0 then did else odid end), lapses
+                from cards where lapses > 0 order by id",
+            )?
+            .query_map(NO_PARAMS, |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        // whether each note was tagged before this scan
+        let mut tagged: HashMap<NoteID, bool> = HashMap::new();
+        let mut card_ids = vec![];
+        let mut to_suspend = vec![];
+        let mut note_ids = vec![];
+        for (cid, nid, did, lapses) in lapsed {
+            let conf = match lapse_conf(did) {
+                Some(conf) => conf,
+                None => continue,
+            };
+            if conf.leech_fails == 0 || lapses < conf.leech_fails {
+                continue;
+            }
+            let already_tagged = match tagged.get(&nid) {
+                Some(tagged) => *tagged,
+                None => {
+                    let tags: String = self.storage.db.query_row(
+                        "select tags from notes where id = ?",
+                        params![nid],
+                        |row| row.get(0),
+                    )?;
+                    let is_tagged =
+                        split_tags(&tags).any(|tag| UniCase::new(tag) == UniCase::new(LEECH_TAG));
+                    tagged.insert(nid, is_tagged);
+                    if !is_tagged {
+                        note_ids.push(nid);
+                    }
+                    is_tagged
+                }
+            };
+            if already_tagged {
+                continue;
+            }
+            card_ids.push(cid);
+            if conf.suspends_leeches() {
+                to_suspend.push(cid);
+            }
+        }
+
+        self.add_tag_to_notes(&note_ids, LEECH_TAG)?;
+        let usn = self.storage.usn()?;
+        let mtime = TimestampSecs::now();
+        for cid in to_suspend {
+            if let Some(mut card) = self.storage.get_card(cid)? {
+                if card.queue != CardQueue::Suspended {
+                    card.queue = CardQueue::Suspended;
+                    card.mtime = mtime;
+                    card.usn = usn;
+                    self.storage.update_card(&card)?;
+                }
+            }
+        }
+
+        Ok((card_ids, note_ids))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LapsePreview;
+    use crate::card::{CardID, CardQueue};
+    use crate::collection::test::open_test_collection;
+    use crate::collection::RequestContext;
+    use crate::deckconf::DEFAULT_DECK_CONF_ID;
+    use crate::err::Result;
+    use crate::notes::NoteID;
+
+    #[test]
+    fn preview_lapse() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        let cid = CardID(1581236445527);
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                "update cards set type = 2, queue = 2, ivl = 30, factor = 2500, lapses = 0
+                 where id = 1581236445527",
+            )?;
+
+            // the default config relearns in 10 minutes, and resets the
+            // interval to the minimum of 1 day
+            assert_eq!(
+                ctx.preview_lapse(cid)?,
+                LapsePreview {
+                    interval: 1,
+                    ease_factor: 2300,
+                    lapses: 1,
+                    relearning_delay: Some(600),
+                    leech: false,
+                    suspended: false,
+                }
+            );
+            // the card is unchanged
+            assert_eq!(ctx.storage.get_card(cid)?.unwrap().lapses, 0);
+
+            // keeping half the interval, with no relearning steps
+            let mut conf = ctx.get_deck_conf(DEFAULT_DECK_CONF_ID)?;
+            conf.lapse.mult = 0.5;
+            conf.lapse.delays.clear();
+            ctx.storage.update_deck_conf(&conf)?;
+            let preview = ctx.preview_lapse(cid)?;
+            assert_eq!(preview.interval, 15);
+            assert_eq!(preview.relearning_delay, None);
+
+            // the eighth lapse makes it a leech, which is suspended by
+            // default, and every fourth lapse after that
+            ctx.storage.db.execute_batch(
+                "update cards set lapses = 7, factor = 1400 where id = 1581236445527",
+            )?;
+            let preview = ctx.preview_lapse(cid)?;
+            assert_eq!(preview.ease_factor, 1300);
+            assert!(preview.leech && preview.suspended);
+            ctx.storage
+                .db
+                .execute_batch("update cards set lapses = 9 where id = 1581236445527")?;
+            assert!(!ctx.preview_lapse(cid)?.leech);
+            ctx.storage
+                .db
+                .execute_batch("update cards set lapses = 11 where id = 1581236445527")?;
+            assert!(ctx.preview_lapse(cid)?.leech);
+
+            // new cards can't lapse
+            ctx.storage
+                .db
+                .execute_batch("update cards set type = 0 where id = 1581236445527")?;
+            assert!(ctx.preview_lapse(cid).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn mark_leeches() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        fn note_tags(ctx: &RequestContext) -> Result<Vec<String>> {
+            let mut stmt = ctx
+                .storage
+                .db
+                .prepare("select tags from notes order by id")?;
+            let tags = stmt
+                .query_map(rusqlite::NO_PARAMS, |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(tags)
+        }
+
+        let [c1, c2, c3] = [
+            CardID(1581236445527),
+            CardID(1581236461565),
+            CardID(1581236488474),
+        ];
+        col.transact(None, |ctx| {
+            // the threshold is 8 lapses; the third card's note is already
+            // tagged
+            ctx.storage.db.execute_batch(
+                "update cards set type = 2, queue = 2;
+                 update cards set lapses = 8 where id = 1581236445527;
+                 update cards set lapses = 7 where id = 1581236461565;
+                 update cards set lapses = 9 where id = 1581236488474;
+                 update notes set tags = ' Leech ' where id = 1581236461568;",
+            )?;
+
+            assert_eq!(ctx.mark_leeches()?, (vec![c1], vec![NoteID(1581236386334)]));
+            assert_eq!(note_tags(ctx)?, vec![" leech ", "", " Leech "]);
+            assert_eq!(
+                ctx.storage.get_card(c1)?.unwrap().queue,
+                CardQueue::Suspended
+            );
+            assert_eq!(ctx.storage.get_card(c3)?.unwrap().queue, CardQueue::Review);
+            assert!(ctx.storage.all_tags()?.contains_key("leech"));
+
+            // a second scan finds nothing new, even if the card was
+            // unsuspended
+            ctx.storage
+                .db
+                .execute_batch("update cards set queue = 2 where id = 1581236445527")?;
+            assert_eq!(ctx.mark_leeches()?, (vec![], vec![]));
+            assert_eq!(ctx.storage.get_card(c1)?.unwrap().queue, CardQueue::Review);
+
+            // reaching the threshold with a config that only tags
+            let mut conf = ctx.get_deck_conf(DEFAULT_DECK_CONF_ID)?;
+            conf.lapse.other.insert("leechAction".into(), 1.into());
+            ctx.storage.update_deck_conf(&conf)?;
+            ctx.storage
+                .db
+                .execute_batch("update cards set lapses = 8 where id = 1581236461565")?;
+            assert_eq!(ctx.mark_leeches()?, (vec![c2], vec![NoteID(1581236445532)]));
+            assert_eq!(ctx.storage.get_card(c2)?.unwrap().queue, CardQueue::Review);
+
+            // a threshold of 0 disables leeches
+            conf.lapse.leech_fails = 0;
+            ctx.storage.update_deck_conf(&conf)?;
+            ctx.storage.db.execute_batch("update notes set tags = ''")?;
+            assert_eq!(ctx.mark_leeches()?, (vec![], vec![]));
+
+            Ok(())
+        })
+    }
+}
@@ -1,2 +1,9 @@
+pub(crate) mod congrats;
 pub mod cutoff;
+pub(crate) mod ease;
+pub(crate) mod lapse;
+pub(crate) mod new;
+pub(crate) mod projected;
+pub(crate) mod queue;
 pub mod timespan;
+pub(crate) mod upgrade;
@@ -0,0 +1,318 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::card::{Card, CardID, CardType};
+use crate::collection::RequestContext;
+use crate::err::{AnkiError, Result};
+use crate::notes::{get_note, NoteID};
+use crate::search::{search_cards, SortMode};
+use crate::text::strip_html;
+use crate::timestamp::TimestampSecs;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use unicase::UniCase;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum NewCardOrder {
+    /// Shuffle the notes. The same seed gives the same order.
+    Random {
+        seed: u64,
+    },
+    NoteCreation,
+    /// By the text of the named field, ignoring HTML and case. Notes
+    /// without the field come first.
+    Field(String),
+}
+
+impl Card {
+    /// The position of a new card. Cards in a filtered deck store it in
+    /// odue.
+    fn new_position(&self) -> i32 {
+        if self.odid.0 != 0 {
+            self.odue
+        } else {
+            self.due
+        }
+    }
+
+    fn set_new_position(&mut self, position: i32) {
+        if self.odid.0 != 0 {
+            self.odue = position;
+        } else {
+            self.due = position;
+        }
+    }
+}
+
+impl RequestContext<'_> {
+    /// Reorder the new cards matching `search`, giving them positions that
+    /// begin at the lowest position they currently use. Cards of the same
+    /// note share a position. Returns the number of cards reordered.
+    pub(crate) fn reorder_new_cards(&mut self, search: &str, order: NewCardOrder) -> Result<usize> {
+        let cids = search_cards(self, search, SortMode::NoOrder, false)?;
+        let cards = self.new_cards(&cids)?;
+        let start = match cards.iter().map(Card::new_position).min() {
+            Some(start) => start,
+            None => return Ok(0),
+        };
+
+        let mut nids: Vec<_> = cards.iter().map(|card| card.nid).collect();
+        nids.sort();
+        nids.dedup();
+        match order {
+            NewCardOrder::Random { seed } => nids.shuffle(&mut StdRng::seed_from_u64(seed)),
+            NewCardOrder::NoteCreation => (),
+            NewCardOrder::Field(name) => {
+                let keys = self.field_sort_keys(&nids, &name)?;
+                nids.sort_by(|a, b| keys[a].cmp(&keys[b]));
+            }
+        }
+
+        let positions = nids
+            .into_iter()
+            .enumerate()
+            .map(|(idx, nid)| (nid, start + idx as i32))
+            .collect();
+        self.set_new_positions(cards, &positions)
+    }
+
+    /// Give the notes of the provided cards the positions `start`,
+    /// `start + step`, and so on, in the order the cards were provided or
+    /// shuffled, as the browser's Reposition does. Notes of cards that are
+    /// not new still use up a position. If `shift` is set, queued new cards
+    /// from the lowest position at or after `start` are moved to follow the
+    /// repositioned ones. Returns the number of cards repositioned.
+    pub(crate) fn reposition_new_cards(
+        &mut self,
+        cids: &[CardID],
+        start: i32,
+        step: i32,
+        randomize: bool,
+        shift: bool,
+    ) -> Result<usize> {
+        let mut nids = vec![];
+        let mut seen = HashSet::new();
+        for cid in cids {
+            if let Some(card) = self.storage.get_card(*cid)? {
+                if seen.insert(card.nid) {
+                    nids.push(card.nid);
+                }
+            }
+        }
+        if nids.is_empty() {
+            return Ok(0);
+        }
+        if randomize {
+            nids.shuffle(&mut rand::thread_rng());
+        }
+
+        if shift {
+            let high = start + (nids.len() as i32 - 1) * step;
+            if let Some(low) = self.storage.lowest_new_position_from(start, cids)? {
+                let usn = self.storage.usn()?;
+                self.storage
+                    .shift_new_positions(low, high - low + 1, cids, usn)?;
+            }
+        }
+
+        let positions = nids
+            .into_iter()
+            .enumerate()
+            .map(|(idx, nid)| (nid, start + idx as i32 * step))
+            .collect();
+        let cards = self.new_cards(cids)?;
+        self.set_new_positions(cards, &positions)
+    }
+
+    /// The cards with the provided ids that are new, in the same order.
+    fn new_cards(&mut self, cids: &[CardID]) -> Result<Vec<Card>> {
+        let mut cards = vec![];
+        for cid in cids {
+            if let Some(card) = self.storage.get_card(*cid)? {
+                if card.ctype == CardType::New {
+                    cards.push(card);
+                }
+            }
+        }
+        Ok(cards)
+    }
+
+    /// Give each card the position of its note, returning the number of
+    /// cards updated.
+    fn set_new_positions(
+        &mut self,
+        cards: Vec<Card>,
+        positions: &HashMap<NoteID, i32>,
+    ) -> Result<usize> {
+        let usn = self.storage.usn()?;
+        let mtime = TimestampSecs::now();
+        let count = cards.len();
+        for mut card in cards {
+            card.set_new_position(positions[&card.nid]);
+            card.mtime = mtime;
+            card.usn = usn;
+            self.storage.update_card(&card)?;
+        }
+        Ok(count)
+    }
+
+    fn field_sort_keys(
+        &mut self,
+        nids: &[NoteID],
+        field_name: &str,
+    ) -> Result<HashMap<NoteID, UniCase<String>>> {
+        let field_indices: HashMap<_, _> = self
+            .storage
+            .all_note_types()?
+            .into_iter()
+            .filter_map(|(ntid, nt)| {
+                nt.fields
+                    .iter()
+                    .position(|field| field.name == field_name)
+                    .map(|idx| (ntid, idx))
+            })
+            .collect();
+        if field_indices.is_empty() {
+            return Err(AnkiError::invalid_input(format!(
+                "no note type has a field named {}",
+                field_name
+            )));
+        }
+
+        let mut keys = HashMap::new();
+        for nid in nids {
+            let note = get_note(self.storage.db, *nid)?
+                .ok_or_else(|| AnkiError::invalid_input(format!("note {} not found", nid)))?;
+            let text = field_indices
+                .get(&note.ntid)
+                .and_then(|idx| note.fields().get(*idx))
+                .map(|field| strip_html(field).into_owned())
+                .unwrap_or_default();
+            keys.insert(*nid, UniCase::new(text));
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NewCardOrder;
+    use crate::card::CardID;
+    use crate::collection::test::open_test_collection;
+    use crate::collection::RequestContext;
+    use crate::err::Result;
+
+    const CIDS: [i64; 3] = [1581236445527, 1581236461565, 1581236488474];
+
+    fn positions(ctx: &mut RequestContext) -> Vec<i32> {
+        CIDS.iter()
+            .map(|cid| ctx.storage.get_card(CardID(*cid)).unwrap().unwrap().due)
+            .collect()
+    }
+
+    #[test]
+    fn reorder() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            // make all three cards new; the notes are in creation order, and
+            // their front fields sort in reverse
+            ctx.storage.db.execute_batch(
+                "update cards set type = 0, queue = 0, due = id % 100 + 10;
+                 update notes set flds = 'c' || char(31) where id = 1581236386334;
+                 update notes set flds = '<b>B</b>' || char(31) where id = 1581236445532;
+                 update notes set flds = 'a' || char(31) where id = 1581236461568;",
+            )?;
+
+            let count = ctx.reorder_new_cards("", NewCardOrder::Field("Front".into()))?;
+            assert_eq!(count, 3);
+            // the lowest existing position was 37
+            assert_eq!(positions(ctx), vec![39, 38, 37]);
+
+            ctx.reorder_new_cards("", NewCardOrder::NoteCreation)?;
+            assert_eq!(positions(ctx), vec![37, 38, 39]);
+
+            // the same seed gives the same order
+            ctx.reorder_new_cards("", NewCardOrder::Random { seed: 5 })?;
+            let shuffled = positions(ctx);
+            ctx.reorder_new_cards("", NewCardOrder::NoteCreation)?;
+            ctx.reorder_new_cards("", NewCardOrder::Random { seed: 5 })?;
+            assert_eq!(positions(ctx), shuffled);
+            let mut sorted = shuffled.clone();
+            sorted.sort();
+            assert_eq!(sorted, vec![37, 38, 39]);
+
+            // only matching cards are changed
+            ctx.reorder_new_cards("", NewCardOrder::NoteCreation)?;
+            assert_eq!(
+                ctx.reorder_new_cards("nid:1581236461568", NewCardOrder::NoteCreation)?,
+                1
+            );
+            assert_eq!(positions(ctx), vec![37, 38, 39]);
+
+            assert!(ctx
+                .reorder_new_cards("", NewCardOrder::Field("Missing".into()))
+                .is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reposition() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        let [c1, c2, c3] = [CardID(CIDS[0]), CardID(CIDS[1]), CardID(CIDS[2])];
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                "update cards set type = 0, queue = 0;
+                 update cards set due = 1 where id = 1581236445527;
+                 update cards set due = 2 where id = 1581236461565;
+                 update cards set due = 3 where id = 1581236488474;",
+            )?;
+
+            // moving the last card to the front shifts the others up
+            assert_eq!(ctx.reposition_new_cards(&[c3], 1, 1, false, true)?, 1);
+            assert_eq!(positions(ctx), vec![2, 3, 1]);
+
+            // without shifting, other cards are left alone
+            assert_eq!(ctx.reposition_new_cards(&[c1, c2], 10, 5, false, false)?, 2);
+            assert_eq!(positions(ctx), vec![10, 15, 1]);
+
+            // nothing at or after the target, so nothing is shifted
+            ctx.reposition_new_cards(&[c3], 20, 1, false, true)?;
+            assert_eq!(positions(ctx), vec![10, 15, 20]);
+
+            // as in the desktop client, cards are shifted so they directly
+            // follow the repositioned ones, which closes the gap here
+            ctx.reposition_new_cards(&[c3], 12, 1, false, true)?;
+            assert_eq!(positions(ctx), vec![10, 13, 12]);
+
+            // suspended cards count when finding the first position to
+            // shift, but are not shifted themselves
+            ctx.storage
+                .db
+                .execute_batch("update cards set queue = -1 where id = 1581236445527")?;
+            ctx.reposition_new_cards(&[c3], 10, 1, false, true)?;
+            assert_eq!(positions(ctx), vec![10, 14, 10]);
+
+            // a shuffle uses the same positions
+            ctx.reposition_new_cards(&[c1, c2, c3], 1, 1, true, false)?;
+            let mut shuffled = positions(ctx);
+            shuffled.sort();
+            assert_eq!(shuffled, vec![1, 2, 3]);
+
+            // cards that are not new keep their due, but use up a position
+            ctx.storage
+                .db
+                .execute_batch("update cards set type = 2, due = 100 where id = 1581236445527")?;
+            assert_eq!(
+                ctx.reposition_new_cards(&[c1, c2, c3], 5, 1, false, false)?,
+                2
+            );
+            assert_eq!(positions(ctx), vec![100, 6, 7]);
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::card::{Card, CardQueue, CardType};
+use crate::collection::RequestContext;
+use crate::deckconf::{DeckConf, NewConf, RevConf};
+use crate::decks::DeckID;
+use crate::err::Result;
+use crate::search::{search_cards, SortMode};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+
+/// The number of cards that would get each interval if every due card
+/// matching a search were answered Good today.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ProjectedIntervals {
+    /// Intervals in days and the number of cards getting them, in interval
+    /// order.
+    pub(crate) intervals: Vec<(u32, u32)>,
+    /// Due learning cards that would move to their next step instead of
+    /// getting an interval.
+    pub(crate) learning: u32,
+}
+
+impl NewConf {
+    /// The interval given to a card leaving learning with Good.
+    fn graduating_interval(&self) -> u32 {
+        self.other
+            .get("ints")
+            .and_then(|ints| ints.get(0))
+            .and_then(|ivl| ivl.as_u64())
+            .unwrap_or(1) as u32
+    }
+}
+
+impl RevConf {
+    fn interval_factor(&self) -> f32 {
+        self.other
+            .get("ivlFct")
+            .and_then(|fct| fct.as_f64())
+            .unwrap_or(1.0) as f32
+    }
+
+    fn hard_factor(&self) -> f32 {
+        self.other
+            .get("hardFactor")
+            .and_then(|fct| fct.as_f64())
+            .unwrap_or(1.2) as f32
+    }
+
+    /// Apply the interval modifier and maximum interval to `ivl`, making it
+    /// at least a day longer than `previous`.
+    fn constrained_interval(&self, ivl: f32, previous: u32) -> u32 {
+        ((ivl * self.interval_factor()) as u32)
+            .max(previous + 1)
+            .min(self.max_ivl)
+    }
+
+    /// The interval of a review card answered Good `days_late` days after
+    /// it was due, without fuzz.
+    fn good_interval(&self, card: &Card, days_late: u32) -> u32 {
+        let hard_factor = self.hard_factor();
+        let hard_minimum = if hard_factor > 1.0 { card.ivl } else { 0 };
+        let hard = self.constrained_interval(card.ivl as f32 * hard_factor, hard_minimum);
+        self.constrained_interval(
+            (card.ivl + days_late / 2) as f32 * card.factor as f32 / 1000.0,
+            hard,
+        )
+    }
+}
+
+impl RequestContext<'_> {
+    /// The intervals the due cards matching `search` would get if they
+    /// were all answered Good today, as the v2 scheduler does it, without
+    /// changing any cards. Cards in a filtered deck use the due date and
+    /// config of their original deck.
+    pub(crate) fn projected_intervals(&mut self, search: &str) -> Result<ProjectedIntervals> {
+        let timing = self.storage.timing_today()?;
+        let today = timing.days_elapsed as i32;
+        let cids = search_cards(self, search, SortMode::NoOrder, false)?;
+
+        let mut confs: HashMap<DeckID, DeckConf> = HashMap::new();
+        let mut intervals: BTreeMap<u32, u32> = BTreeMap::new();
+        let mut learning = 0;
+        for cid in cids {
+            let card = match self.storage.get_card(cid)? {
+                Some(card) => card,
+                None => continue,
+            };
+            let due = if card.odid.0 != 0 && card.odue != 0 {
+                card.odue
+            } else {
+                card.due
+            };
+            let is_due = match card.queue {
+                CardQueue::Review | CardQueue::DayLearn => due <= today,
+                CardQueue::Learn => (due as i64) < timing.next_day_at,
+                _ => false,
+            };
+            if !is_due {
+                continue;
+            }
+
+            let did = if card.odid.0 != 0 {
+                card.odid
+            } else {
+                card.did
+            };
+            let conf = match confs.entry(did) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(self.deck_conf_for_deck(did)?),
+            };
+
+            let ivl = match card.ctype {
+                CardType::Review => conf.rev.good_interval(&card, (today - due).max(0) as u32),
+                // learning cards not on their last step move to the next one
+                _ if card.left % 1000 > 1 => {
+                    learning += 1;
+                    continue;
+                }
+                CardType::Relearn => card.ivl.max(1),
+                _ => conf.new.graduating_interval(),
+            };
+            *intervals.entry(ivl).or_default() += 1;
+        }
+
+        Ok(ProjectedIntervals {
+            intervals: intervals.into_iter().collect(),
+            learning,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProjectedIntervals;
+    use crate::card::CardID;
+    use crate::collection::test::open_test_collection;
+    use crate::err::Result;
+
+    #[test]
+    fn projected_intervals() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            // a review card 4 days late, a learning card on its last step,
+            // and one with two steps left
+            let today = ctx.storage.timing_today()?.days_elapsed as i32;
+            ctx.storage.db.execute(
+                "update cards set type = 2, queue = 2, ivl = 10, factor = 2500, due = ?
+                 where id = 1581236445527",
+                &[today - 4],
+            )?;
+            ctx.storage.db.execute_batch(
+                "update cards set left = 1001 where id = 1581236461565;
+                 update cards set type = 1, queue = 1, left = 2002, due = 1600000000
+                 where id = 1581236488474;",
+            )?;
+            let before = ctx.storage.get_card(CardID(1581236445527))?.unwrap();
+
+            // the review is 12 days with Hard, and (10 + 2) * 2.5 with Good
+            assert_eq!(
+                ctx.projected_intervals("")?,
+                ProjectedIntervals {
+                    intervals: vec![(1, 1), (30, 1)],
+                    learning: 1,
+                }
+            );
+            let after = ctx.storage.get_card(CardID(1581236445527))?.unwrap();
+            assert_eq!((after.ivl, after.due), (before.ivl, before.due));
+
+            // cards that aren't due are left out
+            ctx.storage.db.execute(
+                "update cards set due = ? where id = 1581236445527",
+                &[today + 1],
+            )?;
+            assert_eq!(
+                ctx.projected_intervals("")?,
+                ProjectedIntervals {
+                    intervals: vec![(1, 1)],
+                    learning: 1,
+                }
+            );
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,582 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::card::{Card, CardID, CardQueue};
+use crate::collection::RequestContext;
+use crate::config::{COLLAPSE_TIME_KEY, DEFAULT_COLLAPSE_TIME};
+use crate::deckconf::{DeckConf, DEFAULT_DECK_CONF_ID};
+use crate::decks::{child_ids, Deck, DeckConfID, DeckID};
+use crate::err::{AnkiError, Result};
+use crate::timestamp::TimestampSecs;
+use std::collections::{HashMap, VecDeque};
+use unicase::UniCase;
+
+/// The config key for when new cards are shown relative to reviews.
+const NEW_SPREAD_KEY: &str = "newSpread";
+const NEW_CARDS_FIRST: u8 = 2;
+
+const LEARNING_QUEUES: [CardQueue; 2] = [CardQueue::Learn, CardQueue::PreviewRepeat];
+
+/// How many more new cards and reviews a deck may show today.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DailyLimits {
+    new: u32,
+    review: u32,
+}
+
+impl DailyLimits {
+    /// The limits of a single deck, ignoring its parents. Filtered decks,
+    /// which have no config, are unlimited.
+    fn for_deck(deck: &Deck, conf: Option<&DeckConf>, today: u32) -> Self {
+        let conf = match conf {
+            Some(conf) => conf,
+            None => {
+                return DailyLimits {
+                    new: u32::max_value(),
+                    review: u32::max_value(),
+                }
+            }
+        };
+        let studied = |(day, count): (u32, i32)| if day == today { count } else { 0 };
+        let remaining =
+            |per_day: u32, studied: i32| (per_day as i64 - studied as i64).max(0) as u32;
+        DailyLimits {
+            new: remaining(conf.new.per_day, studied(deck.new_today)),
+            review: remaining(conf.rev.per_day, studied(deck.review_today)),
+        }
+    }
+
+    fn min(self, other: Self) -> Self {
+        DailyLimits {
+            new: self.new.min(other.new),
+            review: self.review.min(other.review),
+        }
+    }
+}
+
+/// The limits of each deck, which are used up as cards are queued. A card
+/// counts against its deck and each of the deck's parents, as in the v2
+/// scheduler.
+struct LimitTracker {
+    limits: HashMap<DeckID, DailyLimits>,
+    /// Each deck, followed by its parents.
+    lineage: HashMap<DeckID, Vec<DeckID>>,
+}
+
+impl LimitTracker {
+    fn new(decks: &[Deck], confs: &HashMap<DeckConfID, DeckConf>, today: u32) -> Self {
+        let limits = decks
+            .iter()
+            .map(|deck| {
+                let conf = deck.conf.and_then(|dcid| {
+                    confs
+                        .get(&dcid)
+                        .or_else(|| confs.get(&DEFAULT_DECK_CONF_ID))
+                });
+                (deck.id, DailyLimits::for_deck(deck, conf, today))
+            })
+            .collect();
+
+        let ids_by_name: HashMap<_, _> = decks
+            .iter()
+            .map(|deck| (UniCase::new(deck.name.as_str()), deck.id))
+            .collect();
+        let lineage = decks
+            .iter()
+            .map(|deck| {
+                let mut ids = vec![deck.id];
+                let mut parent_end = deck.name.len();
+                while let Some(idx) = deck.name[..parent_end].rfind("::") {
+                    parent_end = idx;
+                    if let Some(id) = ids_by_name.get(&UniCase::new(&deck.name[..parent_end])) {
+                        ids.push(*id);
+                    }
+                }
+                (deck.id, ids)
+            })
+            .collect();
+
+        LimitTracker { limits, lineage }
+    }
+
+    fn remaining(&self, did: DeckID) -> DailyLimits {
+        self.lineage[&did].iter().map(|id| self.limits[id]).fold(
+            DailyLimits {
+                new: u32::max_value(),
+                review: u32::max_value(),
+            },
+            DailyLimits::min,
+        )
+    }
+
+    fn take_new(&mut self, did: DeckID, count: u32) {
+        for id in &self.lineage[&did] {
+            let limits = self.limits.get_mut(id).unwrap();
+            limits.new = limits.new.saturating_sub(count);
+        }
+    }
+
+    fn take_review(&mut self, did: DeckID) {
+        for id in &self.lineage[&did] {
+            let limits = self.limits.get_mut(id).unwrap();
+            limits.review = limits.review.saturating_sub(1);
+        }
+    }
+}
+
+/// What a study session's queues were built from. If any of it changes,
+/// the queues are rebuilt.
+#[derive(Debug, PartialEq)]
+struct QueueKey {
+    deck_id: DeckID,
+    today: u32,
+    /// The id and name of every deck, with its config and the config's
+    /// modification time.
+    decks: Vec<(DeckID, String, Option<(DeckConfID, Option<TimestampSecs>)>)>,
+}
+
+/// The cards of a study session that are waiting to be shown, which are
+/// kept by the collection between requests. Learning cards become due
+/// throughout the day, so they are looked up as needed instead.
+#[derive(Debug)]
+pub(crate) struct StudyQueues {
+    key: QueueKey,
+    /// The deck and its subdecks.
+    deck_ids: Vec<DeckID>,
+    day_learning: VecDeque<CardID>,
+    review: VecDeque<CardID>,
+    new: VecDeque<CardID>,
+}
+
+/// The number of cards left in a study session.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct StudyCounts {
+    pub(crate) new: u32,
+    /// Learning cards due now or within the collapse time, and cards in day
+    /// learning.
+    pub(crate) learning: u32,
+    pub(crate) review: u32,
+}
+
+impl RequestContext<'_> {
+    /// Build the queues for studying a deck and its subdecks, replacing
+    /// any existing session.
+    pub(crate) fn start_study(
+        &mut self,
+        did: DeckID,
+        queues: &mut Option<StudyQueues>,
+    ) -> Result<StudyCounts> {
+        *queues = Some(self.build_study_queues(did)?);
+        self.study_counts(did, queues)
+    }
+
+    /// The number of cards left in the session for a deck, starting one if
+    /// necessary.
+    pub(crate) fn study_counts(
+        &mut self,
+        did: DeckID,
+        queues: &mut Option<StudyQueues>,
+    ) -> Result<StudyCounts> {
+        let queues = self.current_study_queues(did, queues)?;
+        let collapse_time = self.collapse_time()?;
+        let learning = self.storage.due_cards(
+            &queues.deck_ids,
+            &LEARNING_QUEUES,
+            TimestampSecs::now().0 + collapse_time,
+            u32::max_value(),
+        )?;
+        Ok(StudyCounts {
+            new: queues.new.len() as u32,
+            learning: (learning.len() + queues.day_learning.len()) as u32,
+            review: queues.review.len() as u32,
+        })
+    }
+
+    /// The next card to study in a deck and its subdecks, or None if the
+    /// deck is finished for today. The session's queues are built on first
+    /// use, and rebuilt if the decks or their configs change.
+    ///
+    /// Learning cards that are due come first, then cards in day learning,
+    /// reviews, and new cards, and finally learning cards due within the
+    /// collapse time. New cards are shown before reviews when the
+    /// collection is set to show them first; they are not interleaved with
+    /// reviews. Cards that were changed since they were queued, such as by
+    /// being answered or suspended, are skipped.
+    pub(crate) fn next_card(
+        &mut self,
+        did: DeckID,
+        queues: &mut Option<StudyQueues>,
+    ) -> Result<Option<Card>> {
+        let queues = self.current_study_queues(did, queues)?;
+        let today = self.storage.timing_today()?.days_elapsed as i32;
+        let now = TimestampSecs::now().0;
+        let new_spread: Option<u8> = self.storage.get_config_value(NEW_SPREAD_KEY)?;
+        let new_first = new_spread == Some(NEW_CARDS_FIRST);
+
+        if let Some(card) = self.first_learning_card(&queues.deck_ids, now)? {
+            return Ok(Some(card));
+        }
+        let deck_ids = &queues.deck_ids;
+        if new_first {
+            if let Some(card) = self.pop_card(&mut queues.new, CardQueue::New, deck_ids, today)? {
+                return Ok(Some(card));
+            }
+        }
+        if let Some(card) = self.pop_card(
+            &mut queues.day_learning,
+            CardQueue::DayLearn,
+            deck_ids,
+            today,
+        )? {
+            return Ok(Some(card));
+        }
+        if let Some(card) = self.pop_card(&mut queues.review, CardQueue::Review, deck_ids, today)? {
+            return Ok(Some(card));
+        }
+        if let Some(card) = self.pop_card(&mut queues.new, CardQueue::New, deck_ids, today)? {
+            return Ok(Some(card));
+        }
+        let collapse_time = self.collapse_time()?;
+        self.first_learning_card(deck_ids, now + collapse_time)
+    }
+
+    /// The session for the provided deck, built if there is none, or if it
+    /// is for another deck or is out of date.
+    fn current_study_queues<'q>(
+        &mut self,
+        did: DeckID,
+        queues: &'q mut Option<StudyQueues>,
+    ) -> Result<&'q mut StudyQueues> {
+        let (all_decks, key) = self.study_queue_key(did)?;
+        let current = queues.as_ref().map(|q| q.key == key).unwrap_or(false);
+        if !current {
+            *queues = Some(self.build_queues_from(did, all_decks, key)?);
+        }
+        Ok(queues.as_mut().unwrap())
+    }
+
+    fn build_study_queues(&mut self, did: DeckID) -> Result<StudyQueues> {
+        let (all_decks, key) = self.study_queue_key(did)?;
+        self.build_queues_from(did, all_decks, key)
+    }
+
+    /// All decks sorted by name, and the key for a session of the provided
+    /// deck.
+    fn study_queue_key(&mut self, did: DeckID) -> Result<(Vec<Deck>, QueueKey)> {
+        let mut all_decks: Vec<_> = self
+            .storage
+            .all_decks()?
+            .into_iter()
+            .map(|(_, v)| v)
+            .collect();
+        all_decks.sort_by(|a, b| UniCase::new(&a.name).cmp(&UniCase::new(&b.name)));
+        if !all_decks.iter().any(|d| d.id == did) {
+            return Err(AnkiError::invalid_input(format!("deck {} not found", did)));
+        }
+
+        let confs = self.storage.all_deck_conf()?;
+        let key = QueueKey {
+            deck_id: did,
+            today: self.storage.timing_today()?.days_elapsed,
+            decks: all_decks
+                .iter()
+                .map(|deck| {
+                    let conf = deck
+                        .conf
+                        .map(|dcid| (dcid, confs.get(&dcid).map(|c| c.mtime)));
+                    (deck.id, deck.name.clone(), conf)
+                })
+                .collect(),
+        };
+        Ok((all_decks, key))
+    }
+
+    fn build_queues_from(
+        &mut self,
+        did: DeckID,
+        all_decks: Vec<Deck>,
+        key: QueueKey,
+    ) -> Result<StudyQueues> {
+        let deck = all_decks.iter().find(|d| d.id == did).unwrap();
+        // in name order, so new cards come from parents before children
+        let mut deck_ids = vec![did];
+        deck_ids.extend(child_ids(&all_decks, &deck.name));
+
+        let tomorrow = key.today as i64 + 1;
+        let mut limits = LimitTracker::new(&all_decks, &self.storage.all_deck_conf()?, key.today);
+
+        let day_learning = self
+            .storage
+            .due_cards(
+                &deck_ids,
+                &[CardQueue::DayLearn],
+                tomorrow,
+                u32::max_value(),
+            )?
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .collect();
+
+        let mut review = VecDeque::new();
+        for (cid, card_did) in
+            self.storage
+                .due_cards(&deck_ids, &[CardQueue::Review], tomorrow, u32::max_value())?
+        {
+            if limits.remaining(did).review == 0 {
+                break;
+            }
+            if limits.remaining(card_did).review > 0 {
+                review.push_back(cid);
+                limits.take_review(card_did);
+            }
+        }
+
+        let mut new = VecDeque::new();
+        for did in &deck_ids {
+            let limit = limits.remaining(*did).new;
+            if limit == 0 {
+                continue;
+            }
+            let cards =
+                self.storage
+                    .due_cards(&[*did], &[CardQueue::New], i64::max_value(), limit)?;
+            limits.take_new(*did, cards.len() as u32);
+            new.extend(cards.into_iter().map(|(cid, _)| cid));
+        }
+
+        Ok(StudyQueues {
+            key,
+            deck_ids,
+            day_learning,
+            review,
+            new,
+        })
+    }
+
+    fn first_learning_card(&mut self, dids: &[DeckID], due_before: i64) -> Result<Option<Card>> {
+        match self
+            .storage
+            .due_cards(dids, &LEARNING_QUEUES, due_before, 1)?
+            .first()
+        {
+            Some((cid, _)) => self.storage.get_card(*cid),
+            None => Ok(None),
+        }
+    }
+
+    /// Take the first card from a queue that is still in the expected
+    /// queue and decks, and due if it is a review.
+    fn pop_card(
+        &mut self,
+        queue: &mut VecDeque<CardID>,
+        kind: CardQueue,
+        deck_ids: &[DeckID],
+        today: i32,
+    ) -> Result<Option<Card>> {
+        while let Some(cid) = queue.pop_front() {
+            if let Some(card) = self.storage.get_card(cid)? {
+                if card.queue == kind
+                    && deck_ids.contains(&card.did)
+                    && (kind == CardQueue::New || card.due <= today)
+                {
+                    return Ok(Some(card));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn collapse_time(&self) -> Result<i64> {
+        Ok(self
+            .storage
+            .get_config_value(COLLAPSE_TIME_KEY)?
+            .unwrap_or(DEFAULT_COLLAPSE_TIME))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StudyCounts, StudyQueues};
+    use crate::collection::test::open_test_collection;
+    use crate::collection::RequestContext;
+    use crate::decks::DeckID;
+    use crate::err::Result;
+    use crate::timestamp::TimestampSecs;
+    use serde_json::json;
+
+    const C1: i64 = 1581236445527;
+    const C2: i64 = 1581236461565;
+    const C3: i64 = 1581236488474;
+
+    /// Default and its child share a config; Other is unrelated.
+    fn set_decks(
+        ctx: &mut RequestContext,
+        review_today: [(u32, i32); 2],
+        new_today: (u32, i32),
+    ) -> Result<()> {
+        let decks = json!({
+            "1": {"id": 1, "name": "Default", "conf": 1, "revToday": review_today[0]},
+            "2": {"id": 2, "name": "Default::Child", "conf": 1, "revToday": review_today[1]},
+            "3": {"id": 3, "name": "Other", "conf": 1, "newToday": new_today},
+        });
+        ctx.storage
+            .db
+            .execute("update col set decks = ?", &[decks.to_string()])?;
+        Ok(())
+    }
+
+    /// The next card of a new session.
+    fn next(ctx: &mut RequestContext, did: i64) -> Result<Option<i64>> {
+        Ok(ctx.next_card(DeckID(did), &mut None)?.map(|card| card.id.0))
+    }
+
+    #[test]
+    fn next_card() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            let today = ctx.storage.timing_today()?.days_elapsed;
+            let now = TimestampSecs::now().0;
+            set_decks(ctx, [(0, 0), (0, 0)], (0, 0))?;
+            ctx.storage.db.execute_batch(
+                "update cards set type = 0, queue = 0;
+                 update cards set did = 1, due = 3 where id = 1581236445527;
+                 update cards set did = 2, due = 2 where id = 1581236461565;
+                 update cards set did = 3, due = 1 where id = 1581236488474;",
+            )?;
+
+            // new cards come from the parent before its children
+            assert_eq!(next(ctx, 1)?, Some(C1));
+            assert_eq!(next(ctx, 2)?, Some(C2));
+
+            // reviews come before new cards, unless new cards are shown first
+            ctx.storage.db.execute(
+                "update cards set type = 2, queue = 2, due = ? where id = 1581236461565",
+                &[today],
+            )?;
+            assert_eq!(next(ctx, 1)?, Some(C2));
+            ctx.storage.set_config_value("newSpread", &2)?;
+            assert_eq!(next(ctx, 1)?, Some(C1));
+            ctx.storage.set_config_value("newSpread", &0)?;
+
+            // a full review limit on the child or its parent holds the review
+            // back, but counts from previous days are ignored
+            set_decks(ctx, [(0, 0), (today, 200)], (0, 0))?;
+            assert_eq!(next(ctx, 1)?, Some(C1));
+            set_decks(ctx, [(today, 200), (0, 0)], (0, 0))?;
+            assert_eq!(next(ctx, 1)?, Some(C1));
+            set_decks(ctx, [(today - 1, 200), (0, 0)], (0, 0))?;
+            assert_eq!(next(ctx, 1)?, Some(C2));
+
+            // due learning cards come first
+            ctx.storage.db.execute(
+                "update cards set type = 1, queue = 1, due = ? where id = 1581236445527",
+                &[now - 10],
+            )?;
+            assert_eq!(next(ctx, 1)?, Some(C1));
+
+            // learning cards due soon are shown once nothing else is left
+            ctx.storage.db.execute(
+                "update cards set due = ? where id = 1581236445527",
+                &[now + 600],
+            )?;
+            assert_eq!(next(ctx, 1)?, Some(C2));
+            ctx.storage
+                .db
+                .execute_batch("update cards set queue = -2 where id = 1581236461565")?;
+            assert_eq!(next(ctx, 1)?, Some(C1));
+            ctx.storage.db.execute(
+                "update cards set due = ? where id = 1581236445527",
+                &[now + 3600],
+            )?;
+            assert_eq!(next(ctx, 1)?, None);
+
+            // new cards respect the daily limit, and suspended cards are
+            // never shown
+            assert_eq!(next(ctx, 3)?, Some(C3));
+            set_decks(ctx, [(0, 0), (0, 0)], (today, 20))?;
+            assert_eq!(next(ctx, 3)?, None);
+            set_decks(ctx, [(0, 0), (0, 0)], (0, 0))?;
+            ctx.storage
+                .db
+                .execute_batch("update cards set queue = -1 where id = 1581236488474")?;
+            assert_eq!(next(ctx, 3)?, None);
+
+            assert!(ctx.next_card(DeckID(5), &mut None).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn session() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        fn next(ctx: &mut RequestContext, queues: &mut Option<StudyQueues>) -> Result<Option<i64>> {
+            Ok(ctx.next_card(DeckID(1), queues)?.map(|card| card.id.0))
+        }
+        fn new_counts(new: u32) -> StudyCounts {
+            StudyCounts {
+                new,
+                ..Default::default()
+            }
+        }
+
+        col.transact(None, |ctx| {
+            let today = ctx.storage.timing_today()?.days_elapsed;
+            set_decks(ctx, [(0, 0), (0, 0)], (0, 0))?;
+            ctx.storage.db.execute_batch(
+                "update cards set type = 0, queue = 0, did = 1;
+                 update cards set due = 1 where id = 1581236445527;
+                 update cards set due = 2 where id = 1581236461565;
+                 update cards set due = 3 where id = 1581236488474;",
+            )?;
+
+            let mut queues = None;
+            assert_eq!(ctx.start_study(DeckID(1), &mut queues)?, new_counts(3));
+            assert_eq!(next(ctx, &mut queues)?, Some(C1));
+            assert_eq!(ctx.study_counts(DeckID(1), &mut queues)?, new_counts(2));
+
+            // cards changed since the queues were built are skipped
+            ctx.storage
+                .db
+                .execute_batch("update cards set queue = -1 where id = 1581236461565")?;
+            assert_eq!(next(ctx, &mut queues)?, Some(C3));
+            assert_eq!(ctx.study_counts(DeckID(1), &mut queues)?, new_counts(0));
+            assert_eq!(next(ctx, &mut queues)?, None);
+
+            // the first card wasn't answered, but the session remembers it
+            // was shown until the decks change
+            ctx.storage
+                .db
+                .execute_batch("update col set decks = replace(decks, 'Other', 'Renamed')")?;
+            assert_eq!(next(ctx, &mut queues)?, Some(C1));
+            assert_eq!(ctx.study_counts(DeckID(1), &mut queues)?, new_counts(1));
+
+            // a session for another deck replaces it
+            assert_eq!(ctx.study_counts(DeckID(2), &mut queues)?, new_counts(0));
+
+            // the child's review limit can't exceed its parent's, and learning
+            // cards due now are counted
+            ctx.storage.db.execute(
+                "update cards set type = 2, queue = 2, did = 2, due = ?",
+                &[today],
+            )?;
+            ctx.storage.db.execute(
+                "update cards set type = 1, queue = 1, due = ? where id = 1581236445527",
+                &[TimestampSecs::now().0],
+            )?;
+            set_decks(ctx, [(today, 199), (0, 0)], (0, 0))?;
+            assert_eq!(
+                ctx.start_study(DeckID(2), &mut queues)?,
+                StudyCounts {
+                    new: 0,
+                    learning: 1,
+                    review: 1
+                }
+            );
+
+            Ok(())
+        })
+    }
+}
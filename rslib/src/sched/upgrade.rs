@@ -0,0 +1,237 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::collection::RequestContext;
+use crate::err::{AnkiError, Result};
+use crate::notes::NoteID;
+use crate::timestamp::TimestampSecs;
+use crate::types::Usn;
+use rusqlite::{params, NO_PARAMS};
+use std::collections::HashMap;
+
+/// The starting ease of cards returned to the new queue, in permille.
+const STARTING_FACTOR: u16 = 2500;
+/// The hour the v2 scheduler starts a new day at, if not configured.
+const DEFAULT_ROLLOVER: i8 = 4;
+/// The steps reported to the progress callback.
+const UPGRADE_STEPS: u32 = 4;
+
+impl RequestContext<'_> {
+    /// The scheduler version the collection uses; collections without one
+    /// set use v1.
+    pub(crate) fn scheduler_version(&self) -> Result<u8> {
+        Ok(self.storage.get_config_value("schedVer")?.unwrap_or(1))
+    }
+
+    /// Convert a collection from the v1 scheduler to v2, returning false
+    /// without changing anything if it was already using v2. Filtered
+    /// decks are emptied, cards in learning are returned to the review or
+    /// new queue, and the answer buttons of learning answers in the review
+    /// log are renumbered, as v2 has an extra learning button. The next
+    /// sync will be a full sync.
+    ///
+    /// `progress` is called with the steps completed and the total; if it
+    /// returns false, the upgrade is aborted.
+    pub(crate) fn upgrade_scheduler<F>(&mut self, mut progress: F) -> Result<bool>
+    where
+        F: FnMut(u32, u32) -> bool,
+    {
+        if self.scheduler_version()? >= 2 {
+            return Ok(false);
+        }
+        let usn = self.storage.usn()?;
+        let mtime = TimestampSecs::now();
+        let mut step = |done| {
+            if progress(done, UPGRADE_STEPS) {
+                Ok(())
+            } else {
+                Err(AnkiError::Interrupted)
+            }
+        };
+
+        // empty filtered decks, returning learning cards to the new queue
+        // and keeping suspended and buried cards as they are
+        self.storage.db.execute(
+            "update cards set did = odid,
+             queue = (case when queue < 0 then queue when type = 1 then 0 else type end),
+             type = (case when type = 1 then 0 else type end),
+             due = (case when odue > 0 then odue else due end),
+             odue = 0, odid = 0, mod = ?, usn = ?
+             where odid != 0",
+            params![mtime, usn],
+        )?;
+        step(1)?;
+
+        // v1 keeps the review due date of relearning cards in odue
+        self.storage.db.execute(
+            "update cards set due = odue, queue = 2, type = 2, odue = 0, mod = ?, usn = ?
+             where queue in (1, 3) and type in (2, 3)",
+            params![mtime, usn],
+        )?;
+        self.forget_learning_cards(mtime, usn)?;
+        step(2)?;
+
+        // v1's Good and Easy learning buttons are v2's Good and Easy, which
+        // are one higher
+        self.storage.db.execute(
+            "update revlog set ease = ease + 1 where ease in (2, 3) and type in (0, 2)",
+            NO_PARAMS,
+        )?;
+        step(3)?;
+
+        self.storage.set_config_value("schedVer", &2)?;
+        if self.storage.all_config()?.rollover.is_none() {
+            self.storage
+                .set_config_value("rollover", &DEFAULT_ROLLOVER)?;
+        }
+        self.storage.set_schema_modified()?;
+        step(4)?;
+
+        Ok(true)
+    }
+
+    /// Return new cards that are in learning to the end of the new queue,
+    /// keeping the cards of each note together.
+    fn forget_learning_cards(&mut self, mtime: TimestampSecs, usn: Usn) -> Result<()> {
+        let cards: Vec<(i64, NoteID)> = self
+            .storage
+            .db
+            .prepare("select id, nid from cards where queue in (1, 3) order by id")?
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<_, _>>()?;
+        if cards.is_empty() {
+            return Ok(());
+        }
+        let last_position: i32 = self.storage.db.query_row(
+            "select coalesce(max(due), 0) from cards where type = 0",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = self.storage.db.prepare(
+            "update cards set type = 0, queue = 0, ivl = 0, due = ?, odue = 0, factor = ?,
+             mod = ?, usn = ? where id = ?",
+        )?;
+        let mut position = last_position;
+        let mut positions = HashMap::new();
+        for (cid, nid) in cards {
+            let due = *positions.entry(nid).or_insert_with(|| {
+                position += 1;
+                position
+            });
+            stmt.execute(params![due, STARTING_FACTOR, mtime, usn, cid])?;
+        }
+
+        let next_position: i32 = self.storage.get_config_value("nextPos")?.unwrap_or(1);
+        if position >= next_position {
+            self.storage.set_config_value("nextPos", &(position + 1))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::{CardID, CardQueue, CardType};
+    use crate::collection::test::open_test_collection;
+    use crate::err::Result;
+    use rusqlite::NO_PARAMS;
+
+    #[test]
+    fn scheduler_version() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            assert_eq!(ctx.scheduler_version()?, 1);
+            ctx.storage.set_config_value("schedVer", &2)?;
+            assert_eq!(ctx.scheduler_version()?, 2);
+
+            // older collections don't store a version
+            let conf: String =
+                ctx.storage
+                    .db
+                    .query_row("select conf from col", NO_PARAMS, |row| row.get(0))?;
+            let mut conf: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&conf)?;
+            conf.remove("schedVer");
+            ctx.storage.db.execute(
+                "update col set conf = ?",
+                rusqlite::params![serde_json::to_string(&conf)?],
+            )?;
+            assert_eq!(ctx.scheduler_version()?, 1);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn upgrade_scheduler() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        let c1 = CardID(1581236445527);
+        let c2 = CardID(1581236461565);
+        let c3 = CardID(1581236488474);
+
+        col.transact(None, |ctx| {
+            // c1 is relearning, c2 is a new card in learning, and c3 is a new
+            // card in a filtered deck
+            ctx.storage.db.execute_batch(
+                "update cards set type = 2, queue = 1, due = 1600000000, odue = 50, ivl = 10
+                 where id = 1581236445527;
+                 update cards set did = 5, odid = 1, due = -100000, odue = 3
+                 where id = 1581236488474;
+                 update revlog set type = 0, ease = 2 where id = (select min(id) from revlog);
+                 update revlog set type = 1, ease = 3 where id = (select max(id) from revlog);
+                 update col set scm = 0;",
+            )?;
+            assert_eq!(ctx.scheduler_version()?, 1);
+
+            let mut calls = vec![];
+            let progress = |done, total| {
+                calls.push((done, total));
+                true
+            };
+            assert!(ctx.upgrade_scheduler(progress)?);
+            assert_eq!(calls, vec![(1, 4), (2, 4), (3, 4), (4, 4)]);
+
+            let card = ctx.storage.get_card(c1)?.unwrap();
+            assert_eq!(
+                (card.ctype, card.queue, card.due, card.odue),
+                (CardType::Review, CardQueue::Review, 50, 0)
+            );
+            let card = ctx.storage.get_card(c2)?.unwrap();
+            assert_eq!(
+                (card.ctype, card.queue, card.due, card.ivl, card.factor),
+                (CardType::New, CardQueue::New, 4, 0, 2500)
+            );
+            let card = ctx.storage.get_card(c3)?.unwrap();
+            assert_eq!(
+                (card.did.0, card.odid.0, card.queue, card.due),
+                (1, 0, CardQueue::New, 3)
+            );
+
+            let eases: Vec<u8> = ctx
+                .storage
+                .db
+                .prepare("select ease from revlog order by id")?
+                .query_map(NO_PARAMS, |row| row.get(0))?
+                .collect::<std::result::Result<_, _>>()?;
+            assert_eq!(eases, vec![3, 3]);
+
+            assert_eq!(ctx.scheduler_version()?, 2);
+            let conf = ctx.storage.all_config()?;
+            assert_eq!(conf.rollover, Some(4));
+            assert_eq!(ctx.storage.get_config_value::<i32>("nextPos")?, Some(5));
+            let scm: i64 = ctx
+                .storage
+                .db
+                .query_row("select scm from col", NO_PARAMS, |row| row.get(0))?;
+            assert!(scm > 0);
+
+            // upgrading again does nothing
+            assert!(!ctx.upgrade_scheduler(|_, _| true)?);
+
+            Ok(())
+        })
+    }
+}
@@ -1,33 +1,86 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+use super::relevance::{search_cards_by_relevance, search_terms};
 use super::{parser::Node, sqlwriter::node_to_sql};
 use crate::card::CardID;
 use crate::card::CardType;
 use crate::collection::RequestContext;
 use crate::config::SortKind;
 use crate::err::Result;
-use crate::search::parser::parse;
+use crate::search::parser::{parse, top_level_terms};
 use rusqlite::params;
 
 pub(crate) enum SortMode {
     NoOrder,
     FromConfig,
-    Builtin { kind: SortKind, reverse: bool },
+    Builtin {
+        kind: SortKind,
+        reverse: bool,
+    },
     Custom(String),
+    /// Notes that best match the search text first. Slower than the other
+    /// modes, as each note's fields need to be checked.
+    Relevance,
+}
+
+/// Searches with more top-level terms than this only have the first ones
+/// counted by search_breakdown().
+const MAX_BREAKDOWN_TERMS: usize = 20;
+
+/// How many cards a search matches, and how many each of its top-level
+/// terms matches on its own.
+#[derive(Debug, PartialEq)]
+pub(crate) struct SearchBreakdown {
+    pub total: u32,
+    pub terms: Vec<(String, u32)>,
+    /// Set if the search had more than MAX_BREAKDOWN_TERMS terms.
+    pub truncated: bool,
+}
+
+pub(crate) fn count_cards(req: &mut RequestContext, search: &str) -> Result<u32> {
+    let top_node = Node::Group(parse(search)?);
+    let (sql, args) = node_to_sql(req, &top_node, false)?;
+    let sql = format!(
+        "select count(*) from cards c, notes n where c.nid=n.id and {}",
+        sql
+    );
+    Ok(req.storage.db.query_row(&sql, &args, |row| row.get(0))?)
+}
+
+/// Count the cards matching a search, and each of its top-level terms.
+pub(crate) fn search_breakdown(req: &mut RequestContext, search: &str) -> Result<SearchBreakdown> {
+    let total = count_cards(req, search)?;
+    let terms = top_level_terms(search)?;
+    let truncated = terms.len() > MAX_BREAKDOWN_TERMS;
+    let terms = terms
+        .into_iter()
+        .take(MAX_BREAKDOWN_TERMS)
+        .map(|term| Ok((term.to_string(), count_cards(req, term)?)))
+        .collect::<Result<_>>()?;
+    Ok(SearchBreakdown {
+        total,
+        terms,
+        truncated,
+    })
 }
 
 pub(crate) fn search_cards<'a, 'b>(
     req: &'a mut RequestContext<'b>,
     search: &'a str,
     order: SortMode,
+    strip_clozes: bool,
 ) -> Result<Vec<CardID>> {
     let top_node = Node::Group(parse(search)?);
-    let (sql, args) = node_to_sql(req, &top_node)?;
+    let (sql, args) = node_to_sql(req, &top_node, strip_clozes)?;
 
+    let columns = match order {
+        SortMode::Relevance => "c.id, n.flds",
+        _ => "c.id",
+    };
     let mut sql = format!(
-        "select c.id from cards c, notes n where c.nid=n.id and {}",
-        sql
+        "select {} from cards c, notes n where c.nid=n.id and {}",
+        columns, sql
     );
 
     match order {
@@ -47,6 +100,11 @@ pub(crate) fn search_cards<'a, 'b>(
             sql.push_str(" order by ");
             sql.push_str(&order_clause);
         }
+        SortMode::Relevance => {
+            // notes that score the same are returned in creation order
+            sql.push_str(" order by n.id asc, c.ord asc");
+            return search_cards_by_relevance(req, &sql, &args, &search_terms(&top_node));
+        }
     }
 
     let mut stmt = req.storage.db.prepare(&sql)?;
@@ -67,6 +125,15 @@ fn write_order(sql: &mut String, kind: &SortKind, reverse: bool) -> Result<()> {
         SortKind::CardMod => "c.mod asc",
         SortKind::CardReps => "c.reps asc",
         SortKind::CardDue => "c.type asc, c.due asc",
+        SortKind::NewCardPosition => {
+            // new cards by position, followed by other cards in creation order
+            tmp_str = format!(
+                "c.type != {new} asc, \
+                 (case when c.type = {new} then c.due else 0 end) asc, c.id asc",
+                new = CardType::New as i8
+            );
+            &tmp_str
+        }
         SortKind::CardEase => {
             tmp_str = format!("c.type = {} asc, c.factor asc", CardType::New as i8);
             &tmp_str
@@ -152,3 +219,133 @@ fn prepare_sort_order_table2(req: &mut RequestContext) -> Result<()> {
         .execute_batch(include_str!("sort_order2.sql"))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{search_breakdown, search_cards, SearchBreakdown, SortMode};
+    use crate::card::{Card, CardID};
+    use crate::collection::test::open_test_collection;
+    use crate::config::SortKind;
+    use crate::err::Result;
+    use crate::notes::NoteID;
+
+    #[test]
+    fn new_card_position() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.with_ctx(|ctx| {
+            let mut card = Card::default();
+            card.nid = NoteID(1);
+            ctx.storage.add_card(&mut card)?;
+
+            let order = |reverse| SortMode::Builtin {
+                kind: SortKind::NewCardPosition,
+                reverse,
+            };
+            let cids = search_cards(ctx, "", SortMode::NoOrder, false)?;
+            let mut cids: Vec<_> = cids.into_iter().map(|c| c.0).collect();
+            cids.sort();
+            assert_eq!(cids.len(), 4);
+
+            // two review cards, and two new cards in reverse position order,
+            // with review due numbers that would sort them first
+            for (cid, ctype, due) in &[(cids[0], 2, 5), (cids[1], 0, 10), (cids[2], 0, 3)] {
+                ctx.storage.db.execute(
+                    "update cards set type = ?, due = ? where id = ?",
+                    rusqlite::params![ctype, due, cid],
+                )?;
+            }
+            ctx.storage.db.execute(
+                "update cards set type = 2, due = 1 where id = ?",
+                rusqlite::params![cids[3]],
+            )?;
+
+            let expected: Vec<_> = vec![cids[2], cids[1], cids[0], cids[3]]
+                .into_iter()
+                .map(CardID)
+                .collect();
+            assert_eq!(search_cards(ctx, "", order(false), false)?, expected);
+            // repeated searches give the same order
+            assert_eq!(search_cards(ctx, "", order(false), false)?, expected);
+
+            let reversed: Vec<_> = expected.into_iter().rev().collect();
+            assert_eq!(search_cards(ctx, "", order(true), false)?, reversed);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn relevance() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.with_ctx(|ctx| {
+            let cids = search_cards(
+                ctx,
+                "",
+                SortMode::Builtin {
+                    kind: SortKind::NoteCreation,
+                    reverse: false,
+                },
+                false,
+            )?;
+            assert_eq!(cids.len(), 3);
+
+            // the oldest note only contains the text as part of a word, and
+            // the newest has a field containing only the text
+            for (cid, flds) in &[
+                (cids[0], "hotdogs\x1f"),
+                (cids[1], "x\x1fa dog"),
+                (cids[2], "Dog\x1fx"),
+            ] {
+                ctx.storage.db.execute(
+                    "update notes set flds = ? where id = (select nid from cards where id = ?)",
+                    rusqlite::params![flds, cid],
+                )?;
+            }
+
+            assert_eq!(
+                search_cards(ctx, "dog", SortMode::Relevance, false)?,
+                vec![cids[2], cids[1], cids[0]]
+            );
+            // with nothing to rank by, creation order is used
+            assert_eq!(search_cards(ctx, "", SortMode::Relevance, false)?, cids);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn breakdown() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.with_ctx(|ctx| {
+            assert_eq!(
+                search_breakdown(ctx, "")?,
+                SearchBreakdown {
+                    total: 3,
+                    terms: vec![],
+                    truncated: false
+                }
+            );
+            assert_eq!(
+                search_breakdown(ctx, "nid:1581236386334 or -(nid:1581236386334)")?,
+                SearchBreakdown {
+                    total: 3,
+                    terms: vec![
+                        ("nid:1581236386334".into(), 1),
+                        ("-(nid:1581236386334)".into(), 2)
+                    ],
+                    truncated: false
+                }
+            );
+
+            let search = vec!["is:new"; 25].join(" ");
+            let breakdown = search_breakdown(ctx, &search)?;
+            assert_eq!(breakdown.terms.len(), 20);
+            assert!(breakdown.truncated);
+
+            Ok(())
+        })
+    }
+}
@@ -1,7 +1,10 @@
 mod cards;
 mod notes;
 mod parser;
+mod relevance;
+mod saved;
 mod sqlwriter;
 
-pub(crate) use cards::{search_cards, SortMode};
-pub(crate) use notes::search_notes;
+pub(crate) use cards::{search_breakdown, search_cards, SortMode};
+pub(crate) use notes::{search_notes, search_notes_after, search_notes_in_batches};
+pub(crate) use sqlwriter::{check_search_fields, ids_to_string};
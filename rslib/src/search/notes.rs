@@ -3,16 +3,17 @@
 
 use super::{parser::Node, sqlwriter::node_to_sql};
 use crate::collection::RequestContext;
-use crate::err::Result;
+use crate::err::{AnkiError, Result};
 use crate::notes::NoteID;
 use crate::search::parser::parse;
 
 pub(crate) fn search_notes<'a, 'b>(
     req: &'a mut RequestContext<'b>,
     search: &'a str,
+    strip_clozes: bool,
 ) -> Result<Vec<NoteID>> {
     let top_node = Node::Group(parse(search)?);
-    let (sql, args) = node_to_sql(req, &top_node)?;
+    let (sql, args) = node_to_sql(req, &top_node, strip_clozes)?;
 
     let sql = format!(
         "select n.id from cards c, notes n where c.nid=n.id and {}",
@@ -26,3 +27,139 @@ pub(crate) fn search_notes<'a, 'b>(
 
     Ok(ids)
 }
+
+/// One page of search_notes(): up to `limit` distinct matching notes with
+/// ids above `after`, in id order.
+pub(crate) fn search_notes_after(
+    req: &mut RequestContext,
+    search: &str,
+    strip_clozes: bool,
+    after: NoteID,
+    limit: usize,
+) -> Result<Vec<NoteID>> {
+    let top_node = Node::Group(parse(search)?);
+    let (sql, args) = node_to_sql(req, &top_node, strip_clozes)?;
+
+    let sql = format!(
+        "select distinct n.id from cards c, notes n where c.nid=n.id and ({}) \
+         and n.id > {} order by n.id limit {}",
+        sql, after, limit
+    );
+
+    let mut stmt = req.storage.db.prepare(&sql)?;
+    let ids: Vec<_> = stmt
+        .query_map(&args, |row| row.get(0))?
+        .collect::<std::result::Result<_, _>>()?;
+
+    Ok(ids)
+}
+
+/// Passes the notes matching a search to `func` in batches of up to
+/// `batch_size`, sending each batch as soon as it has been read, instead of
+/// collecting them all first. `page` is called with the last id seen and
+/// the batch size to read the next batch, usually via search_notes_after(),
+/// so the caller can decide how long the collection is held for. Each note
+/// is included once. Returns the number of notes found.
+///
+/// The batches are read separately, so notes added or removed while the
+/// search is running may or may not be included. If `func` returns false,
+/// the search is aborted.
+pub(crate) fn search_notes_in_batches<P, F>(
+    batch_size: usize,
+    mut page: P,
+    mut func: F,
+) -> Result<u32>
+where
+    P: FnMut(NoteID, usize) -> Result<Vec<NoteID>>,
+    F: FnMut(&[NoteID]) -> bool,
+{
+    if batch_size == 0 {
+        return Err(AnkiError::invalid_input("batch size must be positive"));
+    }
+
+    let mut after = NoteID(0);
+    let mut total = 0;
+    loop {
+        let batch = page(after, batch_size)?;
+        if let Some(last) = batch.last() {
+            after = *last;
+            total += batch.len() as u32;
+            if !func(&batch) {
+                return Err(AnkiError::Interrupted);
+            }
+        }
+        if batch.len() < batch_size {
+            return Ok(total);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{search_notes_after, search_notes_in_batches};
+    use crate::collection::test::open_test_collection;
+    use crate::err::{AnkiError, Result};
+    use crate::notes::NoteID;
+
+    #[test]
+    fn batches() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.with_ctx(|ctx| {
+            let mut batches = vec![];
+            let total = search_notes_in_batches(
+                2,
+                |after, limit| search_notes_after(ctx, "", false, after, limit),
+                |nids| {
+                    batches.push(nids.to_vec());
+                    true
+                },
+            )?;
+            assert_eq!(total, 3);
+            assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 1]);
+            // in id order, with no note repeated
+            let nids: Vec<_> = batches.concat();
+            assert!(nids.windows(2).all(|w| w[0] < w[1]));
+
+            // the same notes are found in a single page
+            assert_eq!(search_notes_after(ctx, "", false, NoteID(0), 10)?, nids);
+
+            let total = search_notes_in_batches(
+                2,
+                |after, limit| search_notes_after(ctx, "another", false, after, limit),
+                |nids| {
+                    assert_eq!(nids.len(), 1);
+                    true
+                },
+            )?;
+            assert_eq!(total, 1);
+
+            // the caller can stop early
+            assert!(matches!(
+                search_notes_in_batches(
+                    1,
+                    |after, limit| search_notes_after(ctx, "", false, after, limit),
+                    |_| false
+                ),
+                Err(AnkiError::Interrupted)
+            ));
+
+            // including on the last, partial batch
+            let mut calls = 0;
+            assert!(matches!(
+                search_notes_in_batches(
+                    2,
+                    |after, limit| search_notes_after(ctx, "", false, after, limit),
+                    |nids| {
+                        calls += 1;
+                        nids.len() == 2
+                    }
+                ),
+                Err(AnkiError::Interrupted)
+            ));
+            assert_eq!(calls, 2);
+
+            Ok(())
+        })
+    }
+}
@@ -57,7 +57,10 @@ pub(super) enum SearchNode<'a> {
     },
     AddedInDays(u32),
     CardTemplate(TemplateKind),
+    /// A deck and its subdecks.
     Deck(Cow<'a, str>),
+    /// A deck, without its subdecks.
+    DeckWithoutChildren(Cow<'a, str>),
     NoteTypeID(NoteTypeID),
     NoteType(Cow<'a, str>),
     Rated {
@@ -120,6 +123,26 @@ pub(super) fn parse(input: &str) -> Result<Vec<Node>> {
     Ok(nodes)
 }
 
+/// The text of each top-level term of a search, leaving out the AND/OR
+/// operators between them. Groups and negated terms are returned whole.
+pub(super) fn top_level_terms(input: &str) -> Result<Vec<&str>> {
+    // make sure the whole search is valid first
+    parse(input)?;
+
+    let mut remaining = input.trim();
+    let mut terms = vec![];
+    while !remaining.is_empty() {
+        let (rem, parsed) =
+            node(remaining).map_err(|_e| AnkiError::invalid_input("unable to parse search"))?;
+        if !matches!(parsed, Node::And | Node::Or) {
+            terms.push(remaining[..remaining.len() - rem.len()].trim_start());
+        }
+        remaining = rem;
+    }
+
+    Ok(terms)
+}
+
 /// One or more nodes surrounded by brackets, eg (one OR two)
 fn group(s: &str) -> IResult<&str, Node> {
     map(delimited(char('('), group_inner, char(')')), |nodes| {
@@ -256,6 +279,29 @@ fn partially_quoted_term(s: &str) -> IResult<&str, Node> {
     }
 }
 
+/// deck:name matches subdecks as well; deck:=name matches only the deck
+/// itself.
+fn parse_deck(val: Cow<str>) -> SearchNode {
+    if val.starts_with('=') {
+        SearchNode::DeckWithoutChildren(match val {
+            Cow::Borrowed(s) => Cow::Borrowed(&s[1..]),
+            Cow::Owned(s) => Cow::Owned(s[1..].to_string()),
+        })
+    } else {
+        SearchNode::Deck(val)
+    }
+}
+
+/// A tag search also matches the tags nested under it, so a trailing ::
+/// makes no difference.
+fn parse_tag(val: Cow<str>) -> Cow<str> {
+    if val.ends_with("::") {
+        val.trim_end_matches("::").to_string().into()
+    } else {
+        val
+    }
+}
+
 /// Convert a colon-separated key/val pair into the relevant search type.
 fn search_node_for_text_with_argument<'a>(
     key: Cow<'a, str>,
@@ -263,9 +309,9 @@ fn search_node_for_text_with_argument<'a>(
 ) -> ParseResult<SearchNode<'a>> {
     Ok(match key.to_ascii_lowercase().as_str() {
         "added" => SearchNode::AddedInDays(val.parse()?),
-        "deck" => SearchNode::Deck(val),
+        "deck" => parse_deck(val),
         "note" => SearchNode::NoteType(val),
-        "tag" => SearchNode::Tag(val),
+        "tag" => SearchNode::Tag(parse_tag(val)),
         "mid" => SearchNode::NoteTypeID(val.parse()?),
         "nid" => SearchNode::NoteIDs(check_id_list(val)?),
         "cid" => SearchNode::CardIDs(check_id_list(val)?),
@@ -413,6 +459,19 @@ fn parse_single_field(key: &str, mut val: &str) -> SearchNode<'static> {
 mod test {
     use super::*;
 
+    #[test]
+    fn terms() -> Result<()> {
+        assert_eq!(top_level_terms("")?, Vec::<&str>::new());
+        assert_eq!(top_level_terms(" dog ")?, vec!["dog"]);
+        assert_eq!(
+            top_level_terms(r#"a  or (b c) -"d e" and deck:x"#)?,
+            vec!["a", "(b c)", r#"-"d e""#, "deck:x"]
+        );
+        assert!(top_level_terms("a or").is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn parsing() -> Result<()> {
         use Node::*;
@@ -494,9 +553,21 @@ mod test {
             parse("deck:\"default one\"")?,
             vec![Search(Deck("default one".into()))]
         );
+        assert_eq!(
+            parse("deck:=default")?,
+            vec![Search(DeckWithoutChildren("default".into()))]
+        );
+        assert_eq!(
+            parse("-deck:\"=default one\"")?,
+            vec![Node::Not(Box::new(Search(DeckWithoutChildren(
+                "default one".into()
+            ))))]
+        );
 
         assert_eq!(parse("note:basic")?, vec![Search(NoteType("basic".into()))]);
         assert_eq!(parse("tag:hard")?, vec![Search(Tag("hard".into()))]);
+        assert_eq!(parse("tag:lang::es")?, vec![Search(Tag("lang::es".into()))]);
+        assert_eq!(parse("tag:lang::")?, vec![Search(Tag("lang".into()))]);
         assert_eq!(
             parse("nid:1237123712,2,3")?,
             vec![Search(NoteIDs("1237123712,2,3".into()))]
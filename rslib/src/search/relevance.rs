@@ -0,0 +1,143 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use super::parser::{Node, SearchNode};
+use crate::card::CardID;
+use crate::collection::RequestContext;
+use crate::err::Result;
+use crate::text::strip_html;
+
+/// How closely a term matched a note, from worst to best.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MatchKind {
+    None,
+    Substring,
+    /// The term appeared with no letters or digits either side of it.
+    Word,
+    /// A field contained only the term.
+    Field,
+}
+
+/// Sorts by the best match for each term first, then by how often the
+/// terms appeared.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct RelevanceScore {
+    matches: u32,
+    occurrences: u32,
+}
+
+/// The lowercased text of the unqualified terms in a search, which the
+/// results are ranked by. Negated terms are ignored, and wildcards are
+/// dropped.
+pub(super) fn search_terms(node: &Node) -> Vec<String> {
+    let mut terms = vec![];
+    collect_terms(node, &mut terms);
+    terms
+}
+
+fn collect_terms(node: &Node, terms: &mut Vec<String>) {
+    match node {
+        Node::Group(nodes) => {
+            for node in nodes {
+                collect_terms(node, terms);
+            }
+        }
+        Node::Search(SearchNode::UnqualifiedText(text)) => {
+            let term = text.replace(|c| c == '*' || c == '%', "").to_lowercase();
+            if !term.is_empty() {
+                terms.push(term);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Run a query returning (card id, note fields), and return the card ids
+/// with the best matches for `terms` first. The query's order is preserved
+/// for cards that score equally.
+pub(super) fn search_cards_by_relevance(
+    req: &mut RequestContext,
+    sql: &str,
+    args: &[String],
+    terms: &[String],
+) -> Result<Vec<CardID>> {
+    let mut stmt = req.storage.db.prepare(sql)?;
+    let mut scored: Vec<(RelevanceScore, CardID)> = stmt
+        .query_map(args, |row| {
+            let fields: String = row.get(1)?;
+            Ok((note_score(&fields, terms), row.get(0)?))
+        })?
+        .collect::<std::result::Result<_, _>>()?;
+    // sort is stable, so ties keep their existing order
+    scored.sort_by(|a, b| b.0.cmp(&a.0));
+
+    Ok(scored.into_iter().map(|(_, cid)| cid).collect())
+}
+
+fn note_score(fields: &str, terms: &[String]) -> RelevanceScore {
+    let fields: Vec<_> = fields
+        .split('\x1f')
+        .map(|field| strip_html(field).to_lowercase())
+        .collect();
+    let mut score = RelevanceScore::default();
+    for term in terms {
+        let mut best = MatchKind::None;
+        for field in &fields {
+            let (kind, count) = term_match(field, term);
+            best = best.max(kind);
+            score.occurrences += count;
+        }
+        score.matches += best as u32;
+    }
+
+    score
+}
+
+/// The best match of `term` in `field`, and the number of times it
+/// appeared.
+fn term_match(field: &str, term: &str) -> (MatchKind, u32) {
+    if field.trim() == term {
+        return (MatchKind::Field, 1);
+    }
+
+    let mut best = MatchKind::None;
+    let mut count = 0;
+    for (start, _) in field.match_indices(term) {
+        count += 1;
+        let before = field[..start].chars().next_back();
+        let after = field[start + term.len()..].chars().next();
+        let is_boundary = |c: Option<char>| c.map_or(true, |c| !c.is_alphanumeric());
+        let kind = if is_boundary(before) && is_boundary(after) {
+            MatchKind::Word
+        } else {
+            MatchKind::Substring
+        };
+        best = best.max(kind);
+    }
+
+    (best, count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::search::parser::parse;
+
+    #[test]
+    fn scoring() {
+        assert_eq!(term_match("dog", "dog"), (MatchKind::Field, 1));
+        assert_eq!(term_match(" dog ", "dog"), (MatchKind::Field, 1));
+        assert_eq!(term_match("a dog, a dog", "dog"), (MatchKind::Word, 2));
+        assert_eq!(term_match("dogma", "dog"), (MatchKind::Substring, 1));
+        assert_eq!(term_match("cat", "dog"), (MatchKind::None, 0));
+
+        let terms = vec!["dog".to_string()];
+        assert!(note_score("<b>dog</b>\x1fx", &terms) > note_score("hotdog dog", &terms));
+        assert!(note_score("hotdog dog", &terms) > note_score("hot dog", &terms));
+        assert!(note_score("hot dog", &terms) > note_score("hotdogs", &terms));
+        assert!(note_score("hotdogs", &terms) > note_score("cat", &terms));
+
+        let node = Node::Group(parse("Dog* -cat (deck:x or fish)").unwrap());
+        assert_eq!(search_terms(&node), vec!["dog", "fish"]);
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use super::parser::parse;
+use crate::collection::RequestContext;
+use crate::err::{AnkiError, Result};
+use std::collections::HashMap;
+use unicase::UniCase;
+
+/// The config key the browser has always stored saved searches under.
+const SAVED_SEARCHES_KEY: &str = "savedFilters";
+
+impl RequestContext<'_> {
+    /// All saved searches as (name, search), ordered by name.
+    pub(crate) fn saved_searches(&self) -> Result<Vec<(String, String)>> {
+        let mut searches: Vec<_> = self.saved_search_map()?.into_iter().collect();
+        searches.sort_by(|a, b| UniCase::new(&a.0).cmp(&UniCase::new(&b.0)));
+        Ok(searches)
+    }
+
+    /// Add a saved search, or replace an existing one with the same name.
+    /// The search must be valid.
+    pub(crate) fn save_search(&self, name: &str, search: &str) -> Result<()> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(AnkiError::invalid_input("saved search name is empty"));
+        }
+        parse(search)?;
+
+        let mut searches = self.saved_search_map()?;
+        searches.insert(name.into(), search.into());
+        self.storage.set_config_value(SAVED_SEARCHES_KEY, &searches)
+    }
+
+    /// Remove a saved search. Unknown names are ignored.
+    pub(crate) fn remove_saved_search(&self, name: &str) -> Result<()> {
+        let mut searches = self.saved_search_map()?;
+        if searches.remove(name).is_some() {
+            self.storage
+                .set_config_value(SAVED_SEARCHES_KEY, &searches)?;
+        }
+        Ok(())
+    }
+
+    fn saved_search_map(&self) -> Result<HashMap<String, String>> {
+        Ok(self
+            .storage
+            .get_config_value(SAVED_SEARCHES_KEY)?
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::collection::test::open_test_collection;
+    use crate::err::{AnkiError, Result};
+
+    #[test]
+    fn saved_searches() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            assert!(ctx.saved_searches()?.is_empty());
+            ctx.save_search("b", "deck:foo")?;
+            ctx.save_search("A", "is:due")?;
+            ctx.save_search("c", "tag:x")?;
+            ctx.save_search("b", "deck:bar")?;
+            assert_eq!(
+                ctx.saved_searches()?,
+                vec![
+                    ("A".to_string(), "is:due".to_string()),
+                    ("b".into(), "deck:bar".into()),
+                    ("c".into(), "tag:x".into()),
+                ]
+            );
+
+            // invalid searches and names are rejected
+            assert!(matches!(
+                ctx.save_search("d", "flag:5"),
+                Err(AnkiError::InvalidInput { .. })
+            ));
+            assert!(ctx.save_search(" ", "is:due").is_err());
+            assert_eq!(ctx.saved_searches()?.len(), 3);
+
+            ctx.remove_saved_search("c")?;
+            ctx.remove_saved_search("missing")?;
+            assert_eq!(ctx.saved_searches()?.len(), 2);
+
+            // the rest of the config is untouched
+            assert_eq!(ctx.storage.all_config()?.current_deck_id.0, 1);
+
+            Ok(())
+        })
+    }
+}
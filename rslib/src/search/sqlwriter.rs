@@ -1,35 +1,57 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use super::parser::{Node, PropertyKind, SearchNode, StateKind, TemplateKind};
+use super::parser::{parse, Node, PropertyKind, SearchNode, StateKind, TemplateKind};
 use crate::card::CardQueue;
 use crate::decks::child_ids;
 use crate::decks::get_deck;
 use crate::err::{AnkiError, Result};
 use crate::notes::field_checksum;
-use crate::notetypes::NoteTypeID;
+use crate::notetypes::{NoteType, NoteTypeID};
 use crate::text::matches_wildcard;
 use crate::text::without_combining;
 use crate::{collection::RequestContext, text::strip_html_preserving_image_filenames};
+use regex::Regex;
+use std::collections::HashMap;
 use std::fmt::Write;
 
 struct SqlWriter<'a, 'b> {
     req: &'a mut RequestContext<'b>,
     sql: String,
     args: Vec<String>,
+    /// If true, text searches match against fields with cloze markup removed.
+    strip_clozes: bool,
 }
 
-pub(super) fn node_to_sql(req: &mut RequestContext, node: &Node) -> Result<(String, Vec<String>)> {
-    let mut sctx = SqlWriter::new(req);
+pub(super) fn node_to_sql(
+    req: &mut RequestContext,
+    node: &Node,
+    strip_clozes: bool,
+) -> Result<(String, Vec<String>)> {
+    let mut sctx = SqlWriter::new(req, strip_clozes);
     sctx.write_node_to_sql(&node)?;
     Ok((sctx.sql, sctx.args))
 }
 
 impl SqlWriter<'_, '_> {
-    fn new<'a, 'b>(req: &'a mut RequestContext<'b>) -> SqlWriter<'a, 'b> {
+    fn new<'a, 'b>(req: &'a mut RequestContext<'b>, strip_clozes: bool) -> SqlWriter<'a, 'b> {
         let sql = String::new();
         let args = vec![];
-        SqlWriter { req, sql, args }
+        SqlWriter {
+            req,
+            sql,
+            args,
+            strip_clozes,
+        }
+    }
+
+    /// The note fields column, with cloze markup removed if requested.
+    fn fields_column(&self) -> &'static str {
+        if self.strip_clozes {
+            "coalesce(strip_clozes(n.flds), n.flds)"
+        } else {
+            "n.flds"
+        }
     }
 
     fn write_node_to_sql(&mut self, node: &Node) -> Result<()> {
@@ -60,7 +82,8 @@ impl SqlWriter<'_, '_> {
             }
             SearchNode::AddedInDays(days) => self.write_added(*days)?,
             SearchNode::CardTemplate(template) => self.write_template(template)?,
-            SearchNode::Deck(deck) => self.write_deck(deck.as_ref())?,
+            SearchNode::Deck(deck) => self.write_deck(deck.as_ref(), true)?,
+            SearchNode::DeckWithoutChildren(deck) => self.write_deck(deck.as_ref(), false)?,
             SearchNode::NoteTypeID(ntid) => {
                 write!(self.sql, "n.mid = {}", ntid).unwrap();
             }
@@ -80,7 +103,7 @@ impl SqlWriter<'_, '_> {
             }
             SearchNode::Property { operator, kind } => self.write_prop(operator, kind)?,
             SearchNode::WholeCollection => write!(self.sql, "true").unwrap(),
-            SearchNode::Regex(re) => self.write_regex(re.as_ref()),
+            SearchNode::Regex(re) => self.write_regex(re.as_ref())?,
             SearchNode::NoCombining(text) => self.write_no_combining(text.as_ref()),
         };
         Ok(())
@@ -92,8 +115,9 @@ impl SqlWriter<'_, '_> {
         self.args.push(text);
         write!(
             self.sql,
-            "(n.sfld like ?{n} escape '\\' or n.flds like ?{n} escape '\\')",
+            "(n.sfld like ?{n} escape '\\' or {flds} like ?{n} escape '\\')",
             n = self.args.len(),
+            flds = self.fields_column(),
         )
         .unwrap();
     }
@@ -105,9 +129,10 @@ impl SqlWriter<'_, '_> {
             self.sql,
             concat!(
                 "(coalesce(without_combining(cast(n.sfld as text)), n.sfld) like ?{n} escape '\\' ",
-                "or coalesce(without_combining(n.flds), n.flds) like ?{n} escape '\\')"
+                "or coalesce(without_combining({flds}), {flds}) like ?{n} escape '\\')"
             ),
             n = self.args.len(),
+            flds = self.fields_column(),
         )
         .unwrap();
     }
@@ -121,9 +146,15 @@ impl SqlWriter<'_, '_> {
                 write!(self.sql, "true").unwrap();
             }
             text => {
-                let tag = format!("% {} %", text.replace('*', "%"));
-                write!(self.sql, "n.tags like ? escape '\\'").unwrap();
-                self.args.push(tag);
+                // match the tag itself, or any tag nested under it
+                let tag = text.replace('*', "%");
+                write!(
+                    self.sql,
+                    "(n.tags like ? escape '\\' or n.tags like ? escape '\\')"
+                )
+                .unwrap();
+                self.args.push(format!("% {} %", tag));
+                self.args.push(format!("% {}::%", tag));
             }
         }
     }
@@ -206,7 +237,7 @@ impl SqlWriter<'_, '_> {
         Ok(())
     }
 
-    fn write_deck(&mut self, deck: &str) -> Result<()> {
+    fn write_deck(&mut self, deck: &str, include_children: bool) -> Result<()> {
         match deck {
             "*" => write!(self.sql, "true").unwrap(),
             "filtered" => write!(self.sql, "c.odid > 0").unwrap(),
@@ -223,16 +254,20 @@ impl SqlWriter<'_, '_> {
                     let mut dids_with_children = vec![config.current_deck_id];
                     let current = get_deck(&all_decks, config.current_deck_id)
                         .ok_or_else(|| AnkiError::invalid_input("invalid current deck"))?;
-                    for child_did in child_ids(&all_decks, &current.name) {
-                        dids_with_children.push(child_did);
+                    if include_children {
+                        for child_did in child_ids(&all_decks, &current.name) {
+                            dids_with_children.push(child_did);
+                        }
                     }
                     dids_with_children
                 } else {
                     let mut dids_with_children = vec![];
                     for deck in all_decks.iter().filter(|d| matches_wildcard(&d.name, deck)) {
                         dids_with_children.push(deck.id);
-                        for child_id in child_ids(&all_decks, &deck.name) {
-                            dids_with_children.push(child_id);
+                        if include_children {
+                            for child_id in child_ids(&all_decks, &deck.name) {
+                                dids_with_children.push(child_id);
+                            }
                         }
                     }
                     dids_with_children
@@ -296,19 +331,7 @@ impl SqlWriter<'_, '_> {
 
     fn write_single_field(&mut self, field_name: &str, val: &str, is_re: bool) -> Result<()> {
         let note_types = self.req.storage.all_note_types()?;
-
-        let mut field_map = vec![];
-        for nt in note_types.values() {
-            for field in &nt.fields {
-                if matches_wildcard(&field.name, field_name) {
-                    field_map.push((nt.id, field.ord));
-                }
-            }
-        }
-
-        // for now, sort the map for the benefit of unit tests
-        field_map.sort();
-
+        let field_map = matching_fields(&note_types, field_name);
         if field_map.is_empty() {
             write!(self.sql, "false").unwrap();
             return Ok(());
@@ -317,7 +340,7 @@ impl SqlWriter<'_, '_> {
         let cmp;
         if is_re {
             cmp = "regexp";
-            self.args.push(format!("(?i){}", val));
+            self.args.push(regex_arg(val)?);
         } else {
             cmp = "like";
             self.args.push(val.replace('*', "%"));
@@ -328,8 +351,9 @@ impl SqlWriter<'_, '_> {
             .iter()
             .map(|(ntid, ord)| {
                 format!(
-                    "(n.mid = {mid} and field_at_index(n.flds, {ord}) {cmp} ?{n})",
+                    "(n.mid = {mid} and field_at_index({flds}, {ord}) {cmp} ?{n})",
                     mid = ntid,
+                    flds = self.fields_column(),
                     ord = ord,
                     cmp = cmp,
                     n = arg_idx
@@ -360,14 +384,71 @@ impl SqlWriter<'_, '_> {
         Ok(())
     }
 
-    fn write_regex(&mut self, word: &str) {
-        self.sql.push_str("n.flds regexp ?");
-        self.args.push(format!(r"(?i){}", word));
+    fn write_regex(&mut self, word: &str) -> Result<()> {
+        write!(self.sql, "regexp_fields(?, {})", self.fields_column()).unwrap();
+        self.args.push(regex_arg(word)?);
+        Ok(())
     }
 }
 
+/// A regex that matches case-insensitively, unless the pattern turns
+/// that off with (?-i).
+fn regex_arg(pattern: &str) -> Result<String> {
+    let re = format!("(?i){}", pattern);
+    Regex::new(&re).map_err(|err| AnkiError::invalid_input(format!("invalid regex: {}", err)))?;
+    Ok(re)
+}
+
+/// The (note type, field ordinal) of each field matching `field_name`.
+fn matching_fields(
+    note_types: &HashMap<NoteTypeID, NoteType>,
+    field_name: &str,
+) -> Vec<(NoteTypeID, u16)> {
+    let mut field_map = vec![];
+    for nt in note_types.values() {
+        for field in &nt.fields {
+            if matches_wildcard(&field.name, field_name) {
+                field_map.push((nt.id, field.ord));
+            }
+        }
+    }
+
+    // for now, sort the map for the benefit of unit tests
+    field_map.sort();
+    field_map
+}
+
+/// Return an error if the search limits a term to a field that no note type
+/// has. Such terms otherwise match nothing.
+pub(crate) fn check_search_fields(req: &RequestContext, search: &str) -> Result<()> {
+    let node = Node::Group(parse(search)?);
+    let note_types = req.storage.all_note_types()?;
+    check_node_fields(&node, &note_types)
+}
+
+fn check_node_fields(node: &Node, note_types: &HashMap<NoteTypeID, NoteType>) -> Result<()> {
+    match node {
+        Node::Group(nodes) => {
+            for node in nodes {
+                check_node_fields(node, note_types)?;
+            }
+        }
+        Node::Not(node) => check_node_fields(node, note_types)?,
+        Node::Search(SearchNode::SingleField { field, .. }) => {
+            if matching_fields(note_types, field).is_empty() {
+                return Err(AnkiError::invalid_input(format!(
+                    "unknown field: {}",
+                    field
+                )));
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
 // Write a list of IDs as '(x,y,...)' into the provided string.
-fn ids_to_string<T>(buf: &mut String, ids: &[T])
+pub(crate) fn ids_to_string<T>(buf: &mut String, ids: &[T])
 where
     T: std::fmt::Display,
 {
@@ -384,9 +465,7 @@ where
 #[cfg(test)]
 mod test {
     use super::ids_to_string;
-    use crate::{collection::open_collection, i18n::I18n, log};
-    use std::{fs, path::PathBuf};
-    use tempfile::tempdir;
+    use crate::collection::test::open_test_collection;
 
     #[test]
     fn ids_string() {
@@ -411,27 +490,12 @@ mod test {
     // shortcut
     fn s(req: &mut RequestContext, search: &str) -> (String, Vec<String>) {
         let node = Node::Group(parse(search).unwrap());
-        node_to_sql(req, &node).unwrap()
+        node_to_sql(req, &node, false).unwrap()
     }
 
     #[test]
     fn sql() -> Result<()> {
-        // re-use the mediacheck .anki2 file for now
-        use crate::media::check::test::MEDIACHECK_ANKI2;
-        let dir = tempdir().unwrap();
-        let col_path = dir.path().join("col.anki2");
-        fs::write(&col_path, MEDIACHECK_ANKI2).unwrap();
-
-        let i18n = I18n::new(&[""], "", log::terminal());
-        let col = open_collection(
-            &col_path,
-            &PathBuf::new(),
-            &PathBuf::new(),
-            false,
-            i18n,
-            log::terminal(),
-        )
-        .unwrap();
+        let (_dir, col) = open_test_collection().unwrap();
 
         col.with_ctx(|ctx| {
             // unqualified search
@@ -514,11 +578,17 @@ mod test {
             // tags
             assert_eq!(
                 s(ctx, "tag:one"),
-                ("(n.tags like ? escape '\\')".into(), vec!["% one %".into()])
+                (
+                    "((n.tags like ? escape '\\' or n.tags like ? escape '\\'))".into(),
+                    vec!["% one %".into(), "% one::%".into()]
+                )
             );
             assert_eq!(
                 s(ctx, "tag:o*e"),
-                ("(n.tags like ? escape '\\')".into(), vec!["% o%e %".into()])
+                (
+                    "((n.tags like ? escape '\\' or n.tags like ? escape '\\'))".into(),
+                    vec!["% o%e %".into(), "% o%e::%".into()]
+                )
             );
             assert_eq!(s(ctx, "tag:none"), ("(n.tags = '')".into(), vec![]));
             assert_eq!(s(ctx, "tag:*"), ("(true)".into(), vec![]));
@@ -570,7 +640,10 @@ mod test {
             // regex
             assert_eq!(
                 s(ctx, r"re:\bone"),
-                ("(n.flds regexp ?)".into(), vec![r"(?i)\bone".into()])
+                (
+                    "(regexp_fields(?, n.flds))".into(),
+                    vec![r"(?i)\bone".into()]
+                )
             );
 
             Ok(())
@@ -579,4 +652,182 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn cloze_search() -> Result<()> {
+        use crate::card::Card;
+        use crate::notes::NoteID;
+        use crate::search::search_notes;
+        let (_dir, col) = open_test_collection().unwrap();
+
+        col.with_ctx(|ctx| {
+            ctx.storage.db.execute_batch(
+                "insert into notes (id, guid, mid, mod, usn, tags, flds, sfld, csum, flags, data)
+                 values (1, '', 1581236385347, 0, 0, '', '{{c1::Paris}} is\x1f', '', 0, 0, '')",
+            )?;
+            let mut card = Card::default();
+            card.nid = NoteID(1);
+            ctx.storage.add_card(&mut card)?;
+
+            // the text only matches once the cloze markup is removed
+            assert_eq!(search_notes(ctx, "\"paris is\"", false)?, vec![]);
+            assert_eq!(search_notes(ctx, "\"paris is\"", true)?, vec![NoteID(1)]);
+            assert_eq!(search_notes(ctx, "front:paris*", true)?, vec![NoteID(1)]);
+
+            // the raw markup can still be searched
+            assert_eq!(search_notes(ctx, "{{c1", false)?, vec![NoteID(1)]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn nested_tags() -> Result<()> {
+        use crate::notes::NoteID;
+        use crate::search::search_notes;
+        let (_dir, col) = open_test_collection().unwrap();
+
+        col.with_ctx(|ctx| {
+            ctx.storage.db.execute_batch(
+                "update notes set tags = ' lang::es ' where id = 1581236386334;
+                 update notes set tags = ' lang::es::verbs ' where id = 1581236445532;
+                 update notes set tags = ' lang::esp other ' where id = 1581236461568;",
+            )?;
+            let mut search = |text| -> Result<Vec<NoteID>> {
+                let mut nids = search_notes(ctx, text, false)?;
+                nids.sort();
+                Ok(nids)
+            };
+            let (n1, n2, n3) = (
+                NoteID(1581236386334),
+                NoteID(1581236445532),
+                NoteID(1581236461568),
+            );
+
+            // a parent matches its children, but not a sibling sharing a prefix
+            assert_eq!(search("tag:lang::es")?, vec![n1, n2]);
+            assert_eq!(search("tag:Lang::ES::")?, vec![n1, n2]);
+            assert_eq!(search("tag:lang::es::verbs")?, vec![n2]);
+            assert_eq!(search("tag:lang")?, vec![n1, n2, n3]);
+            assert_eq!(search("tag:lang::es*")?, vec![n1, n2, n3]);
+            assert_eq!(search("tag:es")?, vec![]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn field_search() -> Result<()> {
+        use crate::card::Card;
+        use crate::notes::NoteID;
+        use crate::notetypes::NoteTypeID;
+        use crate::search::search_notes;
+        let (_dir, col) = open_test_collection().unwrap();
+
+        col.transact(None, |ctx| {
+            // a copy of Basic with an extra field, and a note using it
+            let ntid = ctx.clone_note_type(NoteTypeID(1581236385347), "Extended")?;
+            ctx.add_note_type_field(ntid, "Extra")?;
+            ctx.storage.db.execute(
+                "insert into notes (id, guid, mid, mod, usn, tags, flds, sfld, csum, flags, data)
+                 values (1, '', ?, 0, 0, '', 'front\x1fhello\x1fhello', '', 0, 0, '')",
+                rusqlite::params![ntid],
+            )?;
+            let mut card = Card::default();
+            card.nid = NoteID(1);
+            ctx.storage.add_card(&mut card)?;
+            ctx.storage.db.execute_batch(
+                "update notes set flds = 'front' || char(31) || 'hello' where id = 1581236445532",
+            )?;
+
+            // the field only exists in the new note type
+            assert_eq!(search_notes(ctx, "extra:hello", false)?, vec![NoteID(1)]);
+            let mut nids = search_notes(ctx, "back:hello", false)?;
+            nids.sort();
+            assert_eq!(nids, vec![NoteID(1), NoteID(1581236445532)]);
+            assert_eq!(search_notes(ctx, "extra:front", false)?, vec![]);
+
+            // unknown fields match nothing, or can be reported
+            assert_eq!(search_notes(ctx, "missing:hello", false)?, vec![]);
+            assert!(check_search_fields(ctx, "extra:hello back:hello").is_ok());
+            assert!(check_search_fields(ctx, "hello (-missing:hello)").is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn regex_search() -> Result<()> {
+        use crate::notes::NoteID;
+        use crate::search::search_notes;
+        let (_dir, col) = open_test_collection().unwrap();
+
+        col.with_ctx(|ctx| {
+            // fields are "another note" and "with no media"
+            let nid = vec![NoteID(1581236445532)];
+
+            // anchors apply to each field
+            assert_eq!(search_notes(ctx, "re:^with", false)?, nid);
+            assert_eq!(search_notes(ctx, "re:note$", false)?, nid);
+            assert_eq!(search_notes(ctx, r#""re:^another note$""#, false)?, nid);
+            assert_eq!(search_notes(ctx, "re:^note", false)?, vec![]);
+            assert_eq!(search_notes(ctx, "back:re:^with.*media$", false)?, nid);
+
+            // case is ignored unless turned back on
+            assert_eq!(search_notes(ctx, "re:^WITH", false)?, nid);
+            assert_eq!(search_notes(ctx, r#""re:(?-i)^WITH""#, false)?, vec![]);
+
+            assert!(matches!(
+                search_notes(ctx, r#""re:(""#, false),
+                Err(AnkiError::InvalidInput { .. })
+            ));
+            assert!(search_notes(ctx, "back:re:[", false).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn deck_search() -> Result<()> {
+        use crate::card::CardID;
+        use crate::search::{search_cards, SortMode};
+        let (_dir, col) = open_test_collection().unwrap();
+
+        col.transact(None, |ctx| {
+            // a parent, its child, and a sibling sharing the parent's prefix
+            ctx.storage.db.execute_batch(
+                r#"update col set decks = '{
+                    "1": {"id": 1, "name": "Default", "conf": 1},
+                    "2": {"id": 2, "name": "Lang", "conf": 1},
+                    "3": {"id": 3, "name": "Lang::Spanish Verbs", "conf": 1},
+                    "4": {"id": 4, "name": "Language", "conf": 1}
+                }';
+                update cards set did = 2 where id = 1581236445527;
+                update cards set did = 3 where id = 1581236461565;
+                update cards set did = 4 where id = 1581236488474;"#,
+            )?;
+            ctx.storage.set_config_value("curDeck", &2)?;
+            let mut search = |text: &str| -> Result<Vec<CardID>> {
+                let mut cids = search_cards(ctx, text, SortMode::NoOrder, false)?;
+                cids.sort();
+                Ok(cids)
+            };
+            let (c1, c2, c3) = (
+                CardID(1581236445527),
+                CardID(1581236461565),
+                CardID(1581236488474),
+            );
+
+            assert_eq!(search("deck:lang")?, vec![c1, c2]);
+            assert_eq!(search("deck:=lang")?, vec![c1]);
+            assert_eq!(search("-deck:lang")?, vec![c3]);
+            assert_eq!(search("-deck:=lang")?, vec![c2, c3]);
+            assert_eq!(search(r#"deck:"lang::spanish verbs""#)?, vec![c2]);
+            assert_eq!(search("deck:lang::*")?, vec![c2]);
+            assert_eq!(search("deck:current")?, vec![c1, c2]);
+            assert_eq!(search("deck:=current")?, vec![c1]);
+
+            Ok(())
+        })
+    }
 }
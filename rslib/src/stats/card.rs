@@ -0,0 +1,190 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::card::{CardID, CardQueue, CardType};
+use crate::collection::RequestContext;
+use crate::decks::DeckID;
+use crate::err::{AnkiError, Result};
+use crate::notes::{get_note, NoteID};
+use crate::sched::cutoff::next_day_at_after_days;
+use crate::timestamp::TimestampSecs;
+
+/// When a card will next be shown.
+#[derive(Debug, PartialEq)]
+pub enum CardDue {
+    /// The start of the day the card is due, or the exact time for cards in
+    /// (re)learning.
+    Time(TimestampSecs),
+    /// The card's position in the new queue.
+    Position(i32),
+}
+
+/// Everything the card info screen displays, gathered from the card, its
+/// note, deck and review history.
+#[derive(Debug)]
+pub struct CardStats {
+    pub card_id: CardID,
+    pub note_id: NoteID,
+    pub deck: String,
+    /// Set when the card is in a filtered deck.
+    pub original_deck: Option<String>,
+    pub notetype: String,
+    pub template: String,
+    pub added: TimestampSecs,
+    pub first_review: Option<TimestampSecs>,
+    pub latest_review: Option<TimestampSecs>,
+    pub due: Option<CardDue>,
+    /// Interval in days, for cards that have graduated.
+    pub interval: Option<u32>,
+    /// Ease in permille, for cards that have graduated.
+    pub ease: Option<u16>,
+    pub reviews: u32,
+    pub lapses: u32,
+    /// Time spent answering, over all reviews.
+    pub total_secs: f32,
+    pub average_secs: Option<f32>,
+}
+
+impl RequestContext<'_> {
+    pub(crate) fn card_stats(&mut self, cid: CardID) -> Result<CardStats> {
+        let card = self
+            .storage
+            .get_card(cid)?
+            .ok_or_else(|| AnkiError::invalid_input(format!("card {} not found", cid.0)))?;
+        let note = get_note(self.storage.db, card.nid)?
+            .ok_or_else(|| AnkiError::invalid_input(format!("note {} not found", card.nid.0)))?;
+        let note_types = self.storage.all_note_types()?;
+        let (notetype, template) = match note_types.get(&note.ntid) {
+            Some(nt) => (nt.name.clone(), nt.template_name(card.ord).to_string()),
+            None => (String::new(), String::new()),
+        };
+
+        let decks = self.storage.all_decks()?;
+        let deck_name = |did: DeckID| decks.get(&did).map(|d| d.name.clone());
+        let deck = deck_name(card.did).unwrap_or_default();
+        let original_deck = if card.odid.0 != 0 {
+            deck_name(card.odid)
+        } else {
+            None
+        };
+
+        let revlog = self.storage.card_revlog(cid)?;
+        let total_millis: u64 = revlog.iter().map(|e| u64::from(e.taken_millis)).sum();
+        let total_secs = total_millis as f32 / 1000.0;
+        let average_secs = if revlog.is_empty() {
+            None
+        } else {
+            Some(total_secs / revlog.len() as f32)
+        };
+
+        // cards in a filtered deck keep their original due in odue
+        let due = if card.odid.0 != 0 && card.odue != 0 {
+            card.odue
+        } else {
+            card.due
+        };
+        let due = match (card.queue, card.ctype) {
+            (CardQueue::New, _) | (_, CardType::New) => Some(CardDue::Position(due)),
+            (CardQueue::Learn, _) | (CardQueue::PreviewRepeat, _) => {
+                Some(CardDue::Time(TimestampSecs(i64::from(due))))
+            }
+            (CardQueue::Review, _)
+            | (CardQueue::DayLearn, _)
+            | (_, CardType::Review)
+            | (_, CardType::Relearn) => Some(CardDue::Time(self.day_to_timestamp(due)?)),
+            // learning card that has been suspended or buried
+            _ => None,
+        };
+
+        let graduated = matches!(card.ctype, CardType::Review | CardType::Relearn);
+
+        Ok(CardStats {
+            card_id: card.id,
+            note_id: card.nid,
+            deck,
+            original_deck,
+            notetype,
+            template,
+            added: TimestampSecs(card.id.0 / 1000),
+            first_review: revlog.first().map(|e| TimestampSecs(e.id.0 / 1000)),
+            latest_review: revlog.last().map(|e| TimestampSecs(e.id.0 / 1000)),
+            due,
+            interval: if graduated { Some(card.ivl) } else { None },
+            ease: if graduated { Some(card.factor) } else { None },
+            reviews: card.reps,
+            lapses: card.lapses,
+            total_secs,
+            average_secs,
+        })
+    }
+
+    /// The rollover at the start of the given day, where day is a number of
+    /// days since the collection was created.
+    fn day_to_timestamp(&mut self, day: i32) -> Result<TimestampSecs> {
+        let timing = self.storage.timing_today()?;
+        let days_from_tomorrow = day - timing.days_elapsed as i32 - 1;
+        Ok(TimestampSecs(next_day_at_after_days(
+            timing.next_day_at,
+            days_from_tomorrow,
+            |stamp| self.storage.local_minutes_west(stamp),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CardDue;
+    use crate::card::CardID;
+    use crate::collection::test::open_test_collection;
+    use crate::err::Result;
+    use crate::timestamp::TimestampSecs;
+
+    #[test]
+    fn card_stats() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.with_ctx(|ctx| {
+            // a card in learning, with one review
+            let stats = ctx.card_stats(CardID(1581236445527))?;
+            assert_eq!(stats.deck, "Default");
+            assert_eq!(stats.original_deck, None);
+            assert_eq!(stats.notetype, "Basic");
+            assert_eq!(stats.template, "Card 1");
+            assert_eq!(stats.added, TimestampSecs(1581236445));
+            assert_eq!(stats.first_review, Some(TimestampSecs(1581236491)));
+            assert_eq!(stats.latest_review, stats.first_review);
+            assert_eq!(stats.due, Some(CardDue::Time(TimestampSecs(1581236553))));
+            assert_eq!(stats.interval, None);
+            assert_eq!(stats.reviews, 1);
+            assert_eq!(stats.average_secs, Some(1.482));
+
+            // a new card
+            let stats = ctx.card_stats(CardID(1581236488474))?;
+            assert_eq!(stats.due, Some(CardDue::Position(3)));
+            assert_eq!(stats.first_review, None);
+            assert_eq!(stats.average_secs, None);
+            assert_eq!(stats.total_secs, 0.0);
+
+            // a review card, due tomorrow
+            let mut card = ctx.storage.get_card(CardID(1581236488474))?.unwrap();
+            let timing = ctx.storage.timing_today()?;
+            card.ctype = crate::card::CardType::Review;
+            card.queue = crate::card::CardQueue::Review;
+            card.due = timing.days_elapsed as i32 + 1;
+            card.ivl = 3;
+            card.factor = 2500;
+            ctx.storage.update_card(&card)?;
+            let stats = ctx.card_stats(card.id)?;
+            assert_eq!(
+                stats.due,
+                Some(CardDue::Time(TimestampSecs(timing.next_day_at)))
+            );
+            assert_eq!(stats.interval, Some(3));
+            assert_eq!(stats.ease, Some(2500));
+
+            assert!(ctx.card_stats(CardID(1)).is_err());
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,32 @@
+-- the casts are required as Anki didn't prevent add-ons from
+-- storing strings or floats in columns before
+select
+  nid,
+  did,
+  ord,
+  cast(mod as integer),
+  usn,
+  type,
+  queue,
+  due,
+  cast(ivl as integer),
+  factor,
+  reps,
+  lapses,
+  left,
+  odue,
+  odid,
+  flags,
+  data,
+  id
+from cards
+where
+  (
+    usn > ?1
+    or usn = -1
+  )
+  and id > ?2
+order by
+  id
+limit
+  ?3
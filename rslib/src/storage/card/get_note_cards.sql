@@ -0,0 +1,28 @@
+-- the casts are required as Anki didn't prevent add-ons from
+-- storing strings or floats in columns before
+select
+  c.nid,
+  c.did,
+  c.ord,
+  cast(c.mod as integer),
+  c.usn,
+  c.type,
+  c.queue,
+  c.due,
+  cast(c.ivl as integer),
+  c.factor,
+  c.reps,
+  c.lapses,
+  c.left,
+  c.odue,
+  c.odid,
+  c.flags,
+  c.data,
+  c.id,
+  n.mid
+from cards c
+join notes n on n.id = c.nid
+where
+  c.nid = ?
+order by
+  c.ord
\ No newline at end of file
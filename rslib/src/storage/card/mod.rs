@@ -2,13 +2,19 @@
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
 use super::sqlite::CachedStatementKind;
-use crate::card::{Card, CardID, CardQueue, CardType};
+use crate::card::{invalid_card_reason, Card, CardID, CardQueue, CardType, InvalidCardReason};
+use crate::decks::DeckID;
 use crate::err::Result;
-use crate::timestamp::TimestampMillis;
-use rusqlite::params;
+use crate::log::{warn, Logger};
+use crate::notes::NoteID;
+use crate::notetypes::NoteTypeID;
+use crate::search::ids_to_string;
+use crate::timestamp::{TimestampMillis, TimestampSecs};
+use crate::types::Usn;
+use rusqlite::{params, NO_PARAMS};
 use rusqlite::{
     types::{FromSql, FromSqlError, ValueRef},
-    OptionalExtension,
+    OptionalExtension, Row,
 };
 use std::convert::TryFrom;
 
@@ -32,40 +38,368 @@ impl FromSql for CardQueue {
     }
 }
 
+/// Build a card from the columns in get_card.sql. An out-of-range type or
+/// queue is an error, unless a logger is provided in `lenient_log`; see
+/// get_card_lenient().
+fn row_to_card(row: &Row, id: CardID, lenient_log: Option<&Logger>) -> rusqlite::Result<Card> {
+    let (ctype, queue) = match lenient_log {
+        None => (row.get(5)?, row.get(6)?),
+        Some(log) => (
+            lenient_card_type(row.get(5)?, id, log),
+            lenient_card_queue(row.get(6)?, id, log),
+        ),
+    };
+    Ok(Card {
+        id,
+        nid: row.get(0)?,
+        did: row.get(1)?,
+        ord: row.get(2)?,
+        mtime: row.get(3)?,
+        usn: row.get(4)?,
+        ctype,
+        queue,
+        due: row.get(7)?,
+        ivl: row.get(8)?,
+        factor: row.get(9)?,
+        reps: row.get(10)?,
+        lapses: row.get(11)?,
+        left: row.get(12)?,
+        odue: row.get(13)?,
+        odid: row.get(14)?,
+        flags: row.get(15)?,
+        data: row.get(16)?,
+    })
+}
+
+fn lenient_card_type(ctype: i64, cid: CardID, log: &Logger) -> CardType {
+    u8::try_from(ctype)
+        .ok()
+        .and_then(|n| CardType::try_from(n).ok())
+        .unwrap_or_else(|| {
+            warn!(log, "invalid card type replaced with new";
+                "cid" => cid.0,
+                "type" => ctype);
+            CardType::New
+        })
+}
+
+fn lenient_card_queue(queue: i64, cid: CardID, log: &Logger) -> CardQueue {
+    i8::try_from(queue)
+        .ok()
+        .and_then(|n| CardQueue::try_from(n).ok())
+        .unwrap_or_else(|| {
+            warn!(log, "invalid card queue replaced with new";
+                "cid" => cid.0,
+                "queue" => queue);
+            CardQueue::New
+        })
+}
+
 impl super::StorageContext<'_> {
     pub fn get_card(&mut self, cid: CardID) -> Result<Option<Card>> {
+        self.get_card_inner(cid, None)
+    }
+
+    /// Like get_card(), but an out-of-range type or queue is replaced with
+    /// New and a warning is logged, so that repair tools can load and fix
+    /// bad cards.
+    pub(crate) fn get_card_lenient(&mut self, cid: CardID, log: &Logger) -> Result<Option<Card>> {
+        self.get_card_inner(cid, Some(log))
+    }
+
+    fn get_card_inner(
+        &mut self,
+        cid: CardID,
+        lenient_log: Option<&Logger>,
+    ) -> Result<Option<Card>> {
         self.with_cached_stmt(
             CachedStatementKind::GetCard,
             include_str!("get_card.sql"),
             |stmt| {
-                stmt.query_row(params![cid], |row| {
-                    Ok(Card {
-                        id: cid,
-                        nid: row.get(0)?,
-                        did: row.get(1)?,
-                        ord: row.get(2)?,
-                        mtime: row.get(3)?,
-                        usn: row.get(4)?,
-                        ctype: row.get(5)?,
-                        queue: row.get(6)?,
-                        due: row.get(7)?,
-                        ivl: row.get(8)?,
-                        factor: row.get(9)?,
-                        reps: row.get(10)?,
-                        lapses: row.get(11)?,
-                        left: row.get(12)?,
-                        odue: row.get(13)?,
-                        odid: row.get(14)?,
-                        flags: row.get(15)?,
-                        data: row.get(16)?,
-                    })
-                })
-                .optional()
-                .map_err(Into::into)
+                stmt.query_row(params![cid], |row| row_to_card(row, cid, lenient_log))
+                    .optional()
+                    .map_err(Into::into)
             },
         )
     }
 
+    /// All cards of the provided note in ordinal order, and the note's
+    /// note type.
+    pub(crate) fn note_cards(&mut self, nid: NoteID) -> Result<(Vec<Card>, Option<NoteTypeID>)> {
+        let mut ntid = None;
+        let cards = self.with_cached_stmt(
+            CachedStatementKind::GetNoteCards,
+            include_str!("get_note_cards.sql"),
+            |stmt| {
+                stmt.query_and_then(params![nid], |row| -> Result<_> {
+                    ntid = Some(row.get(18)?);
+                    Ok(row_to_card(row, row.get(17)?, None)?)
+                })?
+                .collect()
+            },
+        )?;
+        Ok((cards, ntid))
+    }
+
+    /// Up to `limit` cards with an id greater than `after` that were
+    /// modified after the provided USN or have not yet been synced, in id
+    /// order.
+    pub(crate) fn cards_since_usn(
+        &mut self,
+        usn: Usn,
+        after: CardID,
+        limit: u32,
+    ) -> Result<Vec<Card>> {
+        self.db
+            .prepare_cached(include_str!("cards_since_usn.sql"))?
+            .query_and_then(params![usn, after, limit], |row| -> Result<_> {
+                Ok(row_to_card(row, row.get(17)?, None)?)
+            })?
+            .collect()
+    }
+
+    /// Ids of cards deleted after the provided USN, or not yet synced.
+    pub(crate) fn removed_cards_since_usn(&mut self, usn: Usn) -> Result<Vec<CardID>> {
+        self.db
+            .prepare_cached("select oid from graves where type = 0 and (usn > ? or usn = -1)")?
+            .query_and_then(params![usn], |row| -> Result<_> { Ok(row.get(0)?) })?
+            .collect()
+    }
+
+    /// Cards with a type or queue out of range, or other inconsistent
+    /// scheduling state, in id order. The columns are read without decoding
+    /// them, so this works on cards get_card() would fail to load.
+    pub(crate) fn invalid_cards(&self) -> Result<Vec<(CardID, InvalidCardReason)>> {
+        let mut stmt = self
+            .db
+            .prepare("select id, type, queue, ivl from cards order by id")?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+        let mut invalid = vec![];
+        while let Some(row) = rows.next()? {
+            let reason = invalid_card_reason(
+                row.get_raw(1).as_i64().ok(),
+                row.get_raw(2).as_i64().ok(),
+                row.get_raw(3).as_i64().ok(),
+            );
+            if let Some(reason) = reason {
+                invalid.push((row.get(0)?, reason));
+            }
+        }
+
+        Ok(invalid)
+    }
+
+    /// The number of notes of the note type whose only cards use the given
+    /// template.
+    pub(crate) fn notes_only_using_template(&self, ntid: NoteTypeID, ord: u16) -> Result<u32> {
+        self.db
+            .prepare_cached(include_str!("notes_only_using_template.sql"))?
+            .query_row(params![ntid, ord], |row| row.get(0))
+            .map_err(Into::into)
+    }
+
+    pub(crate) fn template_card_count(&self, ntid: NoteTypeID, ord: u16) -> Result<u32> {
+        self.db
+            .prepare_cached(
+                "select count() from cards
+                where ord = ?2 and nid in (select id from notes where mid = ?1)",
+            )?
+            .query_row(params![ntid, ord], |row| row.get(0))
+            .map_err(Into::into)
+    }
+
+    /// Delete the cards of the given template, recording their removal for
+    /// the next sync. Returns the number of cards removed.
+    pub(crate) fn remove_template_cards(
+        &self,
+        ntid: NoteTypeID,
+        ord: u16,
+        usn: Usn,
+    ) -> Result<usize> {
+        self.db.execute(
+            "insert into graves (usn, oid, type)
+            select ?3, id, 0 from cards
+            where ord = ?2 and nid in (select id from notes where mid = ?1)",
+            params![ntid, ord, usn],
+        )?;
+        let removed = self.db.execute(
+            "delete from cards
+            where ord = ?2 and nid in (select id from notes where mid = ?1)",
+            params![ntid, ord],
+        )?;
+        Ok(removed)
+    }
+
+    /// After a template has been removed, move the cards of later templates
+    /// down one ordinal.
+    pub(crate) fn shift_template_cards(&self, ntid: NoteTypeID, ord: u16, usn: Usn) -> Result<()> {
+        self.db.execute(
+            "update cards set ord = ord - 1, usn = ?3, mod = ?4
+            where ord > ?2 and nid in (select id from notes where mid = ?1)",
+            params![ntid, ord, usn, TimestampSecs::now()],
+        )?;
+        Ok(())
+    }
+
+    /// The lowest due number of a new card at or after `start`, ignoring
+    /// the provided cards.
+    pub(crate) fn lowest_new_position_from(
+        &self,
+        start: i32,
+        excluding: &[CardID],
+    ) -> Result<Option<i32>> {
+        let mut sql =
+            "select min(due) from cards where due >= ? and type = ? and id not in ".to_string();
+        ids_to_string(&mut sql, excluding);
+        self.db
+            .query_row(&sql, params![start, CardType::New as u8], |row| row.get(0))
+            .map_err(Into::into)
+    }
+
+    /// Add `by` to the due number of queued new cards at or after `from`,
+    /// other than the provided cards.
+    pub(crate) fn shift_new_positions(
+        &self,
+        from: i32,
+        by: i32,
+        excluding: &[CardID],
+        usn: Usn,
+    ) -> Result<()> {
+        let mut sql = "update cards set mod = ?, usn = ?, due = due + ?
+            where due >= ? and queue = ? and id not in "
+            .to_string();
+        ids_to_string(&mut sql, excluding);
+        self.db.execute(
+            &sql,
+            params![TimestampSecs::now(), usn, by, from, CardQueue::New as i8],
+        )?;
+        Ok(())
+    }
+
+    /// Renumber the positions of new cards from 1, keeping their order.
+    /// Cards that shared a position, such as siblings, continue to share
+    /// one. New cards in a filtered deck have their original position
+    /// updated. Returns the number of cards changed, and the number of
+    /// positions used.
+    pub(crate) fn compact_new_positions(&self, usn: Usn) -> Result<(usize, u32)> {
+        let cards: Vec<(CardID, bool, i32)> = self
+            .db
+            .prepare(
+                "select id, odid != 0, (case when odid = 0 then due else odue end) as pos
+                from cards where type = ? order by pos, id",
+            )?
+            .query_map(params![CardType::New as u8], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mtime = TimestampSecs::now();
+        let mut update_due = self
+            .db
+            .prepare("update cards set due = ?, mod = ?, usn = ? where id = ?")?;
+        let mut update_odue = self
+            .db
+            .prepare("update cards set odue = ?, mod = ?, usn = ? where id = ?")?;
+        let mut changed = 0;
+        let mut last_old = None;
+        let mut new_pos = 0;
+        for (cid, filtered, old_pos) in cards {
+            if last_old != Some(old_pos) {
+                new_pos += 1;
+                last_old = Some(old_pos);
+            }
+            if old_pos != new_pos {
+                let stmt = if filtered {
+                    &mut update_odue
+                } else {
+                    &mut update_due
+                };
+                stmt.execute(params![new_pos, mtime, usn, cid])?;
+                changed += 1;
+            }
+        }
+
+        Ok((changed, new_pos as u32))
+    }
+
+    /// Up to `limit` cards in the provided decks and queues that are due
+    /// before `due_before`, with their decks, in due order. Cards due at the
+    /// same time are ordered by template, then creation.
+    pub(crate) fn due_cards(
+        &self,
+        dids: &[DeckID],
+        queues: &[CardQueue],
+        due_before: i64,
+        limit: u32,
+    ) -> Result<Vec<(CardID, DeckID)>> {
+        let mut sql = "select id, did from cards where due < ? and did in ".to_string();
+        ids_to_string(&mut sql, dids);
+        sql.push_str(" and queue in ");
+        let queues: Vec<_> = queues.iter().map(|q| *q as i8).collect();
+        ids_to_string(&mut sql, &queues);
+        sql.push_str(" order by due, ord, id limit ?");
+        self.db
+            .prepare(&sql)?
+            .query_map(params![due_before, limit], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(Into::into)
+    }
+
+    /// What remains to be studied in the provided decks, as (learning cards
+    /// due later today, when the first of them is due, reviews remaining,
+    /// new cards remaining, have buried cards, other decks have cards due
+    /// today).
+    pub(crate) fn congrats_counts(
+        &self,
+        dids: &[DeckID],
+        today: u32,
+        next_day_at: i64,
+    ) -> Result<(u32, Option<i64>, bool, bool, bool, bool)> {
+        let mut ids = String::new();
+        ids_to_string(&mut ids, dids);
+        // ?1 learning, ?2 review, ?3 day learning, ?4 new, ?5 and ?6 buried,
+        // ?7 the next day's cutoff, ?8 today
+        let sql = format!(
+            "select
+            coalesce(sum(queue = ?1 and due < ?7), 0),
+            min(case when queue = ?1 and due < ?7 then due end),
+            coalesce(max(queue in (?2, ?3) and due <= ?8), 0),
+            coalesce(max(queue = ?4), 0),
+            coalesce(max(queue in (?5, ?6)), 0),
+            exists(select 1 from cards where did not in {ids} and (
+                (queue = ?1 and due < ?7) or (queue in (?2, ?3) and due <= ?8)))
+            from cards where did in {ids}",
+            ids = ids
+        );
+        self.db
+            .query_row(
+                &sql,
+                params![
+                    CardQueue::Learn as i8,
+                    CardQueue::Review as i8,
+                    CardQueue::DayLearn as i8,
+                    CardQueue::New as i8,
+                    CardQueue::UserBuried as i8,
+                    CardQueue::SchedBuried as i8,
+                    next_day_at,
+                    today,
+                ],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                    ))
+                },
+            )
+            .map_err(Into::into)
+    }
+
     pub(crate) fn update_card(&mut self, card: &Card) -> Result<()> {
         self.with_cached_stmt(
             CachedStatementKind::UpdateCard,
@@ -132,7 +466,13 @@ impl super::StorageContext<'_> {
 
 #[cfg(test)]
 mod test {
-    use crate::{card::Card, storage::SqliteStorage};
+    use crate::{
+        card::{Card, CardID, InvalidCardReason},
+        notes::NoteID,
+        notetypes::NoteTypeID,
+        storage::SqliteStorage,
+        types::Usn,
+    };
     use std::path::Path;
 
     #[test]
@@ -145,4 +485,105 @@ mod test {
         ctx.add_card(&mut card).unwrap();
         assert_ne!(id1, card.id);
     }
+
+    #[test]
+    fn note_cards() {
+        let storage = SqliteStorage::open_or_create(Path::new(":memory:")).unwrap();
+        let mut ctx = storage.context(false);
+        ctx.db
+            .execute_batch(
+                "insert into notes (id, guid, mid, mod, usn, tags, flds, sfld, csum, flags, data)
+                 values (1, '', 5, 0, 0, '', '', '', 0, 0, '')",
+            )
+            .unwrap();
+        let mut card = Card::default();
+        card.nid = NoteID(1);
+        card.ord = 1;
+        ctx.add_card(&mut card).unwrap();
+        card.ord = 0;
+        ctx.add_card(&mut card).unwrap();
+
+        let (cards, ntid) = ctx.note_cards(NoteID(1)).unwrap();
+        assert_eq!(ntid, Some(NoteTypeID(5)));
+        assert_eq!(cards.iter().map(|c| c.ord).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(cards[0].id, card.id);
+
+        let (cards, ntid) = ctx.note_cards(NoteID(2)).unwrap();
+        assert!(cards.is_empty());
+        assert_eq!(ntid, None);
+    }
+
+    #[test]
+    fn cards_since_usn() {
+        let storage = SqliteStorage::open_or_create(Path::new(":memory:")).unwrap();
+        let mut ctx = storage.context(false);
+        let mut card = Card::default();
+        for usn in &[3, 5, -1, 6] {
+            card.usn = Usn(*usn);
+            ctx.add_card(&mut card).unwrap();
+        }
+        ctx.db
+            .execute_batch(
+                "insert into graves (usn, oid, type) values
+                 (4, 10, 0), (6, 11, 0), (-1, 12, 0), (6, 13, 1)",
+            )
+            .unwrap();
+
+        let usns = |cards: Vec<Card>| cards.iter().map(|c| c.usn.0).collect::<Vec<_>>();
+        let cards = ctx.cards_since_usn(Usn(4), CardID(0), 10).unwrap();
+        assert_eq!(usns(cards.clone()), vec![5, -1, 6]);
+        let after = cards[0].id;
+        assert_eq!(
+            usns(ctx.cards_since_usn(Usn(4), after, 1).unwrap()),
+            vec![-1]
+        );
+
+        assert_eq!(
+            ctx.removed_cards_since_usn(Usn(4)).unwrap(),
+            vec![CardID(11), CardID(12)]
+        );
+    }
+
+    #[test]
+    fn invalid_cards() {
+        let storage = SqliteStorage::open_or_create(Path::new(":memory:")).unwrap();
+        let mut ctx = storage.context(false);
+        let mut card = Card::default();
+        for _ in 0..5 {
+            ctx.add_card(&mut card).unwrap();
+        }
+        let ids: Vec<CardID> = ctx
+            .db
+            .prepare("select id from cards order by id")
+            .unwrap()
+            .query_map(rusqlite::NO_PARAMS, |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let set = |sql: &str, id: CardID| {
+            ctx.db
+                .execute(
+                    &format!("update cards set {} where id = ?", sql),
+                    rusqlite::params![id],
+                )
+                .unwrap()
+        };
+        set("type = 7", ids[0]);
+        set("queue = -9, type = 2", ids[1]);
+        set("type = 2, queue = 2, ivl = 0", ids[2]);
+        set("type = 2, queue = 2, ivl = 3", ids[3]);
+        set("queue = 'x'", ids[4]);
+
+        assert_eq!(
+            ctx.invalid_cards().unwrap(),
+            vec![
+                (ids[0], InvalidCardReason::InvalidType),
+                (ids[1], InvalidCardReason::InvalidQueue),
+                (ids[2], InvalidCardReason::ReviewWithoutInterval),
+                (ids[4], InvalidCardReason::InvalidQueue),
+            ]
+        );
+        // the invalid cards can't be loaded
+        assert!(ctx.get_card(ids[0]).is_err());
+    }
 }
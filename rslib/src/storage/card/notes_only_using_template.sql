@@ -0,0 +1,21 @@
+select
+  count()
+from notes n
+where
+  n.mid = ?1
+  and exists (
+    select
+      1
+    from cards c
+    where
+      c.nid = n.id
+      and c.ord = ?2
+  )
+  and not exists (
+    select
+      1
+    from cards c
+    where
+      c.nid = n.id
+      and c.ord != ?2
+  )
@@ -1,4 +1,6 @@
 mod card;
+mod revlog;
 mod sqlite;
 
+pub(crate) use revlog::revlog_entries_in_file;
 pub(crate) use sqlite::{SqliteStorage, StorageContext};
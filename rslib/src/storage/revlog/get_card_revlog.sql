@@ -0,0 +1,15 @@
+select
+  id,
+  cid,
+  usn,
+  ease,
+  cast(ivl as integer),
+  cast(lastIvl as integer),
+  factor,
+  time,
+  type
+from revlog
+where
+  cid = ?
+order by
+  id
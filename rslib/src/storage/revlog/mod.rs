@@ -0,0 +1,155 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use super::sqlite::CachedStatementKind;
+use crate::card::CardID;
+use crate::err::Result;
+use crate::revlog::{RevlogEntry, RevlogID, RevlogReviewKind};
+use rusqlite::params;
+use rusqlite::{
+    types::{FromSql, FromSqlError, ValueRef},
+    Connection, OpenFlags, Row,
+};
+use std::convert::TryFrom;
+use std::path::Path;
+
+impl FromSql for RevlogReviewKind {
+    fn column_result(value: ValueRef<'_>) -> std::result::Result<Self, FromSqlError> {
+        if let ValueRef::Integer(i) = value {
+            Ok(Self::try_from(i as u8).map_err(|_| FromSqlError::InvalidType)?)
+        } else {
+            Err(FromSqlError::InvalidType)
+        }
+    }
+}
+
+fn row_to_revlog_entry(row: &Row) -> Result<RevlogEntry> {
+    Ok(RevlogEntry {
+        id: row.get(0)?,
+        cid: row.get(1)?,
+        usn: row.get(2)?,
+        button_chosen: row.get(3)?,
+        interval: row.get(4)?,
+        last_interval: row.get(5)?,
+        ease_factor: row.get(6)?,
+        taken_millis: row.get(7)?,
+        review_kind: row.get(8)?,
+    })
+}
+
+impl super::StorageContext<'_> {
+    /// The review history of a card, oldest first.
+    pub(crate) fn card_revlog(&mut self, cid: CardID) -> Result<Vec<RevlogEntry>> {
+        self.with_cached_stmt(
+            CachedStatementKind::GetCardRevlog,
+            include_str!("get_card_revlog.sql"),
+            |stmt| {
+                stmt.query_and_then(params![cid], row_to_revlog_entry)?
+                    .collect()
+            },
+        )
+    }
+
+    /// Call `func` with each review with an id in the provided range, or
+    /// every review if no range is given, oldest first. Entries are read
+    /// one at a time, so large logs are not loaded into memory.
+    pub(crate) fn for_each_revlog_entry<F>(
+        &self,
+        range: Option<(RevlogID, RevlogID)>,
+        mut func: F,
+    ) -> Result<()>
+    where
+        F: FnMut(RevlogEntry) -> Result<()>,
+    {
+        let (start, end) = revlog_range_or_all(range);
+        let mut stmt = self.db.prepare(include_str!("revlog_in_range.sql"))?;
+        for entry in stmt.query_and_then(params![start, end], row_to_revlog_entry)? {
+            func(entry?)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn revlog_count(&self, range: Option<(RevlogID, RevlogID)>) -> Result<u32> {
+        let (start, end) = revlog_range_or_all(range);
+        self.db
+            .prepare_cached("select count() from revlog where id >= ? and id < ?")?
+            .query_row(params![start, end], |row| row.get(0))
+            .map_err(Into::into)
+    }
+
+    /// Add an entry unless one with the same id already exists. Returns
+    /// true if the entry was added.
+    pub(crate) fn add_revlog_entry_if_missing(&self, entry: &RevlogEntry) -> Result<bool> {
+        let added = self
+            .db
+            .prepare_cached(
+                "insert or ignore into revlog
+                 (id, cid, usn, ease, ivl, lastIvl, factor, time, type)
+                 values (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )?
+            .execute(params![
+                entry.id,
+                entry.cid,
+                entry.usn,
+                entry.button_chosen,
+                entry.interval,
+                entry.last_interval,
+                entry.ease_factor,
+                entry.taken_millis,
+                entry.review_kind as u8
+            ])?;
+        Ok(added > 0)
+    }
+}
+
+/// All review entries in another collection file, oldest first. The file
+/// is opened read-only.
+pub(crate) fn revlog_entries_in_file(path: &Path) -> Result<Vec<RevlogEntry>> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let (start, end) = revlog_range_or_all(None);
+    let mut stmt = db.prepare(include_str!("revlog_in_range.sql"))?;
+    let entries = stmt
+        .query_and_then(params![start, end], row_to_revlog_entry)?
+        .collect();
+    entries
+}
+
+fn revlog_range_or_all(range: Option<(RevlogID, RevlogID)>) -> (RevlogID, RevlogID) {
+    range.unwrap_or((RevlogID(i64::min_value()), RevlogID(i64::max_value())))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::CardID;
+    use crate::revlog::RevlogReviewKind;
+    use crate::storage::SqliteStorage;
+    use std::path::Path;
+
+    #[test]
+    fn card_revlog() {
+        let storage = SqliteStorage::open_or_create(Path::new(":memory:")).unwrap();
+        let mut ctx = storage.context(false);
+        ctx.db
+            .execute_batch(
+                "insert into revlog (id, cid, usn, ease, ivl, lastIvl, factor, time, type)
+                 values (2000, 1, 0, 3, 4, -600, 2500, 8000, 1),
+                        (1000, 1, 0, 1, -600, 0, 0, 12000, 0),
+                        (1500, 2, 0, 1, -60, 0, 0, 5000, 0)",
+            )
+            .unwrap();
+
+        let entries = ctx.card_revlog(CardID(1)).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.id.0).collect::<Vec<_>>(),
+            vec![1000, 2000]
+        );
+        assert_eq!(entries[0].review_kind, RevlogReviewKind::Learning);
+        assert_eq!(entries[0].interval_secs(), 600);
+        assert_eq!(entries[1].review_kind, RevlogReviewKind::Review);
+        assert_eq!(entries[1].interval_secs(), 4 * 86_400);
+        assert_eq!(entries[1].taken_millis, 8000);
+
+        assert!(ctx.card_revlog(CardID(3)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,16 @@
+select
+  id,
+  cid,
+  usn,
+  ease,
+  cast(ivl as integer),
+  cast(lastIvl as integer),
+  factor,
+  time,
+  type
+from revlog
+where
+  id >= ?
+  and id < ?
+order by
+  id
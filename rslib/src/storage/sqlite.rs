@@ -3,20 +3,26 @@
 
 use crate::collection::CollectionOp;
 use crate::config::Config;
-use crate::decks::DeckID;
+use crate::deckconf::DeckConf;
+use crate::decks::{DeckConfID, DeckID};
 use crate::err::Result;
 use crate::err::{AnkiError, DBErrorKind};
 use crate::notetypes::NoteTypeID;
 use crate::timestamp::{TimestampMillis, TimestampSecs};
 use crate::{
+    cloze::strip_clozes,
     decks::Deck,
     notetypes::NoteType,
-    sched::cutoff::{sched_timing_today, SchedTimingToday},
+    sched::cutoff::{sched_timing_today, LocalOffsetCache, SchedTimingToday},
     text::without_combining,
     types::Usn,
 };
 use regex::Regex;
-use rusqlite::{functions::FunctionFlags, params, Connection, NO_PARAMS};
+use rusqlite::{
+    functions::{Context, FunctionFlags},
+    params, Connection, NO_PARAMS,
+};
+use serde::{de::DeserializeOwned, Serialize};
 use std::cmp::Ordering;
 use std::{
     borrow::Cow,
@@ -63,7 +69,9 @@ fn open_or_create_collection_db(path: &Path) -> Result<Connection> {
 
     add_field_index_function(&db)?;
     add_regexp_function(&db)?;
+    add_regexp_fields_function(&db)?;
     add_without_combining_function(&db)?;
+    add_strip_clozes_function(&db)?;
 
     db.create_collation("unicase", unicase_compare)?;
 
@@ -101,6 +109,23 @@ fn add_without_combining_function(db: &Connection) -> rusqlite::Result<()> {
     )
 }
 
+/// Adds sql function strip_clozes(text), which returns null if the text
+/// contained no cloze deletions.
+fn add_strip_clozes_function(db: &Connection) -> rusqlite::Result<()> {
+    db.create_scalar_function(
+        "strip_clozes",
+        1,
+        FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let text = ctx.get_raw(0).as_str()?;
+            Ok(match strip_clozes(text) {
+                Cow::Borrowed(_) => None,
+                Cow::Owned(o) => Some(o),
+            })
+        },
+    )
+}
+
 /// Adds sql function regexp(regex, string) -> is_match
 /// Taken from the rusqlite docs
 fn add_regexp_function(db: &Connection) -> rusqlite::Result<()> {
@@ -108,39 +133,62 @@ fn add_regexp_function(db: &Connection) -> rusqlite::Result<()> {
         "regexp",
         2,
         FunctionFlags::SQLITE_DETERMINISTIC,
+        move |ctx| regexp_match(ctx, |re, text| re.is_match(text)),
+    )
+}
+
+/// Adds sql function regexp_fields(regex, flds) -> is_match
+/// Each field is matched separately, so ^ and $ match at the start and end
+/// of a field.
+fn add_regexp_fields_function(db: &Connection) -> rusqlite::Result<()> {
+    db.create_scalar_function(
+        "regexp_fields",
+        2,
+        FunctionFlags::SQLITE_DETERMINISTIC,
         move |ctx| {
-            assert_eq!(ctx.len(), 2, "called with unexpected number of arguments");
-
-            let saved_re: Option<&Regex> = ctx.get_aux(0)?;
-            let new_re = match saved_re {
-                None => {
-                    let s = ctx.get::<String>(0)?;
-                    match Regex::new(&s) {
-                        Ok(r) => Some(r),
-                        Err(err) => return Err(rusqlite::Error::UserFunctionError(Box::new(err))),
-                    }
-                }
-                Some(_) => None,
-            };
+            regexp_match(ctx, |re, flds| {
+                flds.split('\x1f').any(|field| re.is_match(field))
+            })
+        },
+    )
+}
 
-            let is_match = {
-                let re = saved_re.unwrap_or_else(|| new_re.as_ref().unwrap());
+/// Call `func` with the regex in the first argument, which is compiled
+/// once per statement, and the text in the second.
+fn regexp_match<F>(ctx: &Context, func: F) -> rusqlite::Result<bool>
+where
+    F: FnOnce(&Regex, &str) -> bool,
+{
+    assert_eq!(ctx.len(), 2, "called with unexpected number of arguments");
+
+    let saved_re: Option<&Regex> = ctx.get_aux(0)?;
+    let new_re = match saved_re {
+        None => {
+            let s = ctx.get::<String>(0)?;
+            match Regex::new(&s) {
+                Ok(r) => Some(r),
+                Err(err) => return Err(rusqlite::Error::UserFunctionError(Box::new(err))),
+            }
+        }
+        Some(_) => None,
+    };
 
-                let text = ctx
-                    .get_raw(1)
-                    .as_str()
-                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+    let is_match = {
+        let re = saved_re.unwrap_or_else(|| new_re.as_ref().unwrap());
 
-                re.is_match(text)
-            };
+        let text = ctx
+            .get_raw(1)
+            .as_str()
+            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
 
-            if let Some(re) = new_re {
-                ctx.set_aux(0, re);
-            }
+        func(re, text)
+    };
 
-            Ok(is_match)
-        },
-    )
+    if let Some(re) = new_re {
+        ctx.set_aux(0, re);
+    }
+
+    Ok(is_match)
 }
 
 /// Fetch schema version from database.
@@ -210,6 +258,8 @@ pub(super) enum CachedStatementKind {
     GetCard,
     UpdateCard,
     AddCard,
+    GetNoteCards,
+    GetCardRevlog,
 }
 
 pub(crate) struct StorageContext<'a> {
@@ -218,6 +268,7 @@ pub(crate) struct StorageContext<'a> {
     usn: Option<Usn>,
 
     timing_today: Option<SchedTimingToday>,
+    local_offsets: LocalOffsetCache,
 
     cached_statements: Vec<Option<rusqlite::CachedStatement<'a>>>,
 }
@@ -232,6 +283,7 @@ impl StorageContext<'_> {
             server,
             usn: None,
             timing_today: None,
+            local_offsets: LocalOffsetCache::local(),
             cached_statements: statements,
         }
     }
@@ -315,6 +367,14 @@ impl StorageContext<'_> {
         Ok(())
     }
 
+    /// Mark the schema as changed, so the next sync will be a full sync.
+    pub(crate) fn set_schema_modified(&self) -> Result<()> {
+        self.db
+            .prepare_cached("update col set scm=?")?
+            .execute(params![TimestampMillis::now()])?;
+        Ok(())
+    }
+
     pub(crate) fn usn(&mut self) -> Result<Usn> {
         if self.server {
             if self.usn.is_none() {
@@ -337,6 +397,72 @@ impl StorageContext<'_> {
             })
     }
 
+    pub(crate) fn all_deck_conf(&self) -> Result<HashMap<DeckConfID, DeckConf>> {
+        self.db
+            .query_row_and_then("select dconf from col", NO_PARAMS, |row| -> Result<_> {
+                Ok(serde_json::from_str(row.get_raw(0).as_str()?)?)
+            })
+    }
+
+    /// Read the JSON object in `column` of the col table, pass it to `func`
+    /// to modify, and write it back. The column name must not come from
+    /// user input.
+    fn update_col_json_map<F>(&self, column: &str, func: F) -> Result<()>
+    where
+        F: FnOnce(&mut serde_json::Map<String, serde_json::Value>) -> Result<()>,
+    {
+        let mut map: serde_json::Map<String, serde_json::Value> = self.db.query_row_and_then(
+            &format!("select {} from col", column),
+            NO_PARAMS,
+            |row| -> Result<_> { Ok(serde_json::from_str(row.get_raw(0).as_str()?)?) },
+        )?;
+        func(&mut map)?;
+        self.db.execute(
+            &format!("update col set {} = ?", column),
+            params![serde_json::to_string(&map)?],
+        )?;
+        Ok(())
+    }
+
+    /// Add or replace a deck config.
+    pub(crate) fn update_deck_conf(&self, conf: &DeckConf) -> Result<()> {
+        self.update_col_json_map("dconf", |confs| {
+            confs.insert(conf.id.to_string(), serde_json::to_value(conf)?);
+            Ok(())
+        })
+    }
+
+    pub(crate) fn remove_deck_conf(&self, dcid: DeckConfID) -> Result<()> {
+        self.update_col_json_map("dconf", |confs| {
+            confs.remove(&dcid.to_string());
+            Ok(())
+        })
+    }
+
+    /// Switch the provided decks to a different config, marking them
+    /// modified. Unknown decks are ignored.
+    pub(crate) fn set_deck_conf_id(
+        &self,
+        dids: &[DeckID],
+        dcid: DeckConfID,
+        usn: Usn,
+    ) -> Result<()> {
+        let mtime = TimestampSecs::now();
+        self.update_col_json_map("decks", |decks| {
+            for did in dids {
+                if let Some(deck) = decks
+                    .get_mut(&did.to_string())
+                    .and_then(|deck| deck.as_object_mut())
+                {
+                    deck.insert("conf".into(), serde_json::to_value(dcid)?);
+                    deck.insert("mod".into(), serde_json::to_value(mtime)?);
+                    deck.insert("usn".into(), serde_json::to_value(usn)?);
+                }
+            }
+            Ok(())
+        })
+    }
+
     pub(crate) fn all_config(&self) -> Result<Config> {
         self.db
             .query_row_and_then("select conf from col", NO_PARAMS, |row| -> Result<_> {
@@ -344,6 +470,27 @@ impl StorageContext<'_> {
             })
     }
 
+    /// Get a single key from the config, if it's set.
+    pub(crate) fn get_config_value<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let mut conf: serde_json::Map<String, serde_json::Value> =
+            self.db
+                .query_row_and_then("select conf from col", NO_PARAMS, |row| -> Result<_> {
+                    Ok(serde_json::from_str(row.get_raw(0).as_str()?)?)
+                })?;
+        match conf.remove(key) {
+            Some(val) => Ok(Some(serde_json::from_value(val)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set a single key in the config, leaving the others unchanged.
+    pub(crate) fn set_config_value<T: Serialize>(&self, key: &str, val: &T) -> Result<()> {
+        self.update_col_json_map("conf", |conf| {
+            conf.insert(key.into(), serde_json::to_value(val)?);
+            Ok(())
+        })
+    }
+
     pub(crate) fn all_note_types(&self) -> Result<HashMap<NoteTypeID, NoteType>> {
         let mut stmt = self.db.prepare("select models from col")?;
         let note_types = stmt
@@ -360,26 +507,81 @@ impl StorageContext<'_> {
         Ok(note_types)
     }
 
-    #[allow(dead_code)]
+    /// Add or replace a note type in the legacy JSON format.
+    pub(crate) fn set_legacy_note_type(
+        &self,
+        ntid: NoteTypeID,
+        nt: serde_json::Value,
+    ) -> Result<()> {
+        self.update_col_json_map("models", |note_types| {
+            note_types.insert(ntid.to_string(), nt);
+            Ok(())
+        })
+    }
+
+    /// The tags registered in the collection, with the USN they were added
+    /// at.
+    pub(crate) fn all_tags(&self) -> Result<HashMap<String, Usn>> {
+        self.db
+            .query_row_and_then("select tags from col", NO_PARAMS, |row| -> Result<_> {
+                Ok(serde_json::from_str(row.get_raw(0).as_str()?)?)
+            })
+    }
+
+    pub(crate) fn set_all_tags(&self, tags: &HashMap<String, Usn>) -> Result<()> {
+        self.db.execute(
+            "update col set tags = ?",
+            params![serde_json::to_string(tags)?],
+        )?;
+        Ok(())
+    }
+
+    /// Return the next new card position, and increment it.
+    pub(crate) fn next_new_position(&self) -> Result<i32> {
+        let mut pos = 1;
+        self.update_col_json_map("conf", |conf| {
+            pos = conf.get("nextPos").and_then(|v| v.as_i64()).unwrap_or(1) as i32;
+            conf.insert("nextPos".into(), (pos + 1).into());
+            Ok(())
+        })?;
+        Ok(pos)
+    }
+
+    pub(crate) fn update_note_type(&self, nt: &NoteType) -> Result<()> {
+        self.set_legacy_note_type(nt.id, serde_json::to_value(nt)?)
+    }
+
     pub(crate) fn timing_today(&mut self) -> Result<SchedTimingToday> {
         if self.timing_today.is_none() {
-            let crt: i64 = self
-                .db
-                .prepare_cached("select crt from col")?
-                .query_row(NO_PARAMS, |row| row.get(0))?;
-            let conf = self.all_config()?;
-            let now_offset = if self.server { conf.local_offset } else { None };
-
-            self.timing_today = Some(sched_timing_today(
-                crt,
-                TimestampSecs::now().0,
-                conf.creation_offset,
-                now_offset,
-                conf.rollover,
-            ));
+            self.timing_today = Some(self.timing_at(TimestampSecs::now().0)?);
         }
         Ok(*self.timing_today.as_ref().unwrap())
     }
+
+    /// The day number and next rollover at `now_secs`, using the
+    /// collection's creation time and rollover settings.
+    pub(crate) fn timing_at(&self, now_secs: i64) -> Result<SchedTimingToday> {
+        let crt: i64 = self
+            .db
+            .prepare_cached("select crt from col")?
+            .query_row(NO_PARAMS, |row| row.get(0))?;
+        let conf = self.all_config()?;
+        let now_offset = if self.server { conf.local_offset } else { None };
+
+        Ok(sched_timing_today(
+            crt,
+            now_secs,
+            conf.creation_offset,
+            now_offset,
+            conf.rollover,
+        ))
+    }
+
+    /// The local timezone's offset at `stamp`, cached for the rest of the
+    /// request.
+    pub(crate) fn local_minutes_west(&self, stamp: i64) -> i32 {
+        self.local_offsets.minutes_west(stamp)
+    }
 }
 
 #[cfg(all(feature = "unstable", test))]
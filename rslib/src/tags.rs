@@ -0,0 +1,394 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::collection::RequestContext;
+use crate::err::{AnkiError, Result};
+use crate::notes::NoteID;
+use crate::timestamp::TimestampSecs;
+use rusqlite::{params, NO_PARAMS};
+use std::collections::{HashMap, HashSet};
+use unicase::UniCase;
+
+/// The tags in a note's tag string. Tags are separated by whitespace.
+pub(crate) fn split_tags(tags: &str) -> impl Iterator<Item = &str> {
+    tags.split(char::is_whitespace)
+        .filter(|tag| !tag.is_empty())
+}
+
+/// Tags in the format notes store them, with a space either side so a tag
+/// can be matched with `like '% tag %'`.
+pub(crate) fn join_tags(tags: &[String]) -> String {
+    if tags.is_empty() {
+        String::new()
+    } else {
+        format!(" {} ", tags.join(" "))
+    }
+}
+
+/// A tag, and the tags nested under it with ::.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TagTreeNode {
+    /// The last component of the tag.
+    pub name: String,
+    pub full_name: String,
+    pub children: Vec<TagTreeNode>,
+}
+
+/// Arrange tags into a tree, sorted by name. Parents that are not tags
+/// themselves are added, and components that differ only in case are
+/// merged.
+pub(crate) fn tag_tree<'a>(tags: impl IntoIterator<Item = &'a str>) -> Vec<TagTreeNode> {
+    let mut roots: Vec<TagTreeNode> = vec![];
+    for tag in tags {
+        let mut nodes = &mut roots;
+        let mut parent: Option<String> = None;
+        for component in tag.split("::").filter(|c| !c.is_empty()) {
+            let pos = match nodes
+                .iter()
+                .position(|node| UniCase::new(node.name.as_str()) == UniCase::new(component))
+            {
+                Some(pos) => pos,
+                None => {
+                    // use the spelling of the parent that is already present
+                    let full_name = match &parent {
+                        Some(parent) => format!("{}::{}", parent, component),
+                        None => component.to_string(),
+                    };
+                    nodes.push(TagTreeNode {
+                        name: component.to_string(),
+                        full_name,
+                        children: vec![],
+                    });
+                    nodes.len() - 1
+                }
+            };
+            parent = Some(nodes[pos].full_name.clone());
+            nodes = &mut nodes[pos].children;
+        }
+    }
+    sort_tag_tree(&mut roots);
+    roots
+}
+
+fn sort_tag_tree(nodes: &mut [TagTreeNode]) {
+    nodes.sort_by(|a, b| UniCase::new(&a.name).cmp(&UniCase::new(&b.name)));
+    for node in nodes {
+        sort_tag_tree(&mut node.children);
+    }
+}
+
+impl RequestContext<'_> {
+    /// The collection's tags, as a tree.
+    pub(crate) fn all_tags_tree(&self) -> Result<Vec<TagTreeNode>> {
+        let tags = self.storage.all_tags()?;
+        Ok(tag_tree(tags.keys().map(String::as_str)))
+    }
+
+    /// Merge tags that differ only in case into a single spelling, on every
+    /// note and in the collection's tag list. A group of tags matching one
+    /// of `preferred` uses that spelling; otherwise the spelling used by the
+    /// most notes wins, with ties going to the one that sorts first. Returns
+    /// a map of each replaced spelling to its replacement.
+    pub(crate) fn canonicalize_tags(
+        &mut self,
+        preferred: &[String],
+    ) -> Result<HashMap<String, String>> {
+        if let Some(tag) = preferred
+            .iter()
+            .find(|tag| tag.is_empty() || split_tags(tag).count() != 1)
+        {
+            return Err(AnkiError::invalid_input(format!("invalid tag: {:?}", tag)));
+        }
+
+        let notes = self.notes_with_tags()?;
+        let mut registered = self.storage.all_tags()?;
+        let replacements = {
+            let mut usage: HashMap<&str, u32> = HashMap::new();
+            for (_, tags) in &notes {
+                for tag in split_tags(tags) {
+                    *usage.entry(tag).or_default() += 1;
+                }
+            }
+            // registered tags no note uses are merged as well
+            for tag in registered.keys() {
+                usage.entry(tag).or_default();
+            }
+
+            let mut groups: HashMap<UniCase<&str>, Vec<(&str, u32)>> = HashMap::new();
+            for (tag, count) in usage {
+                groups
+                    .entry(UniCase::new(tag))
+                    .or_default()
+                    .push((tag, count));
+            }
+
+            let mut replacements = HashMap::new();
+            for (key, mut spellings) in groups {
+                let canonical = match preferred
+                    .iter()
+                    .find(|tag| UniCase::new(tag.as_str()) == key)
+                {
+                    Some(tag) => tag.as_str(),
+                    None => {
+                        spellings.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+                        spellings[0].0
+                    }
+                };
+                for (tag, _) in spellings {
+                    if tag != canonical {
+                        replacements.insert(tag.to_string(), canonical.to_string());
+                    }
+                }
+            }
+            replacements
+        };
+        if replacements.is_empty() {
+            return Ok(replacements);
+        }
+
+        let usn = self.storage.usn()?;
+        let mtime = TimestampSecs::now();
+        let mut stmt = self
+            .storage
+            .db
+            .prepare("update notes set tags = ?, mod = ?, usn = ? where id = ?")?;
+        for (nid, tags) in &notes {
+            let mut changed = false;
+            let mut new_tags: Vec<String> = vec![];
+            for tag in split_tags(tags) {
+                let tag = match replacements.get(tag) {
+                    Some(replacement) => {
+                        changed = true;
+                        replacement.as_str()
+                    }
+                    None => tag,
+                };
+                if !new_tags.iter().any(|existing| existing == tag) {
+                    new_tags.push(tag.to_string());
+                }
+            }
+            if changed {
+                stmt.execute(params![join_tags(&new_tags), mtime, usn, nid])?;
+            }
+        }
+
+        for (old, new) in &replacements {
+            if registered.remove(old).is_some() {
+                registered.entry(new.clone()).or_insert(usn);
+            }
+        }
+        self.storage.set_all_tags(&registered)?;
+
+        Ok(replacements)
+    }
+
+    /// Remove tags that no note uses from the collection's tag list, and add
+    /// any that notes use but are missing from it. Returns the number of
+    /// tags removed.
+    pub(crate) fn clear_unused_tags(&mut self) -> Result<usize> {
+        let mut used = HashSet::new();
+        for (_, tags) in self.notes_with_tags()? {
+            used.extend(split_tags(&tags).map(ToString::to_string));
+        }
+
+        let mut registered = self.storage.all_tags()?;
+        let before = registered.len();
+        registered.retain(|tag, _| used.contains(tag));
+        let removed = before - registered.len();
+
+        let usn = self.storage.usn()?;
+        let mut added = false;
+        for tag in used {
+            if !registered.contains_key(&tag) {
+                registered.insert(tag, usn);
+                added = true;
+            }
+        }
+
+        if removed > 0 || added {
+            self.storage.set_all_tags(&registered)?;
+        }
+        Ok(removed)
+    }
+
+    /// Add a tag to the provided notes that don't already have it in some
+    /// case, and to the collection's tag list.
+    pub(crate) fn add_tag_to_notes(&mut self, nids: &[NoteID], tag: &str) -> Result<()> {
+        let usn = self.storage.usn()?;
+        let mtime = TimestampSecs::now();
+        let same_tag = |existing: &String| UniCase::new(existing.as_str()) == UniCase::new(tag);
+
+        let mut get_tags = self
+            .storage
+            .db
+            .prepare("select tags from notes where id = ?")?;
+        let mut set_tags = self
+            .storage
+            .db
+            .prepare("update notes set tags = ?, mod = ?, usn = ? where id = ?")?;
+        for nid in nids {
+            let tags: String = get_tags.query_row(params![nid], |row| row.get(0))?;
+            let mut tags: Vec<String> = split_tags(&tags).map(ToString::to_string).collect();
+            if !tags.iter().any(same_tag) {
+                tags.push(tag.to_string());
+                set_tags.execute(params![join_tags(&tags), mtime, usn, nid])?;
+            }
+        }
+
+        let mut registered = self.storage.all_tags()?;
+        if !registered.keys().any(same_tag) {
+            registered.insert(tag.to_string(), usn);
+            self.storage.set_all_tags(&registered)?;
+        }
+        Ok(())
+    }
+
+    /// The id and tag string of every note with tags.
+    fn notes_with_tags(&self) -> Result<Vec<(NoteID, String)>> {
+        let mut stmt = self
+            .storage
+            .db
+            .prepare("select id, tags from notes where tags != ''")?;
+        let notes = stmt
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(notes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tag_tree, TagTreeNode};
+    use crate::collection::test::open_test_collection;
+    use crate::collection::RequestContext;
+    use crate::err::Result;
+    use crate::types::Usn;
+    use std::collections::HashMap;
+
+    #[test]
+    fn canonicalize() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        fn tags(ctx: &RequestContext) -> Vec<String> {
+            ctx.storage
+                .db
+                .prepare("select tags from notes order by id")
+                .unwrap()
+                .query_map(rusqlite::NO_PARAMS, |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap()
+        }
+
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                "update notes set tags = ' Biology maths ' where id = 1581236386334;
+                 update notes set tags = ' biology Maths ' where id = 1581236445532;
+                 update notes set tags = ' BIOLOGY biology other ' where id = 1581236461568;",
+            )?;
+            let registered: HashMap<_, _> = ["Biology", "biology", "BIOLOGY", "maths", "Maths"]
+                .iter()
+                .map(|tag| (tag.to_string(), Usn(0)))
+                .collect();
+            ctx.storage.set_all_tags(&registered)?;
+
+            // the most used spelling wins, and ties go to the first
+            let merged = ctx.canonicalize_tags(&[])?;
+            let mut expected = HashMap::new();
+            expected.insert("Biology".to_string(), "biology".to_string());
+            expected.insert("BIOLOGY".into(), "biology".into());
+            expected.insert("maths".into(), "Maths".into());
+            assert_eq!(merged, expected);
+            assert_eq!(
+                tags(ctx),
+                vec![" biology Maths ", " biology Maths ", " biology other "]
+            );
+            let mut registered: Vec<_> = ctx.storage.all_tags()?.into_iter().collect();
+            registered.sort();
+            assert_eq!(
+                registered,
+                vec![("Maths".to_string(), Usn(0)), ("biology".into(), Usn(0))]
+            );
+
+            // nothing left to merge
+            assert!(ctx.canonicalize_tags(&[])?.is_empty());
+
+            // a preferred spelling applies even without duplicates
+            let merged = ctx.canonicalize_tags(&["MATHS".into()])?;
+            assert_eq!(merged.get("Maths").map(String::as_str), Some("MATHS"));
+            assert_eq!(
+                tags(ctx),
+                vec![" biology MATHS ", " biology MATHS ", " biology other "]
+            );
+
+            assert!(ctx.canonicalize_tags(&["two words".into()]).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn tree() {
+        let node = |name: &str, full_name: &str, children| TagTreeNode {
+            name: name.into(),
+            full_name: full_name.into(),
+            children,
+        };
+        assert_eq!(
+            tag_tree(vec![
+                "lang::es::verbs",
+                "b",
+                "Lang::fr",
+                "lang::es",
+                "lang-x"
+            ]),
+            vec![
+                node("b", "b", vec![]),
+                node(
+                    "lang",
+                    "lang",
+                    vec![
+                        node(
+                            "es",
+                            "lang::es",
+                            vec![node("verbs", "lang::es::verbs", vec![])]
+                        ),
+                        node("fr", "lang::fr", vec![]),
+                    ]
+                ),
+                node("lang-x", "lang-x", vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn clear_unused() -> Result<()> {
+        let (_dir, col) = open_test_collection()?;
+
+        col.transact(None, |ctx| {
+            ctx.storage.db.execute_batch(
+                "update notes set tags = ' shared only1 ' where id = 1581236386334;
+                 update notes set tags = ' shared ' where id = 1581236445532;",
+            )?;
+            let registered: HashMap<_, _> = ["shared", "only1", "stale"]
+                .iter()
+                .map(|tag| (tag.to_string(), Usn(0)))
+                .collect();
+            ctx.storage.set_all_tags(&registered)?;
+
+            assert_eq!(ctx.clear_unused_tags()?, 1);
+            assert!(!ctx.storage.all_tags()?.contains_key("stale"));
+            assert_eq!(ctx.clear_unused_tags()?, 0);
+
+            // once the last note using a tag is gone, it can be removed
+            ctx.storage
+                .db
+                .execute_batch("delete from notes where id = 1581236386334")?;
+            assert_eq!(ctx.clear_unused_tags()?, 1);
+            let tags: Vec<_> = ctx.storage.all_tags()?.into_iter().collect();
+            assert_eq!(tags, vec![("shared".to_string(), Usn(0))]);
+
+            Ok(())
+        })
+    }
+}
@@ -1,9 +1,10 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+use crate::cloze::cloze_numbers_in_string;
 use crate::err::{AnkiError, Result, TemplateError};
-use crate::i18n::{tr_strs, FString, I18n};
-use crate::template_filters::apply_filters;
+use crate::i18n::{tr_args, tr_strs, FString, I18n};
+use crate::template_filters::{apply_filters, is_builtin_filter};
 use lazy_static::lazy_static;
 use nom::branch::alt;
 use nom::bytes::complete::tag;
@@ -13,6 +14,7 @@ use regex::Regex;
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::iter;
+use unicase::eq as uni_eq;
 
 pub type FieldMap<'a> = HashMap<&'a str, u16>;
 type TemplateResult<T> = std::result::Result<T, TemplateError>;
@@ -132,20 +134,50 @@ enum ParsedNode<'a> {
 #[derive(Debug)]
 pub struct ParsedTemplate<'a>(Vec<ParsedNode<'a>>);
 
+/// Limits that stop a bad template from hanging the renderer or running
+/// out of memory or stack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderLimits {
+    /// The most text each side of a card may render to, in bytes.
+    pub max_output_len: usize,
+    /// How deeply conditionals may be nested.
+    pub max_depth: usize,
+}
+
+impl Default for RenderLimits {
+    fn default() -> Self {
+        RenderLimits {
+            max_output_len: 10 * 1024 * 1024,
+            max_depth: 100,
+        }
+    }
+}
+
 impl ParsedTemplate<'_> {
     /// Create a template from the provided text.
     ///
     /// The legacy alternate syntax is not supported, so the provided text
     /// should be run through without_legacy_template_directives() first.
     pub fn from_text(template: &str) -> TemplateResult<ParsedTemplate> {
+        Self::from_text_with_max_depth(template, RenderLimits::default().max_depth)
+    }
+
+    /// Like from_text(), with a limit on how deeply conditionals may be
+    /// nested.
+    fn from_text_with_max_depth(
+        template: &str,
+        max_depth: usize,
+    ) -> TemplateResult<ParsedTemplate> {
         let mut iter = tokens(template);
-        Ok(Self(parse_inner(&mut iter, None)?))
+        Ok(Self(parse_inner(&mut iter, None, 0, max_depth)?))
     }
 }
 
 fn parse_inner<'a, I: Iterator<Item = TemplateResult<Token<'a>>>>(
     iter: &mut I,
     open_tag: Option<&'a str>,
+    depth: usize,
+    max_depth: usize,
 ) -> TemplateResult<Vec<ParsedNode<'a>>> {
     let mut nodes = vec![];
 
@@ -153,6 +185,9 @@ fn parse_inner<'a, I: Iterator<Item = TemplateResult<Token<'a>>>>(
         use Token::*;
         nodes.push(match token? {
             Text(t) => ParsedNode::Text(t),
+            OpenConditional(_) | OpenNegated(_) if depth == max_depth => {
+                return Err(TemplateError::NestedTooDeeply(max_depth));
+            }
             Replacement(t) => {
                 let mut it = t.rsplit(':');
                 ParsedNode::Replacement {
@@ -162,11 +197,11 @@ fn parse_inner<'a, I: Iterator<Item = TemplateResult<Token<'a>>>>(
             }
             OpenConditional(t) => ParsedNode::Conditional {
                 key: t,
-                children: parse_inner(iter, Some(t))?,
+                children: parse_inner(iter, Some(t), depth + 1, max_depth)?,
             },
             OpenNegated(t) => ParsedNode::NegatedConditional {
                 key: t,
-                children: parse_inner(iter, Some(t))?,
+                children: parse_inner(iter, Some(t), depth + 1, max_depth)?,
             },
             CloseConditional(t) => {
                 let currently_open = if let Some(open) = open_tag {
@@ -210,7 +245,7 @@ fn template_error_to_anki_error(err: TemplateError, q_side: bool, i18n: &I18n) -
     AnkiError::TemplateError { info }
 }
 
-fn localized_template_error(i18n: &I18n, err: TemplateError) -> String {
+pub(crate) fn localized_template_error(i18n: &I18n, err: TemplateError) -> String {
     match err {
         TemplateError::NoClosingBrackets(tag) => i18n.trn(
             FString::CardTemplateRenderingNoClosingBrackets,
@@ -248,6 +283,14 @@ fn localized_template_error(i18n: &I18n, err: TemplateError) -> String {
             "found"=>format!("{{{{{}{}}}}}", filters, field),
             "field"=>field),
         ),
+        TemplateError::NestedTooDeeply(limit) => i18n.trn(
+            FString::CardTemplateRenderingNestedTooDeeply,
+            tr_args!["limit"=>limit],
+        ),
+        TemplateError::OutputTooLarge(limit) => i18n.trn(
+            FString::CardTemplateRenderingTooLarge,
+            tr_args!["limit"=>limit / 1024],
+        ),
     }
 }
 
@@ -297,7 +340,9 @@ fn template_is_empty<'a>(nonempty_fields: &HashSet<&str>, nodes: &[ParsedNode<'a
                 }
             }
             Conditional { key, children } => {
-                if !nonempty_fields.contains(*key) {
+                // the note's tags aren't known here, so a tag conditional is
+                // assumed to be satisfiable
+                if !is_tag_conditional(key) && !nonempty_fields.contains(*key) {
                     continue;
                 }
                 if !template_is_empty(nonempty_fields, children) {
@@ -316,6 +361,183 @@ fn template_is_empty<'a>(nonempty_fields: &HashSet<&str>, nodes: &[ParsedNode<'a
     true
 }
 
+// Field references
+//----------------------------------------
+
+/// Fields that are provided when rendering, in addition to the note's fields.
+static SPECIAL_FIELDS: &[&str] = &[
+    "FrontSide",
+    "Tags",
+    "Type",
+    "Deck",
+    "Subdeck",
+    "Card",
+    "CardFlag",
+];
+
+lazy_static! {
+    /// Conditionals like {{#c1}} are true when rendering the matching cloze.
+    static ref CLOZE_CONDITIONAL: Regex = Regex::new(r"^c\d+$").unwrap();
+}
+
+impl ParsedTemplate<'_> {
+    /// The first field referenced by the template that is neither in
+    /// `fields` nor a special field.
+    pub(crate) fn first_unknown_field(&self, fields: &HashSet<&str>) -> Option<&str> {
+        self.unknown_fields(fields).into_iter().next()
+    }
+
+    /// The names of all fields the template references, including in
+    /// conditionals and filtered replacements. Special fields, cloze
+    /// number conditionals and tag conditionals are not included.
+    pub fn fields(&self) -> HashSet<&str> {
+        let mut fields = HashSet::new();
+        referenced_fields(&self.0, &mut fields);
+        fields
+    }
+
+    /// All fields referenced by the template that are neither in `fields`
+    /// nor special fields, in the order they first appear.
+    pub fn unknown_fields(&self, fields: &HashSet<&str>) -> Vec<&str> {
+        let mut unknown = vec![];
+        unknown_fields(fields, &self.0, &mut unknown);
+        unknown
+    }
+
+    /// All filters used by the template that are neither built in nor in
+    /// `registered_filters`, in the order they first appear.
+    pub fn unknown_filters(&self, registered_filters: &HashSet<&str>) -> Vec<&str> {
+        let mut unknown = vec![];
+        unknown_filters(registered_filters, &self.0, &mut unknown);
+        unknown
+    }
+}
+
+fn referenced_fields<'a>(nodes: &[ParsedNode<'a>], fields: &mut HashSet<&'a str>) {
+    use ParsedNode::*;
+    for node in nodes {
+        match node {
+            Text(_) => (),
+            Replacement { key, .. } => {
+                if !key.is_empty() && !SPECIAL_FIELDS.contains(key) {
+                    fields.insert(key);
+                }
+            }
+            Conditional { key, children } | NegatedConditional { key, children } => {
+                if !SPECIAL_FIELDS.contains(key)
+                    && !CLOZE_CONDITIONAL.is_match(key)
+                    && !is_tag_conditional(key)
+                {
+                    fields.insert(key);
+                }
+                referenced_fields(children, fields);
+            }
+        }
+    }
+}
+
+fn unknown_fields<'a>(
+    fields: &HashSet<&str>,
+    nodes: &[ParsedNode<'a>],
+    unknown: &mut Vec<&'a str>,
+) {
+    use ParsedNode::*;
+    for node in nodes {
+        let (key, children) = match node {
+            Text(_) => continue,
+            // an empty field name is allowed when a filter provides the text
+            Replacement { key: "", filters } if !filters.is_empty() => continue,
+            Replacement { key, .. } => (*key, None),
+            Conditional { key, children } | NegatedConditional { key, children } => {
+                (*key, Some(children))
+            }
+        };
+        let conditional = children.is_some();
+        let known = fields.contains(key)
+            || SPECIAL_FIELDS.contains(&key)
+            || (conditional && (CLOZE_CONDITIONAL.is_match(key) || is_tag_conditional(key)));
+        if !known && !unknown.contains(&key) {
+            unknown.push(key);
+        }
+        if let Some(children) = children {
+            unknown_fields(fields, children, unknown);
+        }
+    }
+}
+
+fn unknown_filters<'a>(
+    registered: &HashSet<&str>,
+    nodes: &[ParsedNode<'a>],
+    unknown: &mut Vec<&'a str>,
+) {
+    use ParsedNode::*;
+    for node in nodes {
+        match node {
+            Text(_) => (),
+            Replacement { filters, .. } => {
+                for filter in filters {
+                    if !is_builtin_filter(filter)
+                        && !registered.contains(filter)
+                        && !unknown.contains(filter)
+                    {
+                        unknown.push(*filter);
+                    }
+                }
+            }
+            Conditional { children, .. } | NegatedConditional { children, .. } => {
+                unknown_filters(registered, children, unknown)
+            }
+        }
+    }
+}
+
+// Removing fields
+//----------------------------------------
+
+impl ParsedTemplate<'_> {
+    /// The template text with references to `field` removed. A conditional
+    /// section on the field is dropped along with its contents, as the field
+    /// would always be empty; a negated section's contents are kept.
+    pub(crate) fn without_field(&self, field: &str) -> String {
+        let mut buf = String::new();
+        write_without_field(&self.0, field, &mut buf);
+        buf
+    }
+}
+
+fn write_without_field(nodes: &[ParsedNode], field: &str, buf: &mut String) {
+    use ParsedNode::*;
+    for node in nodes {
+        match node {
+            Text(text) => buf.push_str(text),
+            Replacement { key, .. } if *key == field => (),
+            Replacement { key, filters } => {
+                buf.push_str("{{");
+                for filter in filters.iter().rev() {
+                    buf.push_str(filter);
+                    buf.push(':');
+                }
+                buf.push_str(key);
+                buf.push_str("}}");
+            }
+            Conditional { key, .. } if *key == field => (),
+            NegatedConditional { key, children } if *key == field => {
+                write_without_field(children, field, buf)
+            }
+            Conditional { key, children } => {
+                buf.push_str(&format!("{{{{#{}}}}}", key));
+                write_without_field(children, field, buf);
+                buf.push_str(&format!("{{{{/{}}}}}", key));
+            }
+            NegatedConditional { key, children } => {
+                buf.push_str(&format!("{{{{^{}}}}}", key));
+                write_without_field(children, field, buf);
+                buf.push_str(&format!("{{{{/{}}}}}", key));
+            }
+        }
+    }
+}
+
 // Rendering
 //----------------------------------------
 
@@ -337,6 +559,34 @@ pub(crate) struct RenderContext<'a> {
     pub nonempty_fields: &'a HashSet<&'a str>,
     pub question_side: bool,
     pub card_ord: u16,
+    /// Filters the caller is able to apply. If provided, any filter that
+    /// is neither built in nor in this list is rendered as an inline error
+    /// instead of being passed back to the caller.
+    pub registered_filters: Option<&'a HashSet<&'a str>>,
+    /// The note's tags, for {{#tag:name}} conditionals.
+    pub tags: &'a [&'a str],
+    pub limits: RenderLimits,
+}
+
+/// True if `key` is of the form `tag:name`, for a conditional on the note's
+/// tags instead of a field.
+fn is_tag_conditional(key: &str) -> bool {
+    key.starts_with("tag:")
+}
+
+impl RenderContext<'_> {
+    /// True if a conditional on `key` should show its contents.
+    ///
+    /// A key of the form `tag:name` checks if the note has the provided
+    /// tag; any other key checks if the field is non-empty.
+    fn conditional_is_true(&self, key: &str) -> bool {
+        if is_tag_conditional(key) {
+            let wanted = &key[4..];
+            self.tags.iter().any(|tag| uni_eq(*tag, wanted))
+        } else {
+            self.nonempty_fields.contains(key)
+        }
+    }
 }
 
 impl ParsedTemplate<'_> {
@@ -347,22 +597,27 @@ impl ParsedTemplate<'_> {
     /// rendered Replacement is returned for the calling code to complete.
     fn render(&self, context: &RenderContext) -> TemplateResult<Vec<RenderedNode>> {
         let mut rendered = vec![];
+        let mut output_len = 0;
 
-        render_into(&mut rendered, self.0.as_ref(), context)?;
+        render_into(&mut rendered, self.0.as_ref(), context, &mut output_len)?;
 
         Ok(rendered)
     }
 }
 
+/// `output_len` is the length of the text rendered so far, which may not
+/// exceed the context's limit.
 fn render_into(
     rendered_nodes: &mut Vec<RenderedNode>,
     nodes: &[ParsedNode],
     context: &RenderContext,
+    output_len: &mut usize,
 ) -> TemplateResult<()> {
     use ParsedNode::*;
     for node in nodes {
         match node {
             Text(text) => {
+                add_output_len(output_len, text.len(), context)?;
                 append_str_to_nodes(rendered_nodes, text);
             }
             Replacement {
@@ -378,6 +633,10 @@ fn render_into(
                 });
             }
             Replacement { key: "", filters } if !filters.is_empty() => {
+                if let Some(filter) = unknown_filter(filters, context) {
+                    append_str_to_nodes(rendered_nodes, &unknown_filter_marker(filter));
+                    continue;
+                }
                 // if a filter is provided, we accept an empty field name to
                 // mean 'pass an empty string to the filter, and it will add
                 // its own text'
@@ -407,9 +666,13 @@ fn render_into(
                     }
                 };
 
+                add_output_len(output_len, text.len(), context)?;
+
                 // fully processed?
                 if remaining_filters.is_empty() {
                     append_str_to_nodes(rendered_nodes, text.as_ref())
+                } else if let Some(filter) = unknown_filter(&remaining_filters, context) {
+                    append_str_to_nodes(rendered_nodes, &unknown_filter_marker(filter));
                 } else {
                     rendered_nodes.push(RenderedNode::Replacement {
                         field_name: (*key).to_string(),
@@ -419,13 +682,13 @@ fn render_into(
                 }
             }
             Conditional { key, children } => {
-                if context.nonempty_fields.contains(key) {
-                    render_into(rendered_nodes, children.as_ref(), context)?;
+                if context.conditional_is_true(key) {
+                    render_into(rendered_nodes, children.as_ref(), context, output_len)?;
                 }
             }
             NegatedConditional { key, children } => {
-                if !context.nonempty_fields.contains(key) {
-                    render_into(rendered_nodes, children.as_ref(), context)?;
+                if !context.conditional_is_true(key) {
+                    render_into(rendered_nodes, children.as_ref(), context, output_len)?;
                 }
             }
         };
@@ -434,6 +697,34 @@ fn render_into(
     Ok(())
 }
 
+/// Add to the length of the rendered text, failing if it is now too long.
+fn add_output_len(
+    output_len: &mut usize,
+    added: usize,
+    context: &RenderContext,
+) -> TemplateResult<()> {
+    *output_len += added;
+    if *output_len > context.limits.max_output_len {
+        Err(TemplateError::OutputTooLarge(context.limits.max_output_len))
+    } else {
+        Ok(())
+    }
+}
+
+/// If the caller provided a list of the filters it supports, return the
+/// first filter that can't be applied by either side.
+fn unknown_filter<'a, S: AsRef<str>>(filters: &'a [S], context: &RenderContext) -> Option<&'a str> {
+    let registered = context.registered_filters?;
+    filters
+        .iter()
+        .map(AsRef::as_ref)
+        .find(|f| !is_builtin_filter(f) && !registered.contains(f))
+}
+
+fn unknown_filter_marker(filter: &str) -> String {
+    format!("{{unknown filter: {}}}", filter)
+}
+
 /// Append to last node if last node is a string, else add new node.
 fn append_str_to_nodes(nodes: &mut Vec<RenderedNode>, text: &str) {
     if let Some(RenderedNode::Text {
@@ -451,7 +742,7 @@ fn append_str_to_nodes(nodes: &mut Vec<RenderedNode>, text: &str) {
 }
 
 /// True if provided text contains only whitespace and/or empty BR/DIV tags.
-fn field_is_empty(text: &str) -> bool {
+pub(crate) fn field_is_empty(text: &str) -> bool {
     lazy_static! {
         static ref RE: Regex = Regex::new(
             r#"(?xsi)
@@ -483,12 +774,15 @@ fn nonempty_fields<'a>(fields: &'a HashMap<&str, &str>) -> HashSet<&'a str> {
 // Rendering both sides
 //----------------------------------------
 
-#[allow(clippy::implicit_hasher)]
+#[allow(clippy::implicit_hasher, clippy::too_many_arguments)]
 pub fn render_card(
     qfmt: &str,
     afmt: &str,
     field_map: &HashMap<&str, &str>,
     card_ord: u16,
+    tags: &[&str],
+    registered_filters: Option<&HashSet<&str>>,
+    limits: RenderLimits,
     i18n: &I18n,
 ) -> Result<(Vec<RenderedNode>, Vec<RenderedNode>)> {
     // prepare context
@@ -497,13 +791,17 @@ pub fn render_card(
         nonempty_fields: &nonempty_fields(field_map),
         question_side: true,
         card_ord,
+        registered_filters,
+        tags,
+        limits,
     };
 
     // question side
     let qnorm = without_legacy_template_directives(qfmt);
-    let (qnodes, qtmpl) = ParsedTemplate::from_text(qnorm.as_ref())
-        .and_then(|tmpl| Ok((tmpl.render(&context)?, tmpl)))
-        .map_err(|e| template_error_to_anki_error(e, true, i18n))?;
+    let (qnodes, qtmpl) =
+        ParsedTemplate::from_text_with_max_depth(qnorm.as_ref(), limits.max_depth)
+            .and_then(|tmpl| Ok((tmpl.render(&context)?, tmpl)))
+            .map_err(|e| template_error_to_anki_error(e, true, i18n))?;
 
     // check if the front side was empty
     if !qtmpl.renders_with_fields(context.nonempty_fields) {
@@ -519,7 +817,7 @@ pub fn render_card(
     // answer side
     context.question_side = false;
     let anorm = without_legacy_template_directives(afmt);
-    let anodes = ParsedTemplate::from_text(anorm.as_ref())
+    let anodes = ParsedTemplate::from_text_with_max_depth(anorm.as_ref(), limits.max_depth)
         .and_then(|tmpl| tmpl.render(&context))
         .map_err(|e| template_error_to_anki_error(e, false, i18n))?;
 
@@ -577,6 +875,66 @@ impl ParsedTemplate<'_> {
             FieldRequirements::None
         }
     }
+
+    /// Return fields required by a cloze template.
+    ///
+    /// A cloze card exists for each cloze number found in the fields the
+    /// template passes through the cloze filter, so any one of those fields
+    /// may be sufficient, and conditionals are not considered. Whether a
+    /// given card exists depends on the note; see renders_cloze().
+    pub fn cloze_requirements(&self, field_map: &FieldMap) -> FieldRequirements {
+        let ords: HashSet<_> = self
+            .cloze_fields()
+            .iter()
+            .filter_map(|name| field_map.get(name).copied())
+            .collect();
+        if ords.is_empty() {
+            FieldRequirements::None
+        } else {
+            FieldRequirements::Any(ords)
+        }
+    }
+
+    /// True if one of the template's cloze fields contains the cloze number
+    /// for the given card ordinal.
+    pub fn renders_cloze(&self, fields: &HashMap<&str, &str>, card_ord: u16) -> bool {
+        self.cloze_fields().iter().any(|name| {
+            fields
+                .get(name)
+                .map(|text| cloze_numbers_in_string(text).contains(&(card_ord + 1)))
+                .unwrap_or_default()
+        })
+    }
+
+    /// True if the template passes any field through the cloze filter.
+    pub(crate) fn uses_cloze(&self) -> bool {
+        !self.cloze_fields().is_empty()
+    }
+
+    /// Names of fields referenced with the cloze filter, including inside
+    /// conditionals and type: references.
+    pub(crate) fn cloze_fields(&self) -> Vec<&str> {
+        let mut names = vec![];
+        cloze_fields(&self.0, &mut names);
+        names
+    }
+}
+
+fn cloze_fields<'a>(nodes: &[ParsedNode<'a>], names: &mut Vec<&'a str>) {
+    use ParsedNode::*;
+    for node in nodes {
+        match node {
+            Text(_) => (),
+            Replacement { key, filters } => {
+                if filters.contains(&"cloze") && !names.contains(key) {
+                    names.push(key);
+                }
+            }
+            Conditional { children, .. } | NegatedConditional { children, .. } => {
+                cloze_fields(children, names)
+            }
+        }
+    }
 }
 
 // Tests
@@ -585,11 +943,12 @@ impl ParsedTemplate<'_> {
 #[cfg(test)]
 mod test {
     use super::{FieldMap, ParsedNode::*, ParsedTemplate as PT};
-    use crate::err::TemplateError;
+    use crate::err::{AnkiError, TemplateError};
     use crate::template::{
-        field_is_empty, nonempty_fields, without_legacy_template_directives, FieldRequirements,
-        RenderContext,
+        field_is_empty, nonempty_fields, render_card, without_legacy_template_directives,
+        FieldRequirements, RenderContext, RenderLimits,
     };
+    use crate::{i18n::I18n, log};
     use std::collections::{HashMap, HashSet};
     use std::iter::FromIterator;
 
@@ -663,6 +1022,11 @@ mod test {
         assert_eq!(tmpl.renders_with_fields(&fields), false);
         tmpl = PT::from_text("{{#3}}{{^2}}{{1}}{{/2}}{{/3}}").unwrap();
         assert_eq!(tmpl.renders_with_fields(&fields), true);
+        // a front wrapped in a tag conditional is not considered empty
+        tmpl = PT::from_text("{{#tag:x}}{{1}}{{/tag:x}}").unwrap();
+        assert_eq!(tmpl.renders_with_fields(&fields), true);
+        tmpl = PT::from_text("{{#tag:x}}{{2}}{{/tag:x}}").unwrap();
+        assert_eq!(tmpl.renders_with_fields(&fields), false);
     }
 
     #[test]
@@ -726,6 +1090,99 @@ mod test {
         );
     }
 
+    #[test]
+    fn referenced_fields() {
+        let fields = |text| -> Vec<String> {
+            let mut fields: Vec<_> = PT::from_text(text)
+                .unwrap()
+                .fields()
+                .into_iter()
+                .map(ToString::to_string)
+                .collect();
+            fields.sort();
+            fields
+        };
+
+        assert_eq!(fields("{{Front}} {{FrontSide}} {{Tags}}"), vec!["Front"]);
+        assert_eq!(
+            fields("{{#A}}{{^B}}{{#C}}{{D}}{{/C}}{{/B}}{{/A}}{{#c1}}{{E}}{{/c1}}"),
+            vec!["A", "B", "C", "D", "E"]
+        );
+        assert_eq!(
+            fields("{{text:A}}{{type:cloze:B}}{{furigana:A}}{{tts en_US:C}}{{tts en_US:}}"),
+            vec!["A", "B", "C"]
+        );
+        assert_eq!(
+            fields("{{#tag:x}}{{A}}{{/tag:x}}{{^tag:y}}{{B}}{{/tag:y}}"),
+            vec!["A", "B"]
+        );
+        assert!(fields("no references").is_empty());
+    }
+
+    #[test]
+    fn unknown_references() {
+        let fields: HashSet<_> = vec!["Front", "Back"].into_iter().collect();
+        let registered: HashSet<_> = vec!["myfilter"].into_iter().collect();
+
+        let tmpl = PT::from_text(
+            "{{Front}}{{Missing}}{{#Other}}{{Missing}}{{^Back}}{{Third}}{{/Back}}{{/Other}}\
+             {{#c1}}{{/c1}}{{Tags}}{{text:Back}}",
+        )
+        .unwrap();
+        assert_eq!(
+            tmpl.unknown_fields(&fields),
+            vec!["Missing", "Other", "Third"]
+        );
+        assert_eq!(tmpl.first_unknown_field(&fields), Some("Missing"));
+
+        // tag conditionals don't refer to a field, but their contents can
+        let tmpl =
+            PT::from_text("{{#tag:x}}{{Front}}{{Missing}}{{/tag:x}}{{^tag:y}}{{/tag:y}}").unwrap();
+        assert_eq!(tmpl.unknown_fields(&fields), vec!["Missing"]);
+
+        let tmpl = PT::from_text(
+            "{{myfilter:Front}}{{bad:text:Front}}{{#Back}}{{worse:Back}}{{bad:Back}}{{/Back}}\
+             {{type:cloze:Front}}{{tts en_US:Front}}",
+        )
+        .unwrap();
+        assert_eq!(tmpl.unknown_filters(&registered), vec!["bad", "worse"]);
+        assert!(tmpl.unknown_fields(&fields).is_empty());
+    }
+
+    #[test]
+    fn cloze_requirements() {
+        let field_map: FieldMap = vec!["Text", "Extra"]
+            .iter()
+            .enumerate()
+            .map(|(a, b)| (*b, a as u16))
+            .collect();
+
+        // the generic logic ignores type: references and honours conditionals
+        let tmpl = PT::from_text("{{#Extra}}{{Extra}}{{/Extra}}{{type:cloze:Text}}").unwrap();
+        assert_eq!(
+            tmpl.requirements(&field_map),
+            FieldRequirements::Any(HashSet::from_iter(vec![1].into_iter()))
+        );
+        assert_eq!(
+            tmpl.cloze_requirements(&field_map),
+            FieldRequirements::Any(HashSet::from_iter(vec![0].into_iter()))
+        );
+
+        let tmpl = PT::from_text("{{Extra}}").unwrap();
+        assert_eq!(tmpl.cloze_requirements(&field_map), FieldRequirements::None);
+
+        let tmpl = PT::from_text("{{cloze:Text}}{{#c2}}x{{/c2}}").unwrap();
+        let mut fields = HashMap::new();
+        fields.insert("Text", "{{c1::one}} {{c3::three}}");
+        fields.insert("Extra", "{{c2::not a cloze field}}");
+        // with the target ordinal
+        assert_eq!(tmpl.renders_cloze(&fields, 0), true);
+        assert_eq!(tmpl.renders_cloze(&fields, 2), true);
+        // without it
+        assert_eq!(tmpl.renders_cloze(&fields, 1), false);
+        assert_eq!(tmpl.renders_cloze(&fields, 3), false);
+    }
+
     #[test]
     fn alt_syntax() {
         let input = "
@@ -752,6 +1209,9 @@ mod test {
             nonempty_fields: &nonempty_fields(&map),
             question_side: true,
             card_ord: 1,
+            registered_filters: None,
+            tags: &[],
+            limits: Default::default(),
         };
 
         use crate::template::RenderedNode as FN;
@@ -848,4 +1308,127 @@ mod test {
             }]
         );
     }
+
+    #[test]
+    fn tag_conditionals() {
+        let map: HashMap<_, _> = vec![("F", "f")].into_iter().collect();
+        let ctx = RenderContext {
+            fields: &map,
+            nonempty_fields: &nonempty_fields(&map),
+            question_side: true,
+            card_ord: 1,
+            registered_filters: None,
+            tags: &["leech", "Marked"],
+            limits: Default::default(),
+        };
+
+        use crate::template::RenderedNode as FN;
+        let tmpl =
+            PT::from_text("{{#tag:marked}}M{{/tag:marked}}{{^tag:foo}}F{{/tag:foo}}").unwrap();
+        assert_eq!(
+            tmpl.render(&ctx).unwrap(),
+            vec![FN::Text {
+                text: "MF".to_owned()
+            }]
+        );
+
+        let tmpl = PT::from_text("{{^tag:leech}}L{{/tag:leech}}{{#tag:foo}}F{{/tag:foo}}").unwrap();
+        assert_eq!(tmpl.render(&ctx).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn unknown_filters() {
+        let map: HashMap<_, _> = vec![("F", "f")].into_iter().collect();
+        let registered: HashSet<_> = vec!["myfilter"].into_iter().collect();
+        let mut ctx = RenderContext {
+            fields: &map,
+            nonempty_fields: &nonempty_fields(&map),
+            question_side: true,
+            card_ord: 1,
+            registered_filters: Some(&registered),
+            tags: &[],
+            limits: Default::default(),
+        };
+
+        use crate::template::RenderedNode as FN;
+        // a typo'd built in filter is flagged
+        let tmpl = PT::from_text("{{txet:F}}").unwrap();
+        assert_eq!(
+            tmpl.render(&ctx).unwrap(),
+            vec![FN::Text {
+                text: "{unknown filter: txet}".to_owned()
+            }]
+        );
+
+        // registered filters are passed back to the caller
+        let tmpl2 = PT::from_text("{{text:myfilter:F}}").unwrap();
+        assert_eq!(
+            tmpl2.render(&ctx).unwrap(),
+            vec![FN::Replacement {
+                field_name: "F".to_owned(),
+                filters: vec!["myfilter".to_string(), "text".to_string()],
+                current_text: "f".to_owned()
+            }]
+        );
+
+        // and the check can be disabled
+        ctx.registered_filters = None;
+        assert_eq!(
+            tmpl.render(&ctx).unwrap(),
+            vec![FN::Replacement {
+                field_name: "F".to_owned(),
+                filters: vec!["txet".to_string()],
+                current_text: "f".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let big = "x".repeat(1000);
+        let map: HashMap<_, _> = vec![("F", big.as_str())].into_iter().collect();
+        let mut ctx = RenderContext {
+            fields: &map,
+            nonempty_fields: &nonempty_fields(&map),
+            question_side: true,
+            card_ord: 1,
+            registered_filters: None,
+            tags: &[],
+            limits: Default::default(),
+        };
+
+        // 2,000 copies of a 1,000 byte field, inside conditionals
+        let huge = "{{#F}}{{F}}{{/F}}".repeat(2000);
+        let tmpl = PT::from_text(&huge).unwrap();
+        assert_eq!(tmpl.render(&ctx).unwrap().len(), 1);
+        ctx.limits.max_output_len = 1_000_000;
+        assert_eq!(
+            tmpl.render(&ctx).unwrap_err(),
+            TemplateError::OutputTooLarge(1_000_000)
+        );
+
+        let nested = format!("{}x{}", "{{#F}}".repeat(150), "{{/F}}".repeat(150));
+        assert_eq!(
+            PT::from_text(&nested).unwrap_err(),
+            TemplateError::NestedTooDeeply(100)
+        );
+        assert!(PT::from_text_with_max_depth(&nested, 150).is_ok());
+
+        // render_card() reports both as template errors
+        let i18n = I18n::new(&[""], "", log::terminal());
+        let limits = RenderLimits {
+            max_output_len: 1_000_000,
+            max_depth: 150,
+        };
+        let render = |qfmt: &str| render_card(qfmt, "", &map, 1, &[], None, limits, &i18n);
+        assert!(render(&nested).is_ok());
+        assert!(matches!(
+            render(&huge),
+            Err(AnkiError::TemplateError { .. })
+        ));
+        assert!(matches!(
+            render(&format!("{{{{#F}}}}{}{{{{/F}}}}", nested)),
+            Err(AnkiError::TemplateError { .. })
+        ));
+    }
 }
@@ -12,6 +12,25 @@ use std::borrow::Cow;
 // Filtering
 //----------------------------------------
 
+/// Filters that are implemented in Rust. Any other filter must be provided
+/// by the calling code.
+static BUILTIN_FILTERS: &[&str] = &[
+    "text",
+    "furigana",
+    "kanji",
+    "kana",
+    "type",
+    "type-cloze",
+    "hint",
+    "cloze",
+    "",
+];
+
+/// True if the filter is handled by apply_filters().
+pub(crate) fn is_builtin_filter(filter_name: &str) -> bool {
+    BUILTIN_FILTERS.contains(&filter_name) || filter_name.starts_with("tts ")
+}
+
 /// Applies built in filters, returning the resulting text and remaining filters.
 ///
 /// The first non-standard filter that is encountered will terminate processing,
@@ -198,8 +217,8 @@ fn tts_filter(filter_name: &str, text: &str) -> Cow<'static, str> {
 mod test {
     use crate::template::RenderContext;
     use crate::template_filters::{
-        apply_filters, cloze_filter, furigana_filter, hint_filter, kana_filter, kanji_filter,
-        tts_filter, type_cloze_filter, type_filter,
+        apply_filters, cloze_filter, furigana_filter, hint_filter, is_builtin_filter, kana_filter,
+        kanji_filter, tts_filter, type_cloze_filter, type_filter,
     };
     use crate::text::strip_html;
 
@@ -238,6 +257,9 @@ field</a>
             nonempty_fields: &Default::default(),
             question_side: false,
             card_ord: 0,
+            registered_filters: None,
+            tags: &[],
+            limits: Default::default(),
         };
         assert_eq!(
             apply_filters("ignored", &["cloze", "type"], "Text", &ctx),
@@ -253,6 +275,9 @@ field</a>
             nonempty_fields: &Default::default(),
             question_side: true,
             card_ord: 0,
+            registered_filters: None,
+            tags: &[],
+            limits: Default::default(),
         };
         assert_eq!(strip_html(&cloze_filter(text, &ctx)).as_ref(), "[...] two");
         assert_eq!(
@@ -273,6 +298,14 @@ field</a>
         assert_eq!(cloze_filter(text, &ctx).as_ref(), "");
     }
 
+    #[test]
+    fn builtin() {
+        assert!(is_builtin_filter("text"));
+        assert!(is_builtin_filter("tts en_US"));
+        assert!(!is_builtin_filter("txet"));
+        assert!(!is_builtin_filter("tts"));
+    }
+
     #[test]
     fn tts() {
         assert_eq!(
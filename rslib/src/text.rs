@@ -72,6 +72,27 @@ lazy_static! {
                 (.*?)           # 3 - field text
             \[/anki:tts\]
             "#).unwrap();
+
+    static ref IMG_TAG_ANY: Regex = Regex::new(r"(?si)<img\b[^>]*>").unwrap();
+
+    static ref ALT_ATTR: Regex = Regex::new(
+        r#"(?xsi)
+            \salt\s*=\s*
+            (?:
+                "([^"]*)"       # 1 - double-quoted
+                |
+                '([^']*)'       # 2 - single-quoted
+                |
+                ([^\s>]+)       # 3 - unquoted
+            )
+            "#
+    ).unwrap();
+
+    static ref PRE_BLOCK: Regex = Regex::new(r"(?si)<pre\b.*?</pre>").unwrap();
+
+    // a run of whitespace and non-breaking spaces, which may be escaped
+    static ref WHITESPACE: Regex =
+        Regex::new(r"(?i)(?:&nbsp;|&#0*160;|&#x0*a0;|\s)+").unwrap();
 }
 
 pub fn strip_html(html: &str) -> Cow<str> {
@@ -214,6 +235,23 @@ pub fn strip_html_preserving_image_filenames(html: &str) -> Cow<str> {
     without_html.into_owned().into()
 }
 
+/// Like strip_html(), but also removes sound and TTS tags, and replaces
+/// images with their alt text, so media filenames don't affect sorting.
+pub fn strip_html_and_media(html: &str) -> Cow<str> {
+    let without_av = strip_av_tags(html);
+    let without_images = IMG_TAG_ANY.replace_all(&without_av, |caps: &Captures| {
+        ALT_ATTR
+            .captures(&caps[0])
+            .and_then(|alt| alt.get(1).or_else(|| alt.get(2)).or_else(|| alt.get(3)))
+            .map(|alt| format!(" {} ", alt.as_str()))
+            .unwrap_or_default()
+    });
+    match strip_html(&without_images) {
+        Cow::Borrowed(b) if ptr::eq(b, html) => Cow::Borrowed(html),
+        other => other.into_owned().into(),
+    }
+}
+
 pub(crate) fn normalize_to_nfc(s: &str) -> Cow<str> {
     if !is_nfc(s) {
         s.chars().nfc().collect::<String>().into()
@@ -233,6 +271,26 @@ pub(crate) fn matches_wildcard(text: &str, search: &str) -> bool {
     }
 }
 
+/// Collapse runs of whitespace and non-breaking spaces, as commonly found
+/// in text pasted from web pages, into a single space. The contents of
+/// <pre> blocks are left alone.
+pub fn normalize_whitespace(html: &str) -> Cow<str> {
+    if !PRE_BLOCK.is_match(html) {
+        return WHITESPACE.replace_all(html, " ");
+    }
+
+    let mut out = String::with_capacity(html.len());
+    let mut last_end = 0;
+    for pre in PRE_BLOCK.find_iter(html) {
+        out.push_str(&WHITESPACE.replace_all(&html[last_end..pre.start()], " "));
+        out.push_str(pre.as_str());
+        last_end = pre.end();
+    }
+    out.push_str(&WHITESPACE.replace_all(&html[last_end..], " "));
+
+    out.into()
+}
+
 /// Convert provided string to NFKD form and strip combining characters.
 pub(crate) fn without_combining(s: &str) -> Cow<str> {
     // if the string is already normalized
@@ -256,7 +314,8 @@ mod test {
     use super::matches_wildcard;
     use crate::text::without_combining;
     use crate::text::{
-        extract_av_tags, strip_av_tags, strip_html, strip_html_preserving_image_filenames, AVTag,
+        extract_av_tags, normalize_whitespace, strip_av_tags, strip_html, strip_html_and_media,
+        strip_html_preserving_image_filenames, AVTag,
     };
     use std::borrow::Cow;
 
@@ -275,6 +334,16 @@ mod test {
             " foo.jpg "
         );
         assert_eq!(strip_html_preserving_image_filenames("<html>"), "");
+
+        assert_eq!(strip_html_and_media("<b>test</b>"), "test");
+        assert_eq!(strip_html_and_media("<img src=foo.jpg>"), "");
+        assert_eq!(
+            strip_html_and_media("<img src='foo.jpg' alt=\"a &amp; b\">"),
+            " a & b "
+        );
+        assert_eq!(strip_html_and_media("<IMG alt=cat src=cat.jpg>"), " cat ");
+        assert_eq!(strip_html_and_media("a[sound:foo.mp3]b"), "ab");
+        assert_eq!(strip_html_and_media("[anki:tts][en_US]hi[/anki:tts]x"), "x");
     }
 
     #[test]
@@ -310,6 +379,29 @@ mod test {
         assert_eq!(matches_wildcard("foo", "b*"), false);
     }
 
+    #[test]
+    fn whitespace() {
+        assert!(matches!(normalize_whitespace("ab"), Cow::Borrowed(_)));
+        assert_eq!(normalize_whitespace("a b"), "a b");
+        assert_eq!(normalize_whitespace("a&nbsp;&nbsp;b"), "a b");
+        assert_eq!(normalize_whitespace("a&NBSP; &#160;\u{a0}b"), "a b");
+        assert_eq!(normalize_whitespace("a\r\n\t  b&#xA0;"), "a b ");
+
+        // pasted from a web page
+        let pasted = "<div>\n  <b>Paris</b>&nbsp;is the\n    capital&nbsp;&nbsp;of France.</div>";
+        assert_eq!(
+            normalize_whitespace(pasted),
+            "<div> <b>Paris</b> is the capital of France.</div>"
+        );
+
+        // preformatted text is kept
+        let code = "<p>See&nbsp;&nbsp;below:</p>\n<pre>fn main() {\n    x&nbsp; y\n}</pre>\n\n<PRE class=x>a  b</PRE> end";
+        assert_eq!(
+            normalize_whitespace(code),
+            "<p>See below:</p> <pre>fn main() {\n    x&nbsp; y\n}</pre> <PRE class=x>a  b</PRE> end"
+        );
+    }
+
     #[test]
     fn combining() {
         assert!(matches!(without_combining("test"), Cow::Borrowed(_)));
@@ -0,0 +1,304 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::text::{normalize_to_nfc, strip_av_tags, strip_html};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use unicode_normalization::char::is_combining_mark;
+
+lazy_static! {
+    static ref LINEBREAKS: Regex = Regex::new(r"(?i)(\n|<br ?/?>|</?div>)+").unwrap();
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AnswerComparison {
+    /// HTML showing the provided answer and the differences to the
+    /// expected answer.
+    pub html: String,
+    /// 0-100, where 100 is an exact match.
+    pub match_percent: f32,
+}
+
+/// Compare the answer typed by the user with the expected field content,
+/// returning HTML that highlights the differences.
+pub fn compare_answer(expected: &str, provided: &str) -> AnswerComparison {
+    let expected = prepare_expected(expected);
+    let expected: Vec<char> = normalize_to_nfc(&expected).chars().collect();
+    let provided: Vec<char> = normalize_to_nfc(provided).chars().collect();
+
+    let blocks = matching_blocks(&provided, &expected);
+    let matched: usize = blocks.iter().map(|b| b.size).sum();
+    let total = provided.len() + expected.len();
+    let match_percent = if total == 0 {
+        100.0
+    } else {
+        (matched * 2) as f32 / total as f32 * 100.0
+    };
+
+    let html = if provided == expected {
+        good(&provided)
+    } else {
+        let (provided_elems, expected_elems) = tokenize(&provided, &expected, &blocks);
+        let mut html = String::new();
+        for (ok, text) in provided_elems {
+            html.push_str(&if ok { good(&text) } else { bad(&text) });
+        }
+        html.push_str("<br><span id=typearrow>&darr;</span><br>");
+        for (ok, text) in expected_elems {
+            html.push_str(&if ok { good(&text) } else { missed(&text) });
+        }
+        html
+    };
+
+    AnswerComparison {
+        html: format!("<div><code id=typeans>{}</code></div>", html),
+        match_percent,
+    }
+}
+
+/// Strip media, HTML and line breaks from the field content.
+fn prepare_expected(expected: &str) -> String {
+    let text = strip_av_tags(expected);
+    let text = LINEBREAKS.replace_all(&text, " ");
+    strip_html(&text).trim().to_string()
+}
+
+fn good(text: &[char]) -> String {
+    span("typeGood", text)
+}
+
+fn bad(text: &[char]) -> String {
+    span("typeBad", text)
+}
+
+fn missed(text: &[char]) -> String {
+    span("typeMissed", text)
+}
+
+fn span(class: &str, text: &[char]) -> String {
+    let text: String = text.iter().collect();
+    format!(
+        "<span class={}>{}</span>",
+        class,
+        htmlescape::encode_minimal(&without_lone_marks(&text))
+    )
+}
+
+/// Ensure a combining character at the start does not join to the
+/// previous text.
+fn without_lone_marks(text: &str) -> Cow<str> {
+    match text.chars().next() {
+        Some(c) if is_combining_mark(c) => format!("\u{a0}{}", text).into(),
+        _ => text.into(),
+    }
+}
+
+// Diffing
+//----------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct MatchingBlock {
+    a: usize,
+    b: usize,
+    size: usize,
+}
+
+/// Split both inputs into matching and non-matching runs. Missing characters
+/// in the provided text are padded with dashes.
+fn tokenize(
+    provided: &[char],
+    expected: &[char],
+    blocks: &[MatchingBlock],
+) -> (Vec<(bool, Vec<char>)>, Vec<(bool, Vec<char>)>) {
+    let mut provided_elems = vec![];
+    let mut expected_elems = vec![];
+    let mut provided_point = 0;
+    let mut expected_point = 0;
+    let mut offby = 0;
+
+    for block in blocks {
+        let MatchingBlock { a: x, b: y, size } = *block;
+        // if anything was missed in expected, pad provided
+        if size > 0 && y - offby > x {
+            provided_elems.push((false, vec!['-'; y - x - offby]));
+            offby = y - x;
+        }
+        // log any preceding bad elements
+        if provided_point != x {
+            provided_elems.push((false, provided[provided_point..x].to_vec()));
+        }
+        if expected_point != y {
+            expected_elems.push((false, expected[expected_point..y].to_vec()));
+        }
+        provided_point = x + size;
+        expected_point = y + size;
+        // log the match
+        if size > 0 {
+            provided_elems.push((true, provided[x..x + size].to_vec()));
+            expected_elems.push((true, expected[y..y + size].to_vec()));
+        }
+    }
+
+    (provided_elems, expected_elems)
+}
+
+/// Equivalent to Python's difflib.SequenceMatcher(None, a, b,
+/// autojunk=False).get_matching_blocks(), so results match older clients.
+fn matching_blocks(a: &[char], b: &[char]) -> Vec<MatchingBlock> {
+    let mut b2j: HashMap<char, Vec<usize>> = HashMap::new();
+    for (idx, c) in b.iter().enumerate() {
+        b2j.entry(*c).or_default().push(idx);
+    }
+
+    let mut blocks = vec![];
+    let mut queue = vec![(0, a.len(), 0, b.len())];
+    while let Some((alo, ahi, blo, bhi)) = queue.pop() {
+        let block = longest_match(a, &b2j, alo, ahi, blo, bhi);
+        if block.size > 0 {
+            if alo < block.a && blo < block.b {
+                queue.push((alo, block.a, blo, block.b));
+            }
+            if block.a + block.size < ahi && block.b + block.size < bhi {
+                queue.push((block.a + block.size, ahi, block.b + block.size, bhi));
+            }
+            blocks.push(block);
+        }
+    }
+    blocks.sort();
+
+    // merge adjacent blocks
+    let mut merged: Vec<MatchingBlock> = vec![];
+    for block in blocks {
+        if let Some(last) = merged.last_mut() {
+            if last.a + last.size == block.a && last.b + last.size == block.b {
+                last.size += block.size;
+                continue;
+            }
+        }
+        merged.push(block);
+    }
+
+    merged.push(MatchingBlock {
+        a: a.len(),
+        b: b.len(),
+        size: 0,
+    });
+
+    merged
+}
+
+/// Find the longest matching block in a[alo..ahi] and b[blo..bhi],
+/// preferring the earliest match in a, then in b.
+fn longest_match(
+    a: &[char],
+    b2j: &HashMap<char, Vec<usize>>,
+    alo: usize,
+    ahi: usize,
+    blo: usize,
+    bhi: usize,
+) -> MatchingBlock {
+    let mut best = MatchingBlock {
+        a: alo,
+        b: blo,
+        size: 0,
+    };
+    // j2len[j] = length of longest match ending with a[i-1] and b[j]
+    let mut j2len: HashMap<usize, usize> = HashMap::new();
+    for (i, c) in a.iter().enumerate().take(ahi).skip(alo) {
+        let mut new_j2len = HashMap::new();
+        if let Some(indices) = b2j.get(c) {
+            for &j in indices {
+                if j < blo {
+                    continue;
+                }
+                if j >= bhi {
+                    break;
+                }
+                let k = if j > 0 {
+                    j2len.get(&(j - 1)).cloned().unwrap_or(0)
+                } else {
+                    0
+                } + 1;
+                new_j2len.insert(j, k);
+                if k > best.size {
+                    best = MatchingBlock {
+                        a: i + 1 - k,
+                        b: j + 1 - k,
+                        size: k,
+                    };
+                }
+            }
+        }
+        j2len = new_j2len;
+    }
+
+    best
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare_answer, matching_blocks, MatchingBlock};
+
+    fn blocks(a: &str, b: &str) -> Vec<(usize, usize, usize)> {
+        let a: Vec<_> = a.chars().collect();
+        let b: Vec<_> = b.chars().collect();
+        matching_blocks(&a, &b)
+            .into_iter()
+            .map(|MatchingBlock { a, b, size }| (a, b, size))
+            .collect()
+    }
+
+    #[test]
+    fn matching() {
+        // results from Python's difflib
+        assert_eq!(
+            blocks("abxcd", "abcd"),
+            vec![(0, 0, 2), (3, 2, 2), (5, 4, 0)]
+        );
+        assert_eq!(
+            blocks("qabxcd", "abycdf"),
+            vec![(1, 0, 2), (4, 3, 2), (6, 6, 0)]
+        );
+        assert_eq!(blocks("", "abc"), vec![(0, 3, 0)]);
+    }
+
+    #[test]
+    fn comparison() {
+        let cmp = compare_answer("<b>Paris</b>[sound:paris.mp3]", "Paris");
+        assert_eq!(
+            cmp.html,
+            "<div><code id=typeans><span class=typeGood>Paris</span></code></div>"
+        );
+        assert_eq!(cmp.match_percent, 100.0);
+
+        let cmp = compare_answer("abcd", "abxd");
+        assert_eq!(
+            cmp.html,
+            concat!(
+                "<div><code id=typeans>",
+                "<span class=typeGood>ab</span><span class=typeBad>x</span>",
+                "<span class=typeGood>d</span>",
+                "<br><span id=typearrow>&darr;</span><br>",
+                "<span class=typeGood>ab</span><span class=typeMissed>c</span>",
+                "<span class=typeGood>d</span>",
+                "</code></div>"
+            )
+        );
+        assert_eq!(cmp.match_percent, 75.0);
+
+        // missing characters are padded, and input is escaped
+        let cmp = compare_answer("a&lt;b", "b");
+        assert_eq!(
+            cmp.html,
+            concat!(
+                "<div><code id=typeans>",
+                "<span class=typeBad>--</span><span class=typeGood>b</span>",
+                "<br><span id=typearrow>&darr;</span><br>",
+                "<span class=typeMissed>a&lt;</span><span class=typeGood>b</span>",
+                "</code></div>"
+            )
+        );
+    }
+}
@@ -0,0 +1,10 @@
+plural = Masz {$hats ->
+     [one]   1 kapelusz
+     [few]   {$hats} kapelusze
+    *[many]  {$hats} kapeluszy
+  }.
+sync-media-checked-count = Sprawdzono: {$count ->
+     [one]   1 plik
+     [few]   {$count} pliki
+    *[many]  {$count} plików
+  }